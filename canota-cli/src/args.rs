@@ -0,0 +1,167 @@
+/**
+ * Command line arguments of the canota tool
+ */
+pub const USAGE: &str = "\
+Usage: canota [-i <interface>] [--json] <command>
+
+Commands:
+    scan                                      List the devices on the bus
+    info <id>                                 Identify a device
+    set-id <id> <new id>                      Change the short id of a device
+    mode <id> <application|bootloader>        Switch a device between its application and bootloader
+    erase <id> <start address> <length>       Erase whole pages of flash. Leaves the device in its bootloader
+    flash <id> <image> [-a <address>] [--stay-in-bootloader]
+                                              Write and verify an Intel HEX, ELF or raw binary image
+    verify <id> <image> [-a <address>]        Compare the device's flash with an image
+    reset <id>                                Restart a device
+
+Options:
+    -i <interface>    SocketCAN interface, defaults to can0
+    --json            Print results as JSON instead of text and progress bars
+    -a <address>      Where a raw binary image starts, defaults to 0x08000000
+
+Ids, addresses and lengths are decimal or hex with a 0x prefix.";
+
+pub const DEFAULT_INTERFACE: &str = "can0";
+pub const DEFAULT_START_ADDRESS: u32 = 0x0800_0000;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Scan,
+    Info { short_device_id: u8 },
+    SetId { short_device_id: u8, new_short_id: u8 },
+    Mode { short_device_id: u8, bootloader: bool },
+    Erase { short_device_id: u8, start_address: u32, length: u32 },
+    Flash { short_device_id: u8, image: String, start_address: u32, stay_in_bootloader: bool },
+    Verify { short_device_id: u8, image: String, start_address: u32 },
+    Reset { short_device_id: u8 }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub interface: String,
+    pub json: bool,
+    pub command: Command
+}
+
+fn parse_number(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok()
+    }
+}
+
+fn parse_u8(value: Option<&String>, name: &str) -> Result<u8, String> {
+    let value = value.ok_or(format!("Missing {}", name))?;
+    parse_number(value).filter(|number| *number <= u8::MAX as u64).map(|number| number as u8)
+        .ok_or(format!("Invalid {}: {}", name, value))
+}
+
+fn parse_u32(value: Option<&String>, name: &str) -> Result<u32, String> {
+    let value = value.ok_or(format!("Missing {}", name))?;
+    parse_number(value).filter(|number| *number <= u32::MAX as u64).map(|number| number as u32)
+        .ok_or(format!("Invalid {}: {}", name, value))
+}
+
+impl Args {
+    /**
+     * @param args the arguments after the program name
+     */
+    pub fn parse(args: &[String]) -> Result<Args, String> {
+        let mut interface = String::from(DEFAULT_INTERFACE);
+        let mut json = false;
+        let mut start_address = DEFAULT_START_ADDRESS;
+        let mut stay_in_bootloader = false;
+        let mut positional: Vec<&String> = Vec::new();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-i" => interface = args.next().ok_or("Missing interface")?.clone(),
+                "-a" => start_address = parse_u32(args.next(), "address")?,
+                "--json" => json = true,
+                "--stay-in-bootloader" => stay_in_bootloader = true,
+                option if option.starts_with('-') && option.len() > 1 => return Err(format!("Unknown option {}", option)),
+                _ => positional.push(arg)
+            }
+        }
+
+        let name = positional.first().ok_or("Missing command")?.as_str();
+        let arg = |index: usize| positional.get(index).copied();
+        let expected_args = match name {
+            "scan" => 1,
+            "info" | "reset" => 2,
+            "set-id" | "mode" | "flash" | "verify" => 3,
+            "erase" => 4,
+            _ => return Err(format!("Unknown command {}", name))
+        };
+        if positional.len() != expected_args {
+            return Err(format!("Wrong number of arguments for {}", name));
+        }
+
+        let command = match name {
+            "scan" => Command::Scan,
+            "info" => Command::Info { short_device_id: parse_u8(arg(1), "id")? },
+            "reset" => Command::Reset { short_device_id: parse_u8(arg(1), "id")? },
+            "set-id" => Command::SetId { short_device_id: parse_u8(arg(1), "id")?, new_short_id: parse_u8(arg(2), "new id")? },
+            "mode" => Command::Mode {
+                short_device_id: parse_u8(arg(1), "id")?,
+                bootloader: match arg(2).map(|mode| mode.as_str()) {
+                    Some("application") => false,
+                    Some("bootloader") => true,
+                    _ => return Err(String::from("Mode must be application or bootloader"))
+                }
+            },
+            "erase" => Command::Erase {
+                short_device_id: parse_u8(arg(1), "id")?,
+                start_address: parse_u32(arg(2), "start address")?,
+                length: parse_u32(arg(3), "length")?
+            },
+            "flash" => Command::Flash {
+                short_device_id: parse_u8(arg(1), "id")?,
+                image: arg(2).cloned().unwrap_or_default(),
+                start_address,
+                stay_in_bootloader
+            },
+            _ => Command::Verify {
+                short_device_id: parse_u8(arg(1), "id")?,
+                image: arg(2).cloned().unwrap_or_default(),
+                start_address
+            }
+        };
+        Ok(Args { interface, json, command })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Args, String> {
+        Args::parse(&args.split_whitespace().map(String::from).collect::<Vec<String>>())
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse("scan").unwrap(), Args { interface: String::from("can0"), json: false, command: Command::Scan });
+        assert_eq!(parse("-i vcan0 --json info 0x1F").unwrap().command, Command::Info { short_device_id: 0x1F });
+        assert_eq!(parse("erase 3 0x08004000 4096").unwrap().command, Command::Erase { short_device_id: 3, start_address: 0x0800_4000, length: 4096 });
+        assert_eq!(parse("flash 3 app.bin -a 0x08008000 --stay-in-bootloader").unwrap().command, Command::Flash {
+            short_device_id: 3,
+            image: String::from("app.bin"),
+            start_address: 0x0800_8000,
+            stay_in_bootloader: true
+        });
+        assert_eq!(parse("mode 2 bootloader").unwrap().command, Command::Mode { short_device_id: 2, bootloader: true });
+    }
+
+    #[test]
+    fn reject_invalid_arguments() {
+        assert!(parse("").is_err());
+        assert!(parse("info").is_err());
+        assert!(parse("info 256").is_err());
+        assert!(parse("mode 2 sleep").is_err());
+        assert!(parse("scan --verbose").is_err());
+        assert!(parse("program 2").is_err());
+    }
+}
@@ -0,0 +1,187 @@
+/**
+ * Runs a command against the devices on a bus. Every command produces JSON for --json and text
+ * for people reading the terminal.
+ */
+use crate::args::Command;
+use canota::{ crc32, CanBus, Canota, CanotaDevice, CanotaDeviceInfo, DeviceMode, FirmwareImage, FlashDevice, FlashProgress };
+use json::{ JsonValue, object };
+
+pub struct Output {
+    pub json: JsonValue,
+    pub text: String
+}
+
+fn device_to_json(device: &CanotaDeviceInfo) -> JsonValue {
+    object!{
+        short_device_id: device.short_device_id(),
+        device_id: device.device_id(),
+        mode: format!("{:?}", device.mode()),
+        device_type: format!("{:?}", device.device_type()),
+        version: device.device_version().to_string(),
+    }
+}
+
+fn device_to_text(device: &CanotaDeviceInfo) -> String {
+    format!("{:>3}  {:#010x}  {:<12} {:<12} v{}",
+        device.short_device_id(), device.device_id(), format!("{:?}", device.mode()), format!("{:?}", device.device_type()), device.device_version())
+}
+
+fn load_image(path: &str, start_address: u32) -> Result<FirmwareImage, String> {
+    FirmwareImage::load(path, start_address).map_err(|err| format!("Unable to load {}: {:?}", path, err))
+}
+
+fn enter_bootloader<B: CanBus>(device: &mut CanotaDevice<'_, B>) -> Result<(), String> {
+    if device.info().mode() != &DeviceMode::Bootloader {
+        device.set_mode(DeviceMode::Bootloader).map_err(|err| format!("Unable to enter the bootloader: {:?}", err))?;
+    }
+    Ok(())
+}
+
+/**
+ * Compare each segment of the image with the checksum computed by the device
+ */
+fn verify_segments<B: CanBus>(device: &mut CanotaDevice<'_, B>, image: &FirmwareImage) -> Result<(bool, JsonValue), String> {
+    let mut matches = true;
+    let mut segments = JsonValue::new_array();
+    for segment in image.segments() {
+        let expected = crc32::crc32(&segment.data);
+        let actual = device.checksum(segment.start_address, segment.length()).map_err(|err| format!("Checksum failed: {:?}", err))?;
+        matches &= expected == actual;
+        segments.push(object!{
+            start_address: segment.start_address,
+            length: segment.length(),
+            expected: expected,
+            actual: actual,
+        }).expect("segments is an array");
+    }
+    Ok((matches, segments))
+}
+
+pub fn run<B: CanBus>(canota: &mut Canota<B>, command: &Command, mut progress: impl FnMut(FlashProgress)) -> Result<Output, String> {
+    let device_error = |err| format!("{:?}", err);
+    match command {
+        Command::Scan => {
+            let inventory = canota.scan().map_err(|err| format!("Scan failed: {:?}", err))?;
+            let mut text: Vec<String> = inventory.devices().iter().map(device_to_text).collect();
+            for collision in inventory.collisions() {
+                text.push(format!("Short id {} is used by {} devices", collision.short_device_id, collision.device_ids.len()));
+            }
+            if inventory.is_empty() {
+                text.push(String::from("No devices found"));
+            }
+            Ok(Output {
+                json: object!{
+                    devices: inventory.devices().iter().map(device_to_json).collect::<Vec<JsonValue>>(),
+                    collisions: inventory.collisions().iter().map(|collision| collision.short_device_id).collect::<Vec<u8>>(),
+                },
+                text: text.join("\n")
+            })
+        },
+        Command::Info { short_device_id } => {
+            let info = canota.info(*short_device_id).map_err(device_error)?;
+            Ok(Output { json: device_to_json(&info), text: device_to_text(&info) })
+        },
+        Command::SetId { short_device_id, new_short_id } => {
+            canota.change_short_id(*short_device_id, *new_short_id).map_err(device_error)?;
+            Ok(Output {
+                json: object!{ short_device_id: *new_short_id },
+                text: format!("Device {} is now {}", short_device_id, new_short_id)
+            })
+        },
+        Command::Mode { short_device_id, bootloader } => {
+            let mode = if *bootloader { DeviceMode::Bootloader } else { DeviceMode::Application };
+            canota.set_mode(*short_device_id, mode.clone()).map_err(device_error)?;
+            Ok(Output {
+                json: object!{ short_device_id: *short_device_id, mode: format!("{:?}", mode) },
+                text: format!("Device {} is in {:?} mode", short_device_id, mode)
+            })
+        },
+        Command::Erase { short_device_id, start_address, length } => {
+            let mut device = canota.device(*short_device_id).map_err(device_error)?;
+            enter_bootloader(&mut device)?;
+            device.erase_section(*start_address, *length).map_err(device_error)?;
+            Ok(Output {
+                json: object!{ short_device_id: *short_device_id, start_address: *start_address, length: *length },
+                text: format!("Erased {} bytes at {:#010x}", length, start_address)
+            })
+        },
+        Command::Flash { short_device_id, image, start_address, stay_in_bootloader } => {
+            let image = load_image(image, *start_address)?;
+            let mut device = canota.device(*short_device_id).map_err(device_error)?;
+            enter_bootloader(&mut device)?;
+            device.flash_image(&image, &mut progress).map_err(|err| format!("Flashing failed: {:?}", err))?;
+            if !stay_in_bootloader {
+                device.set_mode(DeviceMode::Application).map_err(|err| format!("Image verified but the device did not start it: {:?}", err))?;
+            }
+            Ok(Output {
+                json: object!{ short_device_id: *short_device_id, bytes_written: image.total_length(), segments: image.segments().len() },
+                text: format!("Wrote and verified {} bytes in {} segments", image.total_length(), image.segments().len())
+            })
+        },
+        Command::Verify { short_device_id, image, start_address } => {
+            let image = load_image(image, *start_address)?;
+            let mut device = canota.device(*short_device_id).map_err(device_error)?;
+            let mode = device.info().mode().clone();
+            enter_bootloader(&mut device)?;
+            let (matches, segments) = verify_segments(&mut device, &image)?;
+            if mode == DeviceMode::Application {
+                device.set_mode(DeviceMode::Application).map_err(device_error)?;
+            }
+            Ok(Output {
+                json: object!{ short_device_id: *short_device_id, matches: matches, segments: segments },
+                text: if matches { String::from("Flash matches the image") } else { String::from("Flash does not match the image") }
+            })
+        },
+        Command::Reset { short_device_id } => {
+            canota.reset_device(*short_device_id).map_err(device_error)?;
+            Ok(Output { json: object!{ short_device_id: *short_device_id }, text: format!("Device {} restarted", short_device_id) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use canota::simulator::{ SimulatedBus, SimulatedDevice, SimulatedDeviceInitializer };
+
+    fn canota() -> Canota<SimulatedBus> {
+        Canota::with_bus(SimulatedBus::new(vec![
+            SimulatedDevice::new(SimulatedDeviceInitializer { short_device_id: 2, ..Default::default() }),
+            SimulatedDevice::new(SimulatedDeviceInitializer { short_device_id: 7, device_id: 0x77, ..Default::default() })
+        ]))
+    }
+
+    #[test]
+    fn scan_and_set_id() {
+        let mut canota = canota();
+        let output = run(&mut canota, &Command::Scan, |_| {}).unwrap();
+        assert_eq!(output.json["devices"].len(), 2);
+        assert_eq!(output.json["devices"][1]["device_id"], 0x77);
+
+        run(&mut canota, &Command::SetId { short_device_id: 7, new_short_id: 9 }, |_| {}).unwrap();
+        let output = run(&mut canota, &Command::Info { short_device_id: 9 }, |_| {}).unwrap();
+        assert_eq!(output.json["version"], "1.0");
+        assert!(run(&mut canota, &Command::Info { short_device_id: 7 }, |_| {}).is_err());
+    }
+
+    #[test]
+    fn flash_and_verify() {
+        let path = std::env::temp_dir().join(format!("canota-cli-test-{}.bin", std::process::id()));
+        std::fs::write(&path, vec![0x5A; 3000]).unwrap();
+        let image = path.to_string_lossy().into_owned();
+
+        let mut canota = canota();
+        let mut steps = 0;
+        let flash = Command::Flash { short_device_id: 2, image: image.clone(), start_address: 0x0800_8000, stay_in_bootloader: false };
+        let output = run(&mut canota, &flash, |_| steps += 1).unwrap();
+        assert_eq!(output.json["bytes_written"], 3000);
+        assert!(steps > 0);
+        assert_eq!(canota.bus().device(2).unwrap().info().mode(), &DeviceMode::Application);
+
+        let verify = Command::Verify { short_device_id: 2, image: image.clone(), start_address: 0x0800_8000 };
+        assert_eq!(run(&mut canota, &verify, |_| {}).unwrap().json["matches"], true);
+        let verify = Command::Verify { short_device_id: 7, image, start_address: 0x0800_8000 };
+        assert_eq!(run(&mut canota, &verify, |_| {}).unwrap().json["matches"], false);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+/**
+ * Scan, configure and flash canota devices from the command line.
+ * Run `canota` without arguments for the list of commands.
+ */
+mod args;
+mod commands;
+mod progress;
+
+use args::{ Args, USAGE };
+use progress::ProgressBar;
+
+#[cfg(unix)]
+fn run(args: &Args) -> Result<commands::Output, String> {
+    let mut canota = canota::Canota::new(&args.interface).map_err(|err| format!("Unable to open {}: {:?}", args.interface, err))?;
+    let mut progress_bar = ProgressBar::new(!args.json);
+    let output = commands::run(&mut canota, &args.command, |progress| progress_bar.update(progress));
+    progress_bar.finish();
+    output
+}
+
+#[cfg(not(unix))]
+fn run(_args: &Args) -> Result<commands::Output, String> {
+    Err(String::from("SocketCAN is only available on Linux"))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let args = match Args::parse(&args[1..]) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(output) if args.json => println!("{}", output.json.dump()),
+        Ok(output) => println!("{}", output.text),
+        Err(error) if args.json => {
+            println!("{}", json::object!{ error: error.as_str() }.dump());
+            std::process::exit(1);
+        },
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+/**
+ * Progress of a flashing job on stderr, redrawn in place
+ */
+use canota::FlashProgress;
+use std::io::Write;
+
+const WIDTH: usize = 40;
+
+pub struct ProgressBar {
+    enabled: bool,
+    drawn: bool
+}
+
+/**
+ * @param done out of total, clamped to total
+ */
+fn render(label: &str, done: usize, total: usize) -> String {
+    let fraction = if total == 0 { 1.0 } else { done.min(total) as f64 / total as f64 };
+    let filled = (fraction * WIDTH as f64).round() as usize;
+    format!("{:<10} [{}{}] {:>3}%", label, "#".repeat(filled), "-".repeat(WIDTH - filled), (fraction * 100.0).round() as usize)
+}
+
+impl ProgressBar {
+    pub fn new(enabled: bool) -> ProgressBar {
+        ProgressBar { enabled, drawn: false }
+    }
+
+    pub fn update(&mut self, progress: FlashProgress) {
+        if !self.enabled {
+            return;
+        }
+        let line = match progress {
+            FlashProgress::Erasing { start_address, length } => format!("Erasing {} bytes at {:#010x}", length, start_address),
+            FlashProgress::Writing { bytes_written, total_bytes } => render("Writing", bytes_written, total_bytes),
+            FlashProgress::Verified { start_address, checksum } => format!("Verified {:#010x} (crc {:#010x})", start_address, checksum)
+        };
+        let mut stderr = std::io::stderr();
+        // Clear the rest of the previous line, which can be longer
+        let _ = write!(stderr, "\r{:<width$}", line, width = WIDTH + 20);
+        let _ = stderr.flush();
+        self.drawn = true;
+    }
+
+    pub fn finish(&mut self) {
+        if self.drawn {
+            eprintln!();
+            self.drawn = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_bar() {
+        assert_eq!(render("Writing", 0, 10), format!("Writing    [{}]   0%", "-".repeat(WIDTH)));
+        assert_eq!(render("Writing", 5, 10), format!("Writing    [{}{}]  50%", "#".repeat(WIDTH / 2), "-".repeat(WIDTH / 2)));
+        assert_eq!(render("Writing", 12, 10), format!("Writing    [{}] 100%", "#".repeat(WIDTH)));
+    }
+}
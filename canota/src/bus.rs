@@ -0,0 +1,117 @@
+/**
+ * The CAN bus that canota talks over.
+ *
+ * Canota only needs to send frames and to wait for the next frame with a timeout, so any bus which
+ * can do that can be used to flash devices, including an in-memory bus in tests.
+ */
+use crate::Error;
+use std::time::Duration;
+
+pub const MAX_DATA_LENGTH: usize = 8;
+
+/**
+ * A classic CAN frame. Canota only uses 29 bit extended identifiers.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CanFrame {
+    id: u32,
+    data: [u8; MAX_DATA_LENGTH],
+    length: u8
+}
+
+impl CanFrame {
+    /**
+     * Panics if data is longer than the 8 bytes a frame can carry
+     */
+    pub fn new(id: u32, data: &[u8]) -> CanFrame {
+        assert!(data.len() <= MAX_DATA_LENGTH, "A CAN frame carries at most {} bytes", MAX_DATA_LENGTH);
+        let mut frame = CanFrame { id, data: [0; MAX_DATA_LENGTH], length: data.len() as u8 };
+        frame.data[..data.len()].copy_from_slice(data);
+        frame
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.length as usize]
+    }
+}
+
+pub trait CanBus {
+    fn send(&mut self, frame: &CanFrame) -> Result<(), Error>;
+
+    /**
+     * Wait up to timeout for the next frame. Returns None if no frame arrived in time.
+     */
+    fn receive(&mut self, timeout: Duration) -> Result<Option<CanFrame>, Error>;
+}
+
+impl<B: CanBus + ?Sized> CanBus for &mut B {
+    fn send(&mut self, frame: &CanFrame) -> Result<(), Error> {
+        (**self).send(frame)
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Result<Option<CanFrame>, Error> {
+        (**self).receive(timeout)
+    }
+}
+
+#[cfg(all(unix, feature = "socketcan"))]
+pub use self::socket::SocketCanBus;
+
+#[cfg(all(unix, feature = "socketcan"))]
+mod socket {
+    use super::{ CanBus, CanFrame };
+    use crate::Error;
+    use std::time::Duration;
+
+    /**
+     * A SocketCAN interface such as can0
+     */
+    pub struct SocketCanBus {
+        socket: socketcan::CANSocket
+    }
+
+    impl SocketCanBus {
+        pub fn open(can_interface: &str) -> Result<SocketCanBus, Error> {
+            let socket = socketcan::CANSocket::open(can_interface).map_err(|_| Error::InitializationError(errno::errno()))?;
+            Ok(SocketCanBus { socket })
+        }
+    }
+
+    impl CanBus for SocketCanBus {
+        fn send(&mut self, frame: &CanFrame) -> Result<(), Error> {
+            let frame = socketcan::CANFrame::new(frame.id(), frame.data(), false, false)
+                .expect("CanFrame is always a valid SocketCAN frame");
+            Ok(self.socket.write_frame_insist(&frame)?)
+        }
+
+        fn receive(&mut self, timeout: Duration) -> Result<Option<CanFrame>, Error> {
+            // A zero timeout would block forever
+            self.socket.set_read_timeout(timeout.max(Duration::from_micros(1)))?;
+            loop {
+                match self.socket.read_frame() {
+                    Ok(frame) if frame.is_error() || frame.is_rtr() => continue,
+                    Ok(frame) => return Ok(Some(CanFrame::new(frame.id(), frame.data()))),
+                    Err(error) if error.kind() == std::io::ErrorKind::WouldBlock || error.kind() == std::io::ErrorKind::TimedOut => return Ok(None),
+                    Err(error) => return Err(Error::Bus(error))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_data() {
+        let frame = CanFrame::new(0x1F01_0003, &[1, 2, 3]);
+        assert_eq!(frame.id(), 0x1F01_0003);
+        assert_eq!(frame.data(), &[1, 2, 3]);
+        assert!(CanFrame::new(0, &[]).data().is_empty());
+    }
+}
@@ -0,0 +1,391 @@
+/**
+ * Canota over any CanBus.
+ */
+use crate::bus::{ CanBus, CanFrame };
+use crate::protocol;
+use crate::{ data_length, crc32, CanotaDeviceInfo, DeviceMode, Error, FlashDevice, FlashMap, Inventory };
+use std::time::{ Duration, Instant };
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+pub const DEFAULT_SCAN_WINDOW: Duration = Duration::from_millis(500);
+// Erasing a page of the STM32L432KC takes up to 25ms
+const ERASE_TIME_PER_PAGE: Duration = Duration::from_millis(25);
+const MODE_SWITCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Canota<B: CanBus> {
+    bus: B,
+    timeout: Duration,
+    scan_window: Duration
+}
+
+#[cfg(all(unix, feature = "socketcan"))]
+impl Canota<crate::bus::SocketCanBus> {
+    pub fn new(can_interface: &str) -> Result<Canota<crate::bus::SocketCanBus>, Error> {
+        Ok(Canota::with_bus(crate::bus::SocketCanBus::open(can_interface)?))
+    }
+}
+
+impl<B: CanBus> Canota<B> {
+    pub fn with_bus(bus: B) -> Canota<B> {
+        Canota { bus, timeout: DEFAULT_TIMEOUT, scan_window: DEFAULT_SCAN_WINDOW }
+    }
+
+    /**
+     * How long to wait for a device to answer a request
+     */
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /**
+     * How long a scan listens for identification responses
+     */
+    pub fn set_scan_window(&mut self, scan_window: Duration) {
+        self.scan_window = scan_window;
+    }
+
+    pub fn bus(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    /**
+     * Wait for the response to command from short_device_id, skipping any other traffic on the bus
+     */
+    fn receive_response(&mut self, short_device_id: u8, command: u8, timeout: Duration) -> Result<CanFrame, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.bus.receive(remaining)? {
+                Some(frame) if protocol::is_canota_frame(&frame)
+                    && protocol::command(&frame) == command
+                    && protocol::short_device_id(&frame) == short_device_id => return Ok(frame),
+                Some(_) if !remaining.is_zero() => continue,
+                _ => return Err(Error::Timeout { short_device_id, command })
+            }
+        }
+    }
+
+    /**
+     * Send a request and check the status of the device's response
+     */
+    fn request_with_status(&mut self, request: CanFrame, timeout: Duration) -> Result<(), Error> {
+        let short_device_id = protocol::short_device_id(&request);
+        let command = protocol::command(&request);
+        self.bus.send(&request)?;
+        let response = self.receive_response(short_device_id, command, timeout)?;
+        match protocol::parse_status(&response) {
+            Some(protocol::STATUS_OK) => Ok(()),
+            status => Err(Error::DeviceError { short_device_id, command, status: status.unwrap_or(0xFF) })
+        }
+    }
+
+    fn identify(&mut self, short_device_id: u8, timeout: Duration) -> Result<CanotaDeviceInfo, Error> {
+        self.bus.send(&protocol::device_ident_request(short_device_id))?;
+        let response = self.receive_response(short_device_id, protocol::DEVICE_IDENT, timeout)?;
+        protocol::parse_ident_response(&response).ok_or(Error::RawCommandReadError)
+    }
+
+    /**
+     * Get a handle to a device. The device is identified first, so this fails if it does not answer.
+     * The handle borrows the bus, so only one device can be used at a time.
+     */
+    pub fn device(&mut self, short_device_id: u8) -> Result<CanotaDevice<'_, B>, Error> {
+        let timeout = self.timeout;
+        let info = self.identify(short_device_id, timeout)
+            .map_err(|_| Error::DeviceUnavailable { short_device_id })?;
+        Ok(CanotaDevice { canota: self, info })
+    }
+
+    pub fn info(&mut self, short_device_id: u8) -> Result<CanotaDeviceInfo, Error> {
+        Ok(self.device(short_device_id)?.info())
+    }
+
+    /**
+     * Ask every short id to identify itself and collect the answers until the bus goes quiet or the
+     * scan window ends, whichever is first. The window is counted from the first request so that
+     * other traffic on the bus can not keep the scan going.
+     */
+    pub fn scan(&mut self) -> Result<Inventory, Error> {
+        let deadline = Instant::now() + self.scan_window;
+        for short_device_id in 0..=255 {
+            self.bus.send(&protocol::device_ident_request(short_device_id))?;
+        }
+        let mut responses = Vec::new();
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            match self.bus.receive(remaining.min(self.timeout))? {
+                Some(frame) => responses.extend(protocol::parse_ident_response(&frame)),
+                None => break
+            }
+        }
+        Ok(Inventory::from_responses(responses))
+    }
+
+    pub fn change_short_id(&mut self, old_short_id: u8, new_short_id: u8) -> Result<(), Error> {
+        self.device(old_short_id)?.change_short_id(new_short_id)
+    }
+
+    pub fn flash_map(&mut self, short_device_id: u8) -> Result<FlashMap, Error> {
+        self.device(short_device_id)?.flash_map()
+    }
+
+    pub fn set_mode(&mut self, short_device_id: u8, mode: DeviceMode) -> Result<(), Error> {
+        self.device(short_device_id)?.set_mode(mode)
+    }
+
+    pub fn reset_device(&mut self, short_device_id: u8) -> Result<(), Error> {
+        self.device(short_device_id)?.reset()
+    }
+
+    pub fn erase_section(&mut self, short_device_id: u8, start_address: u32, length: u32) -> Result<(), Error> {
+        self.device(short_device_id)?.erase_section(start_address, length)
+    }
+
+    pub fn write_section(&mut self, short_device_id: u8, start_address: u32, data: &[u8]) -> Result<(), Error> {
+        self.device(short_device_id)?.write_section(start_address, data)
+    }
+
+    pub fn get_checksum_for_section(&mut self, short_device_id: u8, start_address: u32, length: u32) -> Result<u32, Error> {
+        self.device(short_device_id)?.checksum(start_address, length)
+    }
+
+    pub fn flash_board(&mut self, short_device_id: u8, start_address: u32, data: &[u8]) -> Result<(), Error> {
+        self.device(short_device_id)?.flash(start_address, data)
+    }
+}
+
+/**
+ * A device which answered an identification request
+ */
+pub struct CanotaDevice<'a, B: CanBus> {
+    canota: &'a mut Canota<B>,
+    info: CanotaDeviceInfo
+}
+
+impl<'a, B: CanBus> CanotaDevice<'a, B> {
+    pub fn short_device_id(&self) -> u8 {
+        self.info.short_device_id
+    }
+
+    /**
+     * The device as it identified itself when the handle was created or after its last mode switch
+     */
+    pub fn info(&self) -> CanotaDeviceInfo {
+        self.info.clone()
+    }
+
+    pub fn change_short_id(&mut self, new_short_id: u8) -> Result<(), Error> {
+        let timeout = self.canota.timeout;
+        self.canota.request_with_status(protocol::change_short_id_request(self.short_device_id(), new_short_id), timeout)
+            .map_err(|_| Error::ErrorChangingDeviceShortId)?;
+        self.info.short_device_id = new_short_id;
+        Ok(())
+    }
+
+    /**
+     * Wait for the device to restart and identify itself, optionally in a given mode
+     */
+    fn wait_for_restart(&mut self, mode: Option<&DeviceMode>) -> Result<(), Error> {
+        let deadline = Instant::now() + MODE_SWITCH_TIMEOUT;
+        let timeout = self.canota.timeout;
+        while Instant::now() < deadline {
+            match self.canota.identify(self.short_device_id(), timeout) {
+                Ok(info) if mode.is_none() || mode == Some(&info.mode) => {
+                    self.info = info;
+                    return Ok(());
+                },
+                Ok(_) => std::thread::sleep(timeout),
+                Err(Error::Timeout { .. }) => {},
+                Err(error) => return Err(error)
+            }
+        }
+        Err(Error::Timeout { short_device_id: self.short_device_id(), command: protocol::DEVICE_IDENT })
+    }
+
+    /**
+     * Switch the device between its application and its bootloader.
+     * Waits for the device to identify itself in the new mode.
+     */
+    pub fn set_mode(&mut self, mode: DeviceMode) -> Result<(), Error> {
+        let mode_value = protocol::mode_value(&mode)?;
+        let short_device_id = self.short_device_id();
+        self.canota.bus.send(&protocol::mode_switch_request(short_device_id, mode_value))?;
+        self.wait_for_restart(Some(&mode)).map_err(|_| Error::ModeSwitchError { short_device_id, mode })
+    }
+
+    /**
+     * Restart the device without changing its mode
+     */
+    pub fn reset(&mut self) -> Result<(), Error> {
+        let short_device_id = self.short_device_id();
+        self.canota.bus.send(&protocol::mode_switch_request(short_device_id, protocol::MODE_RESET_ONLY))?;
+        self.wait_for_restart(None).map_err(|_| Error::ResetError { short_device_id })
+    }
+
+    /**
+     * Fill the device's write buffer with whole words and write it to flash at address
+     */
+    #[allow(clippy::manual_div_ceil)]
+    fn write_buffer(&mut self, address: u32, words: &[u8]) -> Result<(), Error> {
+        let short_device_id = self.short_device_id();
+        for (index, word) in words.chunks(protocol::WORD_SIZE).enumerate() {
+            let mut padded = [protocol::ERASED_BYTE; protocol::WORD_SIZE];
+            padded[..word.len()].copy_from_slice(word);
+            self.canota.bus.send(&protocol::buffer_write_request(short_device_id, index as u8, &padded))?;
+        }
+        let word_count = (words.len() + protocol::WORD_SIZE - 1) / protocol::WORD_SIZE;
+        let mut buffer = words.to_vec();
+        buffer.resize(word_count * protocol::WORD_SIZE, protocol::ERASED_BYTE);
+        let request = protocol::buffer_commit_request(short_device_id, address, crc32::crc32(&buffer), word_count as u8);
+        let timeout = self.canota.timeout;
+        self.canota.request_with_status(request, timeout)
+    }
+}
+
+impl<'a, B: CanBus> FlashDevice for CanotaDevice<'a, B> {
+    fn flash_map(&self) -> Result<FlashMap, Error> {
+        FlashMap::for_device_type(&self.info.device_type).ok_or_else(|| Error::UnsupportedDeviceType(self.info.device_type.clone()))
+    }
+
+    fn erase_section(&mut self, start_address: u32, length: u32) -> Result<(), Error> {
+        let flash_map = self.flash_map()?;
+        flash_map.validate_erase_range(start_address, length)?;
+        let pages = length / flash_map.page_size;
+        let timeout = self.canota.timeout + ERASE_TIME_PER_PAGE * pages;
+        self.canota.request_with_status(protocol::flash_erase_request(self.short_device_id(), start_address, length), timeout)
+    }
+
+    /**
+     * Flash is written a word at a time, so start_address needs to be word aligned.
+     * The last word is padded with the value of erased flash.
+     */
+    #[allow(clippy::manual_is_multiple_of, clippy::manual_div_ceil)]
+    fn write_section(&mut self, start_address: u32, data: &[u8]) -> Result<(), Error> {
+        let length = data_length(start_address, data)?;
+        if start_address as usize % protocol::WORD_SIZE != 0 {
+            return Err(Error::InvalidAddressRange { start_address, length });
+        }
+        let padded_length = (length + protocol::WORD_SIZE as u32 - 1) / protocol::WORD_SIZE as u32 * protocol::WORD_SIZE as u32;
+        self.flash_map()?.validate_range(start_address, padded_length)?;
+        for (index, chunk) in data.chunks(protocol::BUFFER_SIZE).enumerate() {
+            self.write_buffer(start_address + (index * protocol::BUFFER_SIZE) as u32, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn checksum(&mut self, start_address: u32, length: u32) -> Result<u32, Error> {
+        self.flash_map()?.validate_range(start_address, length)?;
+        let short_device_id = self.short_device_id();
+        self.canota.bus.send(&protocol::flash_checksum_request(short_device_id, start_address, length))?;
+        // The device reads the whole section, which takes about as long as erasing it
+        let timeout = self.canota.timeout + ERASE_TIME_PER_PAGE * (length / self.flash_map()?.page_size + 1);
+        let response = self.canota.receive_response(short_device_id, protocol::FLASH_CHECKSUM, timeout)?;
+        match protocol::parse_checksum_response(&response) {
+            Some((checksum, protocol::STATUS_OK)) => Ok(checksum),
+            Some((_, status)) => Err(Error::DeviceError { short_device_id, command: protocol::FLASH_CHECKSUM, status }),
+            None => Err(Error::ChecksumError { short_device_id, start_address })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /**
+     * Answers every frame that is sent with the frames returned by respond
+     */
+    struct ScriptedBus<F: FnMut(&CanFrame) -> Vec<CanFrame>> {
+        respond: F,
+        sent: Vec<CanFrame>,
+        pending: VecDeque<CanFrame>
+    }
+
+    impl<F: FnMut(&CanFrame) -> Vec<CanFrame>> ScriptedBus<F> {
+        fn new(respond: F) -> ScriptedBus<F> {
+            ScriptedBus { respond, sent: Vec::new(), pending: VecDeque::new() }
+        }
+    }
+
+    impl<F: FnMut(&CanFrame) -> Vec<CanFrame>> CanBus for ScriptedBus<F> {
+        fn send(&mut self, frame: &CanFrame) -> Result<(), Error> {
+            self.sent.push(*frame);
+            let responses = (self.respond)(frame);
+            self.pending.extend(responses);
+            Ok(())
+        }
+
+        fn receive(&mut self, _timeout: Duration) -> Result<Option<CanFrame>, Error> {
+            Ok(self.pending.pop_front())
+        }
+    }
+
+    fn ident(short_device_id: u8, mode: u8) -> CanFrame {
+        CanFrame::new(protocol::frame_id(protocol::DEVICE_IDENT, mode, short_device_id), &[0, 1, 2, 0, 0xEF, 0xBE, 0xAD, 0xDE])
+    }
+
+    #[test]
+    fn scan_collects_ident_responses() {
+        let mut canota = Canota::with_bus(ScriptedBus::new(|frame: &CanFrame| {
+            match protocol::short_device_id(frame) {
+                3 | 9 => vec![ident(protocol::short_device_id(frame), protocol::MODE_APPLICATION)],
+                _ => Vec::new()
+            }
+        }));
+        let inventory = canota.scan().unwrap();
+        assert_eq!(canota.bus().sent.len(), 256);
+        assert_eq!(inventory.devices().iter().map(|device| device.short_device_id()).collect::<Vec<u8>>(), vec![3, 9]);
+        assert_eq!(inventory.devices()[0].device_id(), 0xDEAD_BEEF);
+    }
+
+    #[test]
+    fn missing_device() {
+        let mut canota = Canota::with_bus(ScriptedBus::new(|_: &CanFrame| Vec::new()));
+        assert!(matches!(canota.device(4), Err(Error::DeviceUnavailable { short_device_id: 4 })));
+    }
+
+    #[test]
+    fn write_fills_and_commits_buffer() {
+        let mut canota = Canota::with_bus(ScriptedBus::new(|frame: &CanFrame| {
+            match protocol::command(frame) {
+                protocol::DEVICE_IDENT => vec![ident(1, protocol::MODE_BOOTLOADER)],
+                protocol::BUFFER_COMMIT => vec![protocol::status_response(frame, protocol::STATUS_OK)],
+                _ => Vec::new()
+            }
+        }));
+        let data: Vec<u8> = (0..300).map(|byte| byte as u8).collect();
+        canota.write_section(1, 0x0800_0000, &data).unwrap();
+
+        let sent = &canota.bus().sent;
+        let commits: Vec<&CanFrame> = sent.iter().filter(|frame| protocol::command(frame) == protocol::BUFFER_COMMIT).collect();
+        assert_eq!(commits.len(), 2);
+        assert_eq!(protocol::param(commits[0]), protocol::BUFFER_WORDS as u8);
+        assert_eq!(protocol::param(commits[1]), 6); // 44 bytes round up to 6 words
+        let mut last_buffer = data[256..].to_vec();
+        last_buffer.resize(48, protocol::ERASED_BYTE);
+        assert_eq!(protocol::parse_address_and_value(commits[1]), Some((0x0800_0100, crc32::crc32(&last_buffer))));
+        assert_eq!(sent.iter().filter(|frame| protocol::command(frame) == protocol::BUFFER_WRITE).count(), 38);
+
+        assert!(matches!(canota.write_section(1, 0x0800_0004, &data), Err(Error::InvalidAddressRange { .. })));
+    }
+
+    #[test]
+    fn device_errors() {
+        let mut canota = Canota::with_bus(ScriptedBus::new(|frame: &CanFrame| {
+            match protocol::command(frame) {
+                protocol::DEVICE_IDENT => vec![ident(1, protocol::MODE_BOOTLOADER)],
+                protocol::FLASH_ERASE => vec![protocol::status_response(frame, 3)],
+                protocol::FLASH_CHECKSUM => vec![protocol::checksum_response(frame, 0x1234, protocol::STATUS_OK)],
+                _ => Vec::new()
+            }
+        }));
+        assert!(matches!(canota.erase_section(1, 0x0800_0000, 2048), Err(Error::DeviceError { status: 3, .. })));
+        assert!(matches!(canota.erase_section(1, 0x0800_0001, 2048), Err(Error::UnalignedErase { .. })));
+        assert_eq!(canota.get_checksum_for_section(1, 0x0800_0000, 16).unwrap(), 0x1234);
+        assert!(matches!(canota.set_mode(1, DeviceMode::Unknown), Err(Error::InvalidMode(_))));
+    }
+}
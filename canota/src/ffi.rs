@@ -0,0 +1,386 @@
+/**
+ * The canota C library from the canbus-canota submodule, used to cross-check the Rust implementation.
+ * Only built with the c-backend feature.
+ */
+use crate::{ data_length, CanotaDeviceInfo, Inventory, DeviceMode, DeviceType, DeviceVersion, Error, FlashDevice, FlashMap };
+use byteorder::{ LittleEndian, ByteOrder };
+use errno::errno;
+
+/**
+ * Owns a canota context and the CAN socket that it opened.
+ * The socket is closed and the context is freed when the Canota is dropped.
+ */
+pub struct Canota {
+    ctx: std::ptr::NonNull<canota_sys::canota_ctx>,
+}
+
+/**
+ * The context is only reached through the Canota which owns it and the C library keeps no global state,
+ * so it can be moved to another thread. It is not Sync since every call mutates the context.
+ */
+unsafe impl Send for Canota {}
+
+impl DeviceMode {
+    fn to_canota_mode(&self) -> Result<canota_sys::canota_mode, Error> {
+        match self {
+            DeviceMode::Application => Ok(canota_sys::canota_mode_CANOTA_MODE_APPLICATION),
+            DeviceMode::Bootloader => Ok(canota_sys::canota_mode_CANOTA_MODE_BOOTLOADER),
+            DeviceMode::Unknown => Err(Error::InvalidMode(DeviceMode::Unknown))
+        }
+    }
+}
+
+impl From<&canota_sys::canota_device_ctx> for CanotaDeviceInfo {
+    fn from(device_ctx: &canota_sys::canota_device_ctx) -> CanotaDeviceInfo {
+        CanotaDeviceInfo {
+            short_device_id: device_ctx.short_device_id,
+            device_id: device_ctx.device_id,
+            mode: DeviceMode::from(device_ctx.mode),
+            device_type: DeviceType::from(device_ctx.info.device_type),
+            device_version: DeviceVersion {
+                major: device_ctx.info.version_major,
+                minor: device_ctx.info.version_minor
+            }
+        }
+    }
+}
+
+/**
+ * Only valid for Device Identification Responses, see protocol.rs
+ */
+impl From<&canota_sys::can_frame> for CanotaDeviceInfo {
+    fn from(frame: &canota_sys::can_frame) -> CanotaDeviceInfo {
+        let info = canota_sys::canota_device_ctx__bindgen_ty_1::from(&frame.data[0..4]);
+        let device_id = LittleEndian::read_u32(&frame.data[4..8]);
+        CanotaDeviceInfo {
+            short_device_id: (frame.can_id & 0xFF) as u8,
+            device_id,
+            mode: DeviceMode::from(((frame.can_id >> 8) & 0xFF) as u32),
+            device_type: DeviceType::from(info.device_type),
+            device_version: DeviceVersion {
+                major: info.version_major,
+                minor: info.version_minor
+            }
+        }
+    }
+}
+
+/**
+ * Wraps a canota_device_ctx which only has values that are required for
+ * calling canota_raw_cmd. All other values are default and should
+ * not be accessed.
+ *
+ * Valid Values:
+ *  - ctx
+ * Partially Valid Values
+ *  - short_device_id - Only valid if DeviceIdStatus is Valid
+ * Values Not Needed and therefor set to meaningless defaults:
+ *  - device_id
+ *  - mode
+ *  - info
+ *
+ * !INTERNAL: This Should Not be exposed to the end user
+ */
+struct DeviceIdValid;
+struct DeviceIdInvalid;
+struct CanotaDeviceCtxRawCmdCompatible<DeviceIdStatus = DeviceIdInvalid> {
+    device_ctx: canota_sys::canota_device_ctx,
+    device_id_status: std::marker::PhantomData<DeviceIdStatus>
+}
+
+
+impl CanotaDeviceCtxRawCmdCompatible<DeviceIdInvalid>{
+    fn new(ctx: *mut canota_sys::canota_ctx) -> CanotaDeviceCtxRawCmdCompatible<DeviceIdInvalid> {
+        CanotaDeviceCtxRawCmdCompatible {
+            device_ctx: canota_sys::canota_device_ctx {
+                ctx,
+                device_id: 0,
+                short_device_id: 0,
+                mode: canota_sys::canota_mode_CANOTA_MODE_APPLICATION,
+                info: canota_sys::canota_device_ctx__bindgen_ty_1 {
+                    device_type: 0,
+                    version_minor: 0,
+                    version_major: 0,
+                    reserved0: 0
+                }
+            },
+            device_id_status: std::marker::PhantomData
+        }
+    }
+
+    fn set_short_device_id(self, short_device_id: u8) -> CanotaDeviceCtxRawCmdCompatible<DeviceIdValid> {
+        CanotaDeviceCtxRawCmdCompatible {
+            device_ctx: canota_sys::canota_device_ctx {
+                ctx: self.device_ctx.ctx,
+                device_id: 0,
+                short_device_id,
+                mode: canota_sys::canota_mode_CANOTA_MODE_APPLICATION,
+                info: canota_sys::canota_device_ctx__bindgen_ty_1 {
+                    device_type: 0,
+                    version_minor: 0,
+                    version_major: 0,
+                    reserved0: 0
+                }
+            },
+            device_id_status: std::marker::PhantomData
+        }
+    }
+}
+
+impl CanotaDeviceCtxRawCmdCompatible<DeviceIdValid> {
+    pub fn update_short_device_id(&mut self, short_device_id: u8) {
+        self.device_ctx.short_device_id = short_device_id;
+    }
+}
+
+trait CanotaDeviceIdentificationRequest {
+    fn device_identification_request(&mut self) -> Result<(), Error>;
+}
+
+impl CanotaDeviceIdentificationRequest for CanotaDeviceCtxRawCmdCompatible<DeviceIdValid> {
+    fn device_identification_request(&mut self) -> Result<(), Error> {
+        if unsafe { canota_sys::canota_cmd_device_ident_req(&mut self.device_ctx)} {
+            Ok(())
+        } else {
+            Err(Error::RawCommandWriteError)
+        }
+    }
+}
+
+
+impl Canota {
+    pub fn new(can_interface: impl Into<Vec<u8>>) -> Result<Canota, Error> {
+        let can_interface = std::ffi::CString::new(can_interface)?;
+        let ctx = unsafe { canota_sys::canota_init(can_interface.as_ptr()) };
+        match std::ptr::NonNull::new(ctx) {
+            Some(ctx) => Ok(Canota { ctx }),
+            None => Err(Error::InitializationError(errno()))
+        }
+    }
+
+    /**
+     * Get a handle to a device. The handle borrows the context, so it can not outlive it
+     * and only one device can be used at a time.
+     */
+    pub fn device(&mut self, short_device_id: u8) -> Result<CanotaDevice<'_>, Error> {
+        let device = unsafe { canota_sys::canota_device_from_short_id(self.ctx.as_ptr(), short_device_id) };
+        match std::ptr::NonNull::new(device) {
+            Some(device) => Ok(CanotaDevice { device, short_device_id, canota: std::marker::PhantomData }),
+            None => Err(Error::DeviceUnavailable { short_device_id })
+        }
+    }
+
+    // TODO Verify if this changes the context object
+    pub fn info(&mut self, short_device_id: u8) -> Result<CanotaDeviceInfo, Error> {
+        Ok(self.device(short_device_id)?.info())
+    }
+
+    /**
+     * Library Uses Poll to read with timeout Internally
+     */
+    fn raw_receive_frame(&mut self, mask: &mut canota_sys::mask_match, num_matches: u64) -> Result<canota_sys::can_frame, Error> {
+        let mut frame = canota_sys::can_frame::new();
+        let result = unsafe { canota_sys::canota_raw_recv_frame(self.ctx.as_ptr(), &mut frame, mask, num_matches) };
+        if result {
+           Ok(frame)
+        } else {
+            Err(Error::RawCommandReadError)
+        }
+    }
+
+    pub fn scan(&mut self) -> Result<Inventory, Error> {
+        let mut device = CanotaDeviceCtxRawCmdCompatible::new(self.ctx.as_ptr()).set_short_device_id(0);
+        for i in 0..=255 {
+            device.update_short_device_id(i);
+            device.device_identification_request()?;
+        }
+        let mut mask_match = canota_sys::mask_match {
+            mask:   0x1FFF0000,
+            match_: 0x1F010000
+        };
+        let mut responses = Vec::with_capacity(255); // Most Number of devices that we can have
+        while let Ok(frame) = self.raw_receive_frame(&mut mask_match, 1) {
+            responses.push(CanotaDeviceInfo::from(&frame))
+        }
+        Ok(Inventory::from_responses(responses))
+    }
+
+    pub fn change_short_id(&mut self, old_short_id: u8, new_short_id: u8) -> Result<(), Error> {
+        self.device(old_short_id)?.change_short_id(new_short_id)
+    }
+
+    pub fn flash_map(&mut self, short_device_id: u8) -> Result<FlashMap, Error> {
+        self.device(short_device_id)?.flash_map()
+    }
+
+    pub fn set_mode(&mut self, short_device_id: u8, mode: DeviceMode) -> Result<(), Error> {
+        self.device(short_device_id)?.set_mode(mode)
+    }
+
+    pub fn reset_device(&mut self, short_device_id: u8) -> Result<(), Error> {
+        self.device(short_device_id)?.reset()
+    }
+
+    pub fn erase_section(&mut self, short_device_id: u8, start_address: u32, length: u32) -> Result<(), Error> {
+        self.device(short_device_id)?.erase_section(start_address, length)
+    }
+
+    pub fn write_section(&mut self, short_device_id: u8, start_address: u32, data: &[u8]) -> Result<(), Error> {
+        self.device(short_device_id)?.write_section(start_address, data)
+    }
+
+    pub fn get_checksum_for_section(&mut self, short_device_id: u8, start_address: u32, length: u32) -> Result<u32, Error> {
+        self.device(short_device_id)?.checksum(start_address, length)
+    }
+
+    pub fn flash_board(&mut self, short_device_id: u8, start_address: u32, data: &[u8]) -> Result<(), Error> {
+        self.device(short_device_id)?.flash(start_address, data)
+    }
+}
+
+/**
+ * canota_init allocates the context with malloc and opens a CAN socket, but the library has no
+ * function to release them.
+ */
+impl Drop for Canota {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.ctx.as_ref().can_fd);
+            libc::free(self.ctx.as_ptr() as *mut libc::c_void);
+        }
+    }
+}
+
+/**
+ * A device found on the bus. Device contexts are allocated by canota_device_from_short_id
+ * and freed when the handle is dropped.
+ */
+pub struct CanotaDevice<'a> {
+    device: std::ptr::NonNull<canota_sys::canota_device_ctx>,
+    short_device_id: u8,
+    canota: std::marker::PhantomData<&'a mut Canota>
+}
+
+impl<'a> CanotaDevice<'a> {
+    fn ctx(&mut self) -> *mut canota_sys::canota_device_ctx {
+        self.device.as_ptr()
+    }
+
+    pub fn short_device_id(&self) -> u8 {
+        self.short_device_id
+    }
+
+    pub fn info(&self) -> CanotaDeviceInfo {
+        CanotaDeviceInfo::from(unsafe { self.device.as_ref() })
+    }
+
+    pub fn change_short_id(&mut self, new_short_id: u8) -> Result<(), Error> {
+        let result = unsafe { canota_sys::canota_change_short_id(self.ctx(), new_short_id) };
+        if result {
+            self.short_device_id = new_short_id;
+            Ok(())
+        } else {
+            Err(Error::ErrorChangingDeviceShortId)
+        }
+    }
+
+    /**
+     * Switch the device between its application and its bootloader.
+     * Waits for the device to identify itself in the new mode.
+     */
+    pub fn set_mode(&mut self, mode: DeviceMode) -> Result<(), Error> {
+        let canota_mode = mode.to_canota_mode()?;
+        let result = unsafe { canota_sys::canota_mode_switch(self.ctx(), canota_mode) };
+        if result {
+            Ok(())
+        } else {
+            Err(Error::ModeSwitchError { short_device_id: self.short_device_id, mode })
+        }
+    }
+
+    /**
+     * Restart the device without changing its mode
+     */
+    pub fn reset(&mut self) -> Result<(), Error> {
+        let result = unsafe { canota_sys::canota_mode_switch(self.ctx(), canota_sys::canota_mode_CANOTA_MODE_RESET_ONLY) };
+        if result {
+            Ok(())
+        } else {
+            Err(Error::ResetError { short_device_id: self.short_device_id })
+        }
+    }
+}
+
+impl<'a> FlashDevice for CanotaDevice<'a> {
+    /**
+     * Flash layout of the device, used to validate addresses before they are sent to it
+     */
+    fn flash_map(&self) -> Result<FlashMap, Error> {
+        let device_type = DeviceType::from(unsafe { self.device.as_ref() }.info.device_type);
+        FlashMap::for_device_type(&device_type).ok_or(Error::UnsupportedDeviceType(device_type))
+    }
+
+    /**
+     * Erase whole pages of the device's flash. The device needs to be in bootloader mode.
+     */
+    fn erase_section(&mut self, start_address: u32, length: u32) -> Result<(), Error> {
+        self.flash_map()?.validate_erase_range(start_address, length)?;
+        let result = unsafe { canota_sys::canota_flash_erase(self.ctx(), start_address, length) };
+        if result {
+            Ok(())
+        } else {
+            Err(Error::EraseError { short_device_id: self.short_device_id, start_address })
+        }
+    }
+
+    /**
+     * Write data to flash which has already been erased. The device needs to be in bootloader mode.
+     */
+    fn write_section(&mut self, start_address: u32, data: &[u8]) -> Result<(), Error> {
+        let length = data_length(start_address, data)?;
+        self.flash_map()?.validate_range(start_address, length)?;
+        let mut data = data.to_vec(); // canota_flash_write takes a mutable pointer
+        let result = unsafe { canota_sys::canota_flash_write(self.ctx(), data.as_mut_ptr() as *mut std::os::raw::c_void, length, start_address) };
+        if result {
+            Ok(())
+        } else {
+            Err(Error::WriteError { short_device_id: self.short_device_id, start_address })
+        }
+    }
+
+    /**
+     * Checksum of length bytes of the device's flash starting at start_address, as computed by the device
+     */
+    fn checksum(&mut self, start_address: u32, length: u32) -> Result<u32, Error> {
+        self.flash_map()?.validate_range(start_address, length)?;
+        let mut checksum: u32 = 0;
+        let result = unsafe { canota_sys::canota_checksum(self.ctx(), start_address, length, &mut checksum) };
+        if result {
+            Ok(checksum)
+        } else {
+            Err(Error::ChecksumError { short_device_id: self.short_device_id, start_address })
+        }
+    }
+
+    /**
+     * Erase and write data to the device's flash starting at start_address.
+     * The device needs to be in bootloader mode.
+     */
+    fn flash(&mut self, start_address: u32, data: &[u8]) -> Result<(), Error> {
+        let length = data_length(start_address, data)?;
+        self.flash_map()?.validate_range(start_address, length)?;
+        let mut data = data.to_vec(); // canota_flash takes a mutable pointer
+        let result = unsafe { canota_sys::canota_flash(self.ctx(), start_address, data.as_mut_ptr() as *mut std::os::raw::c_void, length) };
+        if result {
+            Ok(())
+        } else {
+            Err(Error::FlashError { short_device_id: self.short_device_id, start_address })
+        }
+    }
+}
+
+impl<'a> Drop for CanotaDevice<'a> {
+    fn drop(&mut self) {
+        unsafe { libc::free(self.device.as_ptr() as *mut libc::c_void) };
+    }
+}
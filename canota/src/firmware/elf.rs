@@ -0,0 +1,94 @@
+/**
+ * 32 bit little endian ELF files, as produced by arm-none-eabi-gcc.
+ * Only the loadable program segments are flashed, at their physical (load) address.
+ */
+use super::{ FirmwareError, SegmentBuilder };
+use byteorder::{ ByteOrder, LittleEndian };
+
+pub(super) const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_32: u8 = 1;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+const PT_LOAD: u32 = 1;
+
+pub(super) fn parse(bytes: &[u8]) -> Result<SegmentBuilder, FirmwareError> {
+    if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+        return Err(FirmwareError::InvalidElf("Missing ELF header"));
+    }
+    if bytes[4] != CLASS_32 || bytes[5] != DATA_LITTLE_ENDIAN {
+        return Err(FirmwareError::InvalidElf("Only 32 bit little endian ELF files are supported"));
+    }
+    let program_header_offset = LittleEndian::read_u32(&bytes[28..32]) as usize;
+    let program_header_size = LittleEndian::read_u16(&bytes[42..44]) as usize;
+    let program_header_count = LittleEndian::read_u16(&bytes[44..46]) as usize;
+    if program_header_size < PROGRAM_HEADER_SIZE {
+        return Err(FirmwareError::InvalidElf("Invalid program header size"));
+    }
+
+    let mut segments = SegmentBuilder::new();
+    for index in 0..program_header_count {
+        let start = program_header_offset + index * program_header_size;
+        let header = bytes.get(start..start + PROGRAM_HEADER_SIZE).ok_or(FirmwareError::InvalidElf("Truncated program header"))?;
+        let segment_type = LittleEndian::read_u32(&header[0..4]);
+        let offset = LittleEndian::read_u32(&header[4..8]) as usize;
+        let physical_address = LittleEndian::read_u32(&header[12..16]);
+        let file_size = LittleEndian::read_u32(&header[16..20]) as usize;
+        if segment_type != PT_LOAD || file_size == 0 {
+            continue; // .bss and friends only take up space in RAM
+        }
+        let data = bytes.get(offset..offset + file_size).ok_or(FirmwareError::InvalidElf("Truncated segment"))?;
+        segments.add(physical_address, data)?;
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /**
+     * Build an ELF file with one loadable segment per (address, data) pair and a segment which is not loaded
+     */
+    pub fn build_elf(segments: &[(u32, &[u8])]) -> Vec<u8> {
+        let program_header_count = segments.len() + 1;
+        let mut elf = vec![0u8; HEADER_SIZE + program_header_count * PROGRAM_HEADER_SIZE];
+        elf[0..4].copy_from_slice(MAGIC);
+        elf[4] = CLASS_32;
+        elf[5] = DATA_LITTLE_ENDIAN;
+        LittleEndian::write_u32(&mut elf[28..32], HEADER_SIZE as u32);
+        LittleEndian::write_u16(&mut elf[42..44], PROGRAM_HEADER_SIZE as u16);
+        LittleEndian::write_u16(&mut elf[44..46], program_header_count as u16);
+        for (index, (address, data)) in segments.iter().enumerate() {
+            let offset = elf.len();
+            elf.extend_from_slice(data);
+            let header = &mut elf[HEADER_SIZE + index * PROGRAM_HEADER_SIZE..];
+            LittleEndian::write_u32(&mut header[0..4], PT_LOAD);
+            LittleEndian::write_u32(&mut header[4..8], offset as u32);
+            LittleEndian::write_u32(&mut header[8..12], 0x2000_0000); // Virtual address in RAM is ignored
+            LittleEndian::write_u32(&mut header[12..16], *address);
+            LittleEndian::write_u32(&mut header[16..20], data.len() as u32);
+            LittleEndian::write_u32(&mut header[20..24], data.len() as u32);
+        }
+        elf
+    }
+
+    #[test]
+    fn parse_load_segments() {
+        let elf = build_elf(&[(0x0800_0000, &[1, 2, 3, 4]), (0x0800_1000, &[5, 6])]);
+        let segments = parse(&elf).unwrap().build().unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].start_address, 0x0800_0000);
+        assert_eq!(segments[1].data, vec![5, 6]);
+    }
+
+    #[test]
+    fn invalid_elf() {
+        assert!(parse(b"\x7fELF").is_err());
+        let mut elf = build_elf(&[(0x0800_0000, &[1, 2, 3, 4])]);
+        elf[4] = 2; // 64 bit
+        assert!(parse(&elf).is_err());
+        let elf = build_elf(&[(0x0800_0000, &[1, 2, 3, 4])]);
+        assert!(parse(&elf[..elf.len() - 1]).is_err());
+    }
+}
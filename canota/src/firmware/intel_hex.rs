@@ -0,0 +1,79 @@
+/**
+ * Intel HEX records:
+ *      :LLAAAATT<data>CC
+ * LL bytes of data at offset AAAA of the current base address, with record type TT.
+ * The checksum CC makes the sum of every byte in the record zero.
+ */
+use super::{ FirmwareError, SegmentBuilder };
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+fn decode_record(line: &str, line_number: usize) -> Result<Vec<u8>, FirmwareError> {
+    let invalid = FirmwareError::InvalidHexRecord { line: line_number };
+    let hex = line.strip_prefix(':').ok_or(FirmwareError::InvalidHexRecord { line: line_number })?;
+    if hex.len() % 2 != 0 || hex.len() < 10 {
+        return Err(invalid);
+    }
+    let record = (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(FirmwareError::InvalidHexRecord { line: line_number })?;
+    if record.len() != record[0] as usize + 5 {
+        return Err(invalid);
+    }
+    if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err(FirmwareError::HexChecksumMismatch { line: line_number });
+    }
+    Ok(record)
+}
+
+pub(super) fn parse(text: &str) -> Result<SegmentBuilder, FirmwareError> {
+    let mut segments = SegmentBuilder::new();
+    let mut base_address: u32 = 0;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let line_number = index + 1;
+        let record = decode_record(line, line_number)?;
+        let offset = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            DATA => segments.add(base_address.wrapping_add(offset), data)?,
+            END_OF_FILE => return Ok(segments),
+            EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => base_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            EXTENDED_LINEAR_ADDRESS if data.len() == 2 => base_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {}, // Entry points are read from the vector table by the bootloader
+            _ => return Err(FirmwareError::InvalidHexRecord { line: line_number })
+        }
+    }
+    Err(FirmwareError::MissingEndOfFile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_records() {
+        let text = ":020000040800F2\n:0400000001020304F2\n:02000400AABB95\n:00000001FF\n";
+        let segments = parse(text).unwrap().build().unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].start_address, 0x0800_0000);
+        assert_eq!(segments[0].data, vec![0x01, 0x02, 0x03, 0x04, 0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn invalid_records() {
+        assert!(matches!(parse(":0400000001020304F3\n:00000001FF\n"), Err(FirmwareError::HexChecksumMismatch { line: 1 })));
+        assert!(matches!(parse("0400000001020304F2\n"), Err(FirmwareError::InvalidHexRecord { line: 1 })));
+        assert!(matches!(parse(":0400000001020304F2\n"), Err(FirmwareError::MissingEndOfFile)));
+    }
+}
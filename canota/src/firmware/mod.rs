@@ -0,0 +1,217 @@
+/**
+ * Firmware images which can be flashed to a device.
+ *
+ * Images are loaded from Intel HEX, ELF or raw binary files into segments of contiguous bytes,
+ * checked against the flash map of the target and then written with CanotaDevice::flash_image.
+ */
+mod intel_hex;
+mod elf;
+
+use crate::FlashMap;
+
+#[derive(Debug)]
+pub enum FirmwareError {
+    Io(std::io::Error),
+    InvalidHexRecord { line: usize },
+    HexChecksumMismatch { line: usize },
+    MissingEndOfFile,
+    InvalidElf(&'static str),
+    OverlappingSegments { address: u32 },
+    SegmentOutsideOfFlash { start_address: u32, length: u32 },
+    Empty
+}
+
+impl From<std::io::Error> for FirmwareError {
+    fn from(error: std::io::Error) -> Self {
+        FirmwareError::Io(error)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub start_address: u32,
+    pub data: Vec<u8>
+}
+
+impl Segment {
+    pub fn length(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub fn end_address(&self) -> u32 {
+        self.start_address + self.length()
+    }
+}
+
+/**
+ * Collects the data from a file in any order and merges it into sorted, non overlapping segments
+ */
+struct SegmentBuilder {
+    chunks: Vec<Segment>
+}
+
+impl SegmentBuilder {
+    fn new() -> SegmentBuilder {
+        SegmentBuilder { chunks: Vec::new() }
+    }
+
+    fn add(&mut self, start_address: u32, data: &[u8]) -> Result<(), FirmwareError> {
+        if start_address as u64 + data.len() as u64 > u32::MAX as u64 + 1 {
+            return Err(FirmwareError::SegmentOutsideOfFlash { start_address, length: data.len() as u32 });
+        }
+        if !data.is_empty() {
+            self.chunks.push(Segment { start_address, data: data.to_vec() });
+        }
+        Ok(())
+    }
+
+    fn build(mut self) -> Result<Vec<Segment>, FirmwareError> {
+        self.chunks.sort_by_key(|chunk| chunk.start_address);
+        let mut segments: Vec<Segment> = Vec::new();
+        for chunk in self.chunks {
+            match segments.last_mut() {
+                Some(last) if chunk.start_address < last.end_address() => {
+                    return Err(FirmwareError::OverlappingSegments { address: chunk.start_address });
+                },
+                Some(last) if chunk.start_address == last.end_address() => last.data.extend_from_slice(&chunk.data),
+                _ => segments.push(chunk)
+            }
+        }
+        if segments.is_empty() {
+            return Err(FirmwareError::Empty);
+        }
+        Ok(segments)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirmwareImage {
+    segments: Vec<Segment>
+}
+
+impl FirmwareImage {
+    pub fn from_intel_hex(text: &str) -> Result<FirmwareImage, FirmwareError> {
+        Ok(FirmwareImage { segments: intel_hex::parse(text)?.build()? })
+    }
+
+    pub fn from_elf(bytes: &[u8]) -> Result<FirmwareImage, FirmwareError> {
+        Ok(FirmwareImage { segments: elf::parse(bytes)?.build()? })
+    }
+
+    /**
+     * A raw binary has no addresses, so it is placed at start_address
+     */
+    pub fn from_bin(bytes: &[u8], start_address: u32) -> Result<FirmwareImage, FirmwareError> {
+        let mut segments = SegmentBuilder::new();
+        segments.add(start_address, bytes)?;
+        Ok(FirmwareImage { segments: segments.build()? })
+    }
+
+    /**
+     * Detect the format of the image from its contents.
+     * ELF files start with their magic number and Intel HEX files with a ':'. Anything else is a raw
+     * binary which is placed at bin_start_address.
+     */
+    pub fn from_bytes(bytes: &[u8], bin_start_address: u32) -> Result<FirmwareImage, FirmwareError> {
+        if bytes.starts_with(elf::MAGIC) {
+            FirmwareImage::from_elf(bytes)
+        } else if bytes.first() == Some(&b':') {
+            let text = std::str::from_utf8(bytes).map_err(|_| FirmwareError::InvalidHexRecord { line: 0 })?;
+            FirmwareImage::from_intel_hex(text)
+        } else {
+            FirmwareImage::from_bin(bytes, bin_start_address)
+        }
+    }
+
+    pub fn load(path: impl AsRef<std::path::Path>, bin_start_address: u32) -> Result<FirmwareImage, FirmwareError> {
+        FirmwareImage::from_bytes(&std::fs::read(path)?, bin_start_address)
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn total_length(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+
+    /**
+     * Check that every segment fits in the flash of the target
+     */
+    pub fn validate(&self, flash_map: &FlashMap) -> Result<(), FirmwareError> {
+        for segment in &self.segments {
+            flash_map.validate_range(segment.start_address, segment.length())
+                .map_err(|_| FirmwareError::SegmentOutsideOfFlash { start_address: segment.start_address, length: segment.length() })?;
+        }
+        Ok(())
+    }
+
+    /**
+     * The whole pages which need to be erased before the image is written, as (start address, length).
+     * Segments which share a page are covered by a single range so that no page is erased twice.
+     * The image needs to have been validated against the flash map.
+     */
+    pub fn erase_ranges(&self, flash_map: &FlashMap) -> Vec<(u32, u32)> {
+        let page_start = |address: u32| address - (address - flash_map.start_address) % flash_map.page_size;
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for segment in &self.segments {
+            let start = page_start(segment.start_address);
+            let end = page_start(segment.end_address() - 1) + flash_map.page_size;
+            match ranges.last_mut() {
+                Some((last_start, last_length)) if start <= *last_start + *last_length => *last_length = end - *last_start,
+                _ => ranges.push((start, end - start))
+            }
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash_map::STM32L432KC_FLASH_MAP;
+
+    #[test]
+    fn merge_segments() {
+        let mut builder = SegmentBuilder::new();
+        builder.add(0x0800_0004, &[5, 6]).unwrap();
+        builder.add(0x0800_0000, &[1, 2, 3, 4]).unwrap();
+        builder.add(0x0800_1000, &[7]).unwrap();
+        let segments = builder.build().unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].data, vec![1, 2, 3, 4, 5, 6]);
+
+        let mut builder = SegmentBuilder::new();
+        builder.add(0x0800_0000, &[1, 2, 3, 4]).unwrap();
+        builder.add(0x0800_0002, &[5]).unwrap();
+        assert!(matches!(builder.build(), Err(FirmwareError::OverlappingSegments { address: 0x0800_0002 })));
+        assert!(matches!(SegmentBuilder::new().build(), Err(FirmwareError::Empty)));
+    }
+
+    #[test]
+    fn detect_format() {
+        let elf = elf::tests::build_elf(&[(0x0800_0000, &[1, 2])]);
+        assert_eq!(FirmwareImage::from_bytes(&elf, 0).unwrap().segments()[0].data, vec![1, 2]);
+        let hex = b":0400000001020304F2\n:00000001FF\n";
+        assert_eq!(FirmwareImage::from_bytes(hex, 0x0800_0000).unwrap().segments()[0].start_address, 0);
+        let bin = FirmwareImage::from_bytes(&[0xAA; 16], 0x0800_4000).unwrap();
+        assert_eq!(bin.segments()[0].start_address, 0x0800_4000);
+        assert_eq!(bin.total_length(), 16);
+    }
+
+    #[test]
+    fn validate_against_flash_map() {
+        let map = STM32L432KC_FLASH_MAP;
+        assert!(FirmwareImage::from_bin(&[0; 1024], 0x0800_0000).unwrap().validate(&map).is_ok());
+        assert!(FirmwareImage::from_bin(&[0; 1024], 0x2000_0000).unwrap().validate(&map).is_err());
+        assert!(FirmwareImage::from_bin(&[0; 1024], 0x0803_FF00).unwrap().validate(&map).is_err());
+    }
+
+    #[test]
+    fn erase_ranges() {
+        let map = STM32L432KC_FLASH_MAP;
+        let elf = elf::tests::build_elf(&[(0x0800_0000, &[0; 3000]), (0x0800_0C00, &[0; 16]), (0x0800_8000, &[0; 1])]);
+        let image = FirmwareImage::from_elf(&elf).unwrap();
+        assert_eq!(image.erase_ranges(&map), vec![(0x0800_0000, 4096), (0x0800_8000, 2048)]);
+    }
+}
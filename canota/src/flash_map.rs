@@ -0,0 +1,92 @@
+/**
+ * Layout of the flash of each supported device type.
+ * Used to validate addresses and lengths before they are sent to a device.
+ */
+use crate::{ DeviceType, Error };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlashMap {
+    pub start_address: u32,
+    pub size: u32,
+    pub page_size: u32
+}
+
+pub const STM32L432KC_FLASH_MAP: FlashMap = FlashMap {
+    start_address: 0x0800_0000,
+    size: 256 * 1024,
+    page_size: 2 * 1024
+};
+
+/**
+ * Size of the largest flash of the supported device types. No firmware image can be larger
+ */
+pub const MAX_FLASH_SIZE: u32 = STM32L432KC_FLASH_MAP.size;
+
+impl FlashMap {
+    pub fn for_device_type(device_type: &DeviceType) -> Option<FlashMap> {
+        match device_type {
+            DeviceType::STM32L432KC => Some(STM32L432KC_FLASH_MAP),
+            DeviceType::Unknown => None
+        }
+    }
+
+    pub fn end_address(&self) -> u32 {
+        self.start_address + self.size
+    }
+
+    /**
+     * Check that length bytes starting at start_address are inside of the flash
+     */
+    pub fn validate_range(&self, start_address: u32, length: u32) -> Result<(), Error> {
+        let end_address = start_address as u64 + length as u64;
+        if length == 0 || start_address < self.start_address || end_address > self.end_address() as u64 {
+            return Err(Error::InvalidAddressRange { start_address, length });
+        }
+        Ok(())
+    }
+
+    /**
+     * Erases work on whole pages, so the range also needs to start and end on a page boundary
+     */
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn validate_erase_range(&self, start_address: u32, length: u32) -> Result<(), Error> {
+        self.validate_range(start_address, length)?;
+        if (start_address - self.start_address) % self.page_size != 0 || length % self.page_size != 0 {
+            return Err(Error::UnalignedErase { start_address, length, page_size: self.page_size });
+        }
+        Ok(())
+    }
+
+    /**
+     * Round length up to a whole number of pages
+     */
+    #[allow(clippy::manual_div_ceil)]
+    pub fn page_aligned_length(&self, length: u32) -> u32 {
+        (length + self.page_size - 1) / self.page_size * self.page_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_range() {
+        let map = STM32L432KC_FLASH_MAP;
+        assert!(map.validate_range(0x0800_0000, 256 * 1024).is_ok());
+        assert!(map.validate_range(0x0800_0000, 0).is_err());
+        assert!(map.validate_range(0x07FF_FFFF, 16).is_err());
+        assert!(map.validate_range(0x0803_FFFF, 2).is_err());
+        assert!(map.validate_range(0xFFFF_FFFF, 2).is_err());
+    }
+
+    #[test]
+    fn validate_erase_range() {
+        let map = STM32L432KC_FLASH_MAP;
+        assert!(map.validate_erase_range(0x0800_0800, 2048).is_ok());
+        assert!(map.validate_erase_range(0x0800_0100, 2048).is_err());
+        assert!(map.validate_erase_range(0x0800_0000, 100).is_err());
+        assert_eq!(map.page_aligned_length(1), 2048);
+        assert_eq!(map.page_aligned_length(4096), 4096);
+    }
+}
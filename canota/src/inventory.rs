@@ -0,0 +1,129 @@
+/**
+ * The devices found by a scan.
+ *
+ * A device can answer an identification request more than once and two devices can be configured with
+ * the same short id. Responses from the same device are merged, and short ids which are used by more than
+ * one device are reported as collisions since requests to them would reach every one of those devices.
+ */
+use crate::CanotaDeviceInfo;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShortIdCollision {
+    pub short_device_id: u8,
+    pub device_ids: Vec<u32>
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Inventory {
+    devices: Vec<CanotaDeviceInfo>,
+    collisions: Vec<ShortIdCollision>
+}
+
+impl Inventory {
+    /**
+     * Build the inventory from identification responses in the order that they were received.
+     * The last response from a device is kept, since a device can change mode during a scan.
+     */
+    pub fn from_responses(responses: impl IntoIterator<Item = CanotaDeviceInfo>) -> Inventory {
+        let mut devices: Vec<CanotaDeviceInfo> = Vec::new();
+        for response in responses {
+            match devices.iter_mut().find(|device| device.short_device_id == response.short_device_id && device.device_id == response.device_id) {
+                Some(device) => *device = response,
+                None => devices.push(response)
+            }
+        }
+        devices.sort_by_key(|device| (device.short_device_id, device.device_id));
+
+        let mut collisions: Vec<ShortIdCollision> = Vec::new();
+        for pair in devices.windows(2) {
+            if pair[0].short_device_id != pair[1].short_device_id {
+                continue;
+            }
+            match collisions.last_mut() {
+                Some(collision) if collision.short_device_id == pair[0].short_device_id => collision.device_ids.push(pair[1].device_id),
+                _ => collisions.push(ShortIdCollision {
+                    short_device_id: pair[0].short_device_id,
+                    device_ids: vec![pair[0].device_id, pair[1].device_id]
+                })
+            }
+        }
+        Inventory { devices, collisions }
+    }
+
+    /**
+     * Every device found, sorted by short id
+     */
+    pub fn devices(&self) -> &[CanotaDeviceInfo] {
+        &self.devices
+    }
+
+    pub fn collisions(&self) -> &[ShortIdCollision] {
+        &self.collisions
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /**
+     * The device using short_device_id. None if no device or more than one device uses it
+     */
+    pub fn device(&self, short_device_id: u8) -> Option<&CanotaDeviceInfo> {
+        let mut devices = self.devices.iter().filter(|device| device.short_device_id == short_device_id);
+        match (devices.next(), devices.next()) {
+            (Some(device), None) => Some(device),
+            _ => None
+        }
+    }
+
+    pub fn is_collision(&self, short_device_id: u8) -> bool {
+        self.collisions.iter().any(|collision| collision.short_device_id == short_device_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ DeviceMode, DeviceType, DeviceVersion };
+
+    fn info(short_device_id: u8, device_id: u32, mode: DeviceMode) -> CanotaDeviceInfo {
+        CanotaDeviceInfo {
+            short_device_id,
+            device_id,
+            mode,
+            device_type: DeviceType::STM32L432KC,
+            device_version: DeviceVersion::new(1, 0)
+        }
+    }
+
+    #[test]
+    fn merge_duplicate_responses() {
+        let inventory = Inventory::from_responses(vec![
+            info(9, 0xA, DeviceMode::Application),
+            info(2, 0xB, DeviceMode::Application),
+            info(9, 0xA, DeviceMode::Bootloader)
+        ]);
+        assert_eq!(inventory.len(), 2);
+        assert_eq!(inventory.devices()[0].short_device_id(), 2);
+        assert_eq!(inventory.device(9).unwrap().mode(), &DeviceMode::Bootloader);
+        assert!(inventory.collisions().is_empty());
+    }
+
+    #[test]
+    fn detect_collisions() {
+        let inventory = Inventory::from_responses(vec![
+            info(4, 0xC, DeviceMode::Application),
+            info(4, 0xA, DeviceMode::Application),
+            info(4, 0xB, DeviceMode::Application),
+            info(5, 0xD, DeviceMode::Application)
+        ]);
+        assert_eq!(inventory.collisions(), &[ShortIdCollision { short_device_id: 4, device_ids: vec![0xA, 0xB, 0xC] }]);
+        assert!(inventory.is_collision(4));
+        assert!(inventory.device(4).is_none());
+        assert!(inventory.device(5).is_some());
+    }
+}
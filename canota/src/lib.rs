@@ -1,4 +1,29 @@
-use errno::{Errno, errno};
+/**
+ * OTA flashing of the pod's boards over the CAN bus.
+ *
+ * The canota protocol is implemented in Rust on top of the CanBus trait, so it can run on a
+ * SocketCAN interface or on any other bus. The bindings to the C library are kept behind the
+ * c-backend feature to cross-check this implementation against it.
+ */
+use errno::Errno;
+
+pub mod flash_map;
+pub mod firmware;
+pub mod crc32;
+pub mod bus;
+pub mod protocol;
+pub mod simulator;
+pub mod inventory;
+mod client;
+#[cfg(feature = "c-backend")]
+pub mod ffi;
+pub use flash_map::FlashMap;
+pub use firmware::{ FirmwareImage, FirmwareError };
+pub use bus::{ CanBus, CanFrame };
+#[cfg(all(unix, feature = "socketcan"))]
+pub use bus::SocketCanBus;
+pub use client::{ Canota, CanotaDevice };
+pub use inventory::{ Inventory, ShortIdCollision };
 
 #[cfg(test)]
 mod tests {
@@ -7,6 +32,20 @@ mod tests {
         assert_eq!(2 + 2, 4);
     }
 
+    #[test]
+    fn canota_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<super::Canota<super::SocketCanBus>>();
+    }
+
+    #[test]
+    fn version_ordering() {
+        use super::DeviceVersion;
+        assert!(DeviceVersion::new(1, 9) < DeviceVersion::new(2, 0));
+        assert!(DeviceVersion::new(2, 1) > DeviceVersion::new(2, 0));
+        assert_eq!(DeviceVersion::new(3, 12).to_string(), "3.12");
+    }
+
     #[test]
     fn create_Canota() {
         let canota = super::Canota::new("can0");
@@ -24,15 +63,121 @@ pub enum Error {
     DeviceUnavailable { short_device_id: u8 },
     RawCommandWriteError,
     RawCommandReadError,
-    ErrorChangingDeviceShortId
+    ErrorChangingDeviceShortId,
+    ModeSwitchError { short_device_id: u8, mode: DeviceMode },
+    ResetError { short_device_id: u8 },
+    EraseError { short_device_id: u8, start_address: u32 },
+    WriteError { short_device_id: u8, start_address: u32 },
+    FlashError { short_device_id: u8, start_address: u32 },
+    ChecksumError { short_device_id: u8, start_address: u32 },
+    InvalidMode(DeviceMode),
+    UnsupportedDeviceType(DeviceType),
+    InvalidAddressRange { start_address: u32, length: u32 },
+    UnalignedErase { start_address: u32, length: u32, page_size: u32 },
+    ChecksumMismatch { start_address: u32, expected: u32, actual: u32 },
+    Firmware(FirmwareError),
+    Bus(std::io::Error),
+    Timeout { short_device_id: u8, command: u8 },
+    DeviceError { short_device_id: u8, command: u8, status: u8 }
+}
+
+impl From<FirmwareError> for Error {
+    fn from(error: FirmwareError) -> Self {
+        Error::Firmware(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Bus(error)
+    }
 }
 
+impl From<std::ffi::NulError> for Error {
+    fn from(error: std::ffi::NulError) -> Self {
+        Error::InvalidStringError(error)
+    }
+}
+
+/**
+ * Reported by FlashDevice::flash_image as it works through the image
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashProgress {
+    Erasing { start_address: u32, length: u32 },
+    Writing { bytes_written: usize, total_bytes: usize },
+    Verified { start_address: u32, checksum: u32 }
+}
+
+/**
+ * The flash operations of a device, shared by the Rust and C implementations of canota.
+ * The device needs to be in bootloader mode for all of them.
+ */
+pub trait FlashDevice {
+    /**
+     * Flash layout of the device, used to validate addresses before they are sent to it
+     */
+    fn flash_map(&self) -> Result<FlashMap, Error>;
+
+    /**
+     * Erase whole pages of the device's flash
+     */
+    fn erase_section(&mut self, start_address: u32, length: u32) -> Result<(), Error>;
+
+    /**
+     * Write data to flash which has already been erased
+     */
+    fn write_section(&mut self, start_address: u32, data: &[u8]) -> Result<(), Error>;
+
+    /**
+     * Checksum of length bytes of the device's flash starting at start_address, as computed by the device
+     */
+    fn checksum(&mut self, start_address: u32, length: u32) -> Result<u32, Error>;
+
+    /**
+     * Erase and write data to the device's flash starting at start_address, then verify it
+     */
+    fn flash(&mut self, start_address: u32, data: &[u8]) -> Result<(), Error> {
+        let image = FirmwareImage::from_bin(data, start_address)?;
+        self.flash_image(&image, |_| {})
+    }
+
+    /**
+     * Write every segment of the image and check it with the device's checksum.
+     * All of the pages used by the image are erased before anything is written, since segments can share a page.
+     */
+    fn flash_image(&mut self, image: &FirmwareImage, mut progress: impl FnMut(FlashProgress)) -> Result<(), Error> {
+        let flash_map = self.flash_map()?;
+        image.validate(&flash_map)?;
+        for (start_address, length) in image.erase_ranges(&flash_map) {
+            progress(FlashProgress::Erasing { start_address, length });
+            self.erase_section(start_address, length)?;
+        }
+
+        let total_bytes = image.total_length();
+        let mut bytes_written = 0;
+        for segment in image.segments() {
+            for (index, chunk) in segment.data.chunks(flash_map.page_size as usize).enumerate() {
+                self.write_section(segment.start_address + index as u32 * flash_map.page_size, chunk)?;
+                bytes_written += chunk.len();
+                progress(FlashProgress::Writing { bytes_written, total_bytes });
+            }
+        }
 
-pub struct Canota {
-    ctx: *mut canota_sys::canota_ctx,
+        for segment in image.segments() {
+            let expected = crc32::crc32(&segment.data);
+            let actual = self.checksum(segment.start_address, segment.length())?;
+            if actual != expected {
+                return Err(Error::ChecksumMismatch { start_address: segment.start_address, expected, actual });
+            }
+            progress(FlashProgress::Verified { start_address: segment.start_address, checksum: actual });
+        }
+        Ok(())
+    }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum DeviceMode {
     Application,
     Bootloader,
@@ -40,7 +185,7 @@ pub enum DeviceMode {
 }
 
 // TODO Maybe represent this as a state type instead?
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum DeviceType {
     STM32L432KC,
     Unknown
@@ -55,13 +200,35 @@ impl From<u8> for DeviceType {
     }
 }
 
-// TODO IMPL Eq and Ord
-#[derive(Clone, Debug)]
+/**
+ * Versions are ordered by major then minor version
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct DeviceVersion {
     major: u8,
     minor: u8
 }
 
+impl std::fmt::Display for DeviceVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl DeviceVersion {
+    pub fn new(major: u8, minor: u8) -> DeviceVersion {
+        DeviceVersion { major, minor }
+    }
+
+    pub fn major(&self) -> u8 {
+        self.major
+    }
+
+    pub fn minor(&self) -> u8 {
+        self.minor
+    }
+}
+
 impl From<u32> for DeviceMode {
     fn from(mode: u32) -> DeviceMode {
         match mode {
@@ -72,7 +239,7 @@ impl From<u32> for DeviceMode {
     }
 }
 
-#[derive(Clone, Debug)] // Change Default debug to the print_info string format in canota/main.c
+#[derive(Clone, Debug, PartialEq, Eq)] // Change Default debug to the print_info string format in canota/main.c
 pub struct CanotaDeviceInfo {
     short_device_id: u8,
     device_id: u32,
@@ -81,208 +248,30 @@ pub struct CanotaDeviceInfo {
     device_version: DeviceVersion
 }
 
-impl From<&canota_sys::canota_device_ctx> for CanotaDeviceInfo {
-    fn from(device_ctx: &canota_sys::canota_device_ctx) -> CanotaDeviceInfo {
-        CanotaDeviceInfo {
-            short_device_id: device_ctx.short_device_id,
-            device_id: device_ctx.device_id,
-            mode: DeviceMode::from(device_ctx.mode),
-            device_type: DeviceType::from(device_ctx.info.device_type),
-            device_version: DeviceVersion {
-                major: device_ctx.info.version_major,
-                minor: device_ctx.info.version_minor
-            }
-        }
+impl CanotaDeviceInfo {
+    pub fn short_device_id(&self) -> u8 {
+        self.short_device_id
     }
-}
 
-/** Valid for Device Identification Responses.
- *  Need to verify for others.
- * May need to change this to be dynamic based on what "type" of can_frame is returned
- */
-use byteorder::{ LittleEndian, ByteOrder };
-impl From<&canota_sys::can_frame> for CanotaDeviceInfo {
-    fn from(frame: &canota_sys::can_frame) -> CanotaDeviceInfo {
-        let info = canota_sys::canota_device_ctx__bindgen_ty_1::from(&frame.data[0..3]);
-        let device_id = LittleEndian::read_u32(&frame.data[4..7]); // TODO Test this
-        CanotaDeviceInfo {
-            short_device_id: (frame.can_id & 0xFF) as u8,
-            device_id,
-            mode: DeviceMode::from(((frame.can_id >> 8) & 0xFF) as u32),
-            device_type: DeviceType::from(info.device_type),
-            device_version: DeviceVersion {
-                major: info.version_major,
-                minor: info.version_minor
-            }
-        }
-    }
-}
-
-impl From<std::ffi::NulError> for Error {
-    fn from(error: std::ffi::NulError) -> Self {
-        Error::InvalidStringError(error)
+    pub fn device_id(&self) -> u32 {
+        self.device_id
     }
-}
 
-/**
- * Wraps a canota_device_ctx which only has values that are required for
- * calling canota_raw_cmd. All other values are default and should
- * not be accessed.
- *
- * Valid Values:
- *  - ctx
- * Partially Valid Values
- *  - short_device_id - Only valid if DeviceIdStatus is Valid
- * Values Not Needed and therefor set to meaningless defaults:
- *  - device_id
- *  - mode
- *  - info
- *
- * !INTERNAL: This Should Not be exposed to the end user
- */
-struct DeviceIdValid;
-struct DeviceIdInvalid;
-struct CanotaDeviceCtxRawCmdCompatible<DeviceIdStatus = DeviceIdInvalid> {
-    device_ctx: canota_sys::canota_device_ctx,
-    device_id_status: std::marker::PhantomData<DeviceIdStatus>
-}
-
-
-impl CanotaDeviceCtxRawCmdCompatible<DeviceIdInvalid>{
-    fn new(ctx: *mut canota_sys::canota_ctx) -> CanotaDeviceCtxRawCmdCompatible<DeviceIdInvalid> {
-        CanotaDeviceCtxRawCmdCompatible {
-            device_ctx: canota_sys::canota_device_ctx {
-                ctx,
-                device_id: 0,
-                short_device_id: 0,
-                mode: canota_sys::canota_mode_CANOTA_MODE_APPLICATION,
-                info: canota_sys::canota_device_ctx__bindgen_ty_1 {
-                    device_type: 0,
-                    version_minor: 0,
-                    version_major: 0,
-                    reserved0: 0
-                }
-            },
-            device_id_status: std::marker::PhantomData
-        }
+    pub fn mode(&self) -> &DeviceMode {
+        &self.mode
     }
 
-    fn set_short_device_id(self, short_device_id: u8) -> CanotaDeviceCtxRawCmdCompatible<DeviceIdValid> {
-        CanotaDeviceCtxRawCmdCompatible {
-            device_ctx: canota_sys::canota_device_ctx {
-                ctx: self.device_ctx.ctx,
-                device_id: 0,
-                short_device_id,
-                mode: canota_sys::canota_mode_CANOTA_MODE_APPLICATION,
-                info: canota_sys::canota_device_ctx__bindgen_ty_1 {
-                    device_type: 0,
-                    version_minor: 0,
-                    version_major: 0,
-                    reserved0: 0
-                }
-            },
-            device_id_status: std::marker::PhantomData
-        }
+    pub fn device_type(&self) -> &DeviceType {
+        &self.device_type
     }
-}
 
-impl CanotaDeviceCtxRawCmdCompatible<DeviceIdValid> {
-    pub fn update_short_device_id(&mut self, short_device_id: u8) {
-        self.device_ctx.short_device_id = short_device_id;
+    pub fn device_version(&self) -> &DeviceVersion {
+        &self.device_version
     }
 }
 
-trait CanotaDeviceIdentificationRequest {
-    fn device_identification_request(&mut self) -> Result<(), Error>;
-}
-
-impl CanotaDeviceIdentificationRequest for CanotaDeviceCtxRawCmdCompatible<DeviceIdValid> {
-    fn device_identification_request(&mut self) -> Result<(), Error> {
-        if unsafe { canota_sys::canota_cmd_device_ident_req(&mut self.device_ctx)} {
-            Ok(())
-        } else {
-            Err(Error::RawCommandWriteError)
-        }
-    }
-}
-
-
-impl Canota {
-    pub fn new(can_interface: impl Into<Vec<u8>>) -> Result<Canota, Error> {
-        let can_interface = std::ffi::CString::new(can_interface)?;
-        let ctx = unsafe { canota_sys::canota_init(can_interface.as_ptr()) }; // AHHHHHH LEAKS memory
-        if ctx.is_null() {
-            return Err(Error::InitializationError(errno()));
-        }
-        Ok(Canota {
-            ctx
-        })
-    }
-
-    // TODO Verify if this changes the context object
-    pub fn info(&mut self, short_device_id: u8) -> Result<CanotaDeviceInfo, Error> {
-        let device = self.get_device_ref(short_device_id)?;
-        Ok(CanotaDeviceInfo::from(device))
-    }
-
-    fn get_device_ref<'a>(&'a mut self, short_device_id: u8) -> Result<&'a mut canota_sys::canota_device_ctx, Error> {
-        // get a device with self and short_device_id
-        let device = unsafe { canota_sys::canota_device_from_short_id(self.ctx, short_device_id) }; // AHHHHH Leaks memory
-        if device.is_null() {
-            Err(Error::DeviceUnavailable { short_device_id })
-        } else {
-            Ok(unsafe { device.as_ref().unwrap() }) // Check this syntax
-        }
-
-    }
-
-    /**
-     * Library Uses Poll to read with timeout Internally
-     */
-    fn raw_receive_frame(&mut self, mask: &mut canota_sys::mask_match, num_matches: u64) -> Result<canota_sys::can_frame, Error> {
-        let mut frame = canota_sys::can_frame::new();
-        let result = unsafe { canota_sys::canota_raw_recv_frame(self.ctx, &mut frame, mask, num_matches) };
-        if result {
-           Ok(frame)
-        } else {
-            Err(Error::RawCommandReadError)
-        }
-    }
-
-    pub fn scan(&mut self) -> Result<Vec<CanotaDeviceInfo>, Error> {
-        let mut device = CanotaDeviceCtxRawCmdCompatible::new(self.ctx).set_short_device_id(0);
-        for i in 0..=255 {
-            device.update_short_device_id(i);
-            device.device_identification_request()?;
-        }
-        let mut mask_match = canota_sys::mask_match {
-            mask:   0x1FFF0000,
-            match_: 0x1F010000
-        };
-        let mut devices = Vec::with_capacity(255); // Most Number of devices that we can have
-        while let Ok(frame) = self.raw_receive_frame(&mut mask_match, 1) {
-            devices.push(CanotaDeviceInfo::from(&frame))
-        }
-        Ok(devices)
-    }
-
-    pub fn change_short_id(&mut self, old_short_id: u8, new_short_id: u8) -> Result<(), Error> {
-        let device = self.get_device_ref(old_short_id)?;
-        let result = unsafe { canota_sys::canota_change_short_id(device, new_short_id) };
-        if result {
-            Ok(())
-        } else {
-            Err(Error::ErrorChangingDeviceShortId)
-        }
-    }
-
-    pub fn set_mode(&mut self) { todo!(); }
-
-    pub fn reset_device(&mut self) { todo!(); }
-
-    pub fn erase_section(&mut self) { todo!(); }
-
-    pub fn get_checksum_for_section(&mut self) { todo!(); }
 
-    pub fn flash_board(&mut self) { todo!(); }
+fn data_length(start_address: u32, data: &[u8]) -> Result<u32, Error> {
+    use std::convert::TryFrom;
+    u32::try_from(data.len()).map_err(|_| Error::InvalidAddressRange { start_address, length: u32::MAX })
 }
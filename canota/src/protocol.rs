@@ -0,0 +1,216 @@
+/**
+ * The canota wire protocol, as spoken by the bootloader of the pod's boards.
+ *
+ * Every frame uses a 29 bit extended identifier which carries the command, a one byte parameter and
+ * the short id of the device. A device answers a request with the same identifier.
+ * ```text
+ *      bits 28-24    23-16     15-8    7-0
+ *           0x1F     command   param   short device id
+ * ```
+ * Multi byte values are little endian.
+ * ```text
+ *  - DEVICE_IDENT     request has no data. The response has the current mode as its param and
+ *                     data = device type, minor version, major version, reserved, device id (u32)
+ *  - MODE_SWITCH      param = mode. The device restarts in the new mode without answering, so the
+ *                     switch is confirmed by identifying the device again
+ *  - CHANGE_SHORT_ID  param = new short id. Answered with a status from the old short id
+ *  - FLASH_ERASE      data = start address, length. Answered with a status
+ *  - FLASH_CHECKSUM   data = start address, length. Answered with data = CRC-32, status
+ *  - BUFFER_WRITE     param = word index, data = 8 bytes for the device's write buffer. Not answered
+ *  - BUFFER_COMMIT    param = number of words, data = address, CRC-32 of the words.
+ *                     Writes the buffer to flash and is answered with a status
+ * ```
+ * A status is a single byte, STATUS_OK or an error code from the device.
+ */
+use crate::bus::CanFrame;
+use crate::{ CanotaDeviceInfo, DeviceMode, DeviceType, DeviceVersion, Error };
+use byteorder::{ ByteOrder, LittleEndian };
+
+pub const FRAME_PREFIX: u32 = 0x1F << 24;
+const PREFIX_MASK: u32 = 0x1F << 24;
+
+pub const DEVICE_IDENT: u8 = 0x01;
+pub const MODE_SWITCH: u8 = 0x02;
+pub const CHANGE_SHORT_ID: u8 = 0x03;
+pub const FLASH_ERASE: u8 = 0x04;
+pub const FLASH_CHECKSUM: u8 = 0x05;
+pub const BUFFER_WRITE: u8 = 0x06;
+pub const BUFFER_COMMIT: u8 = 0x07;
+
+pub const MODE_APPLICATION: u8 = 0x00;
+pub const MODE_BOOTLOADER: u8 = 0x01;
+pub const MODE_RESET_ONLY: u8 = 0xFF;
+
+pub const STATUS_OK: u8 = 0x00;
+
+pub const WORD_SIZE: usize = 8;
+pub const BUFFER_WORDS: usize = 32;
+pub const BUFFER_SIZE: usize = WORD_SIZE * BUFFER_WORDS;
+pub const ERASED_BYTE: u8 = 0xFF;
+
+pub fn frame_id(command: u8, param: u8, short_device_id: u8) -> u32 {
+    FRAME_PREFIX | (command as u32) << 16 | (param as u32) << 8 | short_device_id as u32
+}
+
+pub fn is_canota_frame(frame: &CanFrame) -> bool {
+    frame.id() & PREFIX_MASK == FRAME_PREFIX
+}
+
+pub fn command(frame: &CanFrame) -> u8 {
+    (frame.id() >> 16) as u8
+}
+
+pub fn param(frame: &CanFrame) -> u8 {
+    (frame.id() >> 8) as u8
+}
+
+pub fn short_device_id(frame: &CanFrame) -> u8 {
+    frame.id() as u8
+}
+
+pub fn mode_value(mode: &DeviceMode) -> Result<u8, Error> {
+    match mode {
+        DeviceMode::Application => Ok(MODE_APPLICATION),
+        DeviceMode::Bootloader => Ok(MODE_BOOTLOADER),
+        DeviceMode::Unknown => Err(Error::InvalidMode(DeviceMode::Unknown))
+    }
+}
+
+fn address_and_value(address: u32, value: u32) -> [u8; 8] {
+    let mut data = [0; 8];
+    LittleEndian::write_u32(&mut data[0..4], address);
+    LittleEndian::write_u32(&mut data[4..8], value);
+    data
+}
+
+pub fn device_ident_request(short_device_id: u8) -> CanFrame {
+    CanFrame::new(frame_id(DEVICE_IDENT, 0, short_device_id), &[])
+}
+
+pub fn mode_switch_request(short_device_id: u8, mode: u8) -> CanFrame {
+    CanFrame::new(frame_id(MODE_SWITCH, mode, short_device_id), &[])
+}
+
+pub fn change_short_id_request(short_device_id: u8, new_short_id: u8) -> CanFrame {
+    CanFrame::new(frame_id(CHANGE_SHORT_ID, new_short_id, short_device_id), &[])
+}
+
+pub fn flash_erase_request(short_device_id: u8, start_address: u32, length: u32) -> CanFrame {
+    CanFrame::new(frame_id(FLASH_ERASE, 0, short_device_id), &address_and_value(start_address, length))
+}
+
+pub fn flash_checksum_request(short_device_id: u8, start_address: u32, length: u32) -> CanFrame {
+    CanFrame::new(frame_id(FLASH_CHECKSUM, 0, short_device_id), &address_and_value(start_address, length))
+}
+
+pub fn buffer_write_request(short_device_id: u8, word_index: u8, word: &[u8; WORD_SIZE]) -> CanFrame {
+    CanFrame::new(frame_id(BUFFER_WRITE, word_index, short_device_id), word)
+}
+
+pub fn buffer_commit_request(short_device_id: u8, address: u32, checksum: u32, words: u8) -> CanFrame {
+    CanFrame::new(frame_id(BUFFER_COMMIT, words, short_device_id), &address_and_value(address, checksum))
+}
+
+/**
+ * The device information from a response to DEVICE_IDENT
+ */
+pub fn parse_ident_response(frame: &CanFrame) -> Option<CanotaDeviceInfo> {
+    let data = frame.data();
+    if !is_canota_frame(frame) || command(frame) != DEVICE_IDENT || data.len() < 8 {
+        return None;
+    }
+    Some(CanotaDeviceInfo {
+        short_device_id: short_device_id(frame),
+        device_id: LittleEndian::read_u32(&data[4..8]),
+        mode: DeviceMode::from(param(frame) as u32),
+        device_type: DeviceType::from(data[0]),
+        device_version: DeviceVersion { major: data[2], minor: data[1] }
+    })
+}
+
+pub fn ident_response(info: &CanotaDeviceInfo) -> CanFrame {
+    let mode = mode_value(&info.mode).unwrap_or(MODE_RESET_ONLY);
+    let device_type = match info.device_type {
+        DeviceType::STM32L432KC => 0,
+        DeviceType::Unknown => 0xFF
+    };
+    let mut data = [device_type, info.device_version.minor, info.device_version.major, 0, 0, 0, 0, 0];
+    LittleEndian::write_u32(&mut data[4..8], info.device_id);
+    CanFrame::new(frame_id(DEVICE_IDENT, mode, info.short_device_id), &data)
+}
+
+pub fn status_response(request: &CanFrame, status: u8) -> CanFrame {
+    CanFrame::new(request.id(), &[status])
+}
+
+pub fn checksum_response(request: &CanFrame, checksum: u32, status: u8) -> CanFrame {
+    let mut data = [0; 5];
+    LittleEndian::write_u32(&mut data[0..4], checksum);
+    data[4] = status;
+    CanFrame::new(request.id(), &data)
+}
+
+pub fn parse_status(frame: &CanFrame) -> Option<u8> {
+    frame.data().first().copied()
+}
+
+/**
+ * The checksum and status from a response to FLASH_CHECKSUM
+ */
+pub fn parse_checksum_response(frame: &CanFrame) -> Option<(u32, u8)> {
+    let data = frame.data();
+    if data.len() < 5 {
+        return None;
+    }
+    Some((LittleEndian::read_u32(&data[0..4]), data[4]))
+}
+
+/**
+ * The start address and length of a FLASH_ERASE or FLASH_CHECKSUM request,
+ * or the address and checksum of a BUFFER_COMMIT request
+ */
+pub fn parse_address_and_value(frame: &CanFrame) -> Option<(u32, u32)> {
+    let data = frame.data();
+    if data.len() < 8 {
+        return None;
+    }
+    Some((LittleEndian::read_u32(&data[0..4]), LittleEndian::read_u32(&data[4..8])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_id_layout() {
+        let frame = flash_erase_request(0x12, 0x0800_0800, 0x1000);
+        assert_eq!(frame.id(), 0x1F04_0012);
+        assert!(is_canota_frame(&frame));
+        assert_eq!(command(&frame), FLASH_ERASE);
+        assert_eq!(short_device_id(&frame), 0x12);
+        assert_eq!(parse_address_and_value(&frame), Some((0x0800_0800, 0x1000)));
+        assert_eq!(param(&mode_switch_request(3, MODE_BOOTLOADER)), MODE_BOOTLOADER);
+        assert!(!is_canota_frame(&CanFrame::new(0x123, &[])));
+    }
+
+    #[test]
+    fn ident_round_trip() {
+        let frame = CanFrame::new(frame_id(DEVICE_IDENT, MODE_BOOTLOADER, 7), &[0, 3, 1, 0, 0x78, 0x56, 0x34, 0x12]);
+        let info = parse_ident_response(&frame).unwrap();
+        assert_eq!(info.short_device_id(), 7);
+        assert_eq!(info.device_id(), 0x1234_5678);
+        assert_eq!(info.mode(), &DeviceMode::Bootloader);
+        assert_eq!(info.device_type(), &DeviceType::STM32L432KC);
+        assert_eq!((info.device_version().major(), info.device_version().minor()), (1, 3));
+        assert_eq!(ident_response(&info), frame);
+        assert!(parse_ident_response(&device_ident_request(7)).is_none());
+    }
+
+    #[test]
+    fn checksum_response_round_trip() {
+        let request = flash_checksum_request(1, 0x0800_0000, 16);
+        let response = checksum_response(&request, 0xCBF4_3926, STATUS_OK);
+        assert_eq!(parse_checksum_response(&response), Some((0xCBF4_3926, STATUS_OK)));
+        assert_eq!(parse_status(&status_response(&request, 2)), Some(2));
+    }
+}
@@ -0,0 +1,357 @@
+/**
+ * Simulated canota devices, to test scanning and flashing without the pod's boards.
+ *
+ * A SimulatedDevice answers canota requests like the bootloader does and keeps its flash in memory.
+ * SimulatedBus connects devices to a Canota without a CAN interface. Devices can also be driven from
+ * any other bus, such as a vcan interface, by passing every received frame to SimulatedDevice::handle.
+ */
+use crate::bus::{ CanBus, CanFrame };
+use crate::protocol;
+use crate::{ crc32, CanotaDeviceInfo, DeviceMode, DeviceType, DeviceVersion, Error, FlashMap };
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// Error codes reported by the simulated bootloader
+pub const STATUS_NOT_IN_BOOTLOADER: u8 = 0x01;
+pub const STATUS_INVALID_ADDRESS: u8 = 0x02;
+pub const STATUS_BUFFER_CHECKSUM: u8 = 0x03;
+pub const STATUS_NOT_ERASED: u8 = 0x04;
+
+pub struct SimulatedDeviceInitializer {
+    pub short_device_id: u8,
+    pub device_id: u32,
+    pub device_type: DeviceType,
+    pub major: u8,
+    pub minor: u8,
+    pub mode: DeviceMode
+}
+
+impl Default for SimulatedDeviceInitializer {
+    fn default() -> SimulatedDeviceInitializer {
+        SimulatedDeviceInitializer {
+            short_device_id: 1,
+            device_id: 0x1000_0001,
+            device_type: DeviceType::STM32L432KC,
+            major: 1,
+            minor: 0,
+            mode: DeviceMode::Application
+        }
+    }
+}
+
+/**
+ * Faults injected into a device's handling of requests
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    pub dropped_frames: usize, // The next frames sent to the device are lost
+    pub ignored_commands: Vec<u8>, // Requests with these commands are never answered
+    pub bad_checksum: bool, // FLASH_CHECKSUM answers with a checksum which does not match the flash
+    pub error_status: Option<(u8, u8)> // (command, status) answers every request with command with status
+}
+
+pub struct SimulatedDevice {
+    info: CanotaDeviceInfo,
+    flash_map: Option<FlashMap>,
+    flash: Vec<u8>,
+    buffer: [u8; protocol::BUFFER_SIZE],
+    faults: Faults,
+    resets: usize
+}
+
+impl SimulatedDevice {
+    pub fn new(initializer: SimulatedDeviceInitializer) -> SimulatedDevice {
+        let flash_map = FlashMap::for_device_type(&initializer.device_type);
+        let flash_size = flash_map.map_or(0, |flash_map| flash_map.size as usize);
+        SimulatedDevice {
+            info: CanotaDeviceInfo {
+                short_device_id: initializer.short_device_id,
+                device_id: initializer.device_id,
+                mode: initializer.mode,
+                device_type: initializer.device_type,
+                device_version: DeviceVersion { major: initializer.major, minor: initializer.minor }
+            },
+            flash_map,
+            flash: vec![protocol::ERASED_BYTE; flash_size],
+            buffer: [protocol::ERASED_BYTE; protocol::BUFFER_SIZE],
+            faults: Faults::default(),
+            resets: 0
+        }
+    }
+
+    pub fn info(&self) -> &CanotaDeviceInfo {
+        &self.info
+    }
+
+    pub fn faults(&mut self) -> &mut Faults {
+        &mut self.faults
+    }
+
+    /**
+     * How many times the device has restarted, for a mode switch or a reset
+     */
+    pub fn resets(&self) -> usize {
+        self.resets
+    }
+
+    /**
+     * The contents of the device's flash. Panics if the range is outside of the flash
+     */
+    pub fn flash(&self, start_address: u32, length: u32) -> &[u8] {
+        let start = (start_address - self.flash_map.expect("Device has no flash").start_address) as usize;
+        &self.flash[start..start + length as usize]
+    }
+
+    /**
+     * Offset into the flash of a range which the device's flash map allows
+     */
+    fn flash_offset(&self, start_address: u32, length: u32, erase: bool) -> Result<usize, u8> {
+        let flash_map = self.flash_map.ok_or(STATUS_INVALID_ADDRESS)?;
+        let valid = if erase {
+            flash_map.validate_erase_range(start_address, length)
+        } else {
+            flash_map.validate_range(start_address, length)
+        };
+        valid.map_err(|_| STATUS_INVALID_ADDRESS)?;
+        Ok((start_address - flash_map.start_address) as usize)
+    }
+
+    fn erase(&mut self, request: &CanFrame) -> Result<(), u8> {
+        let (start_address, length) = protocol::parse_address_and_value(request).ok_or(STATUS_INVALID_ADDRESS)?;
+        let start = self.flash_offset(start_address, length, true)?;
+        self.flash[start..start + length as usize].iter_mut().for_each(|byte| *byte = protocol::ERASED_BYTE);
+        Ok(())
+    }
+
+    fn commit(&mut self, request: &CanFrame) -> Result<(), u8> {
+        let (address, checksum) = protocol::parse_address_and_value(request).ok_or(STATUS_INVALID_ADDRESS)?;
+        let length = protocol::param(request) as usize * protocol::WORD_SIZE;
+        if length > protocol::BUFFER_SIZE || crc32::crc32(&self.buffer[..length]) != checksum {
+            return Err(STATUS_BUFFER_CHECKSUM);
+        }
+        let start = self.flash_offset(address, length as u32, false)?;
+        let target = &mut self.flash[start..start + length];
+        if target.iter().any(|byte| *byte != protocol::ERASED_BYTE) {
+            return Err(STATUS_NOT_ERASED);
+        }
+        target.copy_from_slice(&self.buffer[..length]);
+        self.buffer = [protocol::ERASED_BYTE; protocol::BUFFER_SIZE];
+        Ok(())
+    }
+
+    fn checksum(&self, request: &CanFrame) -> Result<u32, u8> {
+        let (start_address, length) = protocol::parse_address_and_value(request).ok_or(STATUS_INVALID_ADDRESS)?;
+        let start = self.flash_offset(start_address, length, false)?;
+        let checksum = crc32::crc32(&self.flash[start..start + length as usize]);
+        Ok(if self.faults.bad_checksum { !checksum } else { checksum })
+    }
+
+    fn restart(&mut self, mode: u8) {
+        self.resets += 1;
+        self.buffer = [protocol::ERASED_BYTE; protocol::BUFFER_SIZE];
+        match mode {
+            protocol::MODE_APPLICATION => self.info.mode = DeviceMode::Application,
+            protocol::MODE_BOOTLOADER => self.info.mode = DeviceMode::Bootloader,
+            _ => {}
+        }
+    }
+
+    fn status_response(request: &CanFrame, result: Result<(), u8>) -> Vec<CanFrame> {
+        vec![protocol::status_response(request, result.err().unwrap_or(protocol::STATUS_OK))]
+    }
+
+    /**
+     * Handle a frame from the bus and return the device's responses
+     */
+    pub fn handle(&mut self, frame: &CanFrame) -> Vec<CanFrame> {
+        if !protocol::is_canota_frame(frame) || protocol::short_device_id(frame) != self.info.short_device_id {
+            return Vec::new();
+        }
+        if self.faults.dropped_frames > 0 {
+            self.faults.dropped_frames -= 1;
+            return Vec::new();
+        }
+        let command = protocol::command(frame);
+        if self.faults.ignored_commands.contains(&command) {
+            return Vec::new();
+        }
+        if let Some((_, status)) = self.faults.error_status.filter(|(error_command, _)| *error_command == command) {
+            return vec![protocol::status_response(frame, status)];
+        }
+        let in_bootloader = self.info.mode == DeviceMode::Bootloader;
+
+        match command {
+            protocol::DEVICE_IDENT => vec![protocol::ident_response(&self.info)],
+            protocol::MODE_SWITCH => {
+                self.restart(protocol::param(frame));
+                Vec::new()
+            },
+            protocol::CHANGE_SHORT_ID => {
+                let response = protocol::status_response(frame, protocol::STATUS_OK);
+                self.info.short_device_id = protocol::param(frame);
+                vec![response]
+            },
+            protocol::FLASH_ERASE if !in_bootloader => Self::status_response(frame, Err(STATUS_NOT_IN_BOOTLOADER)),
+            protocol::FLASH_ERASE => Self::status_response(frame, self.erase(frame)),
+            protocol::FLASH_CHECKSUM => match self.checksum(frame) {
+                Ok(checksum) => vec![protocol::checksum_response(frame, checksum, protocol::STATUS_OK)],
+                Err(status) => vec![protocol::checksum_response(frame, 0, status)]
+            },
+            protocol::BUFFER_WRITE => {
+                let start = protocol::param(frame) as usize * protocol::WORD_SIZE;
+                if in_bootloader && start < protocol::BUFFER_SIZE && frame.data().len() == protocol::WORD_SIZE {
+                    self.buffer[start..start + protocol::WORD_SIZE].copy_from_slice(frame.data());
+                }
+                Vec::new()
+            },
+            protocol::BUFFER_COMMIT if !in_bootloader => Self::status_response(frame, Err(STATUS_NOT_IN_BOOTLOADER)),
+            protocol::BUFFER_COMMIT => Self::status_response(frame, self.commit(frame)),
+            _ => Vec::new()
+        }
+    }
+}
+
+/**
+ * An in-memory bus shared by simulated devices. Devices answer as soon as a frame is sent,
+ * so receive never has to wait.
+ */
+pub struct SimulatedBus {
+    devices: Vec<SimulatedDevice>,
+    pending: VecDeque<CanFrame>,
+    sent: Vec<CanFrame>
+}
+
+impl SimulatedBus {
+    pub fn new(devices: Vec<SimulatedDevice>) -> SimulatedBus {
+        SimulatedBus { devices, pending: VecDeque::new(), sent: Vec::new() }
+    }
+
+    pub fn devices(&mut self) -> &mut Vec<SimulatedDevice> {
+        &mut self.devices
+    }
+
+    /**
+     * The device currently using short_device_id
+     */
+    pub fn device(&mut self, short_device_id: u8) -> Option<&mut SimulatedDevice> {
+        self.devices.iter_mut().find(|device| device.info.short_device_id == short_device_id)
+    }
+
+    /**
+     * Every frame sent on the bus by canota
+     */
+    pub fn sent(&self) -> &[CanFrame] {
+        &self.sent
+    }
+}
+
+impl CanBus for SimulatedBus {
+    fn send(&mut self, frame: &CanFrame) -> Result<(), Error> {
+        self.sent.push(*frame);
+        for device in self.devices.iter_mut() {
+            self.pending.extend(device.handle(frame));
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, _timeout: Duration) -> Result<Option<CanFrame>, Error> {
+        Ok(self.pending.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ Canota, FirmwareImage, FlashDevice, FlashProgress };
+
+    fn device(short_device_id: u8, mode: DeviceMode) -> SimulatedDevice {
+        SimulatedDevice::new(SimulatedDeviceInitializer {
+            short_device_id,
+            device_id: 0xC0DE_0000 | short_device_id as u32,
+            minor: 2,
+            mode,
+            ..Default::default()
+        })
+    }
+
+    fn image() -> FirmwareImage {
+        let data: Vec<u8> = (0..5000u32).map(|byte| (byte * 7) as u8).collect();
+        FirmwareImage::from_bin(&data, 0x0800_4000).unwrap()
+    }
+
+    #[test]
+    fn scan_finds_devices() {
+        let mut canota = Canota::with_bus(SimulatedBus::new(vec![device(2, DeviceMode::Application), device(40, DeviceMode::Bootloader)]));
+        let inventory = canota.scan().unwrap();
+        assert_eq!(inventory.len(), 2);
+        assert_eq!(inventory.devices()[0].device_id(), 0xC0DE_0002);
+        assert_eq!(inventory.device(40).unwrap().mode(), &DeviceMode::Bootloader);
+        assert_eq!(inventory.devices()[1].device_version().minor(), 2);
+    }
+
+    #[test]
+    fn scan_reports_short_id_collisions() {
+        let mut duplicate = device(2, DeviceMode::Application);
+        duplicate.info.device_id = 0xC0DE_1000;
+        let mut canota = Canota::with_bus(SimulatedBus::new(vec![device(2, DeviceMode::Application), duplicate, device(3, DeviceMode::Application)]));
+        let inventory = canota.scan().unwrap();
+        assert_eq!(inventory.len(), 3);
+        assert!(inventory.is_collision(2));
+        assert_eq!(inventory.collisions()[0].device_ids, vec![0xC0DE_0002, 0xC0DE_1000]);
+        assert!(inventory.device(3).is_some());
+    }
+
+    #[test]
+    fn flash_image() {
+        let mut canota = Canota::with_bus(SimulatedBus::new(vec![device(5, DeviceMode::Application)]));
+        let image = image();
+        let mut progress = Vec::new();
+        {
+            let mut device = canota.device(5).unwrap();
+            assert!(matches!(device.erase_section(0x0800_4000, 2048), Err(Error::DeviceError { status: STATUS_NOT_IN_BOOTLOADER, .. })));
+            device.set_mode(DeviceMode::Bootloader).unwrap();
+            device.flash_image(&image, |step| progress.push(step)).unwrap();
+            device.set_mode(DeviceMode::Application).unwrap();
+        }
+        assert_eq!(progress.first(), Some(&FlashProgress::Erasing { start_address: 0x0800_4000, length: 6144 }));
+        assert_eq!(progress.last(), Some(&FlashProgress::Verified { start_address: 0x0800_4000, checksum: crc32::crc32(&image.segments()[0].data) }));
+
+        let simulated = canota.bus().device(5).unwrap();
+        assert_eq!(simulated.flash(0x0800_4000, 5000), &image.segments()[0].data[..]);
+        assert_eq!(simulated.flash(0x0800_4000 + 5000, 8), &[protocol::ERASED_BYTE; 8]);
+        assert_eq!(simulated.info().mode(), &DeviceMode::Application);
+        assert_eq!(simulated.resets(), 2);
+    }
+
+    #[test]
+    fn change_short_id() {
+        let mut canota = Canota::with_bus(SimulatedBus::new(vec![device(5, DeviceMode::Application)]));
+        canota.change_short_id(5, 6).unwrap();
+        assert!(canota.bus().device(5).is_none());
+        assert_eq!(canota.info(6).unwrap().device_id(), 0xC0DE_0005);
+    }
+
+    #[test]
+    fn injected_faults() {
+        let mut bus = SimulatedBus::new(vec![device(5, DeviceMode::Bootloader)]);
+        bus.device(5).unwrap().faults().bad_checksum = true;
+        let mut canota = Canota::with_bus(bus);
+        assert!(matches!(canota.device(5).unwrap().flash_image(&image(), |_| {}), Err(Error::ChecksumMismatch { .. })));
+
+        let device = canota.bus().device(5).unwrap();
+        device.faults().bad_checksum = false;
+        device.faults().dropped_frames = 1;
+        assert!(matches!(canota.info(5), Err(Error::DeviceUnavailable { short_device_id: 5 })));
+        assert!(canota.info(5).is_ok());
+
+        canota.bus().device(5).unwrap().faults().ignored_commands.push(protocol::BUFFER_COMMIT);
+        assert!(matches!(canota.write_section(5, 0x0800_0000, &[1; 8]), Err(Error::Timeout { command: protocol::BUFFER_COMMIT, .. })));
+
+        // Frames are written to erased flash only
+        canota.bus().device(5).unwrap().faults().ignored_commands.clear();
+        assert!(matches!(canota.write_section(5, 0x0800_4000, &[1; 8]), Err(Error::DeviceError { status: STATUS_NOT_ERASED, .. })));
+
+        canota.bus().device(5).unwrap().faults().error_status = Some((protocol::FLASH_ERASE, 0x7F));
+        assert!(matches!(canota.erase_section(5, 0x0800_0000, 2048), Err(Error::DeviceError { status: 0x7F, .. })));
+    }
+}
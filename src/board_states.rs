@@ -4,6 +4,7 @@ use crate::pod_states::{ PodState };
  * @brief Provides an interface for tracking the state of our embeded
  * boards.
  */
+#[derive(Clone, Copy)]
 pub struct BoardStates {
     bms_state: PodState,
     motor_controller_state: PodState,
@@ -14,6 +15,26 @@ pub enum Error {
     InvalidTransision
 }
 
+/**
+ * @brief The boards which take part in pod state transitions
+ */
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum Board {
+    Bms,
+    MotorController,
+    Pressure
+}
+
+impl Board {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Board::Bms             => "bms",
+            Board::MotorController => "motor_controller",
+            Board::Pressure        => "pressure"
+        }
+    }
+}
+
 impl BoardStates {
     /**
      * @breif contruct a BoardState object with default values
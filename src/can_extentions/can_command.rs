@@ -6,6 +6,7 @@
  */
 use crate::can_extentions::fault_reports::{ BmsFaultReport, MotorControllerFaultReport };
 use crate::can_extentions::ack_nack::AckNack;
+use crate::board_states::Board;
 
 // The full list that need to be supported
 // can be found here: (Can Communication Protocol) [https://docs.google.com/document/d/1pAAAPyWClxrq7MwrA0_AGxnqU6B5r5MHmvRERMY6hUo/edit]
@@ -34,5 +35,7 @@ pub enum CanCommand {
     RoboteqTemperatureResult{ sub_index: u8, temp: i8},
     RoboteqBatteryAmpsResult{ motor_number: u8, amps: i16},
     RoboteqMotorEncoderResult{ motor_number: u8, speed: i32},
+    RoboteqHeartbeat{ nmt_state: u8 }, // CANopen heartbeat, only sent when the roboteq is configured to produce one
+    RelayHeartbeatEcho{ board: Board, counter: u16 }, // A board answering the relay heartbeat with the given counter
     Unknown(u32), // Arbitration ID provided for debugging Purposes
 }
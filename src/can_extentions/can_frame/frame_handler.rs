@@ -1,6 +1,8 @@
 use super::super::can_command::CanCommand;
 use super::super::fault_reports::{ BmsFaultReport, MotorControllerFaultReport };
 use super::super::ack_nack::AckNack;
+use crate::board_states::Board;
+use super::super::can_socket::{ ROBOTEQ_NODE_ID, SDO_RESPONSE_BASE, HEARTBEAT_BASE };
 use byteorder::{ LittleEndian, ByteOrder };
 
 /**
@@ -28,35 +30,41 @@ impl FrameHandler for socketcan::CANFrame {
             0x00C => CanCommand::BmsData1{ battery_pack_voltage: parse_first_float(data), state_of_charge: parse_second_float(data)},
             0x00D => CanCommand::BmsData2{ buck_temperature: parse_first_float(data), bms_current: parse_second_float(data)},
             0x00E => CanCommand::BmsData3{ link_cap_voltage: parse_first_float(data)},
+            0x00F => get_heartbeat_echo(id, Board::Bms, data),
             0x014 => CanCommand::MotorControllerFaultReport(MotorControllerFaultReport::from(data)),
             0x015 => CanCommand::MotorControllerStateChange(get_state_change_ack(data)),
             0x016 => CanCommand::MotorControllerData1{ mc_pod_speed: parse_first_float(data), motor_current: parse_second_float(data) },
             0x017 => CanCommand::MotorControllerData2{ battery_current: parse_first_float(data), battery_voltage: parse_second_float(data) },
+            0x018 => get_heartbeat_echo(id, Board::MotorController, data),
             0x01F => CanCommand::PodSpeed{ pod_speed: parse_first_float(data)},
             0x020 => CanCommand::PressureHigh(parse_first_float(data)),
             0x021 => CanCommand::PressureLow1(parse_first_float(data)),
             0x022 => CanCommand::PressureLow2(parse_first_float(data)),
             0x023 => CanCommand::PressureStateChange(get_state_change_ack(data)),
+            0x024 => get_heartbeat_echo(id, Board::Pressure, data),
             0x030 => CanCommand::Current5V(parse_first_float(data)),
             0x031 => CanCommand::Current12V(parse_first_float(data)),
             0x032 => CanCommand::Current24V(parse_first_float(data)),
             0x040 => CanCommand::Torchic1([Some(parse_first_float(data)), Some(parse_second_float(data))]),
             0x041 => CanCommand::Torchic2([Some(parse_first_float(data)), Some(parse_second_float(data))]),
-            0x581 => {
-                /* ROBOTEQ HANDLER */
+            id if id == SDO_RESPONSE_BASE + ROBOTEQ_NODE_ID => {
+                /* ROBOTEQ HANDLER. CANopen SDOs are little endian and always carry 8 bytes */
+                if data.len() < 8 {
+                    return CanCommand::Unknown(id);
+                }
                 let flags = data[0];
-                let index = (data[1] as u16) << 8 | (data[2] as u16);
+                let index = LittleEndian::read_u16(&data[1..3]);
                 let subindex = data[3];
-                match (flags | ROBOTEQ_MSG_CSS) >> 4 {
+                match (flags & ROBOTEQ_MSG_CSS) >> 4 {
                     0x4 => {
                         match index {
                             0x2103 => CanCommand::RoboteqMotorEncoderResult{
                                 motor_number: subindex,
-                                speed: (((data[4] as u32) << 24) | ((data[5] as u32) << 16) | ((data[6] as u32) << 8) | (data[7] as u32)) as i32
+                                speed: LittleEndian::read_i32(&data[4..8])
                             },
                             0x210C => CanCommand::RoboteqBatteryAmpsResult{
                                 motor_number: subindex,
-                                amps: (((data[4] as u32) << 8) | ((data[5] as u32))) as i16
+                                amps: LittleEndian::read_i16(&data[4..6])
                             },
                             0x210f => CanCommand::RoboteqTemperatureResult{
                                 sub_index: subindex,
@@ -73,6 +81,7 @@ impl FrameHandler for socketcan::CANFrame {
                     _ => CanCommand::Unknown(id)
                 }
             }
+            id if id == HEARTBEAT_BASE + ROBOTEQ_NODE_ID && !data.is_empty() => CanCommand::RoboteqHeartbeat{ nmt_state: data[0] },
             id => CanCommand::Unknown(id)
         }
     }
@@ -88,6 +97,15 @@ fn get_state_change_ack(data: &[u8]) -> AckNack {
     else { AckNack::from(data[1]) }
 }
 
+/**
+ * @func:  get_heartbeat_echo
+ * @brief: A board echoing the relay heartbeat starts its frame with the counter it received
+ */
+fn get_heartbeat_echo(id: u32, board: Board, data: &[u8]) -> CanCommand {
+    if data.len() < 2 { CanCommand::Unknown(id) }
+    else { CanCommand::RelayHeartbeatEcho{ board, counter: LittleEndian::read_u16(&data[0..2]) } }
+}
+
 /**
  * @func parse_two_floats
  * @brief parse frames consisting of 2 4-byte floats
@@ -103,3 +121,42 @@ fn parse_first_float(data: &[u8]) -> f32 {
 fn parse_second_float(data: &[u8]) -> f32 {
     LittleEndian::read_f32(&[data[4], data[5], data[6], data[7]])
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(id: u32, data: &[u8]) -> socketcan::CANFrame {
+        socketcan::CANFrame::new(id, data, false, false).unwrap()
+    }
+
+    #[test]
+    fn roboteq_responses() {
+        // Response to roboteq_read_encoder_motor_speed(1, 2) with a speed of -300
+        let speed = frame(0x581, &[0x43, 0x03, 0x21, 0x02, 0xD4, 0xFE, 0xFF, 0xFF]);
+        assert!(matches!(speed.get_command(), CanCommand::RoboteqMotorEncoderResult{ motor_number: 2, speed: -300 }));
+        let amps = frame(0x581, &[0x4B, 0x0C, 0x21, 0x01, 0x2C, 0x01, 0x00, 0x00]);
+        assert!(matches!(amps.get_command(), CanCommand::RoboteqBatteryAmpsResult{ motor_number: 1, amps: 300 }));
+        assert!(matches!(frame(0x582, &[0x43, 0x03, 0x21, 0x02, 0, 0, 0, 0]).get_command(), CanCommand::Unknown(0x582)));
+    }
+
+    #[test]
+    fn short_roboteq_response() {
+        assert!(matches!(frame(0x581, &[0x43, 0x03, 0x21]).get_command(), CanCommand::Unknown(0x581)));
+        assert!(matches!(frame(0x581, &[]).get_command(), CanCommand::Unknown(0x581)));
+    }
+
+    #[test]
+    fn roboteq_heartbeat() {
+        assert!(matches!(frame(0x701, &[0x05]).get_command(), CanCommand::RoboteqHeartbeat{ nmt_state: 0x05 }));
+        assert!(matches!(frame(0x702, &[0x05]).get_command(), CanCommand::Unknown(0x702)));
+        assert!(matches!(frame(0x701, &[]).get_command(), CanCommand::Unknown(0x701)));
+    }
+
+    #[test]
+    fn heartbeat_echoes() {
+        assert!(matches!(frame(0x00F, &[0x34, 0x12]).get_command(), CanCommand::RelayHeartbeatEcho{ board: Board::Bms, counter: 0x1234 }));
+        assert!(matches!(frame(0x024, &[0x01, 0x00, 0x10, 0x00, 0x00, 0x00]).get_command(), CanCommand::RelayHeartbeatEcho{ board: Board::Pressure, counter: 1 }));
+        assert!(matches!(frame(0x018, &[0x01]).get_command(), CanCommand::Unknown(0x018)));
+    }
+}
@@ -1,5 +1,5 @@
 mod relay_can;
 mod roboteq;
 
-pub use relay_can::RelayCanSocket;
-pub use roboteq::RoboteqCanSocket;
\ No newline at end of file
+pub use relay_can::{ RelayCanSocket, RELAY_HEARTBEAT_ID };
+pub use roboteq::{ RoboteqCanSocket, ROBOTEQ_NODE_ID, SDO_RESPONSE_BASE, HEARTBEAT_BASE, NMT_STOPPED };
\ No newline at end of file
@@ -10,9 +10,16 @@
 use crate::pod_states::PodState;
 use crate::can_extentions::prelude::CanError as Error;
 use socketcan::{ CANSocket, CANFrame };
+use byteorder::{ LittleEndian, ByteOrder };
+
+/**
+ * Arbitration id of the relay heartbeat, see crate::relay_heartbeat
+ */
+pub const RELAY_HEARTBEAT_ID: u32 = 0x003;
 
 pub trait RelayCanSocket {
     fn send_pod_state(&self, state: &PodState) -> Result<(), Error>;
+    fn send_heartbeat(&self, counter: u16, uptime_ms: u32) -> Result<(), Error>;
 }
 
 impl RelayCanSocket for CANSocket {
@@ -21,4 +28,13 @@ impl RelayCanSocket for CANSocket {
             &CANFrame::new(0, &[state.into()], false, false)?
         ).map_err(|e| Error::WriteError(e))
     }
+
+    fn send_heartbeat(&self, counter: u16, uptime_ms: u32) -> Result<(), Error> {
+        let mut data = [0u8; 6];
+        LittleEndian::write_u16(&mut data[0..2], counter);
+        LittleEndian::write_u32(&mut data[2..6], uptime_ms);
+        self.write_frame_insist(
+            &CANFrame::new(RELAY_HEARTBEAT_ID, &data, false, false)?
+        ).map_err(Error::WriteError)
+    }
 }
\ No newline at end of file
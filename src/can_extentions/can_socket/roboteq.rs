@@ -11,6 +11,20 @@
 use socketcan;
 use super::super::error::CanError as Error;
 
+/**
+ * CANopen node id of the roboteq on the pod's bus
+ */
+pub const ROBOTEQ_NODE_ID: u32 = 1;
+/**
+ * SDO responses are sent on SDO_RESPONSE_BASE + node id and heartbeats on HEARTBEAT_BASE + node id
+ */
+pub const SDO_RESPONSE_BASE: u32 = 0x580;
+pub const HEARTBEAT_BASE: u32 = 0x700;
+/**
+ * NMT state reported by the heartbeat of a node which has been stopped
+ */
+pub const NMT_STOPPED: u8 = 0x04;
+
 pub trait RoboteqCanSocket {
     fn send_msg(&self, node_id: u32, is_query: bool, empty_bytes: u32, index: u16, subindex: u8, data: &[u8]) -> Result<(), Error>;
     fn set_motor_throttle(&self, node_id: u32, motor_number: u8, throttle_percent: u32) -> Result<(), Error>;
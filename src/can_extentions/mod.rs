@@ -19,6 +19,8 @@ where S: Into<&'a str> {
 pub mod prelude {
     pub use super::can_frame::FrameHandler;
     pub use super::can_socket::{ RoboteqCanSocket, RelayCanSocket };
+    pub use super::can_socket::RELAY_HEARTBEAT_ID;
+    pub use super::can_socket::{ ROBOTEQ_NODE_ID, SDO_RESPONSE_BASE, HEARTBEAT_BASE, NMT_STOPPED };
     pub use super::error::CanError;
     pub use super::can_command::CanCommand;
 }
\ No newline at end of file
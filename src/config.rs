@@ -3,6 +3,9 @@ use std::net::{
     Ipv4Addr,
     SocketAddr,
 };
+use json::{ JsonValue, object };
+use crate::firmware_manifest::FirmwareManifest;
+use crate::device_watchdog::WatchdogConfig;
 
 #[cfg(test)]
 mod test {
@@ -43,6 +46,85 @@ mod test {
         assert_eq!(config_dut.tcp_address.port(), expected_address.port());
         assert_eq!(config_dut.buffer_size, expected_size);
     }
+
+    #[test]
+    fn config_from_args_auth_key() {
+        let key_path = std::env::temp_dir().join("relay_config_from_args_auth_key");
+        std::fs::write(&key_path, "00ff10\n").unwrap();
+        let args = vec!["test program", "-kf", key_path.to_str().unwrap()];
+        let args: Vec<String> = args.iter().map(|arg| String::from(*arg)).collect();
+
+        let config_dut = Config::from_args(&args);
+        std::fs::remove_file(&key_path).unwrap();
+
+        assert_eq!(config_dut.auth_key, Some(vec![0x00, 0xff, 0x10]));
+        assert_eq!(Config::default().auth_key, None);
+    }
+
+    #[test]
+    fn config_from_args_max_malformed_packets() {
+        let args = vec!["test program", "-mp", "3"];
+        let args: Vec<String> = args.iter().map(|arg| String::from(*arg)).collect();
+
+        let config_dut = Config::from_args(&args);
+
+        assert_eq!(config_dut.max_malformed_packets, 3);
+        assert_eq!(Config::default().max_malformed_packets, 10);
+    }
+
+    #[test]
+    fn config_from_args_telemetry_rate() {
+        let args = vec!["test program", "-tr", "50"];
+        let args: Vec<String> = args.iter().map(|arg| String::from(*arg)).collect();
+
+        let config_dut = Config::from_args(&args);
+
+        assert_eq!(config_dut.telemetry_rate_hz, 50);
+        assert_eq!(Config::default().telemetry_rate_hz, 20);
+    }
+
+    #[test]
+    fn config_from_args_firmware_manifest() {
+        let manifest_path = std::env::temp_dir().join("relay_config_from_args_firmware_manifest");
+        std::fs::write(&manifest_path, r#"{ "block_arming": true, "devices": [{ "short_device_id": 4, "role": "BMS", "version": "1.0" }] }"#).unwrap();
+        let args = vec!["test program", "-fm", manifest_path.to_str().unwrap()];
+        let args: Vec<String> = args.iter().map(|arg| String::from(*arg)).collect();
+
+        let config_dut = Config::from_args(&args);
+        std::fs::remove_file(&manifest_path).unwrap();
+
+        let manifest = config_dut.firmware_manifest.as_ref().unwrap();
+        assert!(manifest.block_arming);
+        assert_eq!(manifest.devices[0].short_device_id, 4);
+        assert_eq!(config_dut.to_json()["block_arming_on_firmware_mismatch"], true);
+        assert!(Config::default().firmware_manifest.is_none());
+    }
+
+    #[test]
+    fn config_from_args_watchdog() {
+        let config_path = std::env::temp_dir().join("relay_config_from_args_watchdog");
+        std::fs::write(&config_path, r#"{ "periods_ms": { "BMS": 100 } }"#).unwrap();
+        let args = vec!["test program", "-wc", config_path.to_str().unwrap()];
+        let args: Vec<String> = args.iter().map(|arg| String::from(*arg)).collect();
+
+        let config_dut = Config::from_args(&args);
+        std::fs::remove_file(&config_path).unwrap();
+
+        assert_eq!(config_dut.watchdog.period(crate::device_watchdog::Device::BMS), 100);
+        assert_eq!(config_dut.to_json()["watchdog"]["periods_ms"]["BMS"], 100);
+    }
+
+    #[test]
+    fn config_to_json() {
+        let mut config_dut = Config::default();
+        config_dut.auth_key = Some(vec![0xaa, 0xbb]);
+        let json = config_dut.to_json();
+
+        assert_eq!(json["buffer_size"], 256);
+        assert_eq!(json["can_interface"], "can0");
+        assert_eq!(json["authentication"], true);
+        assert!(!json.dump().contains("aabb"));
+    }
 }
 
 
@@ -50,7 +132,12 @@ pub struct Config<A: std::net::ToSocketAddrs + std::fmt::Debug + Send + 'static>
     pub tcp_address: A,
     pub udp_address: A,
     pub buffer_size: usize,
-    pub can_interface: String
+    pub can_interface: String,
+    pub auth_key: Option<Vec<u8>>, // Shared key for authenticating the desktop. Authentication is disabled when None
+    pub max_malformed_packets: u32, // Consecutive malformed desktop packets before the relay enters recovery
+    pub telemetry_rate_hz: u32, // Rate that PodStateMessages are sent to the desktop
+    pub firmware_manifest: Option<FirmwareManifest>, // Boards expected on the CAN bus and their firmware
+    pub watchdog: WatchdogConfig // Expected message period of each device and the devices each pod state requires
 }
 
 impl<A: std::net::ToSocketAddrs + std::fmt::Debug + Send + 'static> Config<A> {
@@ -60,7 +147,32 @@ impl<A: std::net::ToSocketAddrs + std::fmt::Debug + Send + 'static> Config<A> {
             tcp_address,
             buffer_size,
             can_interface,
-            udp_address
+            udp_address,
+            auth_key: None,
+            max_malformed_packets: 10,
+            telemetry_rate_hz: 20,
+            firmware_manifest: None,
+            watchdog: WatchdogConfig::default()
+        }
+    }
+}
+
+impl<A: std::net::ToSocketAddrs + std::fmt::Debug + Send + 'static> Config<A> {
+    /**
+     * @brief The effective configuration as reported by the CONFIG query. The auth key itself is never reported
+     */
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            tcp_address: format!("{:?}", self.tcp_address),
+            udp_address: format!("{:?}", self.udp_address),
+            buffer_size: self.buffer_size,
+            can_interface: self.can_interface.as_str(),
+            authentication: self.auth_key.is_some(),
+            max_malformed_packets: self.max_malformed_packets,
+            telemetry_rate_hz: self.telemetry_rate_hz,
+            firmware_manifest: self.firmware_manifest.is_some(),
+            block_arming_on_firmware_mismatch: matches!(&self.firmware_manifest, Some(manifest) if manifest.block_arming),
+            watchdog: self.watchdog.to_json(),
         }
     }
 }
@@ -71,7 +183,12 @@ impl Config<SocketAddr> {
             tcp_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080),
             udp_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080),
             buffer_size: 256,
-            can_interface: String::from("can0")
+            can_interface: String::from("can0"),
+            auth_key: None,
+            max_malformed_packets: 10,
+            telemetry_rate_hz: 20,
+            firmware_manifest: None,
+            watchdog: WatchdogConfig::default()
         }
     }
 
@@ -88,6 +205,11 @@ impl Config<SocketAddr> {
      * -ua hostIpv4:port
      * -b buffer_size
      * -ci can_interface
+     * -kf path_to_hex_encoded_auth_key
+     * -mp max_consecutive_malformed_packets
+     * -tr telemetry_rate_hz
+     * -fm path_to_firmware_manifest
+     * -wc path_to_watchdog_config
      */
     pub fn from_args(args: &Vec<String>) -> Config<SocketAddr> {
         if args.len() % 2 == 0 {
@@ -143,6 +265,36 @@ impl Config<SocketAddr> {
                 "-ci" => {
                     let can_interface = String::from(param);
                     config.can_interface = can_interface;
+                },
+                "-kf" => {
+                    let key = std::fs::read_to_string(param).expect("Unable to read auth key file");
+                    let key = crate::utils::hex::decode(&key).expect("Invalid auth key, expected a hex encoded key");
+                    if key.is_empty() {
+                        panic!("Invalid auth key, the key must not be empty");
+                    }
+                    config.auth_key = Some(key);
+                },
+                "-mp" => {
+                    let max_malformed_packets = param.parse::<u32>().unwrap();
+                    if max_malformed_packets == 0 {
+                        panic!("Invalid max malformed packets, expected a value greater than 0");
+                    }
+                    config.max_malformed_packets = max_malformed_packets;
+                },
+                "-tr" => {
+                    let telemetry_rate_hz = param.parse::<u32>().unwrap();
+                    if telemetry_rate_hz == 0 || telemetry_rate_hz > 1000 {
+                        panic!("Invalid telemetry rate, expected a value from 1 to 1000 Hz");
+                    }
+                    config.telemetry_rate_hz = telemetry_rate_hz;
+                },
+                "-fm" => {
+                    let manifest = FirmwareManifest::load(param).unwrap_or_else(|error| panic!("Invalid firmware manifest: {}", error));
+                    config.firmware_manifest = Some(manifest);
+                },
+                "-wc" => {
+                    let watchdog = WatchdogConfig::load(param).unwrap_or_else(|error| panic!("Invalid watchdog config: {}", error));
+                    config.watchdog = watchdog;
                 }
                 _ => (),
             }
@@ -10,4 +10,5 @@ pub enum Error {
     UninitializedCanSocket,
     AddrParseError,
     UnableToHandleTcpMessage,
+    UnauthenticatedRequest,
 }
\ No newline at end of file
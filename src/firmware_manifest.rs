@@ -0,0 +1,358 @@
+/**
+ * @brief The firmware manifest lists the boards the team expects on the CAN bus, by canota short id,
+ * with the firmware each of them has to run. It is loaded with -fm and checked against a scan of
+ * the bus by the flashing thread at startup and on FLASH CHECK.
+ *
+ * ```text
+ *  {
+ *      "block_arming": true,
+ *      "devices": [
+ *          {
+ *              "short_device_id": 1,
+ *              "role": "BMS",
+ *              "device_type": "STM32L432KC",      (optional)
+ *              "version": "1.2",                  (optional)
+ *              "checksum": {                      (optional)
+ *                  "start_address": "0x08000000",
+ *                  "length": 32768,
+ *                  "crc32": "0x1a2b3c4d"
+ *              }
+ *          }
+ *      ]
+ *  }
+ * ```
+ * When block_arming is set, the CAN thread refuses to arm the pod until a check has found every
+ * device of the manifest running its firmware.
+ */
+use chrono::NaiveDateTime;
+use json::{ JsonValue, object };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ManifestChecksum {
+    pub start_address: u32,
+    pub length: u32,
+    pub crc32: u32
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub short_device_id: u8,
+    pub role: String,
+    pub device_type: Option<String>,
+    pub version: Option<(u8, u8)>, // (major, minor)
+    pub checksum: Option<ManifestChecksum>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareManifest {
+    pub block_arming: bool,
+    pub devices: Vec<ManifestEntry>
+}
+
+/**
+ * @brief A device as seen by a scan of the bus
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObservedDevice {
+    pub short_device_id: u8,
+    pub device_id: u32,
+    pub device_type: String,
+    pub version: (u8, u8)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Missing,
+    ShortIdCollision,
+    DeviceType { expected: String, actual: String },
+    Version { expected: (u8, u8), actual: (u8, u8) },
+    ChecksumUnavailable(String),
+    Checksum { expected: u32, actual: u32 }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceCompliance {
+    pub short_device_id: u8,
+    pub role: String,
+    pub mismatches: Vec<Mismatch>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComplianceReport {
+    pub checked_at: NaiveDateTime,
+    pub devices: Vec<DeviceCompliance>,
+    pub unexpected_devices: Vec<u8> // Short ids on the bus which are not in the manifest
+}
+
+/**
+ * @brief The manifest and the result of the last check, kept in the relay status
+ */
+#[derive(Debug)]
+pub struct FirmwareCheck {
+    manifest: Option<FirmwareManifest>,
+    pub report: Option<ComplianceReport>,
+    pub error: Option<String> // Why the last check could not be completed
+}
+
+fn parse_number(value: &JsonValue) -> Option<u32> {
+    match value.as_str() {
+        Some(number) => match number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => number.parse::<u32>().ok()
+        },
+        None => value.as_u32()
+    }
+}
+
+fn parse_version(version: &str) -> Option<(u8, u8)> {
+    let (major, minor) = version.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+fn version_to_string(version: (u8, u8)) -> String {
+    format!("{}.{}", version.0, version.1)
+}
+
+impl ManifestEntry {
+    fn from_json(entry: &JsonValue) -> Result<ManifestEntry, String> {
+        let short_device_id = entry["short_device_id"].as_u8().ok_or("Every device needs a short_device_id")?;
+        let role = entry["role"].as_str().ok_or(format!("Device {} needs a role", short_device_id))?;
+        let version = match entry["version"].as_str() {
+            Some(version) => Some(parse_version(version).ok_or(format!("Invalid version {} for device {}", version, short_device_id))?),
+            None => None
+        };
+        let checksum = &entry["checksum"];
+        let checksum = if checksum.is_null() {
+            None
+        } else {
+            let field = |name: &str| parse_number(&checksum[name]).ok_or(format!("Invalid checksum {} for device {}", name, short_device_id));
+            Some(ManifestChecksum {
+                start_address: field("start_address")?,
+                length: field("length")?,
+                crc32: field("crc32")?
+            })
+        };
+        Ok(ManifestEntry {
+            short_device_id,
+            role: String::from(role),
+            device_type: entry["device_type"].as_str().map(String::from),
+            version,
+            checksum
+        })
+    }
+
+    /**
+     * @param checksum asked to compute the checksum of the entry's section on its device
+     */
+    fn check<F: FnMut(u8, &ManifestChecksum) -> Result<u32, String>>(&self, observed: &[ObservedDevice], checksum: &mut F) -> DeviceCompliance {
+        let matching: Vec<&ObservedDevice> = observed.iter().filter(|device| device.short_device_id == self.short_device_id).collect();
+        let mut mismatches = Vec::new();
+        match matching.as_slice() {
+            [] => mismatches.push(Mismatch::Missing),
+            [device] => {
+                if let Some(expected) = &self.device_type {
+                    if expected != &device.device_type {
+                        mismatches.push(Mismatch::DeviceType { expected: expected.clone(), actual: device.device_type.clone() });
+                    }
+                }
+                if let Some(expected) = self.version {
+                    if expected != device.version {
+                        mismatches.push(Mismatch::Version { expected, actual: device.version });
+                    }
+                }
+                if let Some(expected) = &self.checksum {
+                    match checksum(self.short_device_id, expected) {
+                        Ok(actual) if actual != expected.crc32 => mismatches.push(Mismatch::Checksum { expected: expected.crc32, actual }),
+                        Ok(_) => (),
+                        Err(error) => mismatches.push(Mismatch::ChecksumUnavailable(error))
+                    }
+                }
+            },
+            _ => mismatches.push(Mismatch::ShortIdCollision)
+        }
+        DeviceCompliance { short_device_id: self.short_device_id, role: self.role.clone(), mismatches }
+    }
+}
+
+impl FirmwareManifest {
+    pub fn from_json(manifest: &str) -> Result<FirmwareManifest, String> {
+        let manifest = json::parse(manifest).map_err(|err| format!("Invalid JSON: {}", err))?;
+        if !manifest["devices"].is_array() {
+            return Err(String::from("The manifest needs a list of devices"));
+        }
+        let devices = manifest["devices"].members().map(ManifestEntry::from_json).collect::<Result<Vec<ManifestEntry>, String>>()?;
+        for (i, entry) in devices.iter().enumerate() {
+            if devices[..i].iter().any(|other| other.short_device_id == entry.short_device_id) {
+                return Err(format!("Short id {} is listed more than once", entry.short_device_id));
+            }
+        }
+        Ok(FirmwareManifest {
+            block_arming: manifest["block_arming"].as_bool().unwrap_or(false),
+            devices
+        })
+    }
+
+    pub fn load(path: &str) -> Result<FirmwareManifest, String> {
+        let manifest = std::fs::read_to_string(path).map_err(|err| format!("Unable to read {}: {}", path, err))?;
+        FirmwareManifest::from_json(&manifest)
+    }
+
+    /**
+     * @brief Compare the devices found by a scan with the manifest
+     * @param checksum computes the checksum of a section of flash on the device with the given short id.
+     * Only called for devices which have a checksum in the manifest
+     */
+    pub fn check<F: FnMut(u8, &ManifestChecksum) -> Result<u32, String>>(&self, observed: &[ObservedDevice], mut checksum: F, checked_at: NaiveDateTime) -> ComplianceReport {
+        let devices = self.devices.iter().map(|entry| entry.check(observed, &mut checksum)).collect();
+        let mut unexpected_devices: Vec<u8> = observed.iter()
+            .map(|device| device.short_device_id)
+            .filter(|short_device_id| !self.devices.iter().any(|entry| entry.short_device_id == *short_device_id))
+            .collect();
+        unexpected_devices.sort_unstable();
+        unexpected_devices.dedup();
+        ComplianceReport { checked_at, devices, unexpected_devices }
+    }
+}
+
+impl Mismatch {
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            Mismatch::Missing => object!{ mismatch: "missing" },
+            Mismatch::ShortIdCollision => object!{ mismatch: "short_id_collision" },
+            Mismatch::DeviceType { expected, actual } => object!{ mismatch: "device_type", expected: expected.as_str(), actual: actual.as_str() },
+            Mismatch::Version { expected, actual } => object!{ mismatch: "version", expected: version_to_string(*expected), actual: version_to_string(*actual) },
+            Mismatch::ChecksumUnavailable(error) => object!{ mismatch: "checksum_unavailable", error: error.as_str() },
+            Mismatch::Checksum { expected, actual } => object!{ mismatch: "checksum", expected: *expected, actual: *actual }
+        }
+    }
+}
+
+impl ComplianceReport {
+    pub fn is_compliant(&self) -> bool {
+        self.devices.iter().all(|device| device.mismatches.is_empty())
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let devices: Vec<JsonValue> = self.devices.iter().map(|device| object!{
+            short_device_id: device.short_device_id,
+            role: device.role.as_str(),
+            compliant: device.mismatches.is_empty(),
+            mismatches: device.mismatches.iter().map(Mismatch::to_json).collect::<Vec<JsonValue>>(),
+        }).collect();
+        object!{
+            checked_at: self.checked_at.timestamp_millis(),
+            compliant: self.is_compliant(),
+            devices: devices,
+            unexpected_devices: self.unexpected_devices.clone(),
+        }
+    }
+}
+
+impl FirmwareCheck {
+    pub fn new(manifest: Option<FirmwareManifest>) -> FirmwareCheck {
+        FirmwareCheck { manifest, report: None, error: None }
+    }
+
+    pub fn manifest(&self) -> Option<&FirmwareManifest> {
+        self.manifest.as_ref()
+    }
+
+    /**
+     * @brief Arming is blocked when the manifest asks for it and the last check did not pass,
+     * including while no check has completed yet
+     */
+    pub fn blocks_arming(&self) -> bool {
+        match &self.manifest {
+            Some(manifest) if manifest.block_arming => !matches!(&self.report, Some(report) if report.is_compliant()),
+            _ => false
+        }
+    }
+
+    /**
+     * @brief Short ids of the boards which did not pass the last check. Empty when no check has completed
+     */
+    pub fn mismatched_devices(&self) -> Vec<u8> {
+        self.report.iter()
+            .flat_map(|report| report.devices.iter())
+            .filter(|device| !device.mismatches.is_empty())
+            .map(|device| device.short_device_id)
+            .collect()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut check = object!{
+            manifest: self.manifest.is_some(),
+            block_arming: matches!(&self.manifest, Some(manifest) if manifest.block_arming),
+            arming_blocked: self.blocks_arming(),
+            report: self.report.as_ref().map(ComplianceReport::to_json),
+        };
+        if let Some(error) = &self.error {
+            check["error"] = error.as_str().into();
+        }
+        check
+    }
+}
+
+/********************
+ *      TESTS
+ ********************/
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MANIFEST: &str = r#"{
+        "block_arming": true,
+        "devices": [
+            { "short_device_id": 1, "role": "BMS", "device_type": "STM32L432KC", "version": "1.2" },
+            { "short_device_id": 2, "role": "Pressure", "checksum": { "start_address": "0x08000000", "length": 1024, "crc32": "0xdeadbeef" } },
+            { "short_device_id": 3, "role": "Elekid" }
+        ]
+    }"#;
+
+    fn observed(short_device_id: u8, version: (u8, u8)) -> ObservedDevice {
+        ObservedDevice { short_device_id, device_id: short_device_id as u32, device_type: String::from("STM32L432KC"), version }
+    }
+
+    #[test]
+    fn parse_manifest() {
+        let manifest = FirmwareManifest::from_json(MANIFEST).unwrap();
+        assert!(manifest.block_arming);
+        assert_eq!(manifest.devices[0].version, Some((1, 2)));
+        assert_eq!(manifest.devices[1].checksum, Some(ManifestChecksum { start_address: 0x0800_0000, length: 1024, crc32: 0xdead_beef }));
+        assert_eq!(manifest.devices[2].device_type, None);
+
+        assert!(FirmwareManifest::from_json(r#"{ "devices": [{ "role": "BMS" }] }"#).is_err());
+        assert!(FirmwareManifest::from_json(r#"{ "devices": [{ "short_device_id": 1, "role": "BMS", "version": "one" }] }"#).is_err());
+        assert!(FirmwareManifest::from_json(r#"{ "devices": [{ "short_device_id": 1, "role": "A" }, { "short_device_id": 1, "role": "B" }] }"#).is_err());
+    }
+
+    #[test]
+    fn check_against_scan() {
+        let manifest = FirmwareManifest::from_json(MANIFEST).unwrap();
+        let now = chrono::Utc::now().naive_local();
+        let mut check = FirmwareCheck::new(Some(manifest.clone()));
+        assert!(check.blocks_arming());
+
+        let scan = vec![observed(1, (1, 1)), observed(2, (1, 0)), observed(9, (1, 0))];
+        let report = manifest.check(&scan, |_, _| Ok(0xdead_beef), now);
+        assert!(!report.is_compliant());
+        assert_eq!(report.devices[0].mismatches, vec![Mismatch::Version { expected: (1, 2), actual: (1, 1) }]);
+        assert!(report.devices[1].mismatches.is_empty());
+        assert_eq!(report.devices[2].mismatches, vec![Mismatch::Missing]);
+        assert_eq!(report.unexpected_devices, vec![9]);
+        assert!(check.mismatched_devices().is_empty());
+        check.report = Some(report);
+        assert!(check.blocks_arming());
+        assert_eq!(check.mismatched_devices(), vec![1, 3]);
+        assert_eq!(check.to_json()["report"]["devices"][0]["mismatches"][0]["actual"], "1.1");
+
+        let scan = vec![observed(1, (1, 2)), observed(2, (1, 0)), observed(3, (2, 0))];
+        let report = manifest.check(&scan, |_, _| Err(String::from("Timeout")), now);
+        assert_eq!(report.devices[1].mismatches, vec![Mismatch::ChecksumUnavailable(String::from("Timeout"))]);
+
+        check.report = Some(manifest.check(&scan, |_, _| Ok(0xdead_beef), now));
+        assert!(!check.blocks_arming());
+        assert!(check.mismatched_devices().is_empty());
+        assert!(!FirmwareCheck::new(None).blocks_arming());
+    }
+}
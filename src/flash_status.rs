@@ -0,0 +1,151 @@
+/**
+ * @brief Progress of the flashing thread, queried over TCP with FLASH STATUS.
+ *
+ * The TCP thread starts a job by marking the status busy before handing the job to the
+ * flashing thread, so that only one job can be queued at a time. The flashing thread
+ * reports its progress and the result of the job.
+ */
+use std::sync::{ Arc, Mutex };
+use json::{ JsonValue, object };
+
+pub type SharedFlashStatus = Arc<Mutex<FlashStatus>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashState {
+    Unavailable, // The relay was built without flashing support or has no CAN bus
+    Idle,
+    Scanning,
+    Erasing,
+    Writing,
+    Verifying,
+    Complete,
+    Failed
+}
+
+impl FlashState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlashState::Unavailable => "unavailable",
+            FlashState::Idle        => "idle",
+            FlashState::Scanning    => "scanning",
+            FlashState::Erasing     => "erasing",
+            FlashState::Writing     => "writing",
+            FlashState::Verifying   => "verifying",
+            FlashState::Complete    => "complete",
+            FlashState::Failed      => "failed"
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        matches!(self, FlashState::Scanning | FlashState::Erasing | FlashState::Writing | FlashState::Verifying)
+    }
+
+    /**
+     * @brief A board is being written, the pod has to stay Resting or in LowVoltage until it is done
+     */
+    pub fn is_writing(&self) -> bool {
+        matches!(self, FlashState::Erasing | FlashState::Writing | FlashState::Verifying)
+    }
+}
+
+pub struct FlashStatus {
+    pub state: FlashState,
+    pub short_device_id: Option<u8>,
+    pub start_address: u32,
+    pub bytes_written: usize,
+    pub total_bytes: usize,
+    pub checksum: Option<u32>, // Checksum reported by the device for the last verified segment
+    pub devices: Vec<JsonValue>,
+    pub collisions: Vec<u8>, // Short ids which more than one device answered to in the last scan
+    pub error: Option<String>
+}
+
+impl FlashStatus {
+    pub fn new(available: bool) -> FlashStatus {
+        FlashStatus {
+            state: if available { FlashState::Idle } else { FlashState::Unavailable },
+            short_device_id: None,
+            start_address: 0,
+            bytes_written: 0,
+            total_bytes: 0,
+            checksum: None,
+            devices: Vec::new(),
+            collisions: Vec::new(),
+            error: None
+        }
+    }
+
+    pub fn shared(self) -> SharedFlashStatus {
+        Arc::new(Mutex::new(self))
+    }
+
+    /**
+     * @brief Mark the start of a job. Returns false if flashing is unavailable or another job is running
+     */
+    pub fn begin(&mut self, state: FlashState) -> bool {
+        if self.state == FlashState::Unavailable || self.state.is_busy() {
+            return false;
+        }
+        self.state = state;
+        self.error = None;
+        true
+    }
+
+    /**
+     * @brief Mark the start of writing an image of total_bytes to a device
+     */
+    pub fn begin_write(&mut self, short_device_id: u8, start_address: u32, total_bytes: usize) -> bool {
+        if !self.begin(FlashState::Writing) {
+            return false;
+        }
+        self.short_device_id = Some(short_device_id);
+        self.start_address = start_address;
+        self.bytes_written = 0;
+        self.total_bytes = total_bytes;
+        self.checksum = None;
+        true
+    }
+
+    pub fn fail(&mut self, error: String) {
+        self.state = FlashState::Failed;
+        self.error = Some(error);
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut status = object!{
+            state: self.state.as_str(),
+            short_device_id: self.short_device_id,
+            start_address: self.start_address,
+            bytes_written: self.bytes_written,
+            total_bytes: self.total_bytes,
+            checksum: self.checksum,
+            devices: self.devices.clone(),
+            collisions: self.collisions.clone(),
+        };
+        if let Some(error) = &self.error {
+            status["error"] = error.as_str().into();
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn one_job_at_a_time() {
+        let mut status = FlashStatus::new(true);
+        assert!(status.begin_write(3, 0x0800_0000, 1024));
+        assert!(status.state.is_writing());
+        assert!(!status.begin(FlashState::Scanning));
+        status.fail(String::from("Checksum mismatch"));
+        assert!(status.begin(FlashState::Scanning));
+        assert!(!status.state.is_writing());
+        assert!(status.error.is_none());
+
+        let json = FlashStatus::new(false).to_json();
+        assert_eq!(json["state"], "unavailable");
+        assert!(!FlashStatus::new(false).begin(FlashState::Scanning));
+    }
+}
@@ -16,6 +16,13 @@ pub use utils::device_watchdog;
 pub mod project_butterfree;
 pub mod pod_states;
 pub mod board_states;
+pub mod transition_journal;
+pub mod relay_heartbeat;
+pub mod shutdown;
+pub mod supervisor;
+pub mod relay_status;
+pub mod flash_status;
+pub mod firmware_manifest;
 pub mod pod_data;
 pub mod thread_managers;
 pub mod error;
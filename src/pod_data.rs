@@ -1,4 +1,6 @@
 use json::{ JsonValue, object, array }; // TODO Reimplement with serde json
+use std::io::{ self, Read, Write };
+use byteorder::{ LittleEndian, ReadBytesExt, WriteBytesExt };
 type Float2 = [Option<f32>; 2];
 type Float1 = Option<f32>;
 
@@ -202,6 +204,120 @@ impl PodData {
         }
     }
 
+    /**
+     * The f32 values in the order that they are written in the binary encoding
+     */
+    fn f32_fields(&self) -> [Float1; 24] {
+        [
+            self.battery_pack_current,
+            self.average_cell_temperature,
+            self.igbt_temp,
+            self.motor_voltage,
+            self.battery_pack_voltage,
+            self.state_of_charge,
+            self.buck_temperature,
+            self.bms_current,
+            self.link_cap_voltage,
+            self.mc_pod_speed,
+            self.motor_current,
+            self.battery_current,
+            self.battery_voltage,
+            self.speed,
+            self.current_5v,
+            self.current_12v,
+            self.current_24v,
+            self.torchic_1[0],
+            self.torchic_1[1],
+            self.torchic_2[0],
+            self.torchic_2[1],
+            self.pressure_high,
+            self.pressure_low_1,
+            self.pressure_low_2,
+        ]
+    }
+
+    /**
+     * The f32 fields in the same order as f32_fields
+     */
+    fn f32_fields_mut(&mut self) -> [&mut Float1; 24] {
+        let [torchic_1_a, torchic_1_b] = &mut self.torchic_1;
+        let [torchic_2_a, torchic_2_b] = &mut self.torchic_2;
+        [
+            &mut self.battery_pack_current,
+            &mut self.average_cell_temperature,
+            &mut self.igbt_temp,
+            &mut self.motor_voltage,
+            &mut self.battery_pack_voltage,
+            &mut self.state_of_charge,
+            &mut self.buck_temperature,
+            &mut self.bms_current,
+            &mut self.link_cap_voltage,
+            &mut self.mc_pod_speed,
+            &mut self.motor_current,
+            &mut self.battery_current,
+            &mut self.battery_voltage,
+            &mut self.speed,
+            &mut self.current_5v,
+            &mut self.current_12v,
+            &mut self.current_24v,
+            torchic_1_a,
+            torchic_1_b,
+            torchic_2_a,
+            torchic_2_b,
+            &mut self.pressure_high,
+            &mut self.pressure_low_1,
+            &mut self.pressure_low_2,
+        ]
+    }
+
+    /**
+     * @brief Write the pod data as a u32 bitmap of the fields which are present followed by the present values.
+     * Bits 0-23 are the f32 fields, 24-25 the roboteq motor speeds (f64),
+     * 26-27 the roboteq battery amps (i16) and 28-30 the roboteq temperatures (i8)
+     */
+    pub fn write_binary<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let floats = self.f32_fields();
+        let doubles = [self.roboteq_motor_1_speed, self.roboteq_motor_2_speed];
+        let amps = [self.roboteq_motor_1_battery_amps, self.roboteq_motor_2_battery_amps];
+        let temps = [self.roboteq_mcu_temp, self.roboteq_sensor_1_temp, self.roboteq_sensor_2_temp];
+
+        let presence = floats.iter().map(Option::is_some)
+            .chain(doubles.iter().map(Option::is_some))
+            .chain(amps.iter().map(Option::is_some))
+            .chain(temps.iter().map(Option::is_some))
+            .enumerate()
+            .fold(0u32, |bitmap, (bit, present)| if present { bitmap | (1 << bit) } else { bitmap });
+        writer.write_u32::<LittleEndian>(presence)?;
+
+        for value in floats.iter().flatten() { writer.write_f32::<LittleEndian>(*value)?; }
+        for value in doubles.iter().flatten() { writer.write_f64::<LittleEndian>(*value)?; }
+        for value in amps.iter().flatten() { writer.write_i16::<LittleEndian>(*value)?; }
+        for value in temps.iter().flatten() { writer.write_i8(*value)?; }
+        Ok(())
+    }
+
+    /**
+     * @brief Read pod data written by write_binary. Fails with UnexpectedEof if the buffer is too short
+     */
+    pub fn read_binary<R: Read>(reader: &mut R) -> io::Result<PodData> {
+        let presence = reader.read_u32::<LittleEndian>()?;
+        let present = |bit: usize| presence & (1 << bit) != 0;
+        let mut pod_data = PodData::new();
+        for (bit, field) in pod_data.f32_fields_mut().iter_mut().enumerate() {
+            if present(bit) {
+                **field = Some(reader.read_f32::<LittleEndian>()?);
+            }
+        }
+        if present(24) { pod_data.roboteq_motor_1_speed = Some(reader.read_f64::<LittleEndian>()?); }
+        if present(25) { pod_data.roboteq_motor_2_speed = Some(reader.read_f64::<LittleEndian>()?); }
+        if present(26) { pod_data.roboteq_motor_1_battery_amps = Some(reader.read_i16::<LittleEndian>()?); }
+        if present(27) { pod_data.roboteq_motor_2_battery_amps = Some(reader.read_i16::<LittleEndian>()?); }
+        if present(28) { pod_data.roboteq_mcu_temp = Some(reader.read_i8()?); }
+        if present(29) { pod_data.roboteq_sensor_1_temp = Some(reader.read_i8()?); }
+        if present(30) { pod_data.roboteq_sensor_2_temp = Some(reader.read_i8()?); }
+        Ok(pod_data)
+    }
+
     /**
      * @brief ok()
      * Check if the board data is okay
@@ -233,3 +349,48 @@ impl PodData {
     &&  (self.pressure_low_2.is_none() ||  { self.pressure_low_2.unwrap() < 100.0 })
     }
 }
+
+/********************
+ *      TESTS
+ ********************/
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn binary_round_trip() {
+        let mut pod_data = PodData::new();
+        pod_data.battery_pack_current = Some(12.5);
+        pod_data.torchic_2 = [None, Some(30.0)];
+        pod_data.pressure_low_2 = Some(-1.0);
+        pod_data.roboteq_motor_2_speed = Some(1234.5);
+        pod_data.roboteq_motor_1_battery_amps = Some(-20);
+        pod_data.roboteq_sensor_2_temp = Some(-5);
+
+        let mut bytes = Vec::new();
+        pod_data.write_binary(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 4 + 3 * 4 + 8 + 2 + 1);
+
+        let mut reader = &bytes[..];
+        let parsed = PodData::read_binary(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(parsed.battery_pack_current, Some(12.5));
+        assert_eq!(parsed.torchic_2, [None, Some(30.0)]);
+        assert_eq!(parsed.pressure_low_2, Some(-1.0));
+        assert_eq!(parsed.average_cell_temperature, None);
+        assert_eq!(parsed.roboteq_motor_1_speed, None);
+        assert_eq!(parsed.roboteq_motor_2_speed, Some(1234.5));
+        assert_eq!(parsed.roboteq_motor_1_battery_amps, Some(-20));
+        assert_eq!(parsed.roboteq_sensor_2_temp, Some(-5));
+    }
+
+    #[test]
+    fn binary_truncated() {
+        let mut pod_data = PodData::new();
+        pod_data.speed = Some(3.0);
+        let mut bytes = Vec::new();
+        pod_data.write_binary(&mut bytes).unwrap();
+        let error = PodData::read_binary(&mut &bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
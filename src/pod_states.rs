@@ -27,6 +27,15 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_shutdown_states() {
+        assert_eq!(PodState::Armed.shutdown_state(), PodState::LowVoltage);
+        assert_eq!(PodState::AutoPilot.shutdown_state(), PodState::Braking);
+        assert_eq!(PodState::AtSpeed.shutdown_state(), PodState::SystemFailure);
+        assert_eq!(PodState::LowVoltage.shutdown_state(), PodState::LowVoltage);
+        assert_eq!(PodState::EmergencyBrake.shutdown_state(), PodState::EmergencyBrake);
+    }
+
     #[test]
     fn test_transitions() {
         let all_states = vec![
@@ -128,6 +137,19 @@ impl PodState {
     pub fn is_error_state(&self) -> bool {
         matches!(self, PodState::EmergencyBrake | PodState::SystemFailure)
     }
+
+    /**
+     * @brief The state to leave the pod in when the relay shuts down or loses the UDP thread. Follows the first step of the
+     * recovery procedure, a pod which is being driven manually can only be stopped by a system failure
+     */
+    pub fn shutdown_state(&self) -> PodState {
+        match self {
+            PodState::Armed => PodState::LowVoltage,
+            PodState::AutoPilot => PodState::Braking,
+            PodState::Accelerating | PodState::AtSpeed | PodState::Decelerating => PodState::SystemFailure,
+            state => *state
+        }
+    }
 }
 
 impl From<u8> for PodState {
@@ -0,0 +1,237 @@
+/**
+ * Authentication for Project Butterfree.
+ *
+ * The desktop and the relay share a secret key. When a key is configured, every
+ * DesktopStateMessage and every state changing TCP request must carry an HMAC-SHA256
+ * computed with that key.
+ *
+ * TCP requests include an authentication line after the command line:
+ *      AUTH <timestamp_ms> <nonce> <hex mac>\r\n
+ * where the mac is computed over "<COMMAND>|<timestamp_ms>|<nonce>|<hex SHA-256 of the body>".
+ * The body is every other line of the request, in order and each terminated by \r\n, so that
+ * DEVICE, ADDRESS, PORT and ENCODING can not be changed without invalidating the mac. The body of
+ * a FLASH UPLOAD is the decoded image instead. Requests outside of the replay window or which
+ * reuse a nonce are rejected.
+ *
+ * A successful CONNECT returns a session id. UDP messages include the session id in their
+ * signed payload and are protected against replays within the session by their sequence number.
+ */
+use std::collections::VecDeque;
+use hmac::{ Hmac, Mac };
+use sha2::{ Sha256, Digest };
+use crate::utils::hex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const AUTH_WINDOW_MS: i64 = 30_000;
+const AUTH_PREFIX: &str = "AUTH ";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    MissingAuthentication,
+    MalformedAuthentication,
+    InvalidMac,
+    Expired,
+    Replayed
+}
+
+pub struct Authenticator {
+    key: Vec<u8>,
+    seen_nonces: VecDeque<(i64, String)>,
+    session_counter: u64
+}
+
+impl Authenticator {
+    pub fn new(key: Vec<u8>) -> Authenticator {
+        Authenticator {
+            key,
+            seen_nonces: VecDeque::new(),
+            session_counter: 0
+        }
+    }
+
+    fn hmac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length")
+    }
+
+    pub fn sign(&self, payload: &[u8]) -> Vec<u8> {
+        let mut mac = self.hmac();
+        mac.update(payload);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /**
+     * @brief Constant time comparison of the mac against the expected mac for payload
+     */
+    pub fn verify(&self, payload: &[u8], mac: &[u8]) -> bool {
+        let mut expected = self.hmac();
+        expected.update(payload);
+        expected.verify_slice(mac).is_ok()
+    }
+
+    /**
+     * @brief Create an unpredictable id for a new desktop session
+     */
+    pub fn new_session_id(&mut self) -> String {
+        self.session_counter += 1;
+        let seed = format!("session|{}|{}", chrono::Utc::now().timestamp_nanos(), self.session_counter);
+        hex::encode(&self.sign(seed.as_bytes())[..8])
+    }
+
+    /**
+     * @brief Verify the AUTH line which follows a TCP request for command, signed together with the rest of the request.
+     * @param request the remainder of the request after the command line has been stripped
+     * @param now_ms the current time in milliseconds since the unix epoch
+     */
+    pub fn verify_tcp_request(&mut self, command: &str, request: &[u8], now_ms: i64) -> Result<(), AuthError> {
+        self.verify_tcp_request_body(command, request, &tcp_request_body(request), now_ms)
+    }
+
+    /**
+     * @brief Verify the AUTH line of a TCP request whose body is signed separately, like the image of a FLASH UPLOAD
+     */
+    pub fn verify_tcp_request_body(&mut self, command: &str, request: &[u8], body: &[u8], now_ms: i64) -> Result<(), AuthError> {
+        let request = String::from_utf8_lossy(request);
+        let line = request.trim_matches(char::from(0))
+            .lines()
+            .find(|line| line.starts_with(AUTH_PREFIX))
+            .ok_or(AuthError::MissingAuthentication)?;
+        let fields: Vec<&str> = line[AUTH_PREFIX.len()..].split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(AuthError::MalformedAuthentication);
+        }
+        let timestamp = fields[0].parse::<i64>().map_err(|_| AuthError::MalformedAuthentication)?;
+        let nonce = fields[1];
+        let mac = hex::decode(fields[2]).ok_or(AuthError::MalformedAuthentication)?;
+
+        if !self.verify(tcp_signing_payload(command, timestamp, nonce, body).as_bytes(), &mac) {
+            return Err(AuthError::InvalidMac);
+        }
+        if (now_ms - timestamp).abs() > AUTH_WINDOW_MS {
+            return Err(AuthError::Expired);
+        }
+        while let Some((seen_at, _)) = self.seen_nonces.front() {
+            if now_ms - seen_at > 2 * AUTH_WINDOW_MS {
+                self.seen_nonces.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.seen_nonces.iter().any(|(_, seen)| seen == nonce) {
+            return Err(AuthError::Replayed);
+        }
+        self.seen_nonces.push_back((now_ms, String::from(nonce)));
+        Ok(())
+    }
+
+    /**
+     * @brief Build the AUTH line for a TCP request. Used by desktop implementations and tests.
+     * @param body the other lines of the request, see tcp_request_body, or the image of a FLASH UPLOAD
+     */
+    pub fn tcp_auth_line(&self, command: &str, body: &[u8], timestamp: i64, nonce: &str) -> String {
+        let mac = self.sign(tcp_signing_payload(command, timestamp, nonce, body).as_bytes());
+        format!("{}{} {} {}\r\n", AUTH_PREFIX, timestamp, nonce, hex::encode(&mac))
+    }
+}
+
+/**
+ * @brief The signed body of a TCP request: every line apart from the AUTH line, each terminated by \r\n.
+ * Blank lines and the padding left by read_all are not part of it
+ */
+pub fn tcp_request_body(request: &[u8]) -> Vec<u8> {
+    let request = String::from_utf8_lossy(request);
+    request.trim_matches(char::from(0))
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with(AUTH_PREFIX))
+        .flat_map(|line| format!("{}\r\n", line).into_bytes())
+        .collect()
+}
+
+fn tcp_signing_payload(command: &str, timestamp: i64, nonce: &str, body: &[u8]) -> String {
+    format!("{}|{}|{}|{}", command, timestamp, nonce, hex::encode(&Sha256::digest(body)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(b"super secret key".to_vec())
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let dut = authenticator();
+        let mac = dut.sign(b"payload");
+        assert!(dut.verify(b"payload", &mac));
+        assert!(!dut.verify(b"other payload", &mac));
+        assert!(!Authenticator::new(b"wrong key".to_vec()).verify(b"payload", &mac));
+    }
+
+    #[test]
+    fn tcp_request() {
+        let mut dut = authenticator();
+        let line = dut.tcp_auth_line("CONNECT", b"", 1000, "abc");
+        assert_eq!(dut.verify_tcp_request("CONNECT", line.as_bytes(), 1500), Ok(()));
+    }
+
+    #[test]
+    fn tcp_request_replayed() {
+        let mut dut = authenticator();
+        let line = dut.tcp_auth_line("CONNECT", b"", 1000, "abc");
+        assert_eq!(dut.verify_tcp_request("CONNECT", line.as_bytes(), 1500), Ok(()));
+        assert_eq!(dut.verify_tcp_request("CONNECT", line.as_bytes(), 1600), Err(AuthError::Replayed));
+    }
+
+    #[test]
+    fn tcp_request_expired() {
+        let mut dut = authenticator();
+        let line = dut.tcp_auth_line("CONNECT", b"", 1000, "abc");
+        assert_eq!(dut.verify_tcp_request("CONNECT", line.as_bytes(), 1000 + AUTH_WINDOW_MS + 1), Err(AuthError::Expired));
+    }
+
+    #[test]
+    fn tcp_request_wrong_command() {
+        let mut dut = authenticator();
+        let line = dut.tcp_auth_line("DISCONNECT", b"", 1000, "abc");
+        assert_eq!(dut.verify_tcp_request("CONNECT", line.as_bytes(), 1000), Err(AuthError::InvalidMac));
+    }
+
+    #[test]
+    fn tcp_request_body_is_signed() {
+        let mut dut = authenticator();
+        let body = b"DEVICE 3\r\nADDRESS 0x08000000\r\n";
+        let line = dut.tcp_auth_line("FLASH WRITE", body, 1000, "abc");
+        let request = format!("{}DEVICE 3\r\nADDRESS 0x08000000\r\n\0\0", line);
+        assert_eq!(tcp_request_body(request.as_bytes()), body.to_vec());
+        assert_eq!(dut.verify_tcp_request("FLASH WRITE", request.as_bytes(), 1000), Ok(()));
+
+        let line = dut.tcp_auth_line("FLASH WRITE", body, 1000, "def");
+        let tampered = format!("{}DEVICE 4\r\nADDRESS 0x08000000\r\n", line);
+        assert_eq!(dut.verify_tcp_request("FLASH WRITE", tampered.as_bytes(), 1000), Err(AuthError::InvalidMac));
+        let added = format!("{}DEVICE 3\r\nADDRESS 0x08000000\r\nENCODING BINARY\r\n", line);
+        assert_eq!(dut.verify_tcp_request("FLASH WRITE", added.as_bytes(), 1000), Err(AuthError::InvalidMac));
+    }
+
+    #[test]
+    fn tcp_upload_image_is_signed() {
+        let mut dut = authenticator();
+        let line = dut.tcp_auth_line("FLASH UPLOAD", &[0x00, 0x01, 0xab, 0xff], 1000, "abc");
+        let request = format!("{}LENGTH 4\r\nDATA 0001abff\r\n", line);
+        assert_eq!(dut.verify_tcp_request_body("FLASH UPLOAD", request.as_bytes(), &[0x00, 0x01, 0xab, 0xfe], 1000), Err(AuthError::InvalidMac));
+        assert_eq!(dut.verify_tcp_request_body("FLASH UPLOAD", request.as_bytes(), &[0x00, 0x01, 0xab, 0xff], 1000), Ok(()));
+    }
+
+    #[test]
+    fn tcp_request_missing_auth() {
+        let mut dut = authenticator();
+        assert_eq!(dut.verify_tcp_request("CONNECT", b"", 1000), Err(AuthError::MissingAuthentication));
+        assert_eq!(dut.verify_tcp_request("CONNECT", b"AUTH 1000 abc\r\n", 1000), Err(AuthError::MalformedAuthentication));
+    }
+
+    #[test]
+    fn session_ids_are_unique() {
+        let mut dut = authenticator();
+        assert_ne!(dut.new_session_id(), dut.new_session_id());
+    }
+}
@@ -0,0 +1,184 @@
+/**
+ * Remote flashing of the pod's boards through the relay over TCP.
+ * ```text
+ *      FLASH SCAN\r\n                      Look for canota devices on the CAN bus
+ *
+ *      FLASH UPLOAD\r\n                    Store an Intel HEX, ELF or raw binary firmware image on the relay
+ *      LENGTH <bytes>\r\n
+ *      DATA <hex>\r\n                      Repeated until LENGTH bytes have been sent
+ *
+ *      FLASH WRITE\r\n                     Write the uploaded image to a device and verify it
+ *      DEVICE <short id>\r\n
+ *      ADDRESS <start address>\r\n         (optional, where raw binaries are written. Defaults to DEFAULT_START_ADDRESS)
+ *
+ *      FLASH STATUS\r\n                    Progress of the current or last flashing job as JSON
+ *
+ *      FLASH CHECK\r\n                     Compare the boards on the bus with the firmware manifest
+ * ```
+ * SCAN, UPLOAD, WRITE and CHECK carry an AUTH line when authentication is enabled. For uploads it signs the
+ * decoded image and is checked once the upload is complete. Uploads are limited to MAX_IMAGE_SIZE bytes and have
+ * to be completed within 30 seconds, other clients wait in the meantime.
+ * Flashing is refused unless the pod is Resting or in LowVoltage, the desktop cannot move the pod out of those states
+ * until the write is done (errno FlashInProgress), and WRITE fails if more than one
+ * device answers to the short id. Such short ids are listed as collisions by FLASH STATUS after a scan.
+ * The result of the last firmware check is reported under firmware by the STATUS query.
+ */
+use crate::pod_states::PodState;
+use crate::utils::hex;
+
+/**
+ * Start of the application section of an STM32L432KC
+ */
+pub const DEFAULT_START_ADDRESS: u32 = 0x0800_0000;
+
+/**
+ * Size of the largest flash of the boards canota supports. Larger uploads are refused before they are read
+ */
+pub const MAX_IMAGE_SIZE: usize = canota::flash_map::MAX_FLASH_SIZE as usize;
+
+/**
+ * Room left in an upload request for the AUTH and LENGTH lines and the line endings
+ */
+const UPLOAD_HEADER_ALLOWANCE: usize = 1024;
+
+const LENGTH_PREFIX: &str = "LENGTH ";
+const DATA_PREFIX: &str = "DATA ";
+const DEVICE_PREFIX: &str = "DEVICE ";
+const ADDRESS_PREFIX: &str = "ADDRESS ";
+
+fn find_line<'a>(request: &'a str, prefix: &str) -> Option<&'a str> {
+    request.lines()
+        .find(|line| line.starts_with(prefix))
+        .map(|line| line[prefix.len()..].trim())
+}
+
+fn parse_number(number: &str) -> Option<u32> {
+    if let Some(hex) = number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        number.parse::<u32>().ok()
+    }
+}
+
+/**
+ * @brief The pod must be idle while its boards are being flashed
+ */
+pub fn pod_state_allows_flashing(state: PodState) -> bool {
+    matches!(state, PodState::Resting | PodState::LowVoltage)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct FirmwareUpload {
+    pub length: usize,
+    pub image: Vec<u8>
+}
+
+impl FirmwareUpload {
+    /**
+     * @brief Decode the rest of a FLASH UPLOAD request.
+     * Only DATA lines terminated by a newline are decoded, so a request which has not been fully
+     * read yet returns an incomplete upload rather than an error.
+     */
+    pub fn from_request(request: &[u8]) -> Result<FirmwareUpload, String> {
+        let text = String::from_utf8_lossy(request);
+        let text = text.trim_end_matches(char::from(0));
+        let length = find_line(text, LENGTH_PREFIX).ok_or_else(|| String::from("Missing LENGTH"))?;
+        let length = match length.parse::<usize>() {
+            Ok(length) if length > 0 && length <= MAX_IMAGE_SIZE => length,
+            _ => return Err(format!("Invalid LENGTH {}, expected 1 to {}", length, MAX_IMAGE_SIZE))
+        };
+
+        let complete_lines = match text.rfind('\n') {
+            Some(end) => &text[..end],
+            None => ""
+        };
+        let mut image = Vec::with_capacity(length);
+        for line in complete_lines.lines().filter(|line| line.starts_with(DATA_PREFIX)) {
+            let mut data = hex::decode(&line[DATA_PREFIX.len()..]).ok_or_else(|| String::from("Invalid DATA"))?;
+            image.append(&mut data);
+        }
+        if image.len() > length {
+            return Err(format!("Received {} bytes, expected {}", image.len(), length));
+        }
+        Ok(FirmwareUpload { length, image })
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.image.len() == self.length
+    }
+
+    /**
+     * @brief Longest request which is read for the upload. Each DATA line has to carry at least 4 bytes of the
+     * image, except for the last one, so the request stays within 4 times the length of the image
+     */
+    pub fn max_request_length(&self) -> usize {
+        UPLOAD_HEADER_ALLOWANCE + 4 * self.length
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct FlashWriteRequest {
+    pub short_device_id: u8,
+    pub start_address: u32
+}
+
+impl FlashWriteRequest {
+    pub fn from_request(request: &[u8]) -> Result<FlashWriteRequest, String> {
+        let text = String::from_utf8_lossy(request);
+        let device = find_line(&text, DEVICE_PREFIX).ok_or_else(|| String::from("Missing DEVICE"))?;
+        let short_device_id = match parse_number(device) {
+            Some(short_device_id) if short_device_id <= u8::MAX as u32 => short_device_id as u8,
+            _ => return Err(format!("Invalid DEVICE {}", device))
+        };
+        let start_address = match find_line(&text, ADDRESS_PREFIX) {
+            Some(address) => parse_number(address).ok_or_else(|| format!("Invalid ADDRESS {}", address))?,
+            None => DEFAULT_START_ADDRESS
+        };
+        Ok(FlashWriteRequest { short_device_id, start_address })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn firmware_upload() {
+        let upload = FirmwareUpload::from_request(b"LENGTH 4\r\nDATA 0001\r\nDATA abff\r\n\0\0").unwrap();
+        assert!(upload.is_complete());
+        assert_eq!(upload.image, vec![0x00, 0x01, 0xab, 0xff]);
+
+        // The last line has not been fully received yet
+        let upload = FirmwareUpload::from_request(b"LENGTH 4\r\nDATA 0001\r\nDATA ab").unwrap();
+        assert!(!upload.is_complete());
+        assert_eq!(upload.image, vec![0x00, 0x01]);
+        assert_eq!(upload.max_request_length(), 1024 + 16);
+    }
+
+    #[test]
+    fn invalid_firmware_upload() {
+        assert!(FirmwareUpload::from_request(b"DATA 00\r\n").is_err());
+        assert!(FirmwareUpload::from_request(b"LENGTH 0\r\n").is_err());
+        assert!(FirmwareUpload::from_request(b"LENGTH 1\r\nDATA 0001\r\n").is_err());
+        assert!(FirmwareUpload::from_request(b"LENGTH 1\r\nDATA zz\r\n").is_err());
+        assert!(FirmwareUpload::from_request(format!("LENGTH {}\r\n", MAX_IMAGE_SIZE + 1).as_bytes()).is_err());
+        assert!(FirmwareUpload::from_request(format!("LENGTH {}\r\n", MAX_IMAGE_SIZE).as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn flash_write_request() {
+        let request = FlashWriteRequest::from_request(b"DEVICE 3\r\nADDRESS 0x08004000\r\n").unwrap();
+        assert_eq!(request, FlashWriteRequest { short_device_id: 3, start_address: 0x0800_4000 });
+        let request = FlashWriteRequest::from_request(b"DEVICE 0x10\r\n").unwrap();
+        assert_eq!(request, FlashWriteRequest { short_device_id: 0x10, start_address: DEFAULT_START_ADDRESS });
+        assert!(FlashWriteRequest::from_request(b"DEVICE 256\r\n").is_err());
+        assert!(FlashWriteRequest::from_request(b"ADDRESS 0\r\n").is_err());
+    }
+
+    #[test]
+    fn flashing_pod_states() {
+        assert!(pod_state_allows_flashing(PodState::Resting));
+        assert!(pod_state_allows_flashing(PodState::LowVoltage));
+        assert!(!pod_state_allows_flashing(PodState::Armed));
+    }
+}
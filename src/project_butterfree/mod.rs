@@ -1,4 +1,6 @@
 /**
  * Project Butterfree is the protocol used from communication between the Desktop and the Relayboard
  */
-pub mod udp;
\ No newline at end of file
+pub mod udp;
+pub mod auth;
+pub mod flash;
\ No newline at end of file
@@ -1,21 +1,41 @@
-const REQUESTED_STATE: &'static str = "requested_state";
-const MOST_RECENT_TIMESTAMP: &'static str = "most_recent_timestamp";
+const PROTOCOL_VERSION: &str = "protocol_version";
+const SENDER_ID: &str = "sender_id";
+const SEQUENCE: &str = "sequence";
+const REQUESTED_STATE: &str = "requested_state";
+const MOST_RECENT_TIMESTAMP: &str = "most_recent_timestamp";
+const LINK_STATS: &str = "link_stats";
+const MAC: &str = "mac";
 use json::{
     object,
+    JsonValue,
     JsonValue::Number
 };
+use std::io::{ self, Read, Write };
+use byteorder::{ LittleEndian, ReadBytesExt, WriteBytesExt };
 use chrono::{ NaiveDateTime };
 use crate::pod_states::PodState;
+use crate::utils::hex;
+use super::{
+    encoding::{ MessageEncoding, BINARY_MAGIC },
+    sequence::LinkStats,
+    timestamp_from_millis
+};
 
 pub struct DesktopStateMessage {
+    pub protocol_version: u8,
+    pub sender_id: u32,
+    pub sequence: Option<u32>, // None for messages from desktops which predate versioning
     pub requested_state: PodState,
-    pub most_recent_timestamp: NaiveDateTime
+    pub most_recent_timestamp: NaiveDateTime,
+    pub link_stats: Option<LinkStats>, // The desktop's view of the messages sent by the relay
+    pub mac: Option<Vec<u8>> // HMAC-SHA256 over signing_payload. Required when the relay is configured with a key
 }
 
 #[derive(Debug)]
 pub enum DesktopStateMessageError {
     JsonParseError(json::Error),
     InvalidMessage(String),
+    UnsupportedProtocolVersion(u8),
 }
 
 
@@ -27,15 +47,32 @@ impl DesktopStateMessage {
         if parsed[REQUESTED_STATE].is_null() || parsed[MOST_RECENT_TIMESTAMP].is_null() {
             return Err(DesktopStateMessageError::InvalidMessage(format!("An expected field was null in message: {:?}", parsed.dump())));
         }
+        let protocol_version = parsed[PROTOCOL_VERSION].as_u8().unwrap_or(0);
+        if protocol_version > super::PROTOCOL_VERSION {
+            return Err(DesktopStateMessageError::UnsupportedProtocolVersion(protocol_version));
+        }
+        let sequence = parsed[SEQUENCE].as_u32();
+        if protocol_version > 0 && sequence.is_none() {
+            return Err(DesktopStateMessageError::InvalidMessage(format!("Versioned message is missing a sequence number: {:?}", parsed.dump())));
+        }
         if let Number(requested_state) = parsed[REQUESTED_STATE] {
             if let Number(timestamp) = parsed[MOST_RECENT_TIMESTAMP] {
                 if let Some(requested_state) = requested_state.as_fixed_point_u64(0) {
                     if requested_state < 256 {
                         let requested_state_byte = (requested_state & 0xff) as u8;
                         if let Some(timestamp) = timestamp.as_fixed_point_i64(0) {
+                            // Desktops which predate versioning send the timestamp in seconds
+                            let timestamp_millis = if protocol_version == 0 { timestamp.saturating_mul(1000) } else { timestamp };
+                            let most_recent_timestamp = timestamp_from_millis(timestamp_millis)
+                                .ok_or_else(|| DesktopStateMessageError::InvalidMessage(format!("Timestamp is out of range in message: {:?}", parsed.dump())))?;
                             return Ok(DesktopStateMessage {
+                                protocol_version,
+                                sender_id: parsed[SENDER_ID].as_u32().unwrap_or(0),
+                                sequence,
                                 requested_state: PodState::from_byte(requested_state_byte),
-                                most_recent_timestamp: NaiveDateTime::from_timestamp(timestamp, 0)
+                                most_recent_timestamp,
+                                link_stats: LinkStats::from_json(&parsed[LINK_STATS]),
+                                mac: parsed[MAC].as_str().and_then(hex::decode)
                             });
                         }
                     }
@@ -45,12 +82,227 @@ impl DesktopStateMessage {
         return Err(DesktopStateMessageError::InvalidMessage(format!("Unable to read numbers from parsed message: {:?}", parsed.dump())));
     }
 
+    /**
+     * @brief Fixed layout binary encoding:
+     *      magic: u8, protocol_version: u8, sender_id: u32, sequence: u32,
+     *      requested_state: u8, most_recent_timestamp: i64 (ms),
+     *      flags: u8 (bit 0 link_stats present, bit 1 mac present),
+     *      link_stats: 6 x u64, only when present
+     *      mac_length: u8 followed by the mac, only when present
+     */
+    pub fn from_binary_bytes(bytes: &[u8]) -> Result<DesktopStateMessage, DesktopStateMessageError> {
+        let truncated = |_: io::Error| DesktopStateMessageError::InvalidMessage(format!("Binary message is truncated: {}", hex::encode(bytes)));
+        let mut reader = bytes;
+        if reader.read_u8().ok() != Some(BINARY_MAGIC) {
+            return Err(DesktopStateMessageError::InvalidMessage(format!("Binary message does not start with the magic byte: {}", hex::encode(bytes))));
+        }
+        let protocol_version = reader.read_u8().map_err(truncated)?;
+        if protocol_version > super::PROTOCOL_VERSION {
+            return Err(DesktopStateMessageError::UnsupportedProtocolVersion(protocol_version));
+        }
+        let sender_id = reader.read_u32::<LittleEndian>().map_err(truncated)?;
+        let sequence = reader.read_u32::<LittleEndian>().map_err(truncated)?;
+        let requested_state = reader.read_u8().map_err(truncated)?;
+        let timestamp = reader.read_i64::<LittleEndian>().map_err(truncated)?;
+        let most_recent_timestamp = timestamp_from_millis(timestamp)
+            .ok_or_else(|| DesktopStateMessageError::InvalidMessage(format!("Timestamp is out of range in binary message: {}", hex::encode(bytes))))?;
+        let flags = reader.read_u8().map_err(truncated)?;
+        let link_stats = if flags & 0x1 != 0 {
+            Some(LinkStats::read_binary(&mut reader).map_err(truncated)?)
+        } else {
+            None
+        };
+        let mac = if flags & 0x2 != 0 {
+            let mut mac = vec![0u8; reader.read_u8().map_err(truncated)? as usize];
+            reader.read_exact(&mut mac).map_err(truncated)?;
+            Some(mac)
+        } else {
+            None
+        };
+        Ok(DesktopStateMessage {
+            protocol_version,
+            sender_id,
+            sequence: Some(sequence),
+            requested_state: PodState::from_byte(requested_state),
+            most_recent_timestamp,
+            link_stats,
+            mac
+        })
+    }
+
+    pub fn to_binary_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_binary(&mut bytes).expect("Writing to a Vec does not fail");
+        bytes
+    }
+
+    fn write_binary<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u8(BINARY_MAGIC)?;
+        writer.write_u8(self.protocol_version)?;
+        writer.write_u32::<LittleEndian>(self.sender_id)?;
+        writer.write_u32::<LittleEndian>(self.sequence.unwrap_or(0))?;
+        writer.write_u8(self.requested_state.to_byte())?;
+        writer.write_i64::<LittleEndian>(self.most_recent_timestamp.timestamp_millis())?;
+        let mut flags = 0u8;
+        if self.link_stats.is_some() { flags |= 0x1; }
+        if self.mac.is_some() { flags |= 0x2; }
+        writer.write_u8(flags)?;
+        if let Some(link_stats) = &self.link_stats {
+            link_stats.write_binary(writer)?;
+        }
+        if let Some(mac) = &self.mac {
+            writer.write_u8(mac.len() as u8)?;
+            writer.write_all(mac)?;
+        }
+        Ok(())
+    }
+
+    pub fn from_bytes(bytes: &[u8], encoding: MessageEncoding) -> Result<DesktopStateMessage, DesktopStateMessageError> {
+        match encoding {
+            MessageEncoding::Json => DesktopStateMessage::from_json_bytes(bytes),
+            MessageEncoding::Binary => DesktopStateMessage::from_binary_bytes(bytes)
+        }
+    }
+
+    pub fn to_bytes(&self, encoding: MessageEncoding) -> Vec<u8> {
+        match encoding {
+            MessageEncoding::Json => self.to_json_bytes(),
+            MessageEncoding::Binary => self.to_binary_bytes()
+        }
+    }
+
     pub fn to_json_bytes(&self) -> Vec<u8> {
-        let json_data = object!{
+        let mut json_data = object!{
+            protocol_version: self.protocol_version,
+            sender_id: self.sender_id,
+            sequence: self.sequence,
             requested_state: self.requested_state.to_byte(),
-            most_recent_timestamp: self.most_recent_timestamp.timestamp(),
+            most_recent_timestamp: self.most_recent_timestamp.timestamp_millis(),
         };
+        if let Some(link_stats) = self.link_stats {
+            json_data[LINK_STATS] = JsonValue::from(link_stats);
+        }
+        if let Some(mac) = &self.mac {
+            json_data[MAC] = JsonValue::from(hex::encode(mac));
+        }
 
         json_data.dump().into_bytes()
     }
+
+    /**
+     * @brief The fields covered by the message's mac, bound to the session id handed out on CONNECT
+     */
+    pub fn signing_payload(&self, session_id: &str) -> String {
+        format!(
+            "{}|{}|{}|{}|{}|{}",
+            session_id,
+            self.protocol_version,
+            self.sender_id,
+            self.sequence.unwrap_or(0),
+            self.requested_state.to_byte(),
+            self.most_recent_timestamp.timestamp_millis()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device_watchdog::get_now;
+
+    #[test]
+    fn json_round_trip() {
+        let message = DesktopStateMessage {
+            protocol_version: super::super::PROTOCOL_VERSION,
+            sender_id: 7,
+            sequence: Some(42),
+            requested_state: PodState::Armed,
+            most_recent_timestamp: timestamp_from_millis(get_now().timestamp_millis()).unwrap(),
+            link_stats: Some(LinkStats { received: 10, dropped: 1, duplicated: 0, reordered: 2, unauthenticated: 0, malformed: 0 }),
+            mac: Some(vec![0xde, 0xad, 0xbe, 0xef])
+        };
+        let parsed = DesktopStateMessage::from_json_bytes(&message.to_json_bytes()).unwrap();
+        assert_eq!(parsed.protocol_version, message.protocol_version);
+        assert_eq!(parsed.sender_id, 7);
+        assert_eq!(parsed.sequence, Some(42));
+        assert_eq!(parsed.requested_state, PodState::Armed);
+        assert_eq!(parsed.most_recent_timestamp, message.most_recent_timestamp);
+        assert_eq!(parsed.link_stats, message.link_stats);
+        assert_eq!(parsed.mac, message.mac);
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let message = DesktopStateMessage {
+            protocol_version: super::super::PROTOCOL_VERSION,
+            sender_id: 3,
+            sequence: Some(1000),
+            requested_state: PodState::Braking,
+            most_recent_timestamp: timestamp_from_millis(123456789).unwrap(),
+            link_stats: Some(LinkStats { received: 1, dropped: 2, duplicated: 3, reordered: 4, unauthenticated: 5, malformed: 6 }),
+            mac: Some(vec![1; 32])
+        };
+        let bytes = message.to_bytes(MessageEncoding::Binary);
+        let parsed = DesktopStateMessage::from_bytes(&bytes, MessageEncoding::Binary).unwrap();
+        assert_eq!(parsed.sender_id, 3);
+        assert_eq!(parsed.sequence, Some(1000));
+        assert_eq!(parsed.requested_state, PodState::Braking);
+        assert_eq!(parsed.most_recent_timestamp, message.most_recent_timestamp);
+        assert_eq!(parsed.link_stats, message.link_stats);
+        assert_eq!(parsed.mac, message.mac);
+        assert_eq!(parsed.signing_payload("session"), message.signing_payload("session"));
+    }
+
+    #[test]
+    fn binary_truncated() {
+        let message = DesktopStateMessage {
+            protocol_version: super::super::PROTOCOL_VERSION,
+            sender_id: 0,
+            sequence: Some(1),
+            requested_state: PodState::Armed,
+            most_recent_timestamp: timestamp_from_millis(0).unwrap(),
+            link_stats: None,
+            mac: None
+        };
+        let bytes = message.to_binary_bytes();
+        assert!(matches!(DesktopStateMessage::from_binary_bytes(&bytes[..bytes.len() - 1]), Err(DesktopStateMessageError::InvalidMessage(_))));
+        assert!(matches!(DesktopStateMessage::from_binary_bytes(b"{}"), Err(DesktopStateMessageError::InvalidMessage(_))));
+    }
+
+    #[test]
+    fn legacy_message() {
+        let parsed = DesktopStateMessage::from_json_bytes(b"{\"requested_state\":1,\"most_recent_timestamp\":1500}\0\0\0").unwrap();
+        assert_eq!(parsed.protocol_version, 0);
+        assert_eq!(parsed.sequence, None);
+        assert_eq!(parsed.requested_state, PodState::LowVoltage);
+        assert_eq!(parsed.most_recent_timestamp.timestamp_millis(), 1_500_000);
+    }
+
+    #[test]
+    fn out_of_range_timestamp() {
+        let result = DesktopStateMessage::from_json_bytes(b"{\"requested_state\":1,\"most_recent_timestamp\":9223372036854775807}");
+        assert!(matches!(result, Err(DesktopStateMessageError::InvalidMessage(_))));
+        let result = DesktopStateMessage::from_json_bytes(b"{\"protocol_version\":1,\"sequence\":1,\"requested_state\":1,\"most_recent_timestamp\":-9223372036854775807}");
+        assert!(matches!(result, Err(DesktopStateMessageError::InvalidMessage(_))));
+
+        let mut bytes = vec![BINARY_MAGIC, super::super::PROTOCOL_VERSION];
+        bytes.extend_from_slice(&0u32.to_le_bytes()); // sender_id
+        bytes.extend_from_slice(&1u32.to_le_bytes()); // sequence
+        bytes.push(PodState::Armed.to_byte());
+        bytes.extend_from_slice(&i64::MAX.to_le_bytes());
+        bytes.push(0); // flags
+        assert!(matches!(DesktopStateMessage::from_binary_bytes(&bytes), Err(DesktopStateMessageError::InvalidMessage(_))));
+    }
+
+    #[test]
+    fn versioned_message_without_sequence() {
+        let result = DesktopStateMessage::from_json_bytes(b"{\"protocol_version\":1,\"requested_state\":1,\"most_recent_timestamp\":1500}");
+        assert!(matches!(result, Err(DesktopStateMessageError::InvalidMessage(_))));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let result = DesktopStateMessage::from_json_bytes(b"{\"protocol_version\":200,\"sequence\":1,\"requested_state\":1,\"most_recent_timestamp\":1500}");
+        assert!(matches!(result, Err(DesktopStateMessageError::UnsupportedProtocolVersion(200))));
+    }
 }
@@ -0,0 +1,66 @@
+/**
+ * The encoding used for UDP messages is negotiated when the desktop connects.
+ *
+ * The desktop requests an encoding by adding a line to its CONNECT request:
+ *      ENCODING <JSON|BINARY>\r\n
+ * When the line is present the relay appends the encoding it selected to the CONNECT response.
+ * Desktops which do not ask for an encoding keep receiving JSON.
+ *
+ * JSON is kept for debugging. BINARY is a fixed little endian layout starting with BINARY_MAGIC.
+ */
+const ENCODING_PREFIX: &str = "ENCODING ";
+
+/**
+ * First byte of every binary message. JSON messages always start with '{'
+ */
+pub const BINARY_MAGIC: u8 = 0xBF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MessageEncoding {
+    #[default]
+    Json,
+    Binary
+}
+
+impl MessageEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageEncoding::Json => "JSON",
+            MessageEncoding::Binary => "BINARY"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MessageEncoding> {
+        match name.trim().to_ascii_uppercase().as_str() {
+            "JSON" => Some(MessageEncoding::Json),
+            "BINARY" => Some(MessageEncoding::Binary),
+            _ => None
+        }
+    }
+
+    /**
+     * @brief Find the ENCODING line in the rest of a CONNECT request.
+     * Returns None if the desktop did not ask for an encoding.
+     * Unknown encodings fall back to JSON so that the desktop can see what was selected in the response.
+     */
+    pub fn from_request(request: &[u8]) -> Option<MessageEncoding> {
+        let request = String::from_utf8_lossy(request);
+        request.trim_matches(char::from(0))
+            .lines()
+            .find(|line| line.starts_with(ENCODING_PREFIX))
+            .map(|line| MessageEncoding::from_name(&line[ENCODING_PREFIX.len()..]).unwrap_or(MessageEncoding::Json))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negotiate_encoding() {
+        assert_eq!(MessageEncoding::from_request(b""), None);
+        assert_eq!(MessageEncoding::from_request(b"ENCODING BINARY\r\n"), Some(MessageEncoding::Binary));
+        assert_eq!(MessageEncoding::from_request(b"AUTH 1 abc ff\r\nENCODING json\r\n"), Some(MessageEncoding::Json));
+        assert_eq!(MessageEncoding::from_request(b"ENCODING CBOR\r\n"), Some(MessageEncoding::Json));
+    }
+}
@@ -1,10 +1,16 @@
+use crate::transition_journal::TransitionRefusal;
+
 #[derive(Copy, Clone)]
 pub enum UdpErrno {
     NoError,
     InvalidTransitionRequest,
     ArmingFault,
     ControllerTimeout,
-    GeneralPodFailure
+    GeneralPodFailure,
+    MalformedPacket,
+    RelayShuttingDown,
+    MissingDevices,
+    FlashInProgress
 }
 
 impl UdpErrno {
@@ -14,7 +20,21 @@ impl UdpErrno {
             UdpErrno::InvalidTransitionRequest => 0x1,
             UdpErrno::ArmingFault              => 0x2,
             UdpErrno::ControllerTimeout        => 0x3,
-            UdpErrno::GeneralPodFailure        => 0x4
+            UdpErrno::GeneralPodFailure        => 0x4,
+            UdpErrno::MalformedPacket          => 0x5,
+            UdpErrno::RelayShuttingDown        => 0x6,
+            UdpErrno::MissingDevices           => 0x7,
+            UdpErrno::FlashInProgress          => 0x8
+        }
+    }
+}
+
+impl From<&TransitionRefusal> for UdpErrno {
+    fn from(refusal: &TransitionRefusal) -> UdpErrno {
+        match refusal {
+            TransitionRefusal::MissingDevices(_)   => UdpErrno::MissingDevices,
+            TransitionRefusal::FirmwareMismatch(_) => UdpErrno::ArmingFault,
+            TransitionRefusal::Flashing(_)         => UdpErrno::FlashInProgress
         }
     }
 }
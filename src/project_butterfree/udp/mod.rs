@@ -5,7 +5,33 @@ pub mod desktop_state_message;
 pub mod pod_state_message;
 pub mod socket_extention;
 pub mod errno;
+pub mod sequence;
+pub mod encoding;
+pub mod subscription;
+
+use chrono::NaiveDateTime;
+
+/**
+ * Version of the Project Butterfree UDP messages.
+ * Version 0 is used for messages from desktops which predate versioning. They have no sequence number and their
+ * timestamps are in seconds. Desktops which connect without negotiating an encoding are answered in version 0
+ * until they send a versioned message.
+ */
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/**
+ * Sender id which the relay stamps on every message it sends
+ */
+pub const RELAY_SENDER_ID: u32 = 0;
+
+/**
+ * Timestamps are sent across the network as milliseconds since the unix epoch, except in version 0.
+ * Returns None when the timestamp is outside of the range chrono can represent
+ */
+pub fn timestamp_from_millis(millis: i64) -> Option<NaiveDateTime> {
+    NaiveDateTime::from_timestamp_opt(millis.div_euclid(1000), (millis.rem_euclid(1000) * 1_000_000) as u32)
+}
 
 pub mod prelude {
     pub use super::socket_extention::ProjectButterfreeUDPSocket;
-}
\ No newline at end of file
+}
@@ -4,19 +4,35 @@ use json::{
 };
 use crate:: {
     pod_data::PodData,
-    pod_states::PodState
+    pod_states::PodState,
+    device_watchdog::{ Device, DeviceHealth },
+    supervisor::ThreadRestart,
+    transition_journal::TransitionRefusal
 };
+use std::io::{ self, Write };
+use byteorder::{ LittleEndian, WriteBytesExt };
 use super::{
-    errno::UdpErrno
+    encoding::{ MessageEncoding, BINARY_MAGIC },
+    errno::UdpErrno,
+    sequence::LinkStats,
+    PROTOCOL_VERSION,
+    RELAY_SENDER_ID
 };
 
+#[derive(Clone)]
 pub struct PodStateMessage {
+    protocol_version: u8, // Version of the JSON encoding, binary messages are always the current version
+    sequence: u32,
     current_state: PodState,
     pending_next_state: PodState,
     errno: UdpErrno,
     telemetry: Option<PodData>,
     telemetry_timestamp: NaiveDateTime,
-    recovering: bool
+    recovering: bool,
+    link_stats: LinkStats, // The relay's view of the messages sent by the desktop
+    devices: Vec<(Device, DeviceHealth)>, // Health of each device as seen by the watchdog
+    restarts: Vec<ThreadRestart>, // Threads restarted by the supervisor
+    refusal: Option<(PodState, TransitionRefusal)> // The last transition the relay refused to request
 }
 
 impl PodStateMessage {
@@ -25,6 +41,47 @@ impl PodStateMessage {
             Some(data) => data.into(),
             _ => json::JsonValue::Null
         };
+        if self.protocol_version == 0 {
+            return self.to_legacy_json_bytes(telemetry);
+        }
+        let devices: Vec<json::JsonValue> = self.devices.iter().map(|(device, health)| object!{
+            device: format!("{:?}", device),
+            state: health.as_str(),
+        }).collect();
+        let restarts: Vec<json::JsonValue> = self.restarts.iter().map(|restart| object!{
+            thread: restart.thread.as_str(),
+            count: restart.count,
+        }).collect();
+        let refusal = match &self.refusal {
+            Some((state, refusal)) => {
+                let mut refusal = refusal.to_json();
+                refusal["state"] = state.to_byte().into();
+                refusal
+            },
+            None => json::JsonValue::Null
+        };
+        let json_data = object!{
+            protocol_version: PROTOCOL_VERSION,
+            sender_id: RELAY_SENDER_ID,
+            sequence: self.sequence,
+            current_state: self.current_state.to_byte(),
+            pending_next_state: self.pending_next_state.to_byte(),
+            errno: self.errno.to_byte(),
+            telemetry: telemetry,
+            telemetry_timestamp: self.telemetry_timestamp.timestamp_millis(),
+            recovering: self.recovering,
+            link_stats: self.link_stats,
+            devices: devices,
+            restarts: restarts,
+            refusal: refusal
+        };
+        json_data.dump().into_bytes()
+    }
+
+    /**
+     * @brief The message understood by desktops which predate versioning: no protocol fields and the timestamp in seconds
+     */
+    fn to_legacy_json_bytes(&self, telemetry: json::JsonValue) -> Vec<u8> {
         let json_data = object!{
             current_state: self.current_state.to_byte(),
             pending_next_state: self.pending_next_state.to_byte(),
@@ -36,25 +93,274 @@ impl PodStateMessage {
         json_data.dump().into_bytes()
     }
 
+    /**
+     * @brief Fixed layout binary encoding:
+     *      magic: u8, protocol_version: u8, sender_id: u32, sequence: u32,
+     *      current_state: u8, pending_next_state: u8, errno: u8,
+     *      flags: u8 (bit 0 recovering, bit 1 telemetry present, bit 2 device states present, bit 3 thread restarts present, bit 4 refusal present),
+     *      telemetry_timestamp: i64 (ms), link_stats: 6 x u64 (received, dropped, duplicated, reordered, unauthenticated, malformed),
+     *      telemetry: PodData::write_binary, only when present
+     *      device_count: u8 followed by device: u8, state: u8 for each device, only when present
+     *      restart_count: u8 followed by thread: u8, restarts: u32 for each restarted thread, only when present
+     *      refused_state: u8, reason: u8, device_count: u8 followed by device: u8 for each device the refusal is about, only when present
+     */
+    pub fn to_binary_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_binary(&mut bytes).expect("Writing to a Vec does not fail");
+        bytes
+    }
+
+    fn write_binary<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u8(BINARY_MAGIC)?;
+        writer.write_u8(PROTOCOL_VERSION)?;
+        writer.write_u32::<LittleEndian>(RELAY_SENDER_ID)?;
+        writer.write_u32::<LittleEndian>(self.sequence)?;
+        writer.write_u8(self.current_state.to_byte())?;
+        writer.write_u8(self.pending_next_state.to_byte())?;
+        writer.write_u8(self.errno.to_byte())?;
+        let mut flags = 0u8;
+        if self.recovering { flags |= 0x1; }
+        if self.telemetry.is_some() { flags |= 0x2; }
+        if !self.devices.is_empty() { flags |= 0x4; }
+        if !self.restarts.is_empty() { flags |= 0x8; }
+        if self.refusal.is_some() { flags |= 0x10; }
+        writer.write_u8(flags)?;
+        writer.write_i64::<LittleEndian>(self.telemetry_timestamp.timestamp_millis())?;
+        self.link_stats.write_binary(writer)?;
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.write_binary(writer)?;
+        }
+        if !self.devices.is_empty() {
+            writer.write_u8(self.devices.len() as u8)?;
+            for (device, health) in &self.devices {
+                writer.write_u8(device.to_byte())?;
+                writer.write_u8(health.to_byte())?;
+            }
+        }
+        if !self.restarts.is_empty() {
+            writer.write_u8(self.restarts.len() as u8)?;
+            for restart in &self.restarts {
+                writer.write_u8(restart.thread.to_byte())?;
+                writer.write_u32::<LittleEndian>(restart.count)?;
+            }
+        }
+        if let Some((state, refusal)) = &self.refusal {
+            let devices = refusal.device_bytes();
+            writer.write_u8(state.to_byte())?;
+            writer.write_u8(refusal.to_byte())?;
+            writer.write_u8(devices.len() as u8)?;
+            writer.write_all(&devices)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self, encoding: MessageEncoding) -> Vec<u8> {
+        match encoding {
+            MessageEncoding::Json => self.to_json_bytes(),
+            MessageEncoding::Binary => self.to_binary_bytes()
+        }
+    }
+
     pub fn new(current_state: PodState, pending_next_state: PodState, errno: UdpErrno, telemetry: &PodData, telemetry_timestamp: NaiveDateTime, recovering: bool) -> PodStateMessage {
         PodStateMessage {
+            protocol_version: PROTOCOL_VERSION,
+            sequence: 0,
             current_state,
             errno,
             pending_next_state,
             recovering,
             telemetry: Some((*telemetry).clone()),
             telemetry_timestamp,
+            link_stats: LinkStats::default(),
+            devices: Vec::new(),
+            restarts: Vec::new(),
+            refusal: None
         }
     }
 
     pub fn new_no_telemetry(current_state: PodState, pending_next_state: PodState, errno: UdpErrno, telemetry_timestamp: NaiveDateTime, recovering: bool) -> PodStateMessage {
         PodStateMessage {
+            protocol_version: PROTOCOL_VERSION,
+            sequence: 0,
             current_state,
             errno,
             pending_next_state,
             recovering,
             telemetry: None,
             telemetry_timestamp,
+            link_stats: LinkStats::default(),
+            devices: Vec::new(),
+            restarts: Vec::new(),
+            refusal: None
         }
     }
-}
\ No newline at end of file
+
+    /**
+     * @brief Encode the message in the protocol version the receiver understands
+     */
+    pub fn for_version(mut self, protocol_version: u8) -> PodStateMessage {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    /**
+     * @brief Stamp the message with its sequence number and the relay's view of the link
+     */
+    pub fn sequenced(mut self, sequence: u32, link_stats: LinkStats) -> PodStateMessage {
+        self.sequence = sequence;
+        self.link_stats = link_stats;
+        self
+    }
+
+    /**
+     * @brief Attach the latest snapshot of the watchdog
+     */
+    pub fn with_devices(mut self, devices: Vec<(Device, DeviceHealth)>) -> PodStateMessage {
+        self.devices = devices;
+        self
+    }
+
+    /**
+     * @brief Attach the threads restarted by the supervisor
+     */
+    pub fn with_restarts(mut self, restarts: Vec<ThreadRestart>) -> PodStateMessage {
+        self.restarts = restarts;
+        self
+    }
+
+    /**
+     * @brief Attach the last transition the relay refused to request
+     */
+    pub fn with_refusal(mut self, refusal: Option<(PodState, TransitionRefusal)>) -> PodStateMessage {
+        self.refusal = refusal;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use byteorder::ReadBytesExt;
+    use crate::supervisor::SupervisedThread;
+    use super::super::timestamp_from_millis;
+
+    #[test]
+    fn binary_is_smaller_than_json() {
+        let mut pod_data = PodData::new();
+        pod_data.battery_pack_voltage = Some(48.2);
+        pod_data.speed = Some(10.0);
+        pod_data.roboteq_motor_1_speed = Some(300.0);
+        let message = PodStateMessage::new(PodState::Armed, PodState::AutoPilot, UdpErrno::NoError, &pod_data, timestamp_from_millis(1500).unwrap(), true)
+            .sequenced(9, LinkStats::default());
+        let bytes = message.to_bytes(MessageEncoding::Binary);
+        assert!(bytes.len() < message.to_bytes(MessageEncoding::Json).len());
+
+        let mut reader = &bytes[..];
+        assert_eq!(reader.read_u8().unwrap(), BINARY_MAGIC);
+        assert_eq!(reader.read_u8().unwrap(), PROTOCOL_VERSION);
+        assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), RELAY_SENDER_ID);
+        assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), 9);
+        assert_eq!(reader.read_u8().unwrap(), PodState::Armed.to_byte());
+        assert_eq!(reader.read_u8().unwrap(), PodState::AutoPilot.to_byte());
+        assert_eq!(reader.read_u8().unwrap(), UdpErrno::NoError.to_byte());
+        assert_eq!(reader.read_u8().unwrap(), 0x3);
+        assert_eq!(reader.read_i64::<LittleEndian>().unwrap(), 1500);
+        assert_eq!(LinkStats::read_binary(&mut reader).unwrap(), LinkStats::default());
+        let telemetry = PodData::read_binary(&mut reader).unwrap();
+        assert_eq!(telemetry.speed, Some(10.0));
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn device_states() {
+        let message = PodStateMessage::new_no_telemetry(PodState::Armed, PodState::Armed, UdpErrno::NoError, timestamp_from_millis(0).unwrap(), false)
+            .with_devices(vec![(Device::BMS, DeviceHealth::Healthy), (Device::PRESSURE_HIGH, DeviceHealth::Lost)]);
+        let json = json::parse(&String::from_utf8(message.to_json_bytes()).unwrap()).unwrap();
+        assert_eq!(json["devices"][1]["device"], "PRESSURE_HIGH");
+        assert_eq!(json["devices"][1]["state"], "lost");
+
+        let bytes = message.to_binary_bytes();
+        let mut reader = &bytes[..];
+        reader = &reader[2 + 4 + 4 + 3..];
+        assert_eq!(reader.read_u8().unwrap(), 0x4);
+        reader = &reader[8 + 6 * 8..];
+        assert_eq!(reader.read_u8().unwrap(), 2);
+        assert_eq!(reader, &[Device::BMS.to_byte(), 0x01, Device::PRESSURE_HIGH.to_byte(), 0x03][..]);
+    }
+
+    #[test]
+    fn thread_restarts() {
+        let restart = ThreadRestart { thread: SupervisedThread::Can, count: 1, last_restart: timestamp_from_millis(0).unwrap() };
+        let message = PodStateMessage::new_no_telemetry(PodState::SystemFailure, PodState::SystemFailure, UdpErrno::NoError, timestamp_from_millis(0).unwrap(), false)
+            .with_restarts(vec![restart]);
+        let json = json::parse(&String::from_utf8(message.to_json_bytes()).unwrap()).unwrap();
+        assert_eq!(json["restarts"][0]["thread"], "can");
+        assert_eq!(json["restarts"][0]["count"], 1);
+
+        let bytes = message.to_binary_bytes();
+        let mut reader = &bytes[..];
+        reader = &reader[2 + 4 + 4 + 3..];
+        assert_eq!(reader.read_u8().unwrap(), 0x8);
+        reader = &reader[8 + 6 * 8..];
+        assert_eq!(reader.read_u8().unwrap(), 1);
+        assert_eq!(reader.read_u8().unwrap(), SupervisedThread::Can.to_byte());
+        assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), 1);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn refusal() {
+        let refusal = TransitionRefusal::MissingDevices(vec![Device::BMS, Device::ROBOTEQ]);
+        let message = PodStateMessage::new_no_telemetry(PodState::LowVoltage, PodState::LowVoltage, UdpErrno::from(&refusal), timestamp_from_millis(0).unwrap(), false)
+            .with_refusal(Some((PodState::Armed, refusal)));
+        let json = json::parse(&String::from_utf8(message.to_json_bytes()).unwrap()).unwrap();
+        assert_eq!(json["errno"], UdpErrno::MissingDevices.to_byte());
+        assert_eq!(json["refusal"]["state"], PodState::Armed.to_byte());
+        assert_eq!(json["refusal"]["reason"], "missing_devices");
+        assert_eq!(json["refusal"]["devices"][1], "ROBOTEQ");
+
+        let bytes = message.to_binary_bytes();
+        let mut reader = &bytes[..];
+        reader = &reader[2 + 4 + 4 + 3..];
+        assert_eq!(reader.read_u8().unwrap(), 0x10);
+        reader = &reader[8 + 6 * 8..];
+        assert_eq!(reader, &[PodState::Armed.to_byte(), 0x0, 2, Device::BMS.to_byte(), Device::ROBOTEQ.to_byte()][..]);
+    }
+
+    #[test]
+    fn firmware_refusal() {
+        let refusal = TransitionRefusal::FirmwareMismatch(vec![3, 7]);
+        let message = PodStateMessage::new_no_telemetry(PodState::LowVoltage, PodState::LowVoltage, UdpErrno::from(&refusal), timestamp_from_millis(0).unwrap(), false)
+            .with_refusal(Some((PodState::Armed, refusal)));
+        let json = json::parse(&String::from_utf8(message.to_json_bytes()).unwrap()).unwrap();
+        assert_eq!(json["errno"], UdpErrno::ArmingFault.to_byte());
+        assert_eq!(json["refusal"]["reason"], "firmware_mismatch");
+        assert_eq!(json["refusal"]["short_device_ids"][1], 7);
+
+        let bytes = message.to_binary_bytes();
+        assert_eq!(&bytes[bytes.len() - 5..], &[PodState::Armed.to_byte(), 0x1, 2, 3, 7][..]);
+    }
+
+    #[test]
+    fn legacy_json() {
+        let message = PodStateMessage::new_no_telemetry(PodState::Armed, PodState::AutoPilot, UdpErrno::NoError, timestamp_from_millis(1_500_250).unwrap(), false)
+            .sequenced(4, LinkStats::default())
+            .with_restarts(vec![ThreadRestart { thread: SupervisedThread::Udp, count: 1, last_restart: timestamp_from_millis(0).unwrap() }])
+            .for_version(0);
+        let json = json::parse(&String::from_utf8(message.to_json_bytes()).unwrap()).unwrap();
+        assert_eq!(json["telemetry_timestamp"], 1500);
+        assert_eq!(json["current_state"], PodState::Armed.to_byte());
+        assert!(!json.has_key("protocol_version"));
+        assert!(!json.has_key("sequence"));
+        assert!(!json.has_key("restarts"));
+
+        // The timestamp the desktop echoes back is read in the same units
+        let echo = format!("{{\"requested_state\":2,\"most_recent_timestamp\":{}}}", json["telemetry_timestamp"]);
+        let echo = crate::project_butterfree::udp::desktop_state_message::DesktopStateMessage::from_json_bytes(echo.as_bytes()).unwrap();
+        assert_eq!(echo.most_recent_timestamp.timestamp(), message.telemetry_timestamp.timestamp());
+
+        let json = json::parse(&String::from_utf8(message.clone().for_version(PROTOCOL_VERSION).to_json_bytes()).unwrap()).unwrap();
+        assert_eq!(json["telemetry_timestamp"], 1_500_250);
+        assert_eq!(json["sequence"], 4);
+    }
+}
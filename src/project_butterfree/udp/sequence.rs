@@ -0,0 +1,264 @@
+/**
+ * Sequence tracking for Project Butterfree UDP messages.
+ *
+ * Every message carries a monotonic sequence number, which wraps around after u32::MAX. The receiving end keeps a
+ * SequenceTracker which uses a sliding window over the most recently received
+ * sequence numbers to detect dropped, duplicated and reordered packets.
+ */
+use json::{ JsonValue, object };
+use std::io::{ self, Read, Write };
+use byteorder::{ LittleEndian, ReadBytesExt, WriteBytesExt };
+
+const WINDOW_SIZE: u32 = 64; // Number of bits in the window
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub received: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub unauthenticated: u64,
+    pub malformed: u64
+}
+
+impl From<LinkStats> for JsonValue {
+    fn from(stats: LinkStats) -> JsonValue {
+        object!{
+            received: stats.received,
+            dropped: stats.dropped,
+            duplicated: stats.duplicated,
+            reordered: stats.reordered,
+            unauthenticated: stats.unauthenticated,
+            malformed: stats.malformed,
+        }
+    }
+}
+
+impl LinkStats {
+    pub fn from_json(jv: &JsonValue) -> Option<LinkStats> {
+        Some(LinkStats {
+            received: jv["received"].as_u64()?,
+            dropped: jv["dropped"].as_u64()?,
+            duplicated: jv["duplicated"].as_u64()?,
+            reordered: jv["reordered"].as_u64()?,
+            unauthenticated: jv["unauthenticated"].as_u64().unwrap_or(0),
+            malformed: jv["malformed"].as_u64().unwrap_or(0),
+        })
+    }
+
+    pub fn write_binary<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(self.received)?;
+        writer.write_u64::<LittleEndian>(self.dropped)?;
+        writer.write_u64::<LittleEndian>(self.duplicated)?;
+        writer.write_u64::<LittleEndian>(self.reordered)?;
+        writer.write_u64::<LittleEndian>(self.unauthenticated)?;
+        writer.write_u64::<LittleEndian>(self.malformed)
+    }
+
+    pub fn read_binary<R: Read>(reader: &mut R) -> io::Result<LinkStats> {
+        Ok(LinkStats {
+            received: reader.read_u64::<LittleEndian>()?,
+            dropped: reader.read_u64::<LittleEndian>()?,
+            duplicated: reader.read_u64::<LittleEndian>()?,
+            reordered: reader.read_u64::<LittleEndian>()?,
+            unauthenticated: reader.read_u64::<LittleEndian>()?,
+            malformed: reader.read_u64::<LittleEndian>()?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SequenceStatus {
+    InOrder,
+    Gap(u32),   // Number of packets that were skipped before this one
+    Duplicate,
+    Reordered,  // An older packet which arrived after a newer one
+    Stale       // An older packet which is outside of the window and can not be classified
+}
+
+impl SequenceStatus {
+    /**
+     * @brief Only messages which are newer than every message seen so far should be acted on
+     */
+    pub fn is_newest(&self) -> bool {
+        matches!(self, SequenceStatus::InOrder | SequenceStatus::Gap(_))
+    }
+}
+
+pub struct SequenceTracker {
+    highest: Option<u32>,
+    window: u64, // Bit n is set if (highest - n) has been received
+    stats: LinkStats
+}
+
+impl SequenceTracker {
+    pub fn new() -> SequenceTracker {
+        SequenceTracker {
+            highest: None,
+            window: 0,
+            stats: LinkStats::default()
+        }
+    }
+
+    /**
+     * @brief Forget all sequence numbers and stats. Used when a new session starts
+     */
+    pub fn reset(&mut self) {
+        *self = SequenceTracker::new();
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /**
+     * @brief Count a packet which was rejected before its sequence number could be trusted
+     */
+    pub fn record_unauthenticated(&mut self) {
+        self.stats.unauthenticated += 1;
+    }
+
+    /**
+     * @brief Count a packet which could not be decoded
+     */
+    pub fn record_malformed(&mut self) {
+        self.stats.malformed += 1;
+    }
+
+    pub fn track(&mut self, sequence: u32) -> SequenceStatus {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(sequence);
+                self.window = 1;
+                self.stats.received += 1;
+                return SequenceStatus::InOrder;
+            }
+        };
+
+        // Sequence numbers wrap around, a sequence is newer if it is less than half of the sequence space ahead
+        if (sequence.wrapping_sub(highest) as i32) > 0 {
+            let distance = sequence.wrapping_sub(highest);
+            let gap = distance - 1;
+            self.window = if distance >= WINDOW_SIZE { 0 } else { self.window << distance };
+            self.window |= 1;
+            self.highest = Some(sequence);
+            self.stats.received += 1;
+            self.stats.dropped += gap as u64;
+            if gap == 0 { SequenceStatus::InOrder } else { SequenceStatus::Gap(gap) }
+        } else {
+            let distance = highest.wrapping_sub(sequence);
+            if distance >= WINDOW_SIZE {
+                self.stats.received += 1;
+                self.stats.reordered += 1;
+                return SequenceStatus::Stale;
+            }
+            let bit = 1u64 << distance;
+            if self.window & bit != 0 {
+                self.stats.duplicated += 1;
+                SequenceStatus::Duplicate
+            } else {
+                /* This packet was counted as dropped when the gap was detected */
+                self.window |= bit;
+                self.stats.received += 1;
+                self.stats.reordered += 1;
+                self.stats.dropped = self.stats.dropped.saturating_sub(1);
+                SequenceStatus::Reordered
+            }
+        }
+    }
+}
+
+impl Default for SequenceTracker {
+    fn default() -> Self {
+        SequenceTracker::new()
+    }
+}
+
+/********************
+ *      TESTS
+ ********************/
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut tracker = SequenceTracker::new();
+        for sequence in 10..20 {
+            assert_eq!(tracker.track(sequence), SequenceStatus::InOrder);
+        }
+        assert_eq!(tracker.stats(), LinkStats { received: 10, ..LinkStats::default() });
+    }
+
+    #[test]
+    fn dropped_packets() {
+        let mut tracker = SequenceTracker::new();
+        tracker.track(1);
+        assert_eq!(tracker.track(4), SequenceStatus::Gap(2));
+        assert_eq!(tracker.stats().dropped, 2);
+        assert_eq!(tracker.stats().received, 2);
+    }
+
+    #[test]
+    fn duplicated_packets() {
+        let mut tracker = SequenceTracker::new();
+        tracker.track(1);
+        tracker.track(2);
+        assert_eq!(tracker.track(2), SequenceStatus::Duplicate);
+        assert_eq!(tracker.track(1), SequenceStatus::Duplicate);
+        assert_eq!(tracker.stats().duplicated, 2);
+        assert_eq!(tracker.stats().received, 2);
+    }
+
+    #[test]
+    fn reordered_packets() {
+        let mut tracker = SequenceTracker::new();
+        tracker.track(1);
+        tracker.track(3);
+        assert_eq!(tracker.stats().dropped, 1);
+        assert_eq!(tracker.track(2), SequenceStatus::Reordered);
+        assert_eq!(tracker.stats().dropped, 0);
+        assert_eq!(tracker.stats().reordered, 1);
+        assert_eq!(tracker.track(2), SequenceStatus::Duplicate);
+        assert!(!SequenceStatus::Reordered.is_newest());
+    }
+
+    #[test]
+    fn stale_packets() {
+        let mut tracker = SequenceTracker::new();
+        tracker.track(1);
+        tracker.track(200);
+        assert_eq!(tracker.track(2), SequenceStatus::Stale);
+    }
+
+    #[test]
+    fn wrap_around() {
+        let mut tracker = SequenceTracker::new();
+        tracker.track(u32::MAX - 1);
+        assert_eq!(tracker.track(u32::MAX), SequenceStatus::InOrder);
+        assert_eq!(tracker.track(0), SequenceStatus::InOrder);
+        assert_eq!(tracker.track(2), SequenceStatus::Gap(1));
+        assert_eq!(tracker.track(1), SequenceStatus::Reordered);
+        assert_eq!(tracker.track(u32::MAX), SequenceStatus::Duplicate);
+        assert_eq!(tracker.track(3), SequenceStatus::InOrder);
+        assert_eq!(tracker.track(u32::MAX - 100), SequenceStatus::Stale);
+        assert_eq!(tracker.stats(), LinkStats { received: 7, duplicated: 1, reordered: 2, ..LinkStats::default() });
+    }
+
+    #[test]
+    fn reset() {
+        let mut tracker = SequenceTracker::new();
+        tracker.track(100);
+        tracker.reset();
+        assert_eq!(tracker.track(0), SequenceStatus::InOrder);
+        assert_eq!(tracker.stats().received, 1);
+    }
+
+    #[test]
+    fn link_stats_json() {
+        let stats = LinkStats { received: 5, dropped: 1, duplicated: 2, reordered: 3, unauthenticated: 4, malformed: 6 };
+        let jv: JsonValue = stats.into();
+        assert_eq!(LinkStats::from_json(&jv), Some(stats));
+    }
+}
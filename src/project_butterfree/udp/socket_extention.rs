@@ -1,13 +1,14 @@
 use super::pod_state_message::PodStateMessage;
+use super::encoding::MessageEncoding;
 use std::net::UdpSocket;
 
 
 pub trait ProjectButterfreeUDPSocket {
-    fn send_pod_state_message(&self, msg: &PodStateMessage) -> std::io::Result<usize>;
+    fn send_pod_state_message(&self, msg: &PodStateMessage, encoding: MessageEncoding) -> std::io::Result<usize>;
 }
 
 impl ProjectButterfreeUDPSocket for UdpSocket {
-    fn send_pod_state_message(&self, msg: &PodStateMessage) -> std::io::Result<usize> {
-        self.send(&msg.to_json_bytes())
+    fn send_pod_state_message(&self, msg: &PodStateMessage, encoding: MessageEncoding) -> std::io::Result<usize> {
+        self.send(&msg.to_bytes(encoding))
     }
 }
@@ -0,0 +1,93 @@
+/**
+ * Read only observers subscribe to the pod state stream over TCP:
+ * ```text
+ *      SUBSCRIBE\r\n
+ *      PORT <udp port>\r\n
+ *      RATE <messages per second>\r\n     (optional, defaults to DEFAULT_RATE_HZ)
+ *      ENCODING <JSON|BINARY>\r\n         (optional, defaults to JSON)
+ *
+ *      UNSUBSCRIBE\r\n
+ *      PORT <udp port>\r\n
+ * ```
+ * PodStateMessages are sent to the port on the address which sent the request.
+ * Observers never send messages to the relay, so they can not request state changes.
+ *
+ * A subscription is acknowledged with `OK <port> <rate> <encoding> <lease seconds>` once the
+ * relay has room for it. It lasts for SUBSCRIPTION_LEASE, observers renew it by sending the same
+ * SUBSCRIBE request again before the lease runs out.
+ */
+use std::time::Duration;
+use super::encoding::MessageEncoding;
+
+pub const DEFAULT_RATE_HZ: u32 = 10;
+pub const MAX_RATE_HZ: u32 = 50;
+pub const SUBSCRIPTION_LEASE: Duration = Duration::from_secs(60);
+
+const PORT_PREFIX: &str = "PORT ";
+const RATE_PREFIX: &str = "RATE ";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SubscriptionRequest {
+    pub port: u16,
+    pub rate_hz: u32,
+    pub encoding: MessageEncoding
+}
+
+fn find_line<'a>(request: &'a str, prefix: &str) -> Option<&'a str> {
+    request.lines()
+        .find(|line| line.starts_with(prefix))
+        .map(|line| line[prefix.len()..].trim())
+}
+
+/**
+ * @brief Read the PORT line from the rest of a SUBSCRIBE or UNSUBSCRIBE request
+ */
+pub fn port_from_request(request: &[u8]) -> Result<u16, String> {
+    let request = String::from_utf8_lossy(request);
+    let port = find_line(&request, PORT_PREFIX).ok_or_else(|| String::from("Missing PORT"))?;
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => Err(format!("Invalid PORT {}", port))
+    }
+}
+
+impl SubscriptionRequest {
+    pub fn from_request(request: &[u8]) -> Result<SubscriptionRequest, String> {
+        let port = port_from_request(request)?;
+        let text = String::from_utf8_lossy(request);
+        let rate_hz = match find_line(&text, RATE_PREFIX) {
+            Some(rate) => match rate.parse::<u32>() {
+                Ok(rate) if rate > 0 && rate <= MAX_RATE_HZ => rate,
+                _ => return Err(format!("Invalid RATE {}, expected 1 to {}", rate, MAX_RATE_HZ))
+            },
+            None => DEFAULT_RATE_HZ
+        };
+        Ok(SubscriptionRequest {
+            port,
+            rate_hz,
+            encoding: MessageEncoding::from_request(request).unwrap_or_default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subscription_request() {
+        let request = SubscriptionRequest::from_request(b"PORT 9000\r\nRATE 5\r\nENCODING BINARY\r\n").unwrap();
+        assert_eq!(request, SubscriptionRequest { port: 9000, rate_hz: 5, encoding: MessageEncoding::Binary });
+
+        let request = SubscriptionRequest::from_request(b"PORT 9000\r\n").unwrap();
+        assert_eq!(request, SubscriptionRequest { port: 9000, rate_hz: DEFAULT_RATE_HZ, encoding: MessageEncoding::Json });
+    }
+
+    #[test]
+    fn invalid_subscription_request() {
+        assert!(SubscriptionRequest::from_request(b"").is_err());
+        assert!(SubscriptionRequest::from_request(b"PORT 0\r\n").is_err());
+        assert!(SubscriptionRequest::from_request(b"PORT 9000\r\nRATE 0\r\n").is_err());
+        assert!(SubscriptionRequest::from_request(b"PORT 9000\r\nRATE 1000\r\n").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+/**
+ * @brief The relay heartbeat lets the boards know the relay is alive, independently of
+ * the pod state which is only rebroadcast every 400ms.
+ *
+ * Every HEARTBEAT_PERIOD the CAN thread sends a frame with a rolling counter and the
+ * relay's uptime. A board which stops receiving heartbeats, or keeps receiving the same
+ * counter, can assume the relay is frozen and make itself safe.
+ *
+ * Boards may echo the counter back. Each echo is matched with the heartbeat it answers
+ * to measure the round trip on the bus, and a board whose echoes fall behind the
+ * counter has stopped hearing the relay.
+ *
+ * ```text
+ * Heartbeat (relay, 0x003): counter u16 LE | uptime_ms u32 LE
+ * Echo (BMS 0x00F, MC 0x018, Pressure 0x024): counter u16 LE | anything
+ * ```
+ */
+use std::collections::VecDeque;
+use std::time::{ Duration, Instant };
+use json::{ JsonValue, object };
+use crate::board_states::Board;
+
+pub const HEARTBEAT_PERIOD: Duration = Duration::from_millis(100);
+/* Heartbeats kept to be matched with late echoes. Older echoes are ignored */
+const REMEMBERED_HEARTBEATS: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BoardEcho {
+    pub board: Board,
+    pub counter: u16,
+    pub round_trip: Duration
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayHeartbeat {
+    started_at: Instant,
+    counter: u16, // Counter of the last heartbeat sent
+    last_sent: Option<Instant>,
+    sent: VecDeque<(u16, Instant)>,
+    echoes: Vec<BoardEcho> // Latest echo from each board
+}
+
+impl RelayHeartbeat {
+    /**
+     * @param started_at the uptime sent in the heartbeats is measured from this instant
+     */
+    pub fn new(started_at: Instant) -> RelayHeartbeat {
+        RelayHeartbeat {
+            started_at,
+            counter: 0,
+            last_sent: None,
+            sent: VecDeque::with_capacity(REMEMBERED_HEARTBEATS),
+            echoes: Vec::new()
+        }
+    }
+
+    pub fn is_due(&self, now: &Instant) -> bool {
+        match self.last_sent {
+            Some(last_sent) => now.saturating_duration_since(last_sent) >= HEARTBEAT_PERIOD,
+            None => true
+        }
+    }
+
+    /**
+     * @brief Record a heartbeat sent at now
+     * @return the counter and the uptime in milliseconds to send. The counter wraps around
+     */
+    pub fn next(&mut self, now: &Instant) -> (u16, u32) {
+        self.counter = self.counter.wrapping_add(1);
+        self.last_sent = Some(*now);
+        if self.sent.len() == REMEMBERED_HEARTBEATS {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((self.counter, *now));
+        (self.counter, now.saturating_duration_since(self.started_at).as_millis() as u32)
+    }
+
+    /**
+     * @brief Match an echo with the heartbeat it answers
+     * @return the round trip, or None if the counter was never sent or is too old to be remembered
+     */
+    pub fn record_echo(&mut self, board: Board, counter: u16, now: &Instant) -> Option<Duration> {
+        let sent_at = self.sent.iter().find(|(sent, _)| *sent == counter).map(|(_, sent_at)| *sent_at)?;
+        let echo = BoardEcho { board, counter, round_trip: now.saturating_duration_since(sent_at) };
+        match self.echoes.iter_mut().find(|echo| echo.board == board) {
+            Some(latest) => *latest = echo,
+            None => self.echoes.push(echo)
+        }
+        Some(echo.round_trip)
+    }
+
+    pub fn echo(&self, board: Board) -> Option<&BoardEcho> {
+        self.echoes.iter().find(|echo| echo.board == board)
+    }
+
+    /**
+     * @brief Heartbeats sent since the latest one echoed by board
+     */
+    pub fn echoes_behind(&self, board: Board) -> Option<u16> {
+        self.echo(board).map(|echo| self.counter.wrapping_sub(echo.counter))
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let echoes: Vec<JsonValue> = self.echoes.iter().map(|echo| object!{
+            board: echo.board.as_str(),
+            counter: echo.counter,
+            round_trip_ms: echo.round_trip.as_secs_f64() * 1000.0,
+            behind: self.counter.wrapping_sub(echo.counter),
+        }).collect();
+        object!{
+            counter: self.counter,
+            period_ms: HEARTBEAT_PERIOD.as_millis() as u64,
+            echoes: echoes,
+        }
+    }
+}
+
+/********************
+ *      TESTS
+ ********************/
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn heartbeat_echoes() {
+        let start = Instant::now();
+        let mut heartbeat = RelayHeartbeat::new(start);
+        assert!(heartbeat.is_due(&start));
+        assert_eq!(heartbeat.next(&start), (1, 0));
+        assert!(!heartbeat.is_due(&(start + Duration::from_millis(50))));
+
+        let second = start + HEARTBEAT_PERIOD;
+        assert!(heartbeat.is_due(&second));
+        assert_eq!(heartbeat.next(&second), (2, 100));
+
+        assert_eq!(heartbeat.record_echo(Board::Bms, 1, &(start + Duration::from_millis(3))), Some(Duration::from_millis(3)));
+        assert_eq!(heartbeat.record_echo(Board::Pressure, 2, &(second + Duration::from_millis(1))), Some(Duration::from_millis(1)));
+        assert_eq!(heartbeat.record_echo(Board::Bms, 7, &second), None);
+        assert_eq!(heartbeat.echoes_behind(Board::Bms), Some(1));
+        assert_eq!(heartbeat.echoes_behind(Board::Pressure), Some(0));
+        assert_eq!(heartbeat.echoes_behind(Board::MotorController), None);
+
+        let json = heartbeat.to_json();
+        assert_eq!(json["counter"], 2);
+        assert_eq!(json["echoes"][0]["board"], "bms");
+        assert_eq!(json["echoes"][0]["round_trip_ms"], 3.0);
+    }
+
+    #[test]
+    fn heartbeat_wraps() {
+        let start = Instant::now();
+        let mut heartbeat = RelayHeartbeat::new(start);
+        for _ in 0..u16::MAX {
+            heartbeat.next(&start);
+        }
+        assert_eq!(heartbeat.next(&start).0, 0);
+        assert_eq!(heartbeat.record_echo(Board::Bms, u16::MAX, &start), Some(Duration::from_millis(0)));
+        assert_eq!(heartbeat.echoes_behind(Board::Bms), Some(1));
+        // Only the latest heartbeats are remembered
+        assert_eq!(heartbeat.record_echo(Board::Bms, 1, &start), None);
+    }
+}
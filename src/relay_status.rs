@@ -0,0 +1,292 @@
+/**
+ * @brief The relay status is a snapshot of the state of each thread which can be
+ * queried over TCP before a run.
+ *
+ * The CAN thread keeps the pod and board states up to date, the worker thread keeps
+ * the watchdog up to date and the effective configuration is written once at startup.
+ * The flashing thread records the result of checking the boards against the firmware manifest.
+ * The CAN thread also records how quickly the roboteq answers its queries and the boards echo its heartbeat.
+ * The supervisor records the threads it had to restart.
+ */
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use chrono::NaiveDateTime;
+use json::{ JsonValue, object };
+use crate::board_states::BoardStates;
+use crate::device_watchdog::{ Device, DeviceHealth, RequiredDevices };
+use crate::pod_states::PodState;
+use crate::transition_journal::{ TransitionCause, TransitionRefusal };
+use crate::firmware_manifest::{ FirmwareCheck, FirmwareManifest };
+use crate::flash_status::FlashStatus;
+use crate::project_butterfree::flash;
+use crate::relay_heartbeat::RelayHeartbeat;
+use crate::supervisor::ThreadRestart;
+
+pub type SharedRelayStatus = Arc<Mutex<RelayStatus>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanInterfaceStatus {
+    Unavailable, // No CAN thread is running on this platform
+    Starting,
+    Up,
+    Error(String)
+}
+
+impl CanInterfaceStatus {
+    pub fn as_str(&self) -> &str {
+        match self {
+            CanInterfaceStatus::Unavailable => "unavailable",
+            CanInterfaceStatus::Starting    => "starting",
+            CanInterfaceStatus::Up          => "up",
+            CanInterfaceStatus::Error(_)    => "error"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceStatus {
+    pub device: Device,
+    pub last_message: Option<NaiveDateTime>,
+    pub health: DeviceHealth
+}
+
+/**
+ * @brief The roboteq does not report to the relay on its own, it answers the SDO queries sent by the CAN thread
+ * and may be configured to send a CANopen heartbeat
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoboteqStatus {
+    pub sdo_latency: Option<Duration>, // Between the last round of queries and the first response to it
+    pub nmt_state: Option<u8> // From the last heartbeat
+}
+
+impl RoboteqStatus {
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            sdo_latency_ms: self.sdo_latency.map(|latency| latency.as_secs_f64() * 1000.0),
+            nmt_state: self.nmt_state,
+        }
+    }
+}
+
+pub struct RelayStatus {
+    started_at: Instant,
+    pub pod_state: PodState,
+    pub requested_pod_state: PodState,
+    pub board_states: BoardStates,
+    pub can_interface: String,
+    pub can_status: CanInterfaceStatus,
+    pub devices: Vec<DeviceStatus>,
+    pub firmware: FirmwareCheck,
+    pub roboteq: RoboteqStatus,
+    pub heartbeat: RelayHeartbeat,
+    pub restarts: Vec<ThreadRestart>,
+    config: JsonValue
+}
+
+impl RelayStatus {
+    /**
+     * @param config the effective configuration of the relay. Reported as is by the CONFIG query
+     * @param firmware_manifest the boards the relay checks the bus against, if any
+     */
+    pub fn new(can_interface: String, config: JsonValue, firmware_manifest: Option<FirmwareManifest>) -> RelayStatus {
+        let started_at = Instant::now();
+        RelayStatus {
+            started_at,
+            pod_state: PodState::LowVoltage,
+            requested_pod_state: PodState::LowVoltage,
+            board_states: BoardStates::default(),
+            can_interface,
+            can_status: if cfg!(unix) { CanInterfaceStatus::Starting } else { CanInterfaceStatus::Unavailable },
+            devices: Vec::new(),
+            firmware: FirmwareCheck::new(firmware_manifest),
+            roboteq: RoboteqStatus::default(),
+            heartbeat: RelayHeartbeat::new(started_at),
+            restarts: Vec::new(),
+            config
+        }
+    }
+
+    pub fn shared(self) -> SharedRelayStatus {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn config(&self) -> &JsonValue {
+        &self.config
+    }
+
+    /**
+     * @brief The devices of required which are not healthy or late. Devices which have not been heard from yet,
+     * are lost or have not been back for long enough after being lost are missing
+     */
+    pub fn missing_devices(&self, required: &[Device]) -> Vec<Device> {
+        required.iter().copied().filter(|device| {
+            !self.devices.iter().any(|status| status.device == *device && status.health.is_available())
+        }).collect()
+    }
+
+    /**
+     * @brief Why the desktop may not move the pod to new_state, if it may not. Arming is refused while the firmware
+     * manifest blocks it, and the pod stays in the states which allow flashing while a board is being written.
+     * Transitions requested by the relay itself (braking timer, recovery, shutdown, watchdog, supervisor) take the
+     * pod towards a safe state and are never refused, whichever devices are missing
+     */
+    pub fn transition_refusal(&self, new_state: PodState, cause: TransitionCause, required_devices: &RequiredDevices, flash_status: &FlashStatus) -> Option<TransitionRefusal> {
+        if cause != TransitionCause::Desktop || new_state.is_error_state() {
+            return None;
+        }
+        if flash_status.state.is_writing() && !flash::pod_state_allows_flashing(new_state) {
+            return Some(TransitionRefusal::Flashing(flash_status.short_device_id.unwrap_or_default()));
+        }
+        if new_state == PodState::Armed && self.firmware.blocks_arming() {
+            return Some(TransitionRefusal::FirmwareMismatch(self.firmware.mismatched_devices()));
+        }
+        let missing = required_devices.get(&new_state).map(|required| self.missing_devices(required)).unwrap_or_default();
+        if !missing.is_empty() {
+            return Some(TransitionRefusal::MissingDevices(missing));
+        }
+        None
+    }
+
+    /**
+     * @param connection the state of the controlling desktop session as seen by the thread answering the query
+     */
+    pub fn to_json(&self, connection: &str) -> JsonValue {
+        let devices: Vec<JsonValue> = self.devices.iter().map(|status| object!{
+            device: format!("{:?}", status.device),
+            last_message: status.last_message.map(|timestamp| timestamp.timestamp_millis()),
+            state: status.health.as_str(),
+            functioning: status.health != DeviceHealth::Lost,
+        }).collect();
+        let restarts: Vec<JsonValue> = self.restarts.iter().map(ThreadRestart::to_json).collect();
+        let mut can = object!{
+            interface: self.can_interface.as_str(),
+            status: self.can_status.as_str(),
+        };
+        if let CanInterfaceStatus::Error(error) = &self.can_status {
+            can["error"] = error.as_str().into();
+        }
+        object!{
+            uptime_ms: self.started_at.elapsed().as_millis() as u64,
+            connection: connection,
+            pod_state: self.pod_state.to_byte(),
+            requested_pod_state: self.requested_pod_state.to_byte(),
+            boards: object!{
+                bms: self.board_states.get_bms_state().to_byte(),
+                motor_controller: self.board_states.get_motor_controller_state().to_byte(),
+                pressure: self.board_states.get_pressure_state().to_byte(),
+            },
+            can: can,
+            watchdog: devices,
+            firmware: self.firmware.to_json(),
+            roboteq: self.roboteq.to_json(),
+            heartbeat: self.heartbeat.to_json(),
+            restarts: restarts,
+        }
+    }
+}
+
+/********************
+ *      TESTS
+ ********************/
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::supervisor::SupervisedThread;
+
+    #[test]
+    fn status_json() {
+        let mut status = RelayStatus::new(String::from("can0"), object!{ buffer_size: 256 }, None);
+        status.pod_state = PodState::Armed;
+        status.can_status = CanInterfaceStatus::Error(String::from("No such device"));
+        status.devices.push(DeviceStatus { device: Device::BMS, last_message: None, health: DeviceHealth::NeverSeen });
+        status.roboteq.sdo_latency = Some(Duration::from_micros(2500));
+        status.restarts.push(ThreadRestart { thread: SupervisedThread::Udp, count: 2, last_restart: crate::device_watchdog::get_now() });
+
+        let json = status.to_json("connected");
+        assert_eq!(json["connection"], "connected");
+        assert_eq!(json["pod_state"], PodState::Armed.to_byte());
+        assert_eq!(json["boards"]["bms"], PodState::LowVoltage.to_byte());
+        assert_eq!(json["can"]["status"], "error");
+        assert_eq!(json["can"]["error"], "No such device");
+        assert_eq!(json["watchdog"][0]["device"], "BMS");
+        assert!(json["watchdog"][0]["last_message"].is_null());
+        assert_eq!(json["watchdog"][0]["state"], "never_seen");
+        assert_eq!(json["firmware"]["manifest"], false);
+        assert_eq!(json["roboteq"]["sdo_latency_ms"], 2.5);
+        assert!(json["roboteq"]["nmt_state"].is_null());
+        assert_eq!(json["heartbeat"]["counter"], 0);
+        assert_eq!(json["restarts"][0]["thread"], "udp");
+        assert_eq!(json["restarts"][0]["count"], 2);
+        assert_eq!(status.config()["buffer_size"], 256);
+    }
+
+    #[test]
+    fn missing_devices() {
+        let mut status = RelayStatus::new(String::from("can0"), object!{}, None);
+        assert_eq!(status.missing_devices(&[Device::BMS]), vec![Device::BMS]);
+
+        let now = crate::device_watchdog::get_now();
+        status.devices.push(DeviceStatus { device: Device::BMS, last_message: Some(now), health: DeviceHealth::Late });
+        status.devices.push(DeviceStatus { device: Device::MC, last_message: Some(now), health: DeviceHealth::Lost });
+        status.devices.push(DeviceStatus { device: Device::ELEKID, last_message: None, health: DeviceHealth::NeverSeen });
+        status.devices.push(DeviceStatus { device: Device::TORCHIC_1, last_message: Some(now), health: DeviceHealth::Recovered });
+        assert_eq!(status.missing_devices(&[Device::BMS, Device::MC, Device::ELEKID, Device::TORCHIC_1]), vec![Device::MC, Device::ELEKID, Device::TORCHIC_1]);
+    }
+
+    #[test]
+    fn transition_refusal() {
+        let mut required_devices = RequiredDevices::new();
+        required_devices.insert(PodState::Armed, vec![Device::BMS]);
+        required_devices.insert(PodState::Braking, vec![Device::BMS]);
+        required_devices.insert(PodState::LowVoltage, vec![Device::BMS]);
+        let flash_status = FlashStatus::new(true);
+        let mut status = RelayStatus::new(String::from("can0"), object!{}, None);
+        status.devices.push(DeviceStatus { device: Device::BMS, last_message: None, health: DeviceHealth::Lost });
+        assert_eq!(
+            status.transition_refusal(PodState::Armed, TransitionCause::Desktop, &required_devices, &flash_status),
+            Some(TransitionRefusal::MissingDevices(vec![Device::BMS]))
+        );
+        assert_eq!(status.transition_refusal(PodState::AutoPilot, TransitionCause::Desktop, &required_devices, &flash_status), None);
+        assert_eq!(status.transition_refusal(PodState::SystemFailure, TransitionCause::Desktop, &required_devices, &flash_status), None);
+
+        // The relay's own transitions towards a safe state go ahead without the devices
+        assert_eq!(status.transition_refusal(PodState::Braking, TransitionCause::BrakingTimer, &required_devices, &flash_status), None);
+        for cause in [TransitionCause::Recovery, TransitionCause::Shutdown, TransitionCause::Supervisor, TransitionCause::Watchdog] {
+            assert_eq!(status.transition_refusal(PodState::LowVoltage, cause, &required_devices, &flash_status), None);
+        }
+
+        status.devices[0].health = DeviceHealth::Healthy;
+        assert_eq!(status.transition_refusal(PodState::Armed, TransitionCause::Desktop, &required_devices, &flash_status), None);
+    }
+
+    #[test]
+    fn arming_blocked_by_manifest() {
+        let manifest = FirmwareManifest::from_json(r#"{ "block_arming": true, "devices": [{ "short_device_id": 4, "role": "BMS", "version": "1.0" }] }"#).unwrap();
+        let mut status = RelayStatus::new(String::from("can0"), object!{}, Some(manifest.clone()));
+        let required_devices = RequiredDevices::new();
+        let flash_status = FlashStatus::new(true);
+        // No check has completed yet
+        assert_eq!(status.transition_refusal(PodState::Armed, TransitionCause::Desktop, &required_devices, &flash_status), Some(TransitionRefusal::FirmwareMismatch(vec![])));
+
+        let scan = vec![crate::firmware_manifest::ObservedDevice { short_device_id: 4, device_id: 4, device_type: String::from("STM32L432KC"), version: (0, 9) }];
+        status.firmware.report = Some(manifest.check(&scan, |_, _| Ok(0), crate::device_watchdog::get_now()));
+        assert_eq!(status.transition_refusal(PodState::Armed, TransitionCause::Desktop, &required_devices, &flash_status), Some(TransitionRefusal::FirmwareMismatch(vec![4])));
+        assert_eq!(status.transition_refusal(PodState::LowVoltage, TransitionCause::Desktop, &required_devices, &flash_status), None);
+    }
+
+    #[test]
+    fn no_pod_states_while_flashing() {
+        let status = RelayStatus::new(String::from("can0"), object!{}, None);
+        let required_devices = RequiredDevices::new();
+        let mut flash_status = FlashStatus::new(true);
+        assert!(flash_status.begin(crate::flash_status::FlashState::Scanning));
+        assert_eq!(status.transition_refusal(PodState::Armed, TransitionCause::Desktop, &required_devices, &flash_status), None);
+
+        let mut flash_status = FlashStatus::new(true);
+        assert!(flash_status.begin_write(5, 0x0800_0000, 1024));
+        assert_eq!(status.transition_refusal(PodState::Armed, TransitionCause::Desktop, &required_devices, &flash_status), Some(TransitionRefusal::Flashing(5)));
+        assert_eq!(status.transition_refusal(PodState::Resting, TransitionCause::Desktop, &required_devices, &flash_status), None);
+        assert_eq!(status.transition_refusal(PodState::SystemFailure, TransitionCause::Desktop, &required_devices, &flash_status), None);
+    }
+}
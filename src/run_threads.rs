@@ -30,8 +30,17 @@ use crate::device_watchdog::{
 };
 
 use crate::utils::rpm_integrator::RpmIntegrator;
+use crate::transition_journal::TransitionJournal;
+use crate::relay_status::{ RelayStatus, DeviceStatus };
+use crate::flash_status::FlashStatus;
+use crate::shutdown::{ ShutdownSignal, SHUTDOWN_DEADLINE, join_before };
+use crate::supervisor::{ Supervisor, SupervisedThread };
+use crate::thread_managers::Mailbox;
+#[cfg(all(unix, feature = "flashing"))]
+use crate::flash_status::FlashState;
 
-pub fn run_threads<A: std::net::ToSocketAddrs +std::fmt::Debug + Send + 'static>(config: crate::config::Config<A>) -> Result<(), Error> {
+pub fn run_threads<A: std::net::ToSocketAddrs +std::fmt::Debug + Clone + Send + 'static>(config: crate::config::Config<A>) -> Result<(), Error> {
+    let shutdown = ShutdownSignal::install().expect("Unable to handle SIGINT and SIGTERM");
     let (udp_message_sender, udp_message_receiver): (Sender<UDPMessage>, Receiver<UDPMessage>) = channel();
     #[allow(unused_variables)] // can_message_receiver is only used in unix, but needs to exist so that other parts of the code can send messages without crashing
     let (can_message_sender, can_message_receiver): (Sender<CANMessage>, Receiver<CANMessage>) = channel();
@@ -42,58 +51,144 @@ pub fn run_threads<A: std::net::ToSocketAddrs +std::fmt::Debug + Send + 'static>
     // Configuration Values
     let tcp_message_buffer_size = 128;
     // TODO - Figure out what these value should be
-    let udp_socket_read_timeout = Duration::from_millis(500); // Amount of time the UDP Socket will wait for a message from the Controller
-    let udp_max_number_timeouts = 10;
+    let udp_controller_timeout = Duration::from_millis(5000); // Amount of time without a message from the Controller before it is considered lost
     // End Configuration Values
 
     // CAN Configuration
     #[cfg(unix)]
-    let can_socket_read_timeout = Duration::from_millis(10000); // Amount of time the CAN Socket will wait for a message from the rest of the POD
+    // Amount of time the CAN Socket will wait for a message from the rest of the POD. Kept short so that watchdog notifications
+    // are handled and the pod state keeps being sent while the bus is silent
+    let can_socket_read_timeout = Duration::from_millis(100);
     // End CAN Configuration
 
+    let transition_journal = TransitionJournal::with_log_file("Transitions.txt").shared();
+    let mut config_json = config.to_json();
+    config_json["tcp_message_buffer_size"] = tcp_message_buffer_size.into();
+    config_json["udp_controller_timeout_ms"] = (udp_controller_timeout.as_millis() as u64).into();
+    let relay_status = RelayStatus::new(config.can_interface.clone(), config_json, config.firmware_manifest.clone()).shared();
+
+    // Flashing is only available when the relay is built with the flashing feature and has a CAN bus
+    let flash_status = FlashStatus::new(cfg!(all(unix, feature = "flashing"))).shared();
+    #[cfg(all(unix, feature = "flashing"))]
+    let (flash_message_sender, flash_handle) = {
+        let (flash_message_sender, flash_message_receiver) = channel::<thread_managers::messages::FlashMessage>();
+        let flash_handle = thread_managers::FlashManager::run(
+            thread_managers::FlashWorkerInitializer {
+                can_interface: config.can_interface.clone(),
+                flash_message_receiver,
+                flash_status: flash_status.clone(),
+                relay_status: relay_status.clone()
+            }
+        );
+        if config.firmware_manifest.is_some() {
+            // Checked once at startup, then again on FLASH CHECK
+            flash_status.lock().expect("Flash status lock poisoned").begin(FlashState::Scanning);
+            flash_message_sender.send(thread_managers::messages::FlashMessage::CheckFirmware).expect("Should be able to send Message to Flash Thread");
+        }
+        (Some(flash_message_sender), Some(flash_handle))
+    };
+    #[cfg(not(all(unix, feature = "flashing")))]
+    let (flash_message_sender, flash_handle): (_, Option<std::thread::JoinHandle<()>>) = {
+        if config.firmware_manifest.is_some() {
+            println!("Unable to check the firmware manifest, the relay was built without flashing support");
+            relay_status.lock().expect("Relay status lock poisoned").firmware.error = Some(String::from("Flashing unavailable"));
+        }
+        (None, None)
+    };
+
     // Thread Handles
-    let tcp_handle = thread_managers::TcpManager::run(
-        config.tcp_address,
-        udp_message_sender.clone(),
-        tcp_receiver,
-        tcp_message_buffer_size
-    );
-    let udp_handle = thread_managers::UdpManager::run(
-        can_message_sender.clone(),
-        tcp_sender.clone(),
-        udp_message_receiver,
-        udp_max_number_timeouts,
-        udp_socket_read_timeout,
-        config.udp_address
-    );
+    // Each worker is spawned by the supervisor, which spawns it again from the same values if it dies
+    let mut supervisor = Supervisor::new(tcp_sender.clone(), udp_message_sender.clone(), can_message_sender.clone(), relay_status.clone());
+    {
+        let udp_message_sender = udp_message_sender.clone();
+        let tcp_mailbox = Mailbox::new(tcp_receiver);
+        let transition_journal = transition_journal.clone();
+        let relay_status = relay_status.clone();
+        let flash_status = flash_status.clone();
+        let auth_key = config.auth_key.clone();
+        let address = config.tcp_address;
+        supervisor.supervise(SupervisedThread::Tcp, move || thread_managers::TcpManager::run(
+            thread_managers::TcpWorkerInitializer {
+                address: address.clone(),
+                udp_message_sender: udp_message_sender.clone(),
+                tcp_message_receiver: tcp_mailbox.clone(),
+                tcp_message_buffer_size,
+                transition_journal: transition_journal.clone(),
+                relay_status: relay_status.clone(),
+                flash_message_sender: flash_message_sender.clone(),
+                flash_status: flash_status.clone(),
+                auth_key: auth_key.clone()
+            }
+        ));
+    }
+    {
+        let can_message_sender = can_message_sender.clone();
+        let tcp_sender = tcp_sender.clone();
+        let udp_mailbox = Mailbox::new(udp_message_receiver);
+        let relay_status = relay_status.clone();
+        let telemetry_period = Duration::from_secs(1) / config.telemetry_rate_hz;
+        let udp_max_malformed_packets = config.max_malformed_packets;
+        let address = config.udp_address;
+        let auth_key = config.auth_key;
+        supervisor.supervise(SupervisedThread::Udp, move || {
+            let pod_state = relay_status.lock().expect("Relay status lock poisoned").pod_state;
+            thread_managers::UdpManager::run(
+                thread_managers::UdpWorkerInitializer {
+                    can_sender: can_message_sender.clone(),
+                    tcp_sender: tcp_sender.clone(),
+                    udp_receiver: udp_mailbox.clone(),
+                    pod_state,
+                    // The supervisor commands the safe state when it restarts this thread
+                    next_pod_state: pod_state.shutdown_state(),
+                    controller_timeout: udp_controller_timeout,
+                    telemetry_period,
+                    udp_max_malformed_packets,
+                    udp_address: address.clone(),
+                    auth_key: auth_key.clone()
+                }
+            )
+        });
+    }
 
     #[cfg(unix)]
-    let can_handle = thread_managers::CanManager::run(
-        thread_managers::CanWorkerInitializer {
-            can_interface: config.can_interface,
-            worker_message_sender,
-            can_message_receiver,
-            can_socket_read_timeout,
-            udp_message_sender: udp_message_sender.clone(),
-        }
-    );
+    {
+        let can_interface = config.can_interface;
+        let can_mailbox = Mailbox::new(can_message_receiver);
+        let udp_message_sender = udp_message_sender.clone();
+        let relay_status = relay_status.clone();
+        let required_devices = config.watchdog.required_devices.clone();
+        let flash_status = flash_status.clone();
+        supervisor.supervise(SupervisedThread::Can, move || thread_managers::CanManager::run(
+            thread_managers::CanWorkerInitializer {
+                can_interface: can_interface.clone(),
+                worker_message_sender: worker_message_sender.clone(),
+                can_message_receiver: can_mailbox.clone(),
+                can_socket_read_timeout,
+                udp_message_sender: udp_message_sender.clone(),
+                transition_journal: transition_journal.clone(),
+                relay_status: relay_status.clone(),
+                flash_status: flash_status.clone(),
+                required_devices: required_devices.clone(),
+            }
+        ));
+    }
 
     udp_message_sender.send(UDPMessage::StartupComplete).expect("To be able to complete startup");
 
     let (send_data_to_logger, data_logger_receiver) = channel::<PodData>();
-    std::thread::spawn(move || {
+    let logger_handle = std::thread::Builder::new().name("Logger Thread".to_string()).spawn(move || {
         let mut out_file = OpenOptions::new()
             .write(true)
             .append(true)
             .open("Logs.txt")
             .unwrap();
-        loop {
-            if let Ok(data) = data_logger_receiver.recv() {
-                let jv: JsonValue = data.into();
-                out_file.write(&jv.dump().as_bytes()).unwrap();
-            }
+        // Runs until the sender is dropped at shutdown, once all of the queued telemetry has been written
+        while let Ok(data) = data_logger_receiver.recv() {
+            let jv: JsonValue = data.into();
+            out_file.write_all(jv.dump().as_bytes()).unwrap();
         }
-    });
+        out_file.sync_all().expect("Unable to flush the telemetry log");
+    }).expect("Should be able to create Thread");
 
 
     // Worker Thread
@@ -101,10 +196,33 @@ pub fn run_threads<A: std::net::ToSocketAddrs +std::fmt::Debug + Send + 'static>
     #[cfg(unix)]
     {
         let mut pod_data = crate::pod_data::PodData::new();
-        let mut watchdog = crate::device_watchdog::DeviceWatchdogMap::with_all_devices(can_message_sender.clone(), CANMessage::DeviceLost, 400);
+        let mut watchdog = crate::device_watchdog::DeviceWatchdogMap::with_config(can_message_sender.clone(), CANMessage::DeviceLost, &config.watchdog);
         let mut rpm_integrator = RpmIntegrator::default();
-        loop {
-            match worker_message_receiver.recv() {
+        // The watchdog runs on its own tick, so that a silent bus is noticed without waiting for a frame
+        let watchdog_tick = config.watchdog.tick_interval();
+        let mut next_watchdog_tick = std::time::Instant::now();
+        while !shutdown.is_requested() {
+            let now = std::time::Instant::now();
+            if now >= next_watchdog_tick {
+                supervisor.check();
+                let transitions = watchdog.update_devices(&now);
+                for transition in &transitions {
+                    println!("WATCHDOG: {:?} {} -> {}", transition.device, transition.from.as_str(), transition.to.as_str());
+                }
+                if !transitions.is_empty() {
+                    udp_message_sender.send(UDPMessage::DeviceStates(watchdog.snapshot())).expect("To be able to send device states to udp from worker");
+                }
+                relay_status.lock().expect("Relay status lock poisoned").devices = watchdog.iter().map(|(device, device_watchdog)| DeviceStatus {
+                    device: *device,
+                    last_message: device_watchdog.last_message(),
+                    health: device_watchdog.health()
+                }).collect();
+                // Ticks stay on a fixed grid, skipping any that were missed
+                while next_watchdog_tick <= now {
+                    next_watchdog_tick += watchdog_tick;
+                }
+            }
+            match worker_message_receiver.recv_timeout(next_watchdog_tick.saturating_duration_since(std::time::Instant::now())) {
                 Ok(message) => {
                     match message {
                         WorkerMessage::CanFrameAndTimeStamp(frame, time) => {
@@ -195,6 +313,7 @@ pub fn run_threads<A: std::net::ToSocketAddrs +std::fmt::Debug + Send + 'static>
                                     } else {
                                         new_data = false;
                                     }
+                                    watchdog.update_device_timestamp(Device::ROBOTEQ, crate::device_watchdog::get_now());
                                 },
                                 CanCommand::RoboteqMotorEncoderResult{ motor_number, speed } => {
                                     match motor_number {
@@ -202,6 +321,7 @@ pub fn run_threads<A: std::net::ToSocketAddrs +std::fmt::Debug + Send + 'static>
                                         2 => { pod_data.roboteq_motor_2_speed = Some(RpmIntegrator::calc_speed(speed));},
                                         _ => { new_data = false; }
                                     }
+                                    watchdog.update_device_timestamp(Device::ROBOTEQ, crate::device_watchdog::get_now());
                                 },
                                 CanCommand::RoboteqTemperatureResult{ sub_index, temp } => {
                                     match sub_index {
@@ -210,15 +330,19 @@ pub fn run_threads<A: std::net::ToSocketAddrs +std::fmt::Debug + Send + 'static>
                                         3 => { pod_data.roboteq_sensor_2_temp = Some(temp); },
                                         _ => { new_data = false; },
                                     }
+                                    watchdog.update_device_timestamp(Device::ROBOTEQ, crate::device_watchdog::get_now());
+                                },
+                                CanCommand::RoboteqHeartbeat{ nmt_state } => {
+                                    // A stopped node still sends its heartbeat but no longer drives the motors
+                                    if nmt_state != NMT_STOPPED {
+                                        watchdog.update_device_timestamp(Device::ROBOTEQ, crate::device_watchdog::get_now());
+                                    }
+                                    new_data = false;
                                 }
                                 _ => {
                                     new_data = false;
                                 }
                             }
-                            let devices = watchdog.check_devices();
-                            for device in &devices {
-                                println!("DEBUG: WATCHDOG DETECTED DEVICE LOST: {:?}", device);
-                            }
                             if new_data {
                                 // println!("NEW DATA Parsed: {:?}", pod_data);
                                 if pod_data.ok() {
@@ -231,19 +355,66 @@ pub fn run_threads<A: std::net::ToSocketAddrs +std::fmt::Debug + Send + 'static>
                         }
                     }
                 },
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}, // Time for the next watchdog tick
                 Err(err) => {
+                    // The supervisor keeps a sender to restart the CAN thread, so this is never expected
                     println!("Worker Receiver Error: {:?}", err);
-                    println!("Exiting");
-                    return Ok(());
+                    break;
                 }
             }
         }
     }
+    #[cfg(not(unix))]
+    while !shutdown.is_requested() {
+        supervisor.check();
+        std::thread::sleep(Duration::from_millis(100));
+    }
 
-    udp_handle.join().expect("Should be able to join at the end of the Program");
-    tcp_handle.join().expect("Should be able to join at the end of the Program");
+    // Shutdown, in the order described in crate::shutdown
+    println!("SHUTDOWN: Stopping the relay");
+    let deadline = std::time::Instant::now() + SHUTDOWN_DEADLINE;
+    // Threads are no longer restarted from here on
+    let tcp_handle = supervisor.take_handle(SupervisedThread::Tcp);
+    let udp_handle = supervisor.take_handle(SupervisedThread::Udp);
+    #[cfg(unix)]
+    let can_handle = supervisor.take_handle(SupervisedThread::Can);
     #[cfg(unix)]
-    can_handle.join().expect("Should be able to join at the end of the Program");
+    let final_pod_state = {
+        let (reply_sender, reply_receiver) = channel();
+        // Nothing would answer once the supervisor has given up on the CAN thread
+        let reply = can_handle.as_ref().and_then(|_| can_message_sender.send(CANMessage::Shutdown(reply_sender)).ok())
+            .and_then(|_| reply_receiver.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now())).ok());
+        reply.unwrap_or_else(|| {
+            println!("SHUTDOWN: The CAN thread did not confirm the pod state");
+            relay_status.lock().expect("Relay status lock poisoned").pod_state
+        })
+    };
+    #[cfg(not(unix))]
+    let final_pod_state = relay_status.lock().expect("Relay status lock poisoned").pod_state;
+
+    let _ = tcp_sender.send(TcpMessage::Shutdown);
+    if let Some(tcp_handle) = tcp_handle {
+        join_before(tcp_handle, deadline);
+    }
+    let _ = udp_message_sender.send(UDPMessage::Shutdown(final_pod_state));
+    if let Some(udp_handle) = udp_handle {
+        join_before(udp_handle, deadline);
+    }
+    #[cfg(unix)]
+    {
+        let _ = can_message_sender.send(CANMessage::Stop);
+        if let Some(can_handle) = can_handle {
+            join_before(can_handle, deadline);
+        }
+    }
+    drop(send_data_to_logger);
+    join_before(logger_handle, deadline);
+    // Drops the senders the supervisor kept to restart the threads
+    drop(supervisor);
+    if let Some(flash_handle) = flash_handle {
+        // Stops once the TCP thread and the supervisor have dropped their senders and the current job is done
+        join_before(flash_handle, deadline);
+    }
 
     Ok(())
 }
@@ -0,0 +1,120 @@
+/**
+ * @brief Shutting the relay down on SIGINT and SIGTERM.
+ *
+ * The signal handler only records the request. The main thread notices it and shuts the
+ * threads down in an order which keeps the pod safe and keeps every channel open for as
+ * long as another thread may still send on it:
+ *  1. The CAN thread commands a safe pod state and waits for the boards to acknowledge it
+ *  2. The TCP thread stops accepting requests
+ *  3. The UDP thread tells the desktop the relay is going away
+ *  4. The CAN thread stops
+ *  5. The telemetry log is flushed
+ *
+ * Every thread has to be joined before SHUTDOWN_DEADLINE, threads which are still running
+ * after it are left behind when the process exits.
+ */
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread::JoinHandle;
+use std::time::{ Duration, Instant };
+
+/* Time from the signal until every thread has to be joined */
+pub const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(3);
+/* Time the boards have to acknowledge the safe pod state */
+pub const SAFE_STATE_TIMEOUT: Duration = Duration::from_secs(1);
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Default)]
+pub struct ShutdownSignal {
+    requested: Arc<AtomicBool>
+}
+
+impl ShutdownSignal {
+    pub fn new() -> ShutdownSignal {
+        ShutdownSignal::default()
+    }
+
+    /**
+     * @brief A signal which is requested on SIGINT and SIGTERM. Only one can be installed per process
+     */
+    pub fn install() -> Result<ShutdownSignal, ctrlc::Error> {
+        let signal = ShutdownSignal::new();
+        let handler_signal = signal.clone();
+        ctrlc::set_handler(move || {
+            if handler_signal.is_requested() {
+                println!("Shutdown already in progress");
+            }
+            handler_signal.request();
+        })?;
+        Ok(signal)
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JoinOutcome {
+    Finished,
+    Panicked,
+    TimedOut // The thread is detached and keeps running
+}
+
+/**
+ * @brief Join the thread if it finishes before the deadline
+ */
+pub fn join_before(handle: JoinHandle<()>, deadline: Instant) -> JoinOutcome {
+    let name = handle.thread().name().unwrap_or("Unnamed Thread").to_string();
+    while !handle.is_finished() {
+        let now = Instant::now();
+        if now >= deadline {
+            println!("SHUTDOWN: {} did not stop in time", name);
+            return JoinOutcome::TimedOut;
+        }
+        std::thread::sleep(JOIN_POLL_INTERVAL.min(deadline - now));
+    }
+    match handle.join() {
+        Ok(()) => {
+            println!("SHUTDOWN: {} stopped", name);
+            JoinOutcome::Finished
+        },
+        Err(_) => {
+            println!("SHUTDOWN: {} panicked", name);
+            JoinOutcome::Panicked
+        }
+    }
+}
+
+/********************
+ *      TESTS
+ ********************/
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shutdown_signal() {
+        let signal = ShutdownSignal::new();
+        let thread_signal = signal.clone();
+        assert!(!thread_signal.is_requested());
+        signal.request();
+        assert!(thread_signal.is_requested());
+    }
+
+    #[test]
+    fn join_before_deadline() {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert_eq!(join_before(std::thread::spawn(|| {}), deadline), JoinOutcome::Finished);
+        assert_eq!(join_before(std::thread::spawn(|| panic!("Expected by the test")), deadline), JoinOutcome::Panicked);
+
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        let blocked = std::thread::spawn(move || { let _ = receiver.recv(); });
+        assert_eq!(join_before(blocked, Instant::now() + Duration::from_millis(30)), JoinOutcome::TimedOut);
+        drop(sender);
+    }
+}
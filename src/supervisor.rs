@@ -0,0 +1,272 @@
+/**
+ * @brief Restarting the TCP, UDP and CAN threads when they die.
+ *
+ * The workers panic on many unexpected conditions. Instead of leaving the other threads to run
+ * with a dead peer, the main thread checks on each worker every watchdog tick and replaces the
+ * ones which stopped with a fresh worker. Each worker reads its channel through a Mailbox, so the
+ * messages queued for it are kept and the threads sending to it never see the channel close.
+ *
+ * Restarting a worker is not always safe on its own:
+ *  - TCP: restarted as is, the desktop reconnects to it
+ *  - UDP: the desktop session is lost, so the pod is commanded to the safe state it would be
+ *    left in at shutdown and the TCP thread drops its session so the desktop can connect again.
+ *    The new worker starts out waiting for that state to be acknowledged
+ *  - CAN: the state of the boards is lost, so the pod is commanded to SystemFailure
+ *
+ * A thread which dies more than MAX_RESTARTS times is left dead. Every restart is reported in the
+ * relay status and in the pod state messages sent to the desktop.
+ */
+use std::thread::JoinHandle;
+use std::sync::mpsc::Sender;
+use chrono::NaiveDateTime;
+use json::{ JsonValue, object };
+use crate::pod_states::PodState;
+use crate::relay_status::SharedRelayStatus;
+use crate::transition_journal::TransitionCause;
+use crate::thread_managers::messages::{ TcpMessage, UDPMessage, CanMessage };
+
+pub const MAX_RESTARTS: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SupervisedThread {
+    Tcp,
+    Udp,
+    Can
+}
+
+impl SupervisedThread {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SupervisedThread::Tcp => "tcp",
+            SupervisedThread::Udp => "udp",
+            SupervisedThread::Can => "can"
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            SupervisedThread::Tcp => 0x1,
+            SupervisedThread::Udp => 0x2,
+            SupervisedThread::Can => 0x3
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThreadRestart {
+    pub thread: SupervisedThread,
+    pub count: u32,
+    pub last_restart: NaiveDateTime
+}
+
+impl ThreadRestart {
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            thread: self.thread.as_str(),
+            count: self.count,
+            last_restart: self.last_restart.timestamp_millis(),
+        }
+    }
+}
+
+struct SupervisedHandle {
+    thread: SupervisedThread,
+    handle: Option<JoinHandle<()>>, // None once the thread has died too often to be restarted
+    spawn: Box<dyn FnMut() -> JoinHandle<()>>,
+    restart: Option<ThreadRestart>
+}
+
+pub struct Supervisor {
+    threads: Vec<SupervisedHandle>,
+    tcp_sender: Sender<TcpMessage>,
+    udp_sender: Sender<UDPMessage>,
+    can_sender: Sender<CanMessage>,
+    relay_status: SharedRelayStatus
+}
+
+impl Supervisor {
+    pub fn new(tcp_sender: Sender<TcpMessage>, udp_sender: Sender<UDPMessage>, can_sender: Sender<CanMessage>, relay_status: SharedRelayStatus) -> Supervisor {
+        Supervisor {
+            threads: Vec::new(),
+            tcp_sender,
+            udp_sender,
+            can_sender,
+            relay_status
+        }
+    }
+
+    /**
+     * @brief Start the thread with spawn, which is called again each time the thread has to be restarted
+     */
+    pub fn supervise<F>(&mut self, thread: SupervisedThread, mut spawn: F)
+    where F: FnMut() -> JoinHandle<()> + 'static {
+        let handle = spawn();
+        self.threads.push(SupervisedHandle { thread, handle: Some(handle), spawn: Box::new(spawn), restart: None });
+    }
+
+    /**
+     * @brief Restart the threads which died since the last check
+     * @return the threads which were restarted
+     */
+    pub fn check(&mut self) -> Vec<SupervisedThread> {
+        let Supervisor { threads, tcp_sender, udp_sender, can_sender, relay_status } = self;
+        let mut restarted = Vec::new();
+        for supervised in threads.iter_mut() {
+            let handle = match supervised.handle.take() {
+                Some(handle) if handle.is_finished() => handle,
+                handle => {
+                    supervised.handle = handle;
+                    continue;
+                }
+            };
+            let thread = supervised.thread;
+            // Workers only return once they are told to stop, which never happens while they are supervised
+            let how = if handle.join().is_err() { "panicked" } else { "stopped" };
+            let count = supervised.restart.map_or(0, |restart| restart.count);
+            if count >= MAX_RESTARTS {
+                println!("SUPERVISOR: The {} thread {}, it was already restarted {} times and is left stopped", thread.as_str(), how, count);
+                continue;
+            }
+            println!("SUPERVISOR: The {} thread {}, restarting it", thread.as_str(), how);
+            // Senders are only dropped at shutdown, once the supervisor is no longer checked
+            match thread {
+                SupervisedThread::Tcp => {},
+                SupervisedThread::Udp => {
+                    let pod_state = relay_status.lock().expect("Relay status lock poisoned").pod_state;
+                    if pod_state.shutdown_state() != pod_state {
+                        let _ = can_sender.send(CanMessage::ChangeState(pod_state.shutdown_state(), TransitionCause::Supervisor));
+                    }
+                },
+                SupervisedThread::Can => {
+                    // Queued before the new worker starts, so that it is the first state it commands
+                    let _ = can_sender.send(CanMessage::ChangeState(PodState::SystemFailure, TransitionCause::Supervisor));
+                }
+            }
+            supervised.handle = Some((supervised.spawn)());
+            supervised.restart = Some(ThreadRestart { thread, count: count + 1, last_restart: chrono::Utc::now().naive_local() });
+            match thread {
+                SupervisedThread::Tcp => {},
+                SupervisedThread::Udp => {
+                    let _ = udp_sender.send(UDPMessage::StartupComplete);
+                    let _ = tcp_sender.send(TcpMessage::UdpRestarted);
+                },
+                SupervisedThread::Can => {
+                    let _ = udp_sender.send(UDPMessage::SystemFault);
+                }
+            }
+            restarted.push(thread);
+        }
+        if !restarted.is_empty() {
+            let restarts: Vec<ThreadRestart> = threads.iter().filter_map(|supervised| supervised.restart).collect();
+            relay_status.lock().expect("Relay status lock poisoned").restarts = restarts.clone();
+            let _ = udp_sender.send(UDPMessage::ThreadRestarts(restarts));
+        }
+        restarted
+    }
+
+    pub fn restarts(&self) -> Vec<ThreadRestart> {
+        self.threads.iter().filter_map(|supervised| supervised.restart).collect()
+    }
+
+    /**
+     * @brief Stop supervising the thread, so that it can be joined at shutdown
+     */
+    pub fn take_handle(&mut self, thread: SupervisedThread) -> Option<JoinHandle<()>> {
+        self.threads.iter_mut().find(|supervised| supervised.thread == thread)?.handle.take()
+    }
+}
+
+/********************
+ *      TESTS
+ ********************/
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::{ channel, Receiver };
+    use std::time::{ Duration, Instant };
+    use crate::relay_status::RelayStatus;
+
+    struct Channels {
+        tcp: Receiver<TcpMessage>,
+        udp: Receiver<UDPMessage>,
+        can: Receiver<CanMessage>,
+        relay_status: SharedRelayStatus
+    }
+
+    fn supervisor() -> (Supervisor, Channels) {
+        let (tcp_sender, tcp) = channel();
+        let (udp_sender, udp) = channel();
+        let (can_sender, can) = channel();
+        let relay_status = RelayStatus::new(String::from("can0"), object!{}, None).shared();
+        (Supervisor::new(tcp_sender, udp_sender, can_sender, relay_status.clone()), Channels { tcp, udp, can, relay_status })
+    }
+
+    fn check_until_restarted(supervisor: &mut Supervisor) -> Vec<SupervisedThread> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let restarted = supervisor.check();
+            if !restarted.is_empty() || Instant::now() >= deadline {
+                return restarted;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn restart_can_thread() {
+        let (mut supervisor, channels) = supervisor();
+        let mut spawned = 0;
+        supervisor.supervise(SupervisedThread::Can, move || {
+            spawned += 1;
+            let first = spawned == 1;
+            std::thread::spawn(move || if first { panic!("Expected by the test") } else { std::thread::park() })
+        });
+        assert_eq!(check_until_restarted(&mut supervisor), vec![SupervisedThread::Can]);
+        assert!(supervisor.check().is_empty());
+
+        match channels.can.try_recv() {
+            Ok(CanMessage::ChangeState(PodState::SystemFailure, TransitionCause::Supervisor)) => {},
+            _ => panic!("The pod should be commanded to SystemFailure")
+        }
+        assert!(matches!(channels.udp.try_recv(), Ok(UDPMessage::SystemFault)));
+        match channels.udp.try_recv() {
+            Ok(UDPMessage::ThreadRestarts(restarts)) => {
+                assert_eq!(restarts.len(), 1);
+                assert_eq!(restarts[0].thread, SupervisedThread::Can);
+                assert_eq!(restarts[0].count, 1);
+            },
+            _ => panic!("The restart should be reported to the UDP thread")
+        }
+        assert_eq!(channels.relay_status.lock().unwrap().restarts, supervisor.restarts());
+        assert!(channels.tcp.try_recv().is_err());
+        assert!(supervisor.take_handle(SupervisedThread::Can).is_some());
+    }
+
+    #[test]
+    fn restart_udp_thread() {
+        let (mut supervisor, channels) = supervisor();
+        channels.relay_status.lock().unwrap().pod_state = PodState::AutoPilot;
+        supervisor.supervise(SupervisedThread::Udp, || std::thread::spawn(|| panic!("Expected by the test")));
+        assert_eq!(check_until_restarted(&mut supervisor), vec![SupervisedThread::Udp]);
+
+        match channels.can.try_recv() {
+            Ok(CanMessage::ChangeState(PodState::Braking, TransitionCause::Supervisor)) => {},
+            _ => panic!("The pod should be commanded to its safe state")
+        }
+        assert!(matches!(channels.udp.try_recv(), Ok(UDPMessage::StartupComplete)));
+        assert!(matches!(channels.tcp.try_recv(), Ok(TcpMessage::UdpRestarted)));
+    }
+
+    #[test]
+    fn give_up_after_max_restarts() {
+        let (mut supervisor, _channels) = supervisor();
+        supervisor.supervise(SupervisedThread::Tcp, || std::thread::spawn(|| panic!("Expected by the test")));
+        for _ in 0..MAX_RESTARTS {
+            assert_eq!(check_until_restarted(&mut supervisor), vec![SupervisedThread::Tcp]);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(supervisor.check().is_empty());
+        assert_eq!(supervisor.restarts()[0].count, MAX_RESTARTS);
+        assert!(supervisor.take_handle(SupervisedThread::Tcp).is_none());
+    }
+}
@@ -10,7 +10,7 @@ impl CanManager {
         std::thread::Builder::new().name("CAN Thread".to_string()).spawn(move || {
             // Setup
             let mut can_worker = CanWorkerState::new(initializer);
-            loop {
+            while !can_worker.is_stopped() {
                 can_worker = can_worker.main_loop();
             }
         }).expect("Should be able to create Thread")
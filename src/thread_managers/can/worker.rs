@@ -1,9 +1,16 @@
 use super::super::worker_states::*;
 use super::super::messages::*;
 use super::super::main_loop::*;
-use crate::board_states::{BoardStates};
+use super::super::mailbox::Mailbox;
+use crate::board_states::{BoardStates, Board};
 use crate::pod_states::PodState;
-use std::sync::mpsc::{ Receiver, Sender };
+use crate::transition_journal::{ SharedTransitionJournal, TransitionCause };
+use crate::relay_status::{ SharedRelayStatus, CanInterfaceStatus };
+use crate::flash_status::SharedFlashStatus;
+use crate::device_watchdog::RequiredDevices;
+use crate::relay_heartbeat::RelayHeartbeat;
+use crate::shutdown::SAFE_STATE_TIMEOUT;
+use std::sync::mpsc::Sender;
 use std::time::{Duration, Instant};
 use std::convert::TryInto;
 use socketcan::ShouldRetry;
@@ -11,26 +18,38 @@ use crate::can_extentions::prelude::*;
 use crate::can_extentions::ack_nack::AckNack;
 
 
-#[repr(C)] //* Required for type transmutations
-pub struct CanWorker<State = Startup> {
+pub struct CanWorkerData {
     // can_interface: String,
     can_handle: socketcan::CANSocket,
     udp_sender: Sender<UDPMessage>,
     worker_sender: Sender<WorkerMessage>,
-    can_receiver: Receiver<CanMessage>,
+    can_receiver: Mailbox<CanMessage>,
     requested_pod_state: PodState,
     current_pod_state: PodState,
     board_state: BoardStates,
     last_send: Instant,
-    state: std::marker::PhantomData<State>
+    roboteq_query_sent: Option<Instant>, // Set while the latest round of roboteq queries has not been answered
+    heartbeat: RelayHeartbeat,
+    shutting_down: bool,
+    shutdown_reply: Option<(Sender<PodState>, Instant)>, // Waiting for the boards to acknowledge the safe state until the deadline
+    transition_journal: SharedTransitionJournal,
+    relay_status: SharedRelayStatus,
+    flash_status: SharedFlashStatus,
+    required_devices: RequiredDevices
 }
 
+pub type CanWorker<State = Disconnected> = Worker<CanWorkerData, State>;
+
 pub struct CanWorkerInitializer {
     pub can_interface: String,
     pub udp_message_sender: Sender<UDPMessage>,
     pub worker_message_sender: Sender<WorkerMessage>,
-    pub can_message_receiver: Receiver<CanMessage>,
-    pub can_socket_read_timeout: Duration
+    pub can_message_receiver: Mailbox<CanMessage>,
+    pub can_socket_read_timeout: Duration,
+    pub transition_journal: SharedTransitionJournal,
+    pub relay_status: SharedRelayStatus,
+    pub flash_status: SharedFlashStatus, // No pod state which prevents flashing is entered while a board is being written
+    pub required_devices: RequiredDevices // Devices which must be present before the pod enters each state
 }
 
 impl CanWorker {
@@ -39,7 +58,9 @@ impl CanWorker {
     ) -> CanWorker<Disconnected> {
         let can_handle = socketcan::CANSocket::open(&initializer.can_interface).expect(&format!("Unable to Connect to CAN interface: {}", initializer.can_interface));
         can_handle.set_read_timeout(initializer.can_socket_read_timeout).expect("Unable to Set Timeout on CAN Socket");
-        CanWorker {
+        // Shares its start with the relay status so the uptime sent to the boards matches the one reported to the desktop
+        let heartbeat = initializer.relay_status.lock().expect("Relay status lock poisoned").heartbeat.clone();
+        Worker::from_data(CanWorkerData {
             can_handle,
             udp_sender: initializer.udp_message_sender,
             worker_sender: initializer.worker_message_sender,
@@ -48,12 +69,121 @@ impl CanWorker {
             current_pod_state: PodState::LowVoltage,
             board_state: BoardStates::default(),
             last_send: Instant::now(),
-            state: std::marker::PhantomData
+            roboteq_query_sent: None,
+            heartbeat,
+            shutting_down: false,
+            shutdown_reply: None,
+            transition_journal: initializer.transition_journal,
+            relay_status: initializer.relay_status,
+            flash_status: initializer.flash_status,
+            required_devices: initializer.required_devices
+        })
+    }
+}
+
+impl<State> CanWorker<State> {
+    /**
+     * @brief Request a new pod state from the boards and record the request in the transition journal.
+     * Once SystemFailure has been requested, it needs to be the final state so all other requests are ignored.
+     * Arming is refused while the firmware manifest blocks it, the desktop cannot move the pod to a state before the devices it requires
+     * are present and the pod is kept Resting or in LowVoltage while a board is being flashed. Refusals are sent back to the UDP thread.
+     * Once the relay is shutting down, only the safe state and error states can be requested.
+     */
+    fn request_pod_state(&mut self, new_state: PodState, cause: TransitionCause) {
+        if self.requested_pod_state == PodState::SystemFailure || self.requested_pod_state == new_state {
+            return;
+        }
+        if self.shutting_down && cause != TransitionCause::Shutdown && !new_state.is_error_state() {
+            println!("CAN THREAD: Refusing to enter {:?}, the relay is shutting down", new_state);
+            return;
+        }
+        let refusal = {
+            // The TCP thread takes the same locks in the same order before it starts a write
+            let flash_status = self.flash_status.lock().expect("Flash status lock poisoned");
+            let mut relay_status = self.relay_status.lock().expect("Relay status lock poisoned");
+            let refusal = relay_status.transition_refusal(new_state, cause, &self.required_devices, &flash_status);
+            if refusal.is_none() {
+                // Published before the locks are released so that no write can begin once the pod is leaving a state which allows flashing
+                relay_status.requested_pod_state = new_state;
+            }
+            refusal
+        };
+        if let Some(refusal) = refusal {
+            println!("CAN THREAD: Refusing to enter {:?}: {:?}", new_state, refusal);
+            // The UDP thread is waiting for the transition
+            let _ = self.udp_sender.send(UDPMessage::PodStateChangeRefused(new_state, refusal));
+            return;
+        }
+        self.transition_journal.lock().expect("Transition journal lock poisoned").begin(
+            self.current_pod_state,
+            new_state,
+            cause,
+            chrono::Utc::now().naive_local()
+        );
+        self.requested_pod_state = new_state;
+    }
+
+    /**
+     * @brief Share the pod and board states with the TCP thread's STATUS query
+     */
+    fn update_relay_status(&self, can_status: Option<CanInterfaceStatus>) {
+        let mut relay_status = self.relay_status.lock().expect("Relay status lock poisoned");
+        relay_status.pod_state = self.current_pod_state;
+        relay_status.requested_pod_state = self.requested_pod_state;
+        relay_status.board_states = self.board_state;
+        relay_status.heartbeat = self.heartbeat.clone();
+        if let Some(can_status) = can_status {
+            relay_status.can_status = can_status;
+        }
+    }
+
+    /**
+     * @brief Record how long the roboteq took to answer the latest round of queries. Only the first response
+     * of a round is timed, the others are queued behind it
+     */
+    fn record_roboteq_response(&mut self) {
+        if let Some(sent) = self.roboteq_query_sent.take() {
+            self.relay_status.lock().expect("Relay status lock poisoned").roboteq.sdo_latency = Some(sent.elapsed());
+        }
+    }
+
+    /**
+     * @brief Command the state the pod should be left in, straight away rather than with the next periodic pod state
+     */
+    fn begin_shutdown(&mut self, reply: Sender<PodState>) {
+        self.shutting_down = true;
+        self.request_pod_state(self.current_pod_state.shutdown_state(), TransitionCause::Shutdown);
+        println!("CAN THREAD: Shutting down, requesting {:?}", self.requested_pod_state);
+        if let Err(err) = self.can_handle.send_pod_state(&self.requested_pod_state) {
+            println!("Error Sending Message on CAN bus: {:?}",  err);
         }
+        self.shutdown_reply = Some((reply, Instant::now() + SAFE_STATE_TIMEOUT));
+    }
+
+    /**
+     * @brief Tell the main thread which state the pod was left in, once the boards acknowledged the safe state or failed to in time
+     */
+    fn reply_to_shutdown(&mut self) {
+        let acknowledged = self.current_pod_state == self.requested_pod_state;
+        let timed_out = matches!(&self.shutdown_reply, Some((_, deadline)) if Instant::now() >= *deadline);
+        if !acknowledged && !timed_out {
+            return;
+        }
+        if let Some((reply, _)) = self.shutdown_reply.take() {
+            if !acknowledged {
+                println!("CAN THREAD: The boards did not acknowledge {:?} before the shutdown", self.requested_pod_state);
+            }
+            // The main thread may have stopped waiting
+            let _ = reply.send(self.current_pod_state);
+        }
+    }
+
+    fn record_board_ack(&mut self, board: Board) {
+        self.transition_journal.lock().expect("Transition journal lock poisoned").record_ack(board);
     }
 }
 
-pub type CanWorkerState = WorkerState<CanWorker<Startup>, CanWorker<Recovery>, CanWorker<Connected>, CanWorker<Disconnected>>;
+pub type CanWorkerState = WorkerState<Unused, Unused, Unused, CanWorker<Disconnected>>;
 
 impl CanWorkerState {
     pub fn new(
@@ -69,28 +199,14 @@ impl CanWorkerState {
  * messages being received on the can bus and forward them to the worker as well as to
  * send transision requests which are generated by the UDP controller.
  */
-impl MainLoop<CanWorkerState> for CanWorker<Startup> {
-    fn main_loop(self) -> CanWorkerState {
-       panic!("CanWorker does not implement startup");
-    }
-}
-impl MainLoop<CanWorkerState> for CanWorker<Recovery>  {
-    fn main_loop(self) -> CanWorkerState {
-        panic!("CanWorker does not implement recovery");
-    }
-}
-impl MainLoop<CanWorkerState> for CanWorker<Connected> {
-    fn main_loop(self) -> CanWorkerState {
-        panic!("CanWorker does not implement connected");
-    }
-}
 impl MainLoop<CanWorkerState> for CanWorker<Disconnected> {
  fn main_loop(mut self) -> CanWorkerState {
     let response = self.can_handle.read_frame(); // with timeout
+    let mut can_status = None;
     if response.should_retry() {
-        // Timeout with no message
-        println!("CAN SOCKET: Read timeout no message Received");
+        // Timeout with no message. Expected while the bus is silent, the watchdog reports the missing devices
     } else if let Ok(frame) = response {
+        can_status = Some(CanInterfaceStatus::Up);
         // Frame Received
         // Check for state messages before passing the frame on to the worker
         match frame.get_command() {
@@ -98,7 +214,9 @@ impl MainLoop<CanWorkerState> for CanWorker<Disconnected> {
                 // println!("BMS STATE CHANGE ACC, {:?}", self.requested_pod_state);
                 match ack_nack {
                     AckNack::Ack => {
-                        self.board_state.set_bms_state(&self.requested_pod_state);
+                        let requested_pod_state = self.requested_pod_state;
+                        self.board_state.set_bms_state(&requested_pod_state);
+                        self.record_board_ack(Board::Bms);
                     }
                     _ => panic!("Received A NACK FROM BMS State Change. Don't know what to do!")
                 }
@@ -107,7 +225,9 @@ impl MainLoop<CanWorkerState> for CanWorker<Disconnected> {
                 // println!("MC STATE CHANGE ACC, {:?}", self.requested_pod_state);
                 match ack_nack {
                     AckNack::Ack => {
-                        self.board_state.set_motor_controller_state(&self.requested_pod_state);
+                        let requested_pod_state = self.requested_pod_state;
+                        self.board_state.set_motor_controller_state(&requested_pod_state);
+                        self.record_board_ack(Board::MotorController);
                     }
                     _ => panic!("Received A NACK FROM MotorController State Change. Don't know what to do!")
                 }
@@ -115,17 +235,34 @@ impl MainLoop<CanWorkerState> for CanWorker<Disconnected> {
             CanCommand::PressureStateChange(ack_nack) => {
                 match ack_nack {
                     AckNack::Ack => {
-                        self.board_state.set_pressure_state(&self.requested_pod_state);
+                        let requested_pod_state = self.requested_pod_state;
+                        self.board_state.set_pressure_state(&requested_pod_state);
+                        self.record_board_ack(Board::Pressure);
                     }
                     _ => panic!("Received A NACK FROM MotorController State Change. Don't know what to do!")
                 }
-            }
+            },
+            CanCommand::RoboteqBatteryAmpsResult{ .. }
+            | CanCommand::RoboteqMotorEncoderResult{ .. }
+            | CanCommand::RoboteqTemperatureResult{ .. } => {
+                self.record_roboteq_response();
+            },
+            CanCommand::RelayHeartbeatEcho{ board, counter } => {
+                let round_trip = self.heartbeat.record_echo(board, counter, &Instant::now());
+                if round_trip.is_none() {
+                    println!("CAN THREAD: Ignoring heartbeat echo {} from {}, it is too old", counter, board.as_str());
+                }
+            },
+            CanCommand::RoboteqHeartbeat{ nmt_state } => {
+                self.relay_status.lock().expect("Relay status lock poisoned").roboteq.nmt_state = Some(nmt_state);
+            },
             _ => {}
         }
         self.worker_sender.send(WorkerMessage::CanFrameAndTimeStamp(frame, chrono::Utc::now().naive_local())).expect("Unable to send message from CAN Thread on Worker Channel");
-    } else {
+    } else if let Err(err) = response {
         // ERROR Reading from Can socket
         println!("Error Reading from CAN Socket");
+        can_status = Some(CanInterfaceStatus::Error(format!("{}", err)));
     }
 
     // Check for Transition Complete
@@ -135,6 +272,7 @@ impl MainLoop<CanWorkerState> for CanWorker<Disconnected> {
     && self.requested_pod_state != self.current_pod_state {
         println!("Sending Ack to UDP for state change");
         self.current_pod_state = self.requested_pod_state;
+        self.transition_journal.lock().expect("Transition journal lock poisoned").complete();
         self.udp_sender.send(UDPMessage::PodStateChangeAck).expect("unable to message UDP thread");
     } else {
         println!("CURRENT {:?}, BMS: {:?}, PYSDUCK: {:?}, REQUESTED: {:?}", self.current_pod_state, self.board_state.get_bms_state(), self.board_state.get_pressure_state(), self.requested_pod_state);
@@ -143,23 +281,37 @@ impl MainLoop<CanWorkerState> for CanWorker<Disconnected> {
     // check for state message from udp or timeout from worker
     if let Ok(message) = self.can_receiver.try_recv() {
         match message {
-            CanMessage::ChangeState(new_state) => {
+            CanMessage::ChangeState(new_state, cause) => {
                 /* This check is just for safety. Since we deal with multiple workers, there could be race conditions. If DeviceLost is received, that needs to be the final state. */
-                if self.requested_pod_state != PodState::SystemFailure {
-                    self.requested_pod_state = new_state;
-                }
+                self.request_pod_state(new_state, cause);
             }
             CanMessage::DeviceLost => {
-                self.requested_pod_state = PodState::SystemFailure;
+                self.request_pod_state(PodState::SystemFailure, TransitionCause::Watchdog);
                 self.udp_sender.send(UDPMessage::SystemFault).unwrap();
             },
             CanMessage::BrakingTimerTimeout => {
                 if self.current_pod_state == PodState::AutoPilot {
-                    self.requested_pod_state = PodState::Braking;
+                    self.request_pod_state(PodState::Braking, TransitionCause::BrakingTimer);
                 }
-            }
+            },
+            CanMessage::Shutdown(reply) => self.begin_shutdown(reply),
+            CanMessage::Stop => return CanWorkerState::Stopped
         }
     }
+    if self.shutdown_reply.is_some() {
+        self.reply_to_shutdown();
+    }
+
+    // The heartbeat has its own period so that the boards notice a frozen relay sooner than the pod state would tell them
+    let now = Instant::now();
+    if self.heartbeat.is_due(&now) {
+        let (counter, uptime_ms) = self.heartbeat.next(&now);
+        if let Err(err) = self.can_handle.send_heartbeat(counter, uptime_ms) {
+            println!("Error Sending Heartbeat on CAN bus: {:?}",  err);
+        }
+    }
+
+    self.update_relay_status(can_status);
 
     if self.last_send.elapsed().as_millis() >= 400 {
         self.last_send = Instant::now();
@@ -172,7 +324,8 @@ impl MainLoop<CanWorkerState> for CanWorker<Disconnected> {
             }
         }
 
-        /* ROBOT EQ Data queries */
+        /* ROBOT EQ Data queries. The responses keep the roboteq alive in the device watchdog */
+        self.roboteq_query_sent = Some(Instant::now());
         let message_result = self.can_handle.roboteq_read_battery_amps(1, 1);
         match message_result {
             Ok(()) => {},
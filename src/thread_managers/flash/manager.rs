@@ -0,0 +1,15 @@
+use super::worker::{ FlashWorker, FlashWorkerInitializer };
+pub struct FlashManager {
+}
+
+impl FlashManager {
+    pub fn run(
+        initializer: FlashWorkerInitializer
+    ) -> std::thread::JoinHandle<()> {
+        std::thread::Builder::new().name("Flash Thread".to_string()).spawn(move || {
+            // Setup
+            let mut flash_worker = FlashWorker::new(initializer);
+            while flash_worker.handle_next_job() {}
+        }).expect("Should be able to create Thread")
+    }
+}
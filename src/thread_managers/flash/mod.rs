@@ -0,0 +1,5 @@
+mod worker;
+mod manager;
+
+pub use manager::FlashManager;
+pub use worker::FlashWorkerInitializer;
@@ -0,0 +1,181 @@
+use super::super::messages::FlashMessage;
+use crate::flash_status::{ SharedFlashStatus, FlashState };
+use crate::relay_status::SharedRelayStatus;
+use crate::firmware_manifest::{ ComplianceReport, ObservedDevice };
+use canota::{ Canota, CanotaDeviceInfo, DeviceMode, FirmwareImage, FlashDevice, FlashProgress, Inventory, SocketCanBus };
+use json::{ JsonValue, object };
+use std::sync::mpsc::Receiver;
+
+pub struct FlashWorkerInitializer {
+    pub can_interface: String,
+    pub flash_message_receiver: Receiver<FlashMessage>,
+    pub flash_status: SharedFlashStatus,
+    pub relay_status: SharedRelayStatus
+}
+
+/**
+ * Jobs are queued by the TCP thread, which has already checked that the pod is in a state where
+ * its boards can be flashed. Each job opens its own canota session, which is closed when the job ends,
+ * so a missing interface fails the job instead of the relay.
+ */
+pub struct FlashWorker {
+    can_interface: String,
+    flash_message_receiver: Receiver<FlashMessage>,
+    flash_status: SharedFlashStatus,
+    relay_status: SharedRelayStatus
+}
+
+fn device_to_json(device: &CanotaDeviceInfo) -> JsonValue {
+    object!{
+        short_device_id: device.short_device_id(),
+        device_id: device.device_id(),
+        mode: format!("{:?}", device.mode()),
+        device_type: format!("{:?}", device.device_type()),
+        version: device.device_version().to_string(),
+    }
+}
+
+fn observed_device(device: &CanotaDeviceInfo) -> ObservedDevice {
+    ObservedDevice {
+        short_device_id: device.short_device_id(),
+        device_id: device.device_id(),
+        device_type: format!("{:?}", device.device_type()),
+        version: (device.device_version().major(), device.device_version().minor())
+    }
+}
+
+impl FlashWorker {
+    pub fn new(initializer: FlashWorkerInitializer) -> FlashWorker {
+        FlashWorker {
+            can_interface: initializer.can_interface,
+            flash_message_receiver: initializer.flash_message_receiver,
+            flash_status: initializer.flash_status,
+            relay_status: initializer.relay_status
+        }
+    }
+
+    /**
+     * @brief Block until the next job is received and run it. Returns false once the TCP thread has hung up
+     */
+    pub fn handle_next_job(&mut self) -> bool {
+        let message = match self.flash_message_receiver.recv() {
+            Ok(message) => message,
+            Err(_) => return false
+        };
+        let result = match message {
+            FlashMessage::Scan => self.open().and_then(|mut canota| self.scan(&mut canota)).map(|_| ()),
+            FlashMessage::CheckFirmware => self.check_firmware(),
+            FlashMessage::Write { short_device_id, start_address, image } => self.write(short_device_id, start_address, &image)
+        };
+        let mut status = self.flash_status.lock().expect("Flash status lock poisoned");
+        match result {
+            Ok(()) => status.state = FlashState::Complete,
+            Err(error) => {
+                println!("FLASH THREAD: Job failed: {}", error);
+                status.fail(error);
+            }
+        }
+        true
+    }
+
+    fn open(&self) -> Result<Canota<SocketCanBus>, String> {
+        Canota::new(self.can_interface.as_str()).map_err(|err| format!("Unable to open {}: {:?}", self.can_interface, err))
+    }
+
+    fn set_state(&self, state: FlashState) {
+        self.flash_status.lock().expect("Flash status lock poisoned").state = state;
+    }
+
+    fn scan(&self, canota: &mut Canota<SocketCanBus>) -> Result<Inventory, String> {
+        self.set_state(FlashState::Scanning);
+        let inventory = canota.scan().map_err(|err| format!("Scan failed: {:?}", err))?;
+        let mut status = self.flash_status.lock().expect("Flash status lock poisoned");
+        status.devices = inventory.devices().iter().map(device_to_json).collect();
+        status.collisions = inventory.collisions().iter().map(|collision| collision.short_device_id).collect();
+        Ok(inventory)
+    }
+
+    /**
+     * @brief Scan the bus and compare the boards with the firmware manifest. The result replaces the
+     * previous report in the relay status, and a failed check clears it so that a stale report can not
+     * allow arming
+     */
+    fn check_firmware(&self) -> Result<(), String> {
+        let manifest = self.relay_status.lock().expect("Relay status lock poisoned").firmware.manifest().cloned()
+            .ok_or_else(|| String::from("No firmware manifest"))?;
+        let report = self.open().and_then(|mut canota| {
+            let inventory = self.scan(&mut canota)?;
+            let observed: Vec<ObservedDevice> = inventory.devices().iter().map(observed_device).collect();
+            Ok(manifest.check(&observed, |short_device_id, section| {
+                canota.get_checksum_for_section(short_device_id, section.start_address, section.length).map_err(|err| format!("{:?}", err))
+            }, chrono::Utc::now().naive_local()))
+        });
+
+        let mut relay_status = self.relay_status.lock().expect("Relay status lock poisoned");
+        match report {
+            Ok(report) => {
+                log_compliance(&report);
+                relay_status.firmware.report = Some(report);
+                relay_status.firmware.error = None;
+                Ok(())
+            },
+            Err(error) => {
+                relay_status.firmware.report = None;
+                relay_status.firmware.error = Some(error.clone());
+                Err(error)
+            }
+        }
+    }
+
+    /**
+     * @brief Write image to a board. The firmware report is cleared before the board is touched
+     * @param image an Intel HEX, ELF or raw binary image. Raw binaries are written at start_address
+     */
+    fn write(&self, short_device_id: u8, start_address: u32, image: &[u8]) -> Result<(), String> {
+        let firmware = FirmwareImage::from_bytes(image, start_address).map_err(|err| format!("Invalid firmware image: {:?}", err))?;
+        self.flash_status.lock().expect("Flash status lock poisoned").total_bytes = firmware.total_length();
+
+        let mut canota = self.open()?;
+        // Scan first so that canota knows about the device and its current mode
+        let inventory = self.scan(&mut canota)?;
+        if inventory.is_collision(short_device_id) {
+            return Err(format!("Short id {} is used by more than one device", short_device_id));
+        }
+        let mode = inventory.device(short_device_id)
+            .map(|device| device.mode().clone())
+            .ok_or_else(|| format!("Device {} not found", short_device_id))?;
+        let mut device = canota.device(short_device_id).map_err(|err| format!("{:?}", err))?;
+        // The last firmware check no longer describes the board, so arming needs a new FLASH CHECK
+        self.relay_status.lock().expect("Relay status lock poisoned").firmware.report = None;
+        if mode != DeviceMode::Bootloader {
+            device.set_mode(DeviceMode::Bootloader).map_err(|err| format!("Unable to enter the bootloader: {:?}", err))?;
+        }
+
+        device.flash_image(&firmware, |progress| {
+            let mut status = self.flash_status.lock().expect("Flash status lock poisoned");
+            match progress {
+                FlashProgress::Erasing { .. } => status.state = FlashState::Erasing,
+                FlashProgress::Writing { bytes_written, .. } => {
+                    status.state = FlashState::Writing;
+                    status.bytes_written = bytes_written;
+                },
+                FlashProgress::Verified { checksum, .. } => {
+                    status.state = FlashState::Verifying;
+                    status.checksum = Some(checksum);
+                }
+            }
+        }).map_err(|err| format!("Flashing failed: {:?}", err))?;
+
+        device.set_mode(DeviceMode::Application)
+            .map_err(|err| format!("Image verified but the device did not start it: {:?}", err))
+    }
+}
+
+fn log_compliance(report: &ComplianceReport) {
+    if report.is_compliant() {
+        println!("FLASH THREAD: Every board matches the firmware manifest");
+    }
+    for device in report.devices.iter().filter(|device| !device.mismatches.is_empty()) {
+        println!("FLASH THREAD: {} (short id {}) does not match the firmware manifest: {:?}", device.role, device.short_device_id, device.mismatches);
+    }
+}
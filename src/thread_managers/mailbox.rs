@@ -0,0 +1,70 @@
+/**
+ * The receiving end of a channel, shared between a worker and the supervisor which restarts it.
+ * Messages queued for a worker which panics are kept for the worker which replaces it, and the
+ * threads sending to it never see the channel close.
+ */
+use std::sync::{ Arc, Mutex, MutexGuard, PoisonError };
+use std::sync::mpsc::{ Receiver, RecvError, RecvTimeoutError, TryRecvError };
+use std::time::Duration;
+
+pub struct Mailbox<T> {
+    receiver: Arc<Mutex<Receiver<T>>>
+}
+
+impl<T> Clone for Mailbox<T> {
+    fn clone(&self) -> Mailbox<T> {
+        Mailbox { receiver: self.receiver.clone() }
+    }
+}
+
+impl<T> Mailbox<T> {
+    pub fn new(receiver: Receiver<T>) -> Mailbox<T> {
+        Mailbox { receiver: Arc::new(Mutex::new(receiver)) }
+    }
+
+    /**
+     * A worker which panicked while waiting poisons the lock, the receiver itself is still usable
+     */
+    fn lock(&self) -> MutexGuard<'_, Receiver<T>> {
+        self.receiver.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.lock().recv()
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.lock().try_recv()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.lock().recv_timeout(timeout)
+    }
+}
+
+/********************
+ *      TESTS
+ ********************/
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn messages_outlive_the_worker() {
+        let (sender, receiver) = channel();
+        let mailbox = Mailbox::new(receiver);
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+
+        let worker_mailbox = mailbox.clone();
+        let worker = std::thread::spawn(move || {
+            assert_eq!(worker_mailbox.recv(), Ok(1));
+            panic!("Expected by the test");
+        });
+        assert!(worker.join().is_err());
+
+        assert_eq!(mailbox.try_recv(), Ok(2));
+        assert_eq!(mailbox.recv_timeout(Duration::from_millis(1)), Err(RecvTimeoutError::Timeout));
+    }
+}
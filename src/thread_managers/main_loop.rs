@@ -1,50 +1,48 @@
 /**
  * Each Worker Implements MainLoop.
  * Each worker has an associated worker state which it will give as an argument to MainLoop.
- * The manager of the worker will then initialize the worker and it's state object in the following manner::
- * #[repr(C)]
- * struct Worker<State = Startup> {
- *  worker_data: u8,
- *  state: std::marker::PhantomData<State>
+ * The data of a worker is kept in a struct of its own, and the worker in each state is a Worker
+ * wrapping that data. The manager of the worker will then initialize the worker and it's state object in the following manner::
+ * struct ExampleData {
+ *  worker_data: u8
  * }
  *
- * pub type WorkerWorkerState = WorkerState<Startup, Recovery, Connected, Disconnected>;
+ * type ExampleWorker<State = Startup> = Worker<ExampleData, State>;
+ * pub type ExampleWorkerState = WorkerState<Unused, ExampleWorker<Recovery>, ExampleWorker<Connected>, ExampleWorker<Disconnected>>;
  *
- * impl MainLoop<WorkerWorkerState> for WorkerState<Startup> {
- *  fn main_loop(self) -> WorkerWorkerState {
+ * impl MainLoop<ExampleWorkerState> for ExampleWorker<Recovery>  {
+ *    fn main_loop(self) -> ExampleWorkerState {
  *      // Do stuff here
- *      WorkerWorkerState::Startup(self)
- * }
- * }
- * impl MainLoop<WorkerWorkerState> for WorkerState<Recovery>  {
- *    fn main_loop(self) -> WorkerWorkerState {
- *      // Do stuff here
- *      WorkerWorkerState::Recovery(self)
+ *      ExampleWorkerState::Recovery(self)
  *    }
  * }
- * impl MainLoop<WorkerWorkerState> for WorkerState<Connected> {
- *      fn main_loop(self) -> WorkerWorkerState {
- *          // Do stuff here
- *          WorkerWorkerState::Connected(self)
+ * impl MainLoop<ExampleWorkerState> for ExampleWorker<Connected> {
+ *      fn main_loop(self) -> ExampleWorkerState {
+ *          // Do stuff here, self.worker_data reads the data of the worker
+ *          ExampleWorkerState::Connected(self)
  *      }
  * }
- * impl MainLoop<WorkerWorkerState> for WorkerState<Disconnected> {
- *    fn main_loop(self) -> WorkerWorkerState {
+ * impl MainLoop<ExampleWorkerState> for ExampleWorker<Disconnected> {
+ *    fn main_loop(self) -> ExampleWorkerState {
  *      // Do stuff here
- *      WorkerWorkerState::Disconnected(self)
+ *      ExampleWorkerState::Connected(self.into_state())
  *    }
  * }
  *
- *
- * NOTE: Where is says "Do stuff here" It is helpful to also implement functions which transform a worker from One state to another. This can be done quickly with the help of std::mem::transmute
+ * NOTE: A worker changes state with Worker::into_state, which moves its data into a worker of the new state.
+ * States which a worker never enters are Unused, so the worker does not need to implement their main loop.
+ * A worker which is done returns Stopped, which drops its data and ends the manager's loop.
  */
-pub trait MainLoop<T>
-where T: WorkerStateTrait {
+use std::marker::PhantomData;
+use std::ops::{ Deref, DerefMut };
+
+pub trait MainLoop<T> {
     fn main_loop(self) -> T;
 }
 
 pub trait WorkerStateTrait {
     fn main_loop(self) -> Self;
+    fn is_stopped(&self) -> bool;
 }
 
 pub enum WorkerState<S,R,C,D>
@@ -55,7 +53,8 @@ D: MainLoop<Self> {
     Startup(S),
     Recovery(R),
     Connected(C),
-    Disconnected(D)
+    Disconnected(D),
+    Stopped
 }
 
 impl<S,R,C,D> WorkerStateTrait for
@@ -71,6 +70,108 @@ where   S: MainLoop<Self>,
             WorkerState::Disconnected(worker) => worker.main_loop(),
             WorkerState::Connected(worker) => worker.main_loop(),
             WorkerState::Recovery(worker) => worker.main_loop(),
+            WorkerState::Stopped => WorkerState::Stopped,
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        matches!(self, WorkerState::Stopped)
+    }
+}
+
+/**
+ * A state which the worker never enters. No value of this type can exist, so its main loop can never be called
+ */
+pub enum Unused {}
+
+impl<T> MainLoop<T> for Unused {
+    fn main_loop(self) -> T {
+        match self {}
+    }
+}
+
+/**
+ * The data of a worker in a given State. The state only exists at compile time, so changing state
+ * moves the data into a new Worker without copying or reinterpreting it.
+ */
+pub struct Worker<T, State> {
+    data: T,
+    state: PhantomData<State>
+}
+
+impl<T, State> Worker<T, State> {
+    pub fn from_data(data: T) -> Worker<T, State> {
+        Worker { data, state: PhantomData }
+    }
+
+    pub fn into_state<Next>(self) -> Worker<T, Next> {
+        Worker { data: self.data, state: PhantomData }
+    }
+}
+
+impl<T, State> Deref for Worker<T, State> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+impl<T, State> DerefMut for Worker<T, State> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+}
+
+/********************
+ *      TESTS
+ ********************/
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::worker_states::*;
+
+    struct Counter {
+        count: u32
+    }
+
+    type CounterWorker<State> = Worker<Counter, State>;
+    type CounterWorkerState = WorkerState<Unused, Unused, CounterWorker<Connected>, CounterWorker<Disconnected>>;
+
+    impl MainLoop<CounterWorkerState> for CounterWorker<Disconnected> {
+        fn main_loop(mut self) -> CounterWorkerState {
+            self.count += 1;
+            CounterWorkerState::Connected(self.into_state())
+        }
+    }
+
+    impl MainLoop<CounterWorkerState> for CounterWorker<Connected> {
+        fn main_loop(mut self) -> CounterWorkerState {
+            self.count += 10;
+            if self.count > 100 {
+                return CounterWorkerState::Stopped;
+            }
+            CounterWorkerState::Disconnected(self.into_state())
+        }
+    }
+
+    #[test]
+    fn state_transitions() {
+        let mut worker = CounterWorkerState::Disconnected(Worker::from_data(Counter { count: 0 }));
+        for _ in 0..3 {
+            worker = worker.main_loop();
+        }
+        match worker {
+            CounterWorkerState::Connected(worker) => assert_eq!(worker.count, 12),
+            _ => panic!("The worker should alternate between disconnected and connected")
+        }
+
+        let mut worker = CounterWorkerState::Disconnected(Worker::from_data(Counter { count: 0 }));
+        let mut loops = 0;
+        while !worker.is_stopped() {
+            worker = worker.main_loop();
+            loops += 1;
         }
+        assert_eq!(loops, 20);
     }
 }
@@ -1,9 +1,14 @@
 use std::net::SocketAddr;
+use std::sync::mpsc::Sender;
 #[cfg(unix)]
 use socketcan::CANFrame;
 use crate::{
+    project_butterfree::udp::encoding::MessageEncoding,
     pod_data,
     pod_states,
+    transition_journal::{ TransitionCause, TransitionRefusal },
+    device_watchdog::{ Device, DeviceHealth },
+    supervisor::ThreadRestart,
 };
 
 pub enum TcpMessage {
@@ -11,25 +16,45 @@ pub enum TcpMessage {
     #[allow(dead_code)] // Not Dead, but it's only constructed when running in unix
     RecoveryComplete,
     UdpFailedToConnect,
+    UdpRestarted, // The UDP thread was replaced and has no desktop session
+    Shutdown
 }
 
 #[derive(Debug)]
 pub enum UDPMessage {
-    ConnectToDesktop(SocketAddr),
+    ConnectToDesktop(SocketAddr, Option<String>, MessageEncoding, u8), // Address of the desktop, the session id when authentication is enabled, the negotiated encoding and protocol version
+    AddSubscriber(SocketAddr, u32, MessageEncoding, Sender<bool>), // Address of a read only observer, the rate in Hz that it should receive messages at and whether it was subscribed is sent back
+    RemoveSubscriber(SocketAddr),
     DisconnectFromHost,
     StartupComplete,
     #[allow(dead_code)] // Not Dead, only constructed when running in unix, but the udp socket needs to be able to check it in all cases
     PodStateChangeAck,
+    #[allow(dead_code)] // Only constructed when running in unix
+    PodStateChangeRefused(pod_states::PodState, TransitionRefusal), // The CAN thread did not request the state
     #[allow(dead_code)]
     TelemetryDataAvailable(pod_data::PodData, chrono::NaiveDateTime),
-    SystemFault
+    #[allow(dead_code)]
+    DeviceStates(Vec<(Device, DeviceHealth)>), // Sent by the worker whenever the health of a device changes
+    SystemFault,
+    ThreadRestarts(Vec<ThreadRestart>), // Sent by the supervisor whenever it restarts a thread
+    Shutdown(pod_states::PodState) // The relay is shutting down with the pod in the given state
 }
 
 #[derive(Clone)]
 pub enum CanMessage {
-    ChangeState(pod_states::PodState),
+    ChangeState(pod_states::PodState, TransitionCause),
     BrakingTimerTimeout,
-    DeviceLost
+    DeviceLost,
+    #[allow(dead_code)] // Only constructed when running in unix
+    Shutdown(Sender<pod_states::PodState>), // Command the safe pod state, the state reached is sent back once the boards acknowledge it or time out
+    #[allow(dead_code)]
+    Stop
+}
+
+pub enum FlashMessage {
+    Scan,
+    CheckFirmware, // Compare the boards on the bus with the firmware manifest
+    Write { short_device_id: u8, start_address: u32, image: Vec<u8> }
 }
 
 pub enum WorkerMessage {
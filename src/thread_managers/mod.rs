@@ -6,10 +6,16 @@ pub mod worker_states {
 }
 pub mod messages;
 mod main_loop;
+mod mailbox;
 mod udp;
 mod tcp;
 mod can;
+#[cfg(all(unix, feature = "flashing"))]
+mod flash;
 
-pub use udp::UdpManager;
-pub use tcp::TcpManager;
-pub use can::{ CanManager, CanWorkerInitializer };
\ No newline at end of file
+pub use mailbox::Mailbox;
+pub use udp::{ UdpManager, UdpWorkerInitializer };
+pub use tcp::{ TcpManager, TcpWorkerInitializer };
+pub use can::{ CanManager, CanWorkerInitializer };
+#[cfg(all(unix, feature = "flashing"))]
+pub use flash::{ FlashManager, FlashWorkerInitializer };
@@ -2,3 +2,4 @@ mod worker;
 mod manager;
 
 pub use manager::TcpManager;
+pub use worker::TcpWorkerInitializer;
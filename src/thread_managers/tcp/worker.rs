@@ -1,25 +1,51 @@
 use crate::error::Error;
 use crate::requests;
 use crate::stream_utils;
+use crate::transition_journal::SharedTransitionJournal;
+use crate::relay_status::SharedRelayStatus;
+use crate::flash_status::{ SharedFlashStatus, FlashState };
+use canota::crc32::crc32;
+use crate::project_butterfree::auth::{ self, Authenticator };
+use crate::project_butterfree::udp::{ encoding::MessageEncoding, PROTOCOL_VERSION };
+use crate::project_butterfree::udp::subscription::{ self, SubscriptionRequest, SUBSCRIPTION_LEASE };
+use crate::project_butterfree::flash::{ self, FirmwareUpload, FlashWriteRequest };
 
 use super::super::worker_states::*;
 use super::super::messages::*;
 use super::super::main_loop::*;
+use super::super::mailbox::Mailbox;
 
 use std::io::prelude::*;
 use std::net::{
+    SocketAddr,
     TcpListener,
     TcpStream
 };
-use std::sync::mpsc::{
-    Sender,
-    Receiver,
-};
+use std::time::{ Duration, Instant };
+use std::sync::mpsc::{ channel, Sender };
+use json::object;
+
+const UPLOAD_CHUNK_SIZE: usize = 4096;
+const UPLOAD_READ_TIMEOUT: Duration = Duration::from_secs(5);
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(30); // Other clients wait while an image is uploaded
+const SUBSCRIBE_REPLY_TIMEOUT: Duration = Duration::from_secs(1); // Time the UDP thread has to accept a new observer
 
 #[derive(Copy, Clone, Debug)]
 enum RequestTypes {
     Connect,
     Disconnect,
+    Journal,
+    Subscribe,
+    Unsubscribe,
+    Status,
+    Ping,
+    Version,
+    Config,
+    FlashScan,
+    FlashUpload,
+    FlashWrite,
+    FlashStatus,
+    FlashCheck,
     Unknown
 }
 
@@ -46,78 +72,321 @@ impl TcpWorkerRequestParser for requests::RequestParser<RequestTypes> {
         * Each Request Type will have a corresponding handler function which is ran
         * when the match occurs
         */
+        self.insert("PING\r\n", RequestTypes::Ping);
+        self.insert("STATUS\r\n", RequestTypes::Status);
         self.insert("CONNECT\r\n", RequestTypes::Connect);
         self.insert("DISCONNECT\r\n", RequestTypes::Disconnect);
+        self.insert("JOURNAL\r\n", RequestTypes::Journal);
+        self.insert("SUBSCRIBE\r\n", RequestTypes::Subscribe);
+        self.insert("UNSUBSCRIBE\r\n", RequestTypes::Unsubscribe);
+        self.insert("VERSION\r\n", RequestTypes::Version);
+        self.insert("CONFIG\r\n", RequestTypes::Config);
+        self.insert("FLASH SCAN\r\n", RequestTypes::FlashScan);
+        self.insert("FLASH UPLOAD\r\n", RequestTypes::FlashUpload);
+        self.insert("FLASH WRITE\r\n", RequestTypes::FlashWrite);
+        self.insert("FLASH STATUS\r\n", RequestTypes::FlashStatus);
+        self.insert("FLASH CHECK\r\n", RequestTypes::FlashCheck);
         self.insert("@@Failed@@\r\n", RequestTypes::Unknown); // Special Message which is written into the request in the event of an error reading the message
         self
     }
 }
 
-#[repr(C)] // Required for type transmutations
-pub struct TcpWorker<State = Disconnected> {
+pub struct TcpWorkerData {
     listener: TcpListener,
     request_parser: requests::RequestParser<RequestTypes>,
     udp_message_sender: Sender<UDPMessage>,
-    tcp_message_receiver: Receiver<TcpMessage>,
+    tcp_message_receiver: Mailbox<TcpMessage>,
     tcp_message_buffer_size: usize,
-    state: std::marker::PhantomData<State>
+    transition_journal: SharedTransitionJournal,
+    relay_status: SharedRelayStatus,
+    flash_message_sender: Option<Sender<FlashMessage>>,
+    flash_status: SharedFlashStatus,
+    firmware_image: Option<Vec<u8>>, // Last image received with FLASH UPLOAD
+    authenticator: Option<Authenticator>,
+    rejected_requests: u64
 }
 
-pub type TcpWorkerState = WorkerState<TcpWorker<Startup>, TcpWorker<Recovery>, TcpWorker<Connected>, TcpWorker<Disconnected>>;
+pub type TcpWorker<State = Disconnected> = Worker<TcpWorkerData, State>;
+pub type TcpWorkerState = WorkerState<Unused, TcpWorker<Recovery>, TcpWorker<Connected>, TcpWorker<Disconnected>>;
+
+pub struct TcpWorkerInitializer<A: std::net::ToSocketAddrs> {
+    pub address: A,
+    pub udp_message_sender: Sender<UDPMessage>,
+    pub tcp_message_receiver: Mailbox<TcpMessage>,
+    pub tcp_message_buffer_size: usize,
+    pub transition_journal: SharedTransitionJournal,
+    pub relay_status: SharedRelayStatus,
+    pub flash_message_sender: Option<Sender<FlashMessage>>, // None when the relay can not flash boards
+    pub flash_status: SharedFlashStatus,
+    pub auth_key: Option<Vec<u8>>
+}
 
 impl TcpWorkerState {
-    pub fn new<A: std::net::ToSocketAddrs>(
-        address: A,
-        udp_message_sender: Sender<UDPMessage>,
-        tcp_message_receiver: Receiver<TcpMessage>,
-        tcp_message_buffer_size: usize
-    ) -> TcpWorkerState {
-        TcpWorkerState::Disconnected(TcpWorker::new(address, udp_message_sender, tcp_message_receiver, tcp_message_buffer_size))
+    pub fn new<A: std::net::ToSocketAddrs>(initializer: TcpWorkerInitializer<A>) -> TcpWorkerState {
+        TcpWorkerState::Disconnected(TcpWorker::new(initializer))
     }
 }
 
 impl TcpWorker {
-    pub fn new<A: std::net::ToSocketAddrs>(
-        address: A,
-        udp_message_sender: Sender<UDPMessage>,
-        tcp_message_receiver: Receiver<TcpMessage>,
-        tcp_message_buffer_size: usize
-    ) -> TcpWorker<Disconnected> {
-        let listener = TcpListener::bind(address).expect("Unable to Connect to Port");
+    pub fn new<A: std::net::ToSocketAddrs>(initializer: TcpWorkerInitializer<A>) -> TcpWorker<Disconnected> {
+        let listener = TcpListener::bind(initializer.address).expect("Unable to Connect to Port");
         listener.set_nonblocking(true).expect("Unable to set non blocking");
-        TcpWorker {
+        Worker::from_data(TcpWorkerData {
             listener,
             request_parser: requests::RequestParser::new().init(),
-            udp_message_sender,
-            tcp_message_receiver,
-            tcp_message_buffer_size,
-            state: std::marker::PhantomData
+            udp_message_sender: initializer.udp_message_sender,
+            tcp_message_receiver: initializer.tcp_message_receiver,
+            tcp_message_buffer_size: initializer.tcp_message_buffer_size,
+            transition_journal: initializer.transition_journal,
+            relay_status: initializer.relay_status,
+            flash_message_sender: initializer.flash_message_sender,
+            flash_status: initializer.flash_status,
+            firmware_image: None,
+            authenticator: initializer.auth_key.map(Authenticator::new),
+            rejected_requests: 0
+        })
+    }
+}
+
+impl<State> TcpWorker<State> {
+    /**
+     * @brief Write the transition journal to the stream as a JSON array
+     */
+    fn write_journal(&self, stream: &mut TcpStream) -> Result<usize, Error> {
+        let journal = self.transition_journal.lock().expect("Transition journal lock poisoned").to_json();
+        stream.write_message(journal.dump().as_bytes())
+    }
+
+    /**
+     * @brief Answer the read only STATUS, PING, VERSION and CONFIG queries with a JSON object
+     * @param connection the state of the controlling session, reported by STATUS
+     */
+    fn write_query_response(&self, query: RequestTypes, stream: &mut TcpStream, connection: &str) -> Result<usize, Error> {
+        let response = match query {
+            RequestTypes::Ping => object!{
+                pong: chrono::Utc::now().timestamp_millis(),
+            },
+            RequestTypes::Version => object!{
+                name: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+                protocol_version: PROTOCOL_VERSION,
+            },
+            RequestTypes::Status => self.relay_status.lock().expect("Relay status lock poisoned").to_json(connection),
+            RequestTypes::Config => self.relay_status.lock().expect("Relay status lock poisoned").config().clone(),
+            _ => object!{
+                error: format!("{:?} is not a query", query),
+            }
+        };
+        stream.write_message(response.dump().as_bytes())
+    }
+
+    /**
+     * @brief Register a read only observer. Observers can subscribe regardless of the state of the controlling session
+     */
+    fn subscribe(&mut self, stream: &mut TcpStream, addr: SocketAddr, request: &[u8]) -> Result<usize, Error> {
+        if !self.authenticate_request("SUBSCRIBE", request) {
+            stream.write_message(b"ERROR Unauthenticated")?;
+            return Err(Error::UnauthenticatedRequest);
+        }
+        match SubscriptionRequest::from_request(request) {
+            Ok(subscription) => {
+                let observer_addr = SocketAddr::new(addr.ip(), subscription.port);
+                let (reply_sender, reply_receiver) = channel();
+                self.udp_message_sender.send(UDPMessage::AddSubscriber(observer_addr, subscription.rate_hz, subscription.encoding, reply_sender)).expect("Should be able to send Message to UDP Socket from TCP Socket");
+                // Only acknowledged once the UDP thread has made room for the observer
+                match reply_receiver.recv_timeout(SUBSCRIBE_REPLY_TIMEOUT) {
+                    Ok(true) => stream.write_message(format!(
+                        "OK {} {} {} {}",
+                        subscription.port,
+                        subscription.rate_hz,
+                        subscription.encoding.as_str(),
+                        SUBSCRIPTION_LEASE.as_secs()
+                    ).as_bytes()),
+                    Ok(false) => stream.write_message(b"ERROR Too many observers"),
+                    Err(_) => stream.write_message(b"ERROR The pod state stream is unavailable")
+                }
+            },
+            Err(error) => stream.write_message(format!("ERROR {}", error).as_bytes())
+        }
+    }
+
+    fn unsubscribe(&mut self, stream: &mut TcpStream, addr: SocketAddr, request: &[u8]) -> Result<usize, Error> {
+        match subscription::port_from_request(request) {
+            Ok(port) => {
+                self.udp_message_sender.send(UDPMessage::RemoveSubscriber(SocketAddr::new(addr.ip(), port))).expect("Should be able to send Message to UDP Socket from TCP Socket");
+                stream.write_message(b"OK")
+            },
+            Err(error) => stream.write_message(format!("ERROR {}", error).as_bytes())
+        }
+    }
+
+    /**
+     * @brief Handle the FLASH requests. Flashing does not depend on the controlling session,
+     * but boards are only written while the pod is Resting or in LowVoltage.
+     */
+    fn handle_flash_request(&mut self, request_type: RequestTypes, stream: &mut TcpStream, request: &[u8]) -> Result<usize, Error> {
+        let command = match request_type {
+            RequestTypes::FlashScan => "FLASH SCAN",
+            RequestTypes::FlashUpload => "FLASH UPLOAD",
+            RequestTypes::FlashWrite => "FLASH WRITE",
+            RequestTypes::FlashCheck => "FLASH CHECK",
+            _ => {
+                let status = self.flash_status.lock().expect("Flash status lock poisoned").to_json();
+                return stream.write_message(status.dump().as_bytes());
+            }
+        };
+        let flash_message_sender = match &self.flash_message_sender {
+            Some(sender) => sender.clone(),
+            None => return stream.write_message(b"ERROR Flashing unavailable")
+        };
+        // The AUTH line of an upload signs the image, so it is checked once the image has been received
+        if !matches!(request_type, RequestTypes::FlashUpload) && !self.authenticate_request(command, request) {
+            stream.write_message(b"ERROR Unauthenticated")?;
+            return Err(Error::UnauthenticatedRequest);
+        }
+        match request_type {
+            RequestTypes::FlashScan => {
+                if !self.flash_status.lock().expect("Flash status lock poisoned").begin(FlashState::Scanning) {
+                    return stream.write_message(b"ERROR Flashing busy");
+                }
+                flash_message_sender.send(FlashMessage::Scan).expect("Should be able to send Message to Flash Thread from TCP Socket");
+                stream.write_message(b"OK")
+            },
+            RequestTypes::FlashUpload => self.receive_firmware_image(stream, request),
+            RequestTypes::FlashCheck => {
+                if self.relay_status.lock().expect("Relay status lock poisoned").firmware.manifest().is_none() {
+                    return stream.write_message(b"ERROR No firmware manifest");
+                }
+                if !self.flash_status.lock().expect("Flash status lock poisoned").begin(FlashState::Scanning) {
+                    return stream.write_message(b"ERROR Flashing busy");
+                }
+                flash_message_sender.send(FlashMessage::CheckFirmware).expect("Should be able to send Message to Flash Thread from TCP Socket");
+                stream.write_message(b"OK")
+            },
+            _ => {
+                let write = match FlashWriteRequest::from_request(request) {
+                    Ok(write) => write,
+                    Err(error) => return stream.write_message(format!("ERROR {}", error).as_bytes())
+                };
+                let image = match &self.firmware_image {
+                    Some(image) => image.clone(),
+                    None => return stream.write_message(b"ERROR No firmware image uploaded")
+                };
+                let refused = {
+                    // Locked in the same order as the CAN thread, which refuses states that prevent flashing once the write has begun
+                    let mut flash_status = self.flash_status.lock().expect("Flash status lock poisoned");
+                    let relay_status = self.relay_status.lock().expect("Relay status lock poisoned");
+                    if !flash::pod_state_allows_flashing(relay_status.pod_state) || !flash::pod_state_allows_flashing(relay_status.requested_pod_state) {
+                        Some(format!("ERROR Pod is in {:?}, flashing requires Resting or LowVoltage", relay_status.pod_state))
+                    } else if !flash_status.begin_write(write.short_device_id, write.start_address, image.len()) {
+                        Some(String::from("ERROR Flashing busy"))
+                    } else {
+                        None
+                    }
+                };
+                if let Some(error) = refused {
+                    return stream.write_message(error.as_bytes());
+                }
+                let response = format!("OK {} 0x{:08x} {}", write.short_device_id, write.start_address, image.len());
+                flash_message_sender.send(FlashMessage::Write {
+                    short_device_id: write.short_device_id,
+                    start_address: write.start_address,
+                    image
+                }).expect("Should be able to send Message to Flash Thread from TCP Socket");
+                stream.write_message(response.as_bytes())
+            }
+        }
+    }
+
+    /**
+     * @brief Read the rest of a FLASH UPLOAD request from the stream and store the image.
+     * Responds with the length and CRC-32 of the image so the uploader can check it was received intact.
+     */
+    fn receive_firmware_image(&mut self, stream: &mut TcpStream, request: &[u8]) -> Result<usize, Error> {
+        let mut request = request.to_vec();
+        while request.last() == Some(&0) {
+            request.pop(); // Remove the padding left by read_all
+        }
+        stream.set_nonblocking(false).map_err(Error::TcpSocketError)?;
+        stream.set_read_timeout(Some(UPLOAD_READ_TIMEOUT)).map_err(Error::TcpSocketError)?;
+        let mut buffer = vec![0; UPLOAD_CHUNK_SIZE];
+        let mut decode_at = 0; // Length of the request at which the image is decoded again
+        let mut max_request_length = 0;
+        let started = Instant::now();
+        let upload = loop {
+            if request.len() >= decode_at {
+                match FirmwareUpload::from_request(&request) {
+                    Ok(upload) if upload.is_complete() => break upload,
+                    Ok(upload) => {
+                        // Each byte of the image takes two hex digits, so there is no point decoding before then
+                        decode_at = 2 * upload.length;
+                        max_request_length = upload.max_request_length();
+                    },
+                    Err(error) => return stream.write_message(format!("ERROR {}", error).as_bytes())
+                }
+            }
+            if request.len() > max_request_length {
+                return stream.write_message(b"ERROR Upload is longer than LENGTH");
+            }
+            if started.elapsed() >= UPLOAD_TIMEOUT {
+                return stream.write_message(b"ERROR Upload timed out");
+            }
+            let bytes_read = stream.read(&mut buffer).map_err(Error::TcpSocketError)?;
+            if bytes_read == 0 {
+                return stream.write_message(b"ERROR Incomplete upload");
+            }
+            request.extend_from_slice(&buffer[..bytes_read]);
+        };
+        if !self.authenticate_request_body("FLASH UPLOAD", &request, &upload.image) {
+            stream.write_message(b"ERROR Unauthenticated")?;
+            return Err(Error::UnauthenticatedRequest);
+        }
+        let response = format!("OK {} {:08x}", upload.image.len(), crc32(&upload.image));
+        println!("TCP THREAD: Received a {} byte firmware image", upload.image.len());
+        self.firmware_image = Some(upload.image);
+        stream.write_message(response.as_bytes())
+    }
+
+    /**
+     * @brief Check the AUTH line which follows a state changing request when authentication is enabled
+     * @param command the request which is being authenticated
+     * @param request the rest of the request after the command line, which is signed with the command
+     */
+    fn authenticate_request(&mut self, command: &str, request: &[u8]) -> bool {
+        self.authenticate_request_body(command, request, &auth::tcp_request_body(request))
+    }
+
+    /**
+     * @brief Check the AUTH line of a request whose signed body is not the rest of the request, like an uploaded image
+     */
+    fn authenticate_request_body(&mut self, command: &str, request: &[u8], body: &[u8]) -> bool {
+        let authenticator = match &mut self.authenticator {
+            Some(authenticator) => authenticator,
+            None => return true
+        };
+        match authenticator.verify_tcp_request_body(command, request, body, chrono::Utc::now().timestamp_millis()) {
+            Ok(()) => true,
+            Err(err) => {
+                self.rejected_requests += 1;
+                println!("TCP THREAD: Rejected unauthenticated {} request: {:?}. {} requests rejected", command, err, self.rejected_requests);
+                false
+            }
         }
     }
 }
 
-/**
- * ALERT: Unsafe code!!!!!
- * Why it's safe:
- * std::mem::transmute takes one object and tells the compiler to treat it like another object. It is very easy to
- * screw this up when using pointers as the compiler would not be able to determine if the new types would be the same size upon dereference.
- * Thankfully, here we are only changing the meta data about the type. This means that the type itself is the same size and contains the same information.
- * We still need to be careful though!! The TcpWorker Struct is a generic type. Due to rules of Rust struct representation, two structs who differ only in
- * PhantomData types cannot be assumed to have the same layout in memory. This is why we opt to use the C representation of  the struct. While it is not as
- * efficient in terms of storage, it will always give us a consistent layout in memory which is important for these operations to work properly.
- */
 impl<State> TcpWorker<State> {
     #[allow(non_snake_case)]
     fn EnterRecovery(self) -> TcpWorker<Recovery> {
-        unsafe { std::mem::transmute::<TcpWorker<State>, TcpWorker<Recovery>>(self) }
+        self.into_state()
     }
     #[allow(non_snake_case)]
     fn EnterConnected(self) -> TcpWorker<Connected> {
-        unsafe { std::mem::transmute::<TcpWorker<State>, TcpWorker<Connected>>(self) }
+        self.into_state()
     }
     #[allow(non_snake_case)]
     fn EnterDisconnected(self) -> TcpWorker<Disconnected> {
-        unsafe { std::mem::transmute::<TcpWorker<State>, TcpWorker<Disconnected>>(self) }
+        self.into_state()
     }
 }
 
@@ -128,7 +397,9 @@ impl MainLoop<TcpWorkerState> for TcpWorker<Disconnected> {
             match message {
                 TcpMessage::EnteringRecovery => return TcpWorkerState::Recovery(self.EnterRecovery()),
                 TcpMessage::RecoveryComplete => return TcpWorkerState::Disconnected(self),
-                TcpMessage::UdpFailedToConnect => return TcpWorkerState::Disconnected(self)
+                TcpMessage::UdpFailedToConnect => return TcpWorkerState::Disconnected(self),
+                TcpMessage::UdpRestarted => return TcpWorkerState::Disconnected(self),
+                TcpMessage::Shutdown => return TcpWorkerState::Stopped
             }
         }
         // Check for incoming connections on TCP Socket
@@ -169,18 +440,50 @@ impl TcpWorker<Disconnected> {
         println!("Request: \n{}", std::str::from_utf8(&request).unwrap());
 
         match self.request_parser.strip_line_and_get_value(request.as_slice()) {
-            requests::RequestParserResult::Success((&value, _request)) => {
+            requests::RequestParserResult::Success((&value, request)) => {
                 match value {
                     RequestTypes::Connect => {
                         println!("Connection Attempt received");
+                        if !self.authenticate_request("CONNECT", request) {
+                            stream.write_message(b"ERROR Unauthenticated")?;
+                            return Err(Error::UnauthenticatedRequest);
+                        }
+                        let session_id = self.authenticator.as_mut().map(|authenticator| authenticator.new_session_id());
+                        let requested_encoding = MessageEncoding::from_request(request);
+                        let encoding = requested_encoding.unwrap_or_default();
+                        // Desktops which predate versioning do not negotiate an encoding. Until the desktop sends a versioned
+                        // message, it is answered in the format it understands
+                        let protocol_version = if requested_encoding.is_some() { PROTOCOL_VERSION } else { 0 };
                         addr.set_port(8090);
-                        self.udp_message_sender.send(UDPMessage::ConnectToDesktop(addr)).expect("Should be able to send Message to UDP Socket from TCP Socket");
-                        stream.write_message(b"OK 8090 8080")?;
+                        self.udp_message_sender.send(UDPMessage::ConnectToDesktop(addr, session_id.clone(), encoding, protocol_version)).expect("Should be able to send Message to UDP Socket from TCP Socket");
+                        let mut response = String::from("OK 8090 8080");
+                        if let Some(session_id) = session_id {
+                            response.push_str(&format!(" {}", session_id));
+                        }
+                        if requested_encoding.is_some() {
+                            response.push_str(&format!(" {}", encoding.as_str()));
+                        }
+                        stream.write_message(response.as_bytes())?;
                     },
                     RequestTypes::Disconnect => {
                         println!("TCP HANDLER: Received a disconnect request while not connected");
                         stream.write_message(b"DISCONNECTED")?;
                     },
+                    RequestTypes::Journal => {
+                        self.write_journal(&mut stream)?;
+                    },
+                    RequestTypes::Subscribe => {
+                        self.subscribe(&mut stream, addr, request)?;
+                    },
+                    RequestTypes::Unsubscribe => {
+                        self.unsubscribe(&mut stream, addr, request)?;
+                    },
+                    RequestTypes::Status | RequestTypes::Ping | RequestTypes::Version | RequestTypes::Config => {
+                        self.write_query_response(value, &mut stream, "disconnected")?;
+                    },
+                    RequestTypes::FlashScan | RequestTypes::FlashUpload | RequestTypes::FlashWrite | RequestTypes::FlashStatus | RequestTypes::FlashCheck => {
+                        self.handle_flash_request(value, &mut stream, request)?;
+                    },
                     RequestTypes::Unknown => {
                         println!("Received a Malformed Input");
                     }
@@ -203,7 +506,9 @@ impl MainLoop<TcpWorkerState> for TcpWorker<Connected> {
             match message {
                 TcpMessage::EnteringRecovery => return TcpWorkerState::Recovery(self.EnterRecovery()),
                 TcpMessage::RecoveryComplete => return TcpWorkerState::Disconnected(self.EnterDisconnected()),
-                TcpMessage::UdpFailedToConnect => return TcpWorkerState::Disconnected(self.EnterDisconnected())
+                TcpMessage::UdpFailedToConnect => return TcpWorkerState::Disconnected(self.EnterDisconnected()),
+                TcpMessage::UdpRestarted => return TcpWorkerState::Disconnected(self.EnterDisconnected()),
+                TcpMessage::Shutdown => return TcpWorkerState::Stopped
             }
         }
         // Check for incoming connections on TCP Socket
@@ -244,16 +549,35 @@ impl TcpWorker<Connected> {
         println!("Request: \n{}", std::str::from_utf8(&request).unwrap());
 
         match self.request_parser.strip_line_and_get_value(request.as_slice()) {
-            requests::RequestParserResult::Success((&value, _request)) => {
+            requests::RequestParserResult::Success((&value, request)) => {
                 match value {
                     RequestTypes::Connect => {
                         stream.write_message(b"ERROR POD Already Connected to Controller")?;
                     },
                     RequestTypes::Disconnect => {
                         println!("TCP THREAD: Disconnect Received");
+                        if !self.authenticate_request("DISCONNECT", request) {
+                            stream.write_message(b"ERROR Unauthenticated")?;
+                            return Err(Error::UnauthenticatedRequest);
+                        }
                         self.udp_message_sender.send(UDPMessage::DisconnectFromHost).expect("Should be able to send message to UDP socket");
                         stream.write_message(b"DISCONNECTED")?;
                     },
+                    RequestTypes::Journal => {
+                        self.write_journal(&mut stream)?;
+                    },
+                    RequestTypes::Subscribe => {
+                        self.subscribe(&mut stream, addr, request)?;
+                    },
+                    RequestTypes::Unsubscribe => {
+                        self.unsubscribe(&mut stream, addr, request)?;
+                    },
+                    RequestTypes::Status | RequestTypes::Ping | RequestTypes::Version | RequestTypes::Config => {
+                        self.write_query_response(value, &mut stream, "connected")?;
+                    },
+                    RequestTypes::FlashScan | RequestTypes::FlashUpload | RequestTypes::FlashWrite | RequestTypes::FlashStatus | RequestTypes::FlashCheck => {
+                        self.handle_flash_request(value, &mut stream, request)?;
+                    },
                     RequestTypes::Unknown => {
                         println!("Received a Malformed Input");
                     }
@@ -277,7 +601,9 @@ impl MainLoop<TcpWorkerState> for TcpWorker<Recovery> {
             match message {
                 TcpMessage::EnteringRecovery => return TcpWorkerState::Recovery(self.EnterRecovery()),
                 TcpMessage::RecoveryComplete => return TcpWorkerState::Disconnected(self.EnterDisconnected()),
-                TcpMessage::UdpFailedToConnect => {} // Continue in Recovery
+                TcpMessage::UdpFailedToConnect => {}, // Continue in Recovery
+                TcpMessage::UdpRestarted => return TcpWorkerState::Disconnected(self.EnterDisconnected()), // The new UDP thread is not recovering
+                TcpMessage::Shutdown => return TcpWorkerState::Stopped
             }
         }
         // Check for incoming connections on TCP Socket
@@ -315,7 +641,7 @@ impl TcpWorker<Recovery> {
         println!("Request: \n{}", std::str::from_utf8(&request).unwrap());
 
         match self.request_parser.strip_line_and_get_value(request.as_slice()) {
-            requests::RequestParserResult::Success((&value, _request)) => {
+            requests::RequestParserResult::Success((&value, request)) => {
                 match value {
                     RequestTypes::Connect => {
                         stream.write_message(b"ERROR POD Already Connected to Controller")?;
@@ -324,6 +650,21 @@ impl TcpWorker<Recovery> {
                         println!("TCP HANDLER: Received a disconnect request while not connected");
                         stream.write_message(b"DISCONNECTED")?;
                     },
+                    RequestTypes::Journal => {
+                        self.write_journal(&mut stream)?;
+                    },
+                    RequestTypes::Subscribe => {
+                        self.subscribe(&mut stream, addr, request)?;
+                    },
+                    RequestTypes::Unsubscribe => {
+                        self.unsubscribe(&mut stream, addr, request)?;
+                    },
+                    RequestTypes::Status | RequestTypes::Ping | RequestTypes::Version | RequestTypes::Config => {
+                        self.write_query_response(value, &mut stream, "recovery")?;
+                    },
+                    RequestTypes::FlashScan | RequestTypes::FlashUpload | RequestTypes::FlashWrite | RequestTypes::FlashStatus | RequestTypes::FlashCheck => {
+                        self.handle_flash_request(value, &mut stream, request)?;
+                    },
                     RequestTypes::Unknown => {
                         println!("Received a Malformed Input");
                     }
@@ -338,8 +679,3 @@ impl TcpWorker<Recovery> {
     }
 }
 
-impl MainLoop<TcpWorkerState> for TcpWorker<Startup> {
-    fn main_loop(self) -> TcpWorkerState {
-        panic!("This function is here for completeness but it should never be called");
-    }
-}
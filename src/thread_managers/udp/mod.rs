@@ -1,4 +1,6 @@
 mod worker;
 mod manager;
+mod subscribers;
 
 pub use manager::UdpManager;
+pub use worker::UdpWorkerInitializer;
@@ -0,0 +1,156 @@
+/**
+ * Book keeping for the read only observers which receive the pod state stream.
+ * Each subscriber receives messages at its own rate with its own sequence numbers,
+ * until its lease runs out without being renewed.
+ */
+use std::net::SocketAddr;
+use std::time::{ Duration, Instant };
+use crate::project_butterfree::udp::encoding::MessageEncoding;
+use crate::project_butterfree::udp::subscription::SUBSCRIPTION_LEASE;
+
+pub const MAX_SUBSCRIBERS: usize = 8;
+
+pub struct Subscriber {
+    pub addr: SocketAddr,
+    pub encoding: MessageEncoding,
+    period: Duration,
+    last_sent: Option<Instant>,
+    sequence: u32,
+    expires_at: Instant
+}
+
+impl Subscriber {
+    /**
+     * @brief Returns the next sequence number if a message is due for the subscriber at now
+     */
+    pub fn next_message(&mut self, now: Instant) -> Option<u32> {
+        if let Some(last_sent) = self.last_sent {
+            if now.duration_since(last_sent) < self.period {
+                return None;
+            }
+        }
+        self.last_sent = Some(now);
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+        Some(sequence)
+    }
+}
+
+#[derive(Default)]
+pub struct SubscriberList {
+    subscribers: Vec<Subscriber>
+}
+
+impl SubscriberList {
+    pub fn new() -> SubscriberList {
+        SubscriberList { subscribers: Vec::new() }
+    }
+
+    /**
+     * @brief Add a subscriber whose lease starts at now. An existing subscription for the same address
+     * is renewed with the new rate and encoding and keeps its sequence numbers.
+     * Returns false if the list is full once the expired subscriptions are removed.
+     */
+    pub fn add(&mut self, addr: SocketAddr, rate_hz: u32, encoding: MessageEncoding, now: Instant) -> bool {
+        let period = Duration::from_secs(1) / rate_hz.max(1);
+        let expires_at = now + SUBSCRIPTION_LEASE;
+        if let Some(subscriber) = self.subscribers.iter_mut().find(|subscriber| subscriber.addr == addr) {
+            subscriber.encoding = encoding;
+            subscriber.period = period;
+            subscriber.expires_at = expires_at;
+            return true;
+        }
+        self.expire(now);
+        if self.subscribers.len() >= MAX_SUBSCRIBERS {
+            return false;
+        }
+        self.subscribers.push(Subscriber {
+            addr,
+            encoding,
+            period,
+            last_sent: None,
+            sequence: 0,
+            expires_at
+        });
+        true
+    }
+
+    /**
+     * @brief Remove the subscribers whose lease ran out before now
+     * @return the addresses of the removed subscribers
+     */
+    pub fn expire(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let expired = self.subscribers.iter().filter(|subscriber| subscriber.expires_at <= now).map(|subscriber| subscriber.addr).collect();
+        self.subscribers.retain(|subscriber| subscriber.expires_at > now);
+        expired
+    }
+
+    pub fn remove(&mut self, addr: SocketAddr) -> bool {
+        let count = self.subscribers.len();
+        self.subscribers.retain(|subscriber| subscriber.addr != addr);
+        self.subscribers.len() != count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Subscriber> {
+        self.subscribers.iter_mut()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn subscriber_rate() {
+        let mut subscribers = SubscriberList::new();
+        subscribers.add(addr(9000), 10, MessageEncoding::Json, Instant::now());
+        let subscriber = subscribers.iter_mut().next().unwrap();
+        let start = Instant::now();
+        assert_eq!(subscriber.next_message(start), Some(0));
+        assert_eq!(subscriber.next_message(start + Duration::from_millis(50)), None);
+        assert_eq!(subscriber.next_message(start + Duration::from_millis(100)), Some(1));
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let mut subscribers = SubscriberList::new();
+        let now = Instant::now();
+        assert!(subscribers.add(addr(9000), 10, MessageEncoding::Json, now));
+        assert_eq!(subscribers.iter_mut().next().unwrap().next_message(now), Some(0));
+        assert!(subscribers.add(addr(9000), 20, MessageEncoding::Binary, now)); // Renews the existing subscription
+        assert_eq!(subscribers.iter_mut().count(), 1);
+        let subscriber = subscribers.iter_mut().next().unwrap();
+        assert_eq!(subscriber.encoding, MessageEncoding::Binary);
+        assert_eq!(subscriber.next_message(now + Duration::from_millis(50)), Some(1));
+        assert!(subscribers.remove(addr(9000)));
+        assert!(!subscribers.remove(addr(9000)));
+        assert!(subscribers.is_empty());
+    }
+
+    #[test]
+    fn subscriber_limit() {
+        let mut subscribers = SubscriberList::new();
+        let start = Instant::now();
+        for port in 0..MAX_SUBSCRIBERS as u16 {
+            assert!(subscribers.add(addr(9000 + port), 10, MessageEncoding::Json, start));
+        }
+        assert!(!subscribers.add(addr(8000), 10, MessageEncoding::Json, start));
+
+        // Only the subscriber which renewed its lease is kept, making room for new observers
+        let renewed = start + SUBSCRIPTION_LEASE / 2;
+        assert!(subscribers.add(addr(9000), 10, MessageEncoding::Json, renewed));
+        let expired = start + SUBSCRIPTION_LEASE;
+        assert!(subscribers.add(addr(8000), 10, MessageEncoding::Json, expired));
+        assert_eq!(subscribers.iter_mut().count(), 2);
+        assert_eq!(subscribers.expire(renewed + SUBSCRIPTION_LEASE), vec![addr(9000)]);
+        assert_eq!(subscribers.iter_mut().map(|subscriber| subscriber.addr).collect::<Vec<_>>(), vec![addr(8000)]);
+    }
+}
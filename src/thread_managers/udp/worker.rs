@@ -1,56 +1,97 @@
 use std::net::{
     UdpSocket,
+    SocketAddr
 };
 use chrono;
-use std::sync::mpsc::{
-    Sender,
-    Receiver
-};
-use std::time::Duration;
+use std::sync::mpsc::Sender;
+use std::time::{ Duration, Instant };
 use crate::{
     pod_data,
     pod_states::{
         PodState
     },
+    transition_journal::{ TransitionCause, TransitionRefusal },
     project_butterfree::udp::{
         pod_state_message::PodStateMessage,
-        desktop_state_message::DesktopStateMessage,
+        desktop_state_message::{ DesktopStateMessage, DesktopStateMessageError },
         errno::UdpErrno,
-        prelude::*
-    }
+        sequence::{ LinkStats, SequenceTracker, SequenceStatus },
+        encoding::MessageEncoding,
+        prelude::*,
+        PROTOCOL_VERSION
+    },
+    project_butterfree::auth::Authenticator,
+    device_watchdog::{ Device, DeviceHealth },
+    supervisor::ThreadRestart,
+    utils::hex
 };
 
 
+use super::subscribers::SubscriberList;
 use super::super::worker_states::*;
 use super::super::messages::*;
 use super::super::main_loop::*;
+use super::super::mailbox::Mailbox;
+
+const SUBSCRIBER_POLL_INTERVAL: Duration = Duration::from_millis(20);
+const MIN_WAIT: Duration = Duration::from_millis(1); // A read timeout of zero is not allowed
+
+pub struct UdpWorkerInitializer<A: std::net::ToSocketAddrs+std::fmt::Debug> {
+    pub can_sender: Sender<CanMessage>,
+    pub tcp_sender: Sender<TcpMessage>,
+    pub udp_receiver: Mailbox<UDPMessage>,
+    pub pod_state: PodState, // State of the pod when the worker starts, it may have been restarted by the supervisor
+    pub next_pod_state: PodState, // State the pod has been commanded to and which is acknowledged to the worker once it is reached
+    pub controller_timeout: Duration, // Time without a message from the desktop before it is considered lost
+    pub telemetry_period: Duration, // Time between PodStateMessages sent to the desktop
+    pub udp_max_malformed_packets: u32, // Number of consecutive malformed packets before the relay enters recovery
+    pub udp_address: A,
+    pub auth_key: Option<Vec<u8>>
+}
 
-#[repr(C)] // Required for type transmutations
-pub struct UdpWorker<State = Startup> {
+pub struct UdpWorkerData {
     udp_socket: UdpSocket,
     current_pod_state: PodState,
     next_pod_state: PodState,
     errno: UdpErrno,
-    timeout_counter: u32,
+    last_desktop_message: Instant,
+    controller_timeout: Duration,
+    telemetry_period: Duration,
+    next_publish: Instant,
     last_received_telemetry_timestamp: chrono::NaiveDateTime,
     current_pod_data: pod_data::PodData,
     current_telemetry_timestamp: chrono::NaiveDateTime,
+    device_states: Vec<(Device, DeviceHealth)>, // Latest snapshot of the watchdog
+    thread_restarts: Vec<ThreadRestart>,
+    refusal: Option<(PodState, TransitionRefusal)>, // Last transition the CAN thread refused, reported until a transition completes
     tcp_sender: Sender<TcpMessage>,
-    udp_message_receiver: Receiver<UDPMessage>,
+    udp_message_receiver: Mailbox<UDPMessage>,
     can_message_sender: Sender<CanMessage>,
-    udp_max_number_timeouts: u32,
-    state: std::marker::PhantomData<State>
+    malformed_packet_count: u32, // Consecutive malformed packets received from the desktop
+    udp_max_malformed_packets: u32,
+    outbound_sequence: u32,
+    inbound_sequence: SequenceTracker,
+    desktop_link_stats: Option<LinkStats>,
+    authenticator: Option<Authenticator>,
+    session_id: Option<String>,
+    encoding: MessageEncoding,
+    desktop_protocol_version: u8, // Version of the messages the desktop understands, messages sent to it use the same version
+    observer_socket: UdpSocket, // Unconnected socket used to send the pod state to read only observers
+    subscribers: SubscriberList
 }
 
+pub type UdpWorker<State = Startup> = Worker<UdpWorkerData, State>;
+
 impl UdpWorker<Connected> {
-    fn send_pod_state_message(&self) {
+    fn send_pod_state_message(&mut self) {
         // Send Message Back to Desktop
-        let pod_state_message = if self.current_telemetry_timestamp.timestamp() > self.last_received_telemetry_timestamp.timestamp() {
+        let sequence = self.next_outbound_sequence();
+        let pod_state_message = if self.desktop_needs_telemetry() {
             PodStateMessage::new(self.current_pod_state, self.next_pod_state, self.errno, &self.current_pod_data, self.current_telemetry_timestamp, false)
         } else {
             PodStateMessage::new_no_telemetry(self.current_pod_state, self.next_pod_state, self.errno, self.current_telemetry_timestamp, false)
-        };
-        match self.udp_socket.send_pod_state_message(&pod_state_message) {
+        }.sequenced(sequence, self.inbound_sequence.stats()).with_devices(self.device_states.clone()).with_restarts(self.thread_restarts.clone()).with_refusal(self.refusal.clone()).for_version(self.desktop_protocol_version);
+        match self.udp_socket.send_pod_state_message(&pod_state_message, self.encoding) {
             Ok(bytes_sent) => {
                 // println!("UDP THREAD: Sent {} to Desktop", bytes_sent);
             },
@@ -63,14 +104,15 @@ impl UdpWorker<Connected> {
 
 
 impl UdpWorker<Recovery> {
-    fn send_pod_state_message(&self) {
+    fn send_pod_state_message(&mut self) {
         // Send Message Back to Desktop
-        let pod_state_message = if self.current_telemetry_timestamp.timestamp() > self.last_received_telemetry_timestamp.timestamp() {
+        let sequence = self.next_outbound_sequence();
+        let pod_state_message = if self.desktop_needs_telemetry() {
             PodStateMessage::new(self.current_pod_state, self.next_pod_state, self.errno, &self.current_pod_data, self.current_telemetry_timestamp, true)
         } else {
             PodStateMessage::new_no_telemetry(self.current_pod_state, self.next_pod_state, self.errno, self.current_telemetry_timestamp, true)
-        };
-        match self.udp_socket.send_pod_state_message(&pod_state_message) {
+        }.sequenced(sequence, self.inbound_sequence.stats()).with_devices(self.device_states.clone()).with_restarts(self.thread_restarts.clone()).with_refusal(self.refusal.clone()).for_version(self.desktop_protocol_version);
+        match self.udp_socket.send_pod_state_message(&pod_state_message, self.encoding) {
             Ok(_bytes_sent) => {
                 // println!("UDP THREAD: Send {} to Desktop", bytes_sent);
             },
@@ -93,8 +135,84 @@ impl<State> UdpWorker<State> {
         }
     }
 
+    /**
+     * @brief Wait for a message from the other threads. Only blocks for SUBSCRIBER_POLL_INTERVAL
+     * when there are observers, so that they keep receiving the pod state
+     */
+    fn get_udp_receiver_message_or_timeout(&self) -> Option<UDPMessage> {
+        if self.subscribers.is_empty() {
+            return Some(self.get_udp_receiver_message_or_panic());
+        }
+        match self.udp_message_receiver.recv_timeout(SUBSCRIBER_POLL_INTERVAL) {
+            Ok(message) => Some(message),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => None,
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => panic!("Error Reading from UDP mpsc channel, Exiting")
+        }
+    }
+
+    /**
+     * @brief Subscribe or renew an observer. The TCP thread waits on reply to acknowledge the request
+     */
+    fn add_subscriber(&mut self, addr: SocketAddr, rate_hz: u32, encoding: MessageEncoding, reply: Sender<bool>) {
+        let subscribed = self.subscribers.add(addr, rate_hz, encoding, Instant::now());
+        if subscribed {
+            println!("UDP THREAD: Observer {:?} subscribed at {} Hz using {} encoding", addr, rate_hz, encoding.as_str());
+        } else {
+            println!("UDP THREAD: Unable to subscribe {:?}, too many observers", addr);
+        }
+        let _ = reply.send(subscribed); // The TCP thread may have given up waiting
+    }
+
+    fn remove_subscriber(&mut self, addr: SocketAddr) {
+        if self.subscribers.remove(addr) {
+            println!("UDP THREAD: Observer {:?} unsubscribed", addr);
+        }
+    }
+
+    /**
+     * @brief Send the pod state to every observer which is due for a message
+     */
+    fn publish_to_subscribers(&mut self, recovering: bool) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        for addr in self.subscribers.expire(now) {
+            println!("UDP THREAD: Observer {:?} did not renew its subscription", addr);
+        }
+        let pod_state_message = PodStateMessage::new(self.current_pod_state, self.next_pod_state, self.errno, &self.current_pod_data, self.current_telemetry_timestamp, recovering)
+            .with_devices(self.device_states.clone())
+            .with_restarts(self.thread_restarts.clone())
+            .with_refusal(self.refusal.clone());
+        let UdpWorkerData { subscribers, observer_socket, .. } = &mut **self;
+        for subscriber in subscribers.iter_mut() {
+            if let Some(sequence) = subscriber.next_message(now) {
+                let bytes = pod_state_message.clone().sequenced(sequence, LinkStats::default()).to_bytes(subscriber.encoding);
+                if let Err(error) = observer_socket.send_to(&bytes, subscriber.addr) {
+                    println!("UDP THREAD: Error sending to observer {:?}: {:?}", subscriber.addr, error);
+                }
+            }
+        }
+    }
+
+    /**
+     * @brief The TCP thread stops before this one when the relay shuts down, so it may no longer be listening
+     */
+    fn notify_tcp(&self, message: TcpMessage) {
+        let _ = self.tcp_sender.send(message);
+    }
+
     fn notify_recovery(&self) {
-        self.tcp_sender.send(TcpMessage::EnteringRecovery).expect("To be able to notify tcp thread that we are entering recovery mode");
+        self.notify_tcp(TcpMessage::EnteringRecovery);
+    }
+
+    /**
+     * @brief The last pod state message tells the desktop which state the pod was left in and that the relay is going away
+     */
+    fn prepare_shutdown_message(&mut self, pod_state: PodState) {
+        self.current_pod_state = pod_state;
+        self.next_pod_state = pod_state;
+        self.errno = UdpErrno::RelayShuttingDown;
     }
 
     fn invalid_transition_recognized(mut self) -> UdpWorker<Recovery> {
@@ -107,84 +225,237 @@ impl<State> UdpWorker<State> {
         self.last_received_telemetry_timestamp = timestamp;
     }
 
-    fn trigger_transition_to_new_state(&mut self, requested_state: PodState) {
-        self.can_message_sender.send(CanMessage::ChangeState(requested_state.clone())).expect("Should be able to Send a message to the Can thread from the UDP thread");
+    /**
+     * @brief Whether the telemetry is newer than the timestamp echoed by the desktop. Desktops which predate
+     * versioning echo the timestamp in seconds
+     */
+    fn desktop_needs_telemetry(&self) -> bool {
+        if self.desktop_protocol_version == 0 {
+            self.current_telemetry_timestamp.timestamp() > self.last_received_telemetry_timestamp.timestamp()
+        } else {
+            self.current_telemetry_timestamp.timestamp_millis() > self.last_received_telemetry_timestamp.timestamp_millis()
+        }
+    }
+
+    /**
+     * @brief Returns true when the next PodStateMessage is due and schedules the one after it.
+     * The schedule is kept on a fixed grid so that the rate does not drift with the loop timing
+     */
+    fn pod_state_message_due(&mut self, now: Instant) -> bool {
+        if now < self.next_publish {
+            return false;
+        }
+        let telemetry_period = self.telemetry_period;
+        self.next_publish += telemetry_period;
+        if self.next_publish <= now {
+            // Fell more than a period behind, skip the missed messages instead of sending a burst
+            self.next_publish = now + self.telemetry_period;
+        }
+        true
+    }
+
+    /**
+     * @brief How long the loop can wait for input before it has to publish again
+     */
+    fn time_until_next_publish(&self, now: Instant) -> Duration {
+        let mut wait = self.next_publish.saturating_duration_since(now);
+        if !self.subscribers.is_empty() {
+            wait = wait.min(SUBSCRIBER_POLL_INTERVAL);
+        }
+        wait.max(MIN_WAIT)
+    }
+
+    fn next_outbound_sequence(&mut self) -> u32 {
+        let sequence = self.outbound_sequence;
+        self.outbound_sequence = self.outbound_sequence.wrapping_add(1);
+        sequence
+    }
+
+    /**
+     * @brief Check the mac on a message from the desktop when authentication is enabled.
+     * Rejected messages are counted in the link stats which are reported back to the desktop.
+     */
+    fn authenticate_desktop_message(&mut self, desktop_state_message: &DesktopStateMessage) -> bool {
+        let authenticator = match &self.authenticator {
+            Some(authenticator) => authenticator,
+            None => return true
+        };
+        let session_id = self.session_id.as_deref().unwrap_or("");
+        let authenticated = match (&desktop_state_message.mac, desktop_state_message.sequence) {
+            (Some(mac), Some(_sequence)) => authenticator.verify(desktop_state_message.signing_payload(session_id).as_bytes(), mac),
+            _ => false // Without a sequence number there is no replay protection
+        };
+        if !authenticated {
+            self.inbound_sequence.record_unauthenticated();
+            println!("UDP THREAD: Rejected unauthenticated desktop message. {} rejected this session", self.inbound_sequence.stats().unauthenticated);
+        }
+        authenticated
+    }
+
+    /**
+     * @brief Track the sequence number of a message from the desktop.
+     * Returns false if the message is a duplicate or older than a message which was already received.
+     * Messages from desktops which predate sequence numbers are always accepted.
+     */
+    fn track_desktop_message(&mut self, desktop_state_message: &DesktopStateMessage) -> bool {
+        if let Some(link_stats) = desktop_state_message.link_stats {
+            let previously_dropped = self.desktop_link_stats.map(|stats| stats.dropped).unwrap_or(0);
+            if link_stats.dropped > previously_dropped {
+                println!("UDP THREAD: Desktop reports {} dropped pod state messages", link_stats.dropped);
+            }
+            self.desktop_link_stats = Some(link_stats);
+        }
+        if let Some(sequence) = desktop_state_message.sequence {
+            match self.inbound_sequence.track(sequence) {
+                SequenceStatus::Gap(gap) => {
+                    println!("UDP THREAD: {} desktop messages dropped before sequence {}", gap, sequence);
+                    true
+                },
+                SequenceStatus::InOrder => true,
+                status => {
+                    println!("UDP THREAD: Ignoring desktop message with sequence {}: {:?}", sequence, status);
+                    false
+                }
+            }
+        } else {
+            true
+        }
+    }
+
+    /**
+     * @brief Count and log a packet from the desktop which could not be decoded.
+     * The packet is otherwise ignored. Returns true once the number of consecutive malformed
+     * packets reaches the configured threshold.
+     */
+    fn handle_malformed_packet(&mut self, packet: &[u8], error: DesktopStateMessageError) -> bool {
+        self.malformed_packet_count += 1;
+        self.inbound_sequence.record_malformed();
+        if let UdpErrno::NoError = self.errno {
+            self.errno = UdpErrno::MalformedPacket;
+        }
+        println!(
+            "UDP THREAD: Ignoring malformed desktop packet ({} consecutive, {} this session): {:?}\n{}",
+            self.malformed_packet_count,
+            self.inbound_sequence.stats().malformed,
+            error,
+            hex::encode(packet)
+        );
+        self.malformed_packet_count >= self.udp_max_malformed_packets
+    }
+
+    fn clear_malformed_packets(&mut self) {
+        self.malformed_packet_count = 0;
+        if let UdpErrno::MalformedPacket = self.errno {
+            self.errno = UdpErrno::NoError;
+        }
+    }
+
+    /**
+     * @brief The CAN thread did not request the state. Stop waiting for it and tell the desktop why
+     */
+    fn transition_refused(&mut self, state: PodState, refusal: TransitionRefusal) {
+        println!("UDP THREAD: Transition to {:?} refused: {:?}", state, refusal);
+        if self.next_pod_state == state {
+            self.next_pod_state = self.current_pod_state;
+        }
+        // Other errors are not hidden by the refusal
+        if self.refusal.is_some() || matches!(self.errno, UdpErrno::NoError) {
+            self.errno = UdpErrno::from(&refusal);
+        }
+        self.refusal = Some((state, refusal));
+    }
+
+    /**
+     * @brief The pod reached the state the UDP thread was waiting for, so the last refusal no longer applies
+     */
+    fn transition_acknowledged(&mut self) {
+        self.current_pod_state = self.next_pod_state;
+        if let Some((_, refusal)) = self.refusal.take() {
+            if self.errno.to_byte() == UdpErrno::from(&refusal).to_byte() {
+                self.errno = UdpErrno::NoError;
+            }
+        }
+    }
+
+    fn trigger_transition_to_new_state(&mut self, requested_state: PodState, cause: TransitionCause) {
+        self.can_message_sender.send(CanMessage::ChangeState(requested_state, cause)).expect("Should be able to Send a message to the Can thread from the UDP thread");
         self.next_pod_state = requested_state;
     }
 
     pub fn new<A: std::net::ToSocketAddrs+std::fmt::Debug>(
-        can_sender: Sender<CanMessage>,
-        tcp_sender: Sender<TcpMessage>,
-        udp_receiver: Receiver<UDPMessage>,
-        udp_max_number_timeouts: u32,
-        udp_socket_read_timeout: Duration,
-        udp_address: A
+        initializer: UdpWorkerInitializer<A>
     ) -> UdpWorker<Startup> {
         let udp_socket = UdpSocket::bind("0.0.0.0:8080").expect("Unable to Bind to UDP Socket on: 0.0.0.0:8080");
-        // let udp_socket = UdpSocket::bind(&udp_address).expect(&format!("Unable to Bind to UDP Socket on: {:?}", &udp_address));
-        udp_socket.set_read_timeout(Some(udp_socket_read_timeout)).expect("Failed to set read timeout on udp_socket");
-        UdpWorker {
+        // let udp_socket = UdpSocket::bind(&initializer.udp_address).expect(&format!("Unable to Bind to UDP Socket on: {:?}", &initializer.udp_address));
+        let observer_socket = UdpSocket::bind("0.0.0.0:0").expect("Unable to Bind to UDP Socket for observers");
+        Worker::from_data(UdpWorkerData {
             udp_socket,
-            current_pod_state: PodState::LowVoltage, // *************  TODO Figure out what the initial Value for this should be
-            next_pod_state: PodState::LowVoltage, // *************  TODO Figure out what the initial Value for this should be
+            current_pod_state: initializer.pod_state,
+            next_pod_state: initializer.next_pod_state,
             errno: UdpErrno::NoError,
-            timeout_counter: 0,
+            last_desktop_message: Instant::now(),
+            controller_timeout: initializer.controller_timeout,
+            telemetry_period: initializer.telemetry_period,
+            next_publish: Instant::now(),
             last_received_telemetry_timestamp: chrono::Utc::now().naive_local(),
             current_pod_data: pod_data::PodData::new(),
             current_telemetry_timestamp: chrono::Utc::now().naive_local(),
-            tcp_sender: tcp_sender,
-            udp_message_receiver: udp_receiver,
-            can_message_sender: can_sender,
-            udp_max_number_timeouts,
-            state: std::marker::PhantomData
-        }
+            device_states: Vec::new(),
+            thread_restarts: Vec::new(),
+            refusal: None,
+            tcp_sender: initializer.tcp_sender,
+            udp_message_receiver: initializer.udp_receiver,
+            can_message_sender: initializer.can_sender,
+            malformed_packet_count: 0,
+            udp_max_malformed_packets: initializer.udp_max_malformed_packets,
+            outbound_sequence: 0,
+            inbound_sequence: SequenceTracker::new(),
+            desktop_link_stats: None,
+            authenticator: initializer.auth_key.map(Authenticator::new),
+            session_id: None,
+            encoding: MessageEncoding::Json,
+            desktop_protocol_version: PROTOCOL_VERSION,
+            observer_socket,
+            subscribers: SubscriberList::new()
+        })
     }
 
-    /**
-     * ALERT: Unsafe code!!!!!
-     * Why it's safe:
-     * std::mem::transmute takes one object and tells the compiler to treat it like another object. It is very easy to
-     * screw this up when using pointers as the compiler would not be able to determine if the new types would be the same size upon dereference.
-     * Thankfully, here we are only changing the meta data about the type. This means that the type itself is the same size and contains the same information.
-     * We still need to be careful though!! The TcpWorker Struct is a generic type. Due to rules of Rust struct representation, two structs who differ only in
-     * PhantomData types cannot be assumed to have the same layout in memory. This is why we opt to use the C representation of  the struct. While it is not as
-     * efficient in terms of storage, it will always give us a consistent layout in memory which is important for these operations to work properly
-     */
     #[allow(non_snake_case)]
     fn EnterRecovery(self) -> UdpWorker<Recovery> {
-        unsafe { std::mem::transmute(self) }
+        self.into_state()
     }
     #[allow(non_snake_case)]
     fn EnterConnected(self) -> UdpWorker<Connected> {
-        unsafe { std::mem::transmute(self) }
+        self.into_state()
     }
     #[allow(non_snake_case)]
     fn EnterDisconnected(self) -> UdpWorker<Disconnected> {
-        unsafe { std::mem::transmute(self) }
+        self.into_state()
     }
 }
 pub type UdpWorkerState = WorkerState<UdpWorker<Startup>, UdpWorker<Recovery>, UdpWorker<Connected>, UdpWorker<Disconnected>>;
 
 impl UdpWorkerState {
     pub fn new<A: std::net::ToSocketAddrs+std::fmt::Debug>(
-        can_sender: Sender<CanMessage>,
-        tcp_sender: Sender<TcpMessage>,
-        udp_receiver: Receiver<UDPMessage>,
-        udp_max_number_timeouts: u32,
-        udp_socket_read_timeout: Duration,
-        udp_address: A,
+        initializer: UdpWorkerInitializer<A>
     ) -> UdpWorkerState {
-        let worker: UdpWorker<Startup> = UdpWorker::<Startup>::new(can_sender, tcp_sender, udp_receiver, udp_max_number_timeouts, udp_socket_read_timeout, udp_address);
+        let worker: UdpWorker<Startup> = UdpWorker::<Startup>::new(initializer);
         UdpWorkerState::Startup(worker)
     }
 }
 
 impl MainLoop<UdpWorkerState> for UdpWorker<Startup> {
-    fn main_loop(self) ->  UdpWorkerState {
+    fn main_loop(mut self) ->  UdpWorkerState {
         match self.get_udp_receiver_message_or_panic() {
             UDPMessage::StartupComplete => {
                 UdpWorkerState::Disconnected(self.EnterDisconnected())
             },
+            UDPMessage::PodStateChangeAck => {
+                // A restarted worker may be waiting for the state commanded by the supervisor
+                self.transition_acknowledged();
+                UdpWorkerState::Startup(self)
+            },
+            UDPMessage::Shutdown(_) => UdpWorkerState::Stopped,
             message => {
                 println!("Received Message on UDP mpsc channel during Startup: {:?}", message);
                 UdpWorkerState::Startup(self)
@@ -195,24 +466,52 @@ impl MainLoop<UdpWorkerState> for UdpWorker<Startup> {
 
 impl MainLoop<UdpWorkerState> for UdpWorker<Disconnected> {
     fn main_loop(mut self) -> UdpWorkerState {
-        match self.get_udp_receiver_message_or_panic() {
-            UDPMessage::ConnectToDesktop(addr) => {
-                if let Ok(_) = self.udp_socket.connect(addr) {
-                    println!("UDP THREAD: Connected to addr: {:?}", addr);
-                    return UdpWorkerState::Connected(self.EnterConnected());
-                } else {
-                    println!("UDP THREAD: Unable to connect to {:?}", addr);
-                    self.tcp_sender.send(TcpMessage::UdpFailedToConnect).expect("To be able to message tcp thread");
+        if let Some(message) = self.get_udp_receiver_message_or_timeout() {
+            match message {
+                UDPMessage::ConnectToDesktop(addr, session_id, encoding, protocol_version) => {
+                    if self.udp_socket.connect(addr).is_ok() {
+                        println!("UDP THREAD: Connected to addr: {:?} using {} encoding and protocol version {}", addr, encoding.as_str(), protocol_version);
+                        self.encoding = encoding;
+                        self.desktop_protocol_version = protocol_version;
+                        self.inbound_sequence.reset();
+                        self.desktop_link_stats = None;
+                        self.malformed_packet_count = 0;
+                        self.session_id = session_id;
+                        self.last_desktop_message = Instant::now();
+                        self.next_publish = Instant::now();
+                        return UdpWorkerState::Connected(self.EnterConnected());
+                    } else {
+                        println!("UDP THREAD: Unable to connect to {:?}", addr);
+                        self.notify_tcp(TcpMessage::UdpFailedToConnect);
+                    }
+                },
+                UDPMessage::PodStateChangeAck => {
+                    // Kept up to date for observers and the next desktop session
+                    self.transition_acknowledged();
+                },
+                UDPMessage::TelemetryDataAvailable(new_data, timestamp) => {
+                    // Kept up to date for observers
+                    self.current_pod_data = new_data;
+                    self.current_telemetry_timestamp = timestamp;
+                },
+                UDPMessage::DeviceStates(device_states) => {
+                    self.device_states = device_states;
+                },
+                UDPMessage::ThreadRestarts(thread_restarts) => {
+                    self.thread_restarts = thread_restarts;
+                },
+                UDPMessage::SystemFault => {
+                    self.current_pod_state = PodState::SystemFailure;
+                },
+                UDPMessage::AddSubscriber(addr, rate_hz, encoding, reply) => self.add_subscriber(addr, rate_hz, encoding, reply),
+                UDPMessage::RemoveSubscriber(addr) => self.remove_subscriber(addr),
+                UDPMessage::Shutdown(_) => return UdpWorkerState::Stopped, // No desktop to tell
+                message => {
+                    println!("UDP THREAD: Received Message on UDP mpsc channel while Disconnected: {:?}", message);
                 }
-            },
-            UDPMessage::TelemetryDataAvailable(_data, _time) => {},
-            UDPMessage::SystemFault => {
-                self.current_pod_state = PodState::SystemFailure;
-            }
-            message => {
-                println!("UDP THREAD: Received Message on UDP mpsc channel while Disconnected: {:?}", message);
             }
         }
+        self.publish_to_subscribers(false);
         UdpWorkerState::Disconnected(self)
     }
 }
@@ -221,10 +520,10 @@ impl MainLoop<UdpWorkerState> for UdpWorker<Connected> {
     fn main_loop(mut self) -> UdpWorkerState {
         // Check for new Messages from other threads
         //println!("UDP THREAD MAINLOOP RUNNING FOR CONNECTED");
-        if let Ok(message) = self.udp_message_receiver.try_recv() {
+        while let Ok(message) = self.udp_message_receiver.try_recv() {
             match message {
                 UDPMessage::PodStateChangeAck => {
-                    self.current_pod_state = self.next_pod_state;
+                    self.transition_acknowledged();
                     if self.current_pod_state == PodState::AutoPilot {
                         {
                             let can_sender = self.can_message_sender.clone();
@@ -235,88 +534,125 @@ impl MainLoop<UdpWorkerState> for UdpWorker<Connected> {
                         }
                     }
                 },
+                UDPMessage::PodStateChangeRefused(state, refusal) => self.transition_refused(state, refusal),
                 UDPMessage::TelemetryDataAvailable(new_data, timestamp) => {
                     // println!("UDP Data Received: {}", timestamp);
                     self.current_pod_data = new_data;
                     self.current_telemetry_timestamp = timestamp;
                 },
+                UDPMessage::DeviceStates(device_states) => {
+                    self.device_states = device_states;
+                },
+                UDPMessage::ThreadRestarts(thread_restarts) => {
+                    self.thread_restarts = thread_restarts;
+                },
                 UDPMessage::DisconnectFromHost => {
                     self.send_pod_state_message();
                     return UdpWorkerState::Recovery(self.EnterRecovery());
                 },
                 UDPMessage::SystemFault => {
                     self.current_pod_state = PodState::SystemFailure;
-                }
+                },
+                UDPMessage::AddSubscriber(addr, rate_hz, encoding, reply) => self.add_subscriber(addr, rate_hz, encoding, reply),
+                UDPMessage::RemoveSubscriber(addr) => self.remove_subscriber(addr),
+                UDPMessage::Shutdown(pod_state) => {
+                    self.prepare_shutdown_message(pod_state);
+                    self.send_pod_state_message();
+                    return UdpWorkerState::Stopped;
+                },
                 unrecognized_message => {
                     panic!("UnExpected Message Received on UDP mpsc channel while in Connected State: {:?}", unrecognized_message);
                 }
             }
         }
         let mut socket_buffer = [0u8; 1024];
-        self.send_pod_state_message();
+        let now = Instant::now();
+        if self.pod_state_message_due(now) {
+            self.send_pod_state_message();
+        }
+        self.publish_to_subscribers(false);
+
+        // Wait for the desktop until the next message has to be published
+        let wait = self.time_until_next_publish(now);
+        self.udp_socket.set_read_timeout(Some(wait)).expect("Failed to set read timeout on udp_socket");
         match self.udp_socket.recv(&mut socket_buffer) {
-            Ok(_bytes_received) => {
+            Ok(bytes_received) => {
                 // When the POD Enters an Error State, we no longer need to follow the decision tree
                 // for where or not we can transition to a new state etc. The Only Goal For Error State is
                 // to hopefully keep the Rpi connected to the desktop long enough to tell the desktop that
                 // A failure was found and that the pod is working to shut down
                 // println!("UDP THREAD: {} Bytes Read", bytes_received);
                 if !self.current_pod_state.is_error_state() {
-                    if let Ok(desktop_state_message) = DesktopStateMessage::from_json_bytes(&socket_buffer) {
-                        // println!("Desktop State_Message: {:?}", desktop_state_message.requested_state);
-                        if desktop_state_message.requested_state == self.current_pod_state {
-                            if self.next_pod_state == self.current_pod_state {
-                                // println!("Case 1");
-                                self.handle_telemetry_timestamp(desktop_state_message.most_recent_timestamp);
-                            } else {
-                                // println!("Case 2");
-                                // println!("Current State: {:?}", self.current_pod_state);
-                                // println!("NEXT State: {:?}", self.next_pod_state);
-                                // println!("requested State: {:?}", desktop_state_message.requested_state);
-                                return UdpWorkerState::Recovery(self.invalid_transition_recognized());
+                    let packet = &socket_buffer[..bytes_received];
+                    let desktop_state_message = match DesktopStateMessage::from_bytes(packet, self.encoding) {
+                        Ok(desktop_state_message) => desktop_state_message,
+                        Err(error) => {
+                            if self.handle_malformed_packet(packet, error) {
+                                println!("UDP THREAD: Too many malformed packets received from the desktop, entering recovery");
+                                self.notify_recovery();
+                                return UdpWorkerState::Recovery(self.EnterRecovery());
                             }
+                            return UdpWorkerState::Connected(self);
+                        }
+                    };
+                    self.clear_malformed_packets();
+                    if !self.authenticate_desktop_message(&desktop_state_message) {
+                        return UdpWorkerState::Connected(self);
+                    }
+                    if !self.track_desktop_message(&desktop_state_message) {
+                        return UdpWorkerState::Connected(self);
+                    }
+                    // Only messages accepted in sequence show the desktop is alive, replayed ones do not
+                    self.last_desktop_message = Instant::now();
+                    self.desktop_protocol_version = desktop_state_message.protocol_version;
+                    // println!("Desktop State_Message: {:?}", desktop_state_message.requested_state);
+                    if desktop_state_message.requested_state == self.current_pod_state {
+                        if self.next_pod_state == self.current_pod_state {
+                            // println!("Case 1");
+                            self.handle_telemetry_timestamp(desktop_state_message.most_recent_timestamp);
                         } else {
-                            if self.current_pod_state.can_transition_to(&desktop_state_message.requested_state) {
-                                if desktop_state_message.requested_state == self.next_pod_state {
-                                    // println!("Case 3");
+                            // println!("Case 2");
+                            // println!("Current State: {:?}", self.current_pod_state);
+                            // println!("NEXT State: {:?}", self.next_pod_state);
+                            // println!("requested State: {:?}", desktop_state_message.requested_state);
+                            return UdpWorkerState::Recovery(self.invalid_transition_recognized());
+                        }
+                    } else {
+                        if self.current_pod_state.can_transition_to(&desktop_state_message.requested_state) {
+                            if desktop_state_message.requested_state == self.next_pod_state {
+                                // println!("Case 3");
+                                self.handle_telemetry_timestamp(desktop_state_message.most_recent_timestamp);
+                            } else {
+                                if self.current_pod_state == self.next_pod_state {
+                                    println!("Case 4");
+                                    println!("Current State: {:?}", self.current_pod_state);
+                                    println!("NEXT State: {:?}", self.next_pod_state);
+                                    println!("requested State: {:?}", desktop_state_message.requested_state);
+                                    self.trigger_transition_to_new_state(desktop_state_message.requested_state, TransitionCause::Desktop);
                                     self.handle_telemetry_timestamp(desktop_state_message.most_recent_timestamp);
                                 } else {
-                                    if self.current_pod_state == self.next_pod_state {
-                                        println!("Case 4");
-                                        println!("Current State: {:?}", self.current_pod_state);
-                                        println!("NEXT State: {:?}", self.next_pod_state);
-                                        println!("requested State: {:?}", desktop_state_message.requested_state);
-                                        self.trigger_transition_to_new_state(desktop_state_message.requested_state);
-                                        self.handle_telemetry_timestamp(desktop_state_message.most_recent_timestamp);
-                                    } else {
-                                        println!("Case 5");
-                                        return UdpWorkerState::Recovery(self.invalid_transition_recognized());
-                                    }
+                                    println!("Case 5");
+                                    return UdpWorkerState::Recovery(self.invalid_transition_recognized());
                                 }
-                            } else {
-                                println!("Case 6");
-                                return UdpWorkerState::Recovery(self.invalid_transition_recognized());
                             }
+                        } else {
+                            println!("Case 6");
+                            return UdpWorkerState::Recovery(self.invalid_transition_recognized());
                         }
-                    } else {
-                        // TODO: This needs to change once we've figured out how we want to handle an error
-                        println!("UDP THREAD: Unable to read: {:?}", std::str::from_utf8(&socket_buffer).expect("To be able to convert to utf"));
-                        panic!("UDP THREAD: Failed to Read DesktopStateMessage in UDP Handler while in Connected State");
                     }
-                    self.timeout_counter = 0;
                 } else {
                     // !! ERROR CASE
                     println!("UDP ERROR STATE");
-                    self.can_message_sender.send(CanMessage::ChangeState(PodState::SystemFailure)).unwrap();
+                    self.last_desktop_message = Instant::now();
+                    self.can_message_sender.send(CanMessage::ChangeState(PodState::SystemFailure, TransitionCause::Recovery)).unwrap();
                 }
             },
             Err(error) => {
                 match error.kind() {
                     std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock  => {
-                        println!("Udp Socket Timed Out while reading");
-                        self.timeout_counter += 1; // Move this to the timeout  portion of the error handler
-                        if self.timeout_counter >= self.udp_max_number_timeouts {
-                            // TODO Enter into Recovery. Assume Desktop Disconnected
+                        // Nothing from the desktop before the next publish, only lost once the controller timeout has passed
+                        if self.last_desktop_message.elapsed() >= self.controller_timeout {
+                            println!("UDP THREAD: No message from the desktop in {:?}, entering recovery", self.controller_timeout);
                             self.notify_recovery();
                             self.errno = UdpErrno::ControllerTimeout;
                             return UdpWorkerState::Recovery(self.EnterRecovery());
@@ -336,24 +672,42 @@ impl MainLoop<UdpWorkerState> for UdpWorker<Connected> {
 
 impl MainLoop<UdpWorkerState> for UdpWorker<Recovery> {
     fn main_loop(mut self) -> UdpWorkerState {
-        self.send_pod_state_message();
+        let now = Instant::now();
+        if self.pod_state_message_due(now) {
+            self.send_pod_state_message();
+        }
+        self.publish_to_subscribers(true);
         while let Ok(message) = self.udp_message_receiver.try_recv() {
             match message {
                 UDPMessage::PodStateChangeAck => {
-                    self.current_pod_state = self.next_pod_state;
+                    self.transition_acknowledged();
                     if self.current_pod_state.is_error_state() {
                         self.errno = UdpErrno::GeneralPodFailure;
                     }
                 },
+                UDPMessage::PodStateChangeRefused(state, refusal) => self.transition_refused(state, refusal),
                 UDPMessage::TelemetryDataAvailable(new_data, timestamp) => {
                     self.current_pod_data = new_data;
                     self.current_telemetry_timestamp = timestamp;
                 },
+                UDPMessage::DeviceStates(device_states) => {
+                    self.device_states = device_states;
+                },
+                UDPMessage::ThreadRestarts(thread_restarts) => {
+                    self.thread_restarts = thread_restarts;
+                },
                 UDPMessage::DisconnectFromHost => {
                 },
                 UDPMessage::SystemFault => {
                     self.current_pod_state = PodState::SystemFailure;
-                }
+                },
+                UDPMessage::AddSubscriber(addr, rate_hz, encoding, reply) => self.add_subscriber(addr, rate_hz, encoding, reply),
+                UDPMessage::RemoveSubscriber(addr) => self.remove_subscriber(addr),
+                UDPMessage::Shutdown(pod_state) => {
+                    self.prepare_shutdown_message(pod_state);
+                    self.send_pod_state_message();
+                    return UdpWorkerState::Stopped;
+                },
                 unrecognized_message => {
                     panic!("UnExpected Message Received on UDP mpsc channel while in Connected State: {:?}", unrecognized_message);
                 }
@@ -361,25 +715,25 @@ impl MainLoop<UdpWorkerState> for UdpWorker<Recovery> {
         }
         match self.current_pod_state {
             PodState::LowVoltage => {
-                self.tcp_sender.send(TcpMessage::RecoveryComplete).expect("To be able to send message");
+                self.notify_tcp(TcpMessage::RecoveryComplete);
                 return UdpWorkerState::Disconnected(self.EnterDisconnected());
             },
             PodState::Armed => {
                 // transision to lowVoltage
                 if self.next_pod_state != PodState::LowVoltage {
-                    self.trigger_transition_to_new_state(PodState::LowVoltage);
+                    self.trigger_transition_to_new_state(PodState::LowVoltage, TransitionCause::Recovery);
                 }
             },
             PodState::AutoPilot => {
                 //transition to braking
                 if self.next_pod_state != PodState::Braking {
-                    self.trigger_transition_to_new_state(PodState::Braking);
+                    self.trigger_transition_to_new_state(PodState::Braking, TransitionCause::Recovery);
                 }
             },
             PodState::Braking => {
                 // wait till regression to lv
                 if self.next_pod_state != PodState::LowVoltage {
-                    self.trigger_transition_to_new_state(PodState::LowVoltage)
+                    self.trigger_transition_to_new_state(PodState::LowVoltage, TransitionCause::Recovery)
                 }
             },
             PodState::SystemFailure => {},
@@ -387,6 +741,7 @@ impl MainLoop<UdpWorkerState> for UdpWorker<Recovery> {
                 println!("Pod state mising in recovery procedure: {:?}", state);
             }
         }
+        std::thread::sleep(self.time_until_next_publish(Instant::now()));
         UdpWorkerState::Recovery(self)
     }
 }
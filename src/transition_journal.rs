@@ -0,0 +1,325 @@
+/**
+ * @brief The transition journal is an append-only record of every pod state
+ * transition that the relay has requested. Each record keeps track of who asked
+ * for the transition, which boards acknowledged it and how long it took for the
+ * transition to complete.
+ *
+ * The journal is shared between the CAN thread, which drives the transitions, and
+ * the TCP thread, which allows the desktop to query the journal after a run.
+ */
+use std::fs::{ File, OpenOptions };
+use std::io::prelude::*;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use chrono::NaiveDateTime;
+use json::{ JsonValue, object };
+use crate::board_states::Board;
+use crate::pod_states::PodState;
+use crate::device_watchdog::Device;
+
+pub type SharedTransitionJournal = Arc<Mutex<TransitionJournal>>;
+
+/**
+ * The actor which requested a state transition
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionCause {
+    Desktop,
+    Watchdog,
+    BrakingTimer,
+    Recovery,
+    Shutdown,
+    Supervisor // A worker thread died and was restarted
+}
+
+impl TransitionCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransitionCause::Desktop      => "desktop",
+            TransitionCause::Watchdog     => "watchdog",
+            TransitionCause::BrakingTimer => "braking_timer",
+            TransitionCause::Recovery     => "recovery",
+            TransitionCause::Shutdown     => "shutdown",
+            TransitionCause::Supervisor   => "supervisor"
+        }
+    }
+}
+
+/**
+ * Why the CAN thread refused to request a state. The refusal is sent back to the UDP thread
+ * so that it stops waiting for the transition and can tell the desktop
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransitionRefusal {
+    MissingDevices(Vec<Device>), // Devices the state requires which are not present
+    FirmwareMismatch(Vec<u8>), // Short ids of the boards which do not match the firmware manifest
+    Flashing(u8) // Short id of the board which is being written
+}
+
+impl TransitionRefusal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransitionRefusal::MissingDevices(_)   => "missing_devices",
+            TransitionRefusal::FirmwareMismatch(_) => "firmware_mismatch",
+            TransitionRefusal::Flashing(_)         => "flashing"
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            TransitionRefusal::MissingDevices(_)   => 0x0,
+            TransitionRefusal::FirmwareMismatch(_) => 0x1,
+            TransitionRefusal::Flashing(_)         => 0x2
+        }
+    }
+
+    /**
+     * @brief The devices the refusal is about, as sent in the binary pod state message. Boards which do not match
+     * the firmware manifest are identified by their short id
+     */
+    pub fn device_bytes(&self) -> Vec<u8> {
+        match self {
+            TransitionRefusal::MissingDevices(devices) => devices.iter().map(Device::to_byte).collect(),
+            TransitionRefusal::FirmwareMismatch(short_device_ids) => short_device_ids.clone(),
+            TransitionRefusal::Flashing(short_device_id) => vec![*short_device_id]
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            TransitionRefusal::MissingDevices(devices) => object!{
+                reason: self.as_str(),
+                devices: devices.iter().map(|device| format!("{:?}", device)).collect::<Vec<String>>(),
+            },
+            TransitionRefusal::FirmwareMismatch(short_device_ids) => object!{
+                reason: self.as_str(),
+                short_device_ids: short_device_ids.clone(),
+            },
+            TransitionRefusal::Flashing(short_device_id) => object!{
+                reason: self.as_str(),
+                short_device_ids: vec![*short_device_id],
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionOutcome {
+    Pending,
+    Completed,
+    Superseded  // A new transition was requested before all of the boards acknowledged this one
+}
+
+impl TransitionOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransitionOutcome::Pending    => "pending",
+            TransitionOutcome::Completed  => "completed",
+            TransitionOutcome::Superseded => "superseded"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BoardAck {
+    pub board: Board,
+    pub elapsed: Duration
+}
+
+#[derive(Debug, Clone)]
+pub struct TransitionRecord {
+    pub from: PodState,
+    pub to: PodState,
+    pub cause: TransitionCause,
+    pub requested_at: NaiveDateTime,
+    pub board_acks: Vec<BoardAck>,
+    pub completed_in: Option<Duration>,
+    pub outcome: TransitionOutcome
+}
+
+impl From<&TransitionRecord> for JsonValue {
+    fn from(record: &TransitionRecord) -> JsonValue {
+        let board_acks: Vec<JsonValue> = record.board_acks.iter().map(|ack| object!{
+            board: ack.board.as_str(),
+            elapsed_ms: ack.elapsed.as_millis() as u64,
+        }).collect();
+        object!{
+            from: record.from.to_byte(),
+            to: record.to.to_byte(),
+            cause: record.cause.as_str(),
+            requested_at: record.requested_at.timestamp_millis(),
+            board_acks: board_acks,
+            completed_in_ms: record.completed_in.map(|duration| duration.as_millis() as u64),
+            outcome: record.outcome.as_str(),
+        }
+    }
+}
+
+pub struct TransitionJournal {
+    records: Vec<TransitionRecord>,
+    pending_since: Option<Instant>,
+    log_file: Option<File>
+}
+
+impl TransitionJournal {
+    pub fn new() -> TransitionJournal {
+        TransitionJournal {
+            records: Vec::new(),
+            pending_since: None,
+            log_file: None
+        }
+    }
+
+    /**
+     * @brief Creates a journal which also appends each finished record to the file at path.
+     * If the file can not be opened, the journal is only kept in memory.
+     */
+    pub fn with_log_file(path: &str) -> TransitionJournal {
+        let mut journal = TransitionJournal::new();
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => journal.log_file = Some(file),
+            Err(err) => println!("Unable to open transition journal file {}: {:?}", path, err)
+        }
+        journal
+    }
+
+    pub fn shared(self) -> SharedTransitionJournal {
+        Arc::new(Mutex::new(self))
+    }
+
+    /**
+     * @brief Record that a transition from `from` to `to` has been requested by `cause`.
+     * If a previous transition is still pending, it is marked as superseded.
+     */
+    pub fn begin(&mut self, from: PodState, to: PodState, cause: TransitionCause, requested_at: NaiveDateTime) {
+        self.finish_pending(TransitionOutcome::Superseded);
+        self.records.push(TransitionRecord {
+            from,
+            to,
+            cause,
+            requested_at,
+            board_acks: Vec::new(),
+            completed_in: None,
+            outcome: TransitionOutcome::Pending
+        });
+        self.pending_since = Some(Instant::now());
+    }
+
+    /**
+     * @brief Record that a board has acknowledged the pending transition.
+     * Repeated acknowledgements from the same board are ignored.
+     */
+    pub fn record_ack(&mut self, board: Board) {
+        if let Some(pending_since) = self.pending_since {
+            if let Some(record) = self.records.last_mut() {
+                if !record.board_acks.iter().any(|ack| ack.board == board) {
+                    record.board_acks.push(BoardAck { board, elapsed: pending_since.elapsed() });
+                }
+            }
+        }
+    }
+
+    /**
+     * @brief Mark the pending transition as completed
+     */
+    pub fn complete(&mut self) {
+        self.finish_pending(TransitionOutcome::Completed);
+    }
+
+    pub fn records(&self) -> &[TransitionRecord] {
+        &self.records
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::Array(self.records.iter().map(JsonValue::from).collect())
+    }
+
+    fn finish_pending(&mut self, outcome: TransitionOutcome) {
+        if let Some(pending_since) = self.pending_since.take() {
+            if let Some(record) = self.records.last_mut() {
+                record.outcome = outcome;
+                if outcome == TransitionOutcome::Completed {
+                    record.completed_in = Some(pending_since.elapsed());
+                }
+                if let Some(file) = &mut self.log_file {
+                    let line = JsonValue::from(&*record).dump() + "\n";
+                    if let Err(err) = file.write_all(line.as_bytes()) {
+                        println!("Unable to write to transition journal file: {:?}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for TransitionJournal {
+    fn default() -> Self {
+        TransitionJournal::new()
+    }
+}
+
+/********************
+ *      TESTS
+ ********************/
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device_watchdog::get_now;
+
+    #[test]
+    fn begin_ack_complete() {
+        let mut journal = TransitionJournal::new();
+        journal.begin(PodState::LowVoltage, PodState::Armed, TransitionCause::Desktop, get_now());
+        journal.record_ack(Board::Bms);
+        journal.record_ack(Board::Bms); /* Duplicate acks should not be recorded twice */
+        journal.record_ack(Board::Pressure);
+        journal.complete();
+
+        let records = journal.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].from, PodState::LowVoltage);
+        assert_eq!(records[0].to, PodState::Armed);
+        assert_eq!(records[0].cause, TransitionCause::Desktop);
+        assert_eq!(records[0].outcome, TransitionOutcome::Completed);
+        assert_eq!(records[0].board_acks.len(), 2);
+        assert!(records[0].completed_in.is_some());
+    }
+
+    #[test]
+    fn superseded_transition() {
+        let mut journal = TransitionJournal::new();
+        journal.begin(PodState::Armed, PodState::AutoPilot, TransitionCause::Desktop, get_now());
+        journal.begin(PodState::Armed, PodState::SystemFailure, TransitionCause::Watchdog, get_now());
+
+        let records = journal.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].outcome, TransitionOutcome::Superseded);
+        assert!(records[0].completed_in.is_none());
+        assert_eq!(records[1].outcome, TransitionOutcome::Pending);
+        assert_eq!(records[1].cause, TransitionCause::Watchdog);
+    }
+
+    #[test]
+    fn ack_without_pending_transition() {
+        let mut journal = TransitionJournal::new();
+        journal.record_ack(Board::Bms);
+        journal.complete();
+        assert_eq!(journal.records().len(), 0);
+    }
+
+    #[test]
+    fn journal_to_json() {
+        let mut journal = TransitionJournal::new();
+        journal.begin(PodState::AutoPilot, PodState::Braking, TransitionCause::BrakingTimer, get_now());
+        journal.record_ack(Board::MotorController);
+        journal.complete();
+
+        let json = journal.to_json();
+        assert_eq!(json.len(), 1);
+        assert_eq!(json[0]["from"], PodState::AutoPilot.to_byte());
+        assert_eq!(json[0]["to"], PodState::Braking.to_byte());
+        assert_eq!(json[0]["cause"], "braking_timer");
+        assert_eq!(json[0]["outcome"], "completed");
+        assert_eq!(json[0]["board_acks"][0]["board"], "motor_controller");
+    }
+}
@@ -3,16 +3,39 @@
  * of the last message received from each board over the can bus.
  * It will be configured with an expected period for the messages
  * and if twice that period is missed then a notification is sent.
+ *
+ * Each device has its own period, and each pod state can require a set of devices
+ * to have been heard from, and not be overdue, before the pod enters it.
+ *
+ * Each device moves between the states of DeviceHealth. A device is late once a period
+ * has been missed and lost once twice the period has been missed. The notification is only
+ * sent when a device becomes lost, and a lost device has to send RECOVERY_MESSAGES messages
+ * in a row before it is considered healthy again.
+ *
+ * Deadlines are measured with the monotonic clock, and the watchdog is evaluated on its own
+ * tick so that a silent bus is detected without waiting for the next CAN frame.
  * */
 
 use std::sync::mpsc::Sender;
 use std::convert::TryInto;
+use std::collections::HashMap;
 
 use std::time::{
+  Duration,
+  Instant,
   SystemTime,
   UNIX_EPOCH
 };
 use chrono::NaiveDateTime;
+use json::{ JsonValue, object };
+use crate::pod_states::PodState;
+
+/* Expected period of the messages from a device which has no period configured */
+pub const DEFAULT_PERIOD: i64 = 400;
+/* Number of missed periods before a device is lost */
+pub const LOST_PERIODS: i64 = 2;
+/* Consecutive messages, each within LOST_PERIODS of the last, for a recovered device to be healthy again */
+pub const RECOVERY_MESSAGES: u32 = 3;
 
 #[allow(non_camel_case_types)]
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
@@ -24,7 +47,171 @@ pub enum Device {
   PRESSURE_LOW_2,
   ELEKID,
   TORCHIC_1,
-  TORCHIC_2
+  TORCHIC_2,
+  ROBOTEQ /* Heard from through its SDO responses to the relay's queries and its CANopen heartbeat */
+}
+
+pub const ALL_DEVICES: [Device; 9] = [
+  Device::BMS,
+  Device::MC,
+  Device::PRESSURE_HIGH,
+  Device::PRESSURE_LOW_1,
+  Device::PRESSURE_LOW_2,
+  Device::ELEKID,
+  Device::TORCHIC_1,
+  Device::TORCHIC_2,
+  Device::ROBOTEQ
+];
+
+impl Device {
+  /* Devices are named as in their Debug representation, e.g. PRESSURE_HIGH */
+  pub fn from_name(name: &str) -> Option<Device> {
+    ALL_DEVICES.iter().copied().find(|device| format!("{:?}", device) == name)
+  }
+
+  /* Position of the device in ALL_DEVICES, used by the binary encoding of the pod state message */
+  pub fn to_byte(&self) -> u8 {
+    ALL_DEVICES.iter().position(|device| device == self).expect("Every device is in ALL_DEVICES") as u8
+  }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum DeviceHealth {
+  NeverSeen,
+  Healthy,
+  Late, /* Missed a period */
+  Lost, /* Missed LOST_PERIODS periods */
+  Recovered /* Sending messages again after being lost, but not for long enough to be healthy */
+}
+
+impl DeviceHealth {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      DeviceHealth::NeverSeen => "never_seen",
+      DeviceHealth::Healthy   => "healthy",
+      DeviceHealth::Late      => "late",
+      DeviceHealth::Lost      => "lost",
+      DeviceHealth::Recovered => "recovered"
+    }
+  }
+
+  pub fn to_byte(&self) -> u8 {
+    match self {
+      DeviceHealth::NeverSeen => 0x00,
+      DeviceHealth::Healthy   => 0x01,
+      DeviceHealth::Late      => 0x02,
+      DeviceHealth::Lost      => 0x03,
+      DeviceHealth::Recovered => 0x04
+    }
+  }
+
+  /* Devices which are healthy or only late can be relied on to enter a pod state */
+  pub fn is_available(&self) -> bool {
+    matches!(self, DeviceHealth::Healthy | DeviceHealth::Late)
+  }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct DeviceTransition {
+  pub device: Device,
+  pub from: DeviceHealth,
+  pub to: DeviceHealth
+}
+
+/* Devices which must be present before the pod enters each state */
+pub type RequiredDevices = HashMap<PodState, Vec<Device>>;
+
+/**
+ * @brief Watchdog configuration loaded with -wc
+ * ```text
+ *  {
+ *      "periods_ms": { "BMS": 100, "PRESSURE_HIGH": 50 },
+ *      "required_devices": { "Armed": ["BMS", "PRESSURE_HIGH"] }
+ *  }
+ * ```
+ * Devices without a period use DEFAULT_PERIOD. When required_devices is given, it replaces the default sets.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchdogConfig {
+  pub periods: HashMap<Device, i64>, /* Time in millis */
+  pub required_devices: RequiredDevices
+}
+
+fn pod_state_from_name(name: &str) -> Option<PodState> {
+  (0u8..=0x0B).map(PodState::from_byte).find(|state| format!("{:?}", state) == name)
+}
+
+fn parse_devices(devices: &JsonValue) -> Result<Vec<Device>, String> {
+  if !devices.is_array() {
+    return Err(String::from("Required devices must be a list of device names"));
+  }
+  devices.members()
+    .map(|device| device.as_str().and_then(Device::from_name).ok_or(format!("Unknown device {}", device)))
+    .collect()
+}
+
+impl Default for WatchdogConfig {
+  /* Every device uses DEFAULT_PERIOD, the pod can not be armed without the BMS and the pressure sensors
+   * and can not enter AutoPilot without the roboteq */
+  fn default() -> WatchdogConfig {
+    let mut required_devices = RequiredDevices::new();
+    required_devices.insert(PodState::Armed, vec![Device::BMS, Device::PRESSURE_HIGH, Device::PRESSURE_LOW_1, Device::PRESSURE_LOW_2]);
+    required_devices.insert(PodState::AutoPilot, vec![Device::ROBOTEQ]);
+    WatchdogConfig {
+      periods: ALL_DEVICES.iter().map(|device| (*device, DEFAULT_PERIOD)).collect(),
+      required_devices
+    }
+  }
+}
+
+impl WatchdogConfig {
+  pub fn from_json(config: &str) -> Result<WatchdogConfig, String> {
+    let config = json::parse(config).map_err(|err| format!("Invalid JSON: {}", err))?;
+    let mut watchdog_config = WatchdogConfig::default();
+    for (name, period) in config["periods_ms"].entries() {
+      let device = Device::from_name(name).ok_or(format!("Unknown device {}", name))?;
+      let period = period.as_i64().filter(|period| *period > 0).ok_or(format!("Invalid period for {}", name))?;
+      watchdog_config.periods.insert(device, period);
+    }
+    if !config["required_devices"].is_null() {
+      watchdog_config.required_devices = RequiredDevices::new();
+      for (name, devices) in config["required_devices"].entries() {
+        let state = pod_state_from_name(name).ok_or(format!("Unknown pod state {}", name))?;
+        watchdog_config.required_devices.insert(state, parse_devices(devices)?);
+      }
+    }
+    Ok(watchdog_config)
+  }
+
+  pub fn load(path: &str) -> Result<WatchdogConfig, String> {
+    let config = std::fs::read_to_string(path).map_err(|err| format!("Unable to read {}: {}", path, err))?;
+    WatchdogConfig::from_json(&config)
+  }
+
+  pub fn period(&self, device: Device) -> i64 {
+    self.periods.get(&device).copied().unwrap_or(DEFAULT_PERIOD)
+  }
+
+  /* Interval between evaluations of the watchdog, a quarter of the shortest period */
+  pub fn tick_interval(&self) -> Duration {
+    let shortest = ALL_DEVICES.iter().map(|device| self.period(*device)).min().unwrap_or(DEFAULT_PERIOD);
+    Duration::from_millis((shortest / 4).max(1) as u64)
+  }
+
+  pub fn to_json(&self) -> JsonValue {
+    let mut periods = JsonValue::new_object();
+    for device in ALL_DEVICES.iter() {
+      periods[format!("{:?}", device)] = self.period(*device).into();
+    }
+    let mut required_devices = JsonValue::new_object();
+    for (state, devices) in &self.required_devices {
+      required_devices[format!("{:?}", state)] = devices.iter().map(|device| format!("{:?}", device)).collect::<Vec<String>>().into();
+    }
+    object!{
+      periods_ms: periods,
+      required_devices: required_devices,
+    }
+  }
 }
 
 /* TODO: This might make more sense in a date time utils module, but for now this is the only relevant file that its needed in */
@@ -42,7 +229,11 @@ pub struct DeviceWatchdog<T> {
   sender: Sender<T>,
   notification: T,
   last_message: Option<NaiveDateTime>, /* If none, then no message has been received and assumed disconnected */
-  period: i64 /* Time in millis  */
+  last_seen: Option<Instant>, /* Monotonic time of last_message, used for the deadlines */
+  period: i64, /* Time in millis  */
+  health: DeviceHealth,
+  reported_health: DeviceHealth, /* Health when the last transition was reported */
+  recovery_messages: u32
 }
 
 
@@ -52,31 +243,99 @@ impl<T: Clone + Send> DeviceWatchdog<T> {
       sender,
       notification,
       last_message: None,
-      period
+      last_seen: None,
+      period,
+      health: DeviceHealth::NeverSeen,
+      reported_health: DeviceHealth::NeverSeen,
+      recovery_messages: 0
     }
   }
 
   pub fn update_last_message(&mut self, timestamp: NaiveDateTime) {
+    self.record_message(Instant::now(), timestamp);
+  }
+
+  /* Record a message received at the monotonic time at, with timestamp as its wall clock time */
+  pub fn record_message(&mut self, at: Instant, timestamp: NaiveDateTime) {
+    /* Catch a device which went quiet for longer than LOST_PERIODS between two checks */
+    self.evaluate(&at);
+    let on_time = matches!(self.elapsed(&at), Some(elapsed) if elapsed <= self.lost_after());
+    self.health = match self.health {
+      DeviceHealth::NeverSeen | DeviceHealth::Healthy | DeviceHealth::Late => DeviceHealth::Healthy,
+      DeviceHealth::Lost => {
+        self.recovery_messages = 1;
+        DeviceHealth::Recovered
+      },
+      DeviceHealth::Recovered => {
+        self.recovery_messages = if on_time { self.recovery_messages + 1 } else { 1 };
+        if self.recovery_messages >= RECOVERY_MESSAGES { DeviceHealth::Healthy } else { DeviceHealth::Recovered }
+      }
+    };
     self.last_message = Some(timestamp);
+    self.last_seen = Some(at);
   }
 
-  pub fn is_device_functioning(&self, now: &NaiveDateTime) -> bool {
-    if let Some(last_message) = self.last_message {
-      if now.signed_duration_since(last_message).num_milliseconds() > 2 * self.period {
-        self.notify();
-        false
-      } else {
-        true
+  pub fn health(&self) -> DeviceHealth {
+    self.health
+  }
+
+  pub fn last_message(&self) -> Option<NaiveDateTime> {
+    self.last_message
+  }
+
+  pub fn period(&self) -> i64 {
+    self.period
+  }
+
+  fn period_duration(&self) -> Duration {
+    Duration::from_millis(self.period as u64)
+  }
+
+  fn lost_after(&self) -> Duration {
+    self.period_duration() * LOST_PERIODS as u32
+  }
+
+  fn elapsed(&self, now: &Instant) -> Option<Duration> {
+    self.last_seen.map(|last_seen| now.saturating_duration_since(last_seen))
+  }
+
+  /* True once LOST_PERIODS periods have passed since the last message. A device which was never heard from is not overdue */
+  pub fn is_overdue(&self, now: &Instant) -> bool {
+    matches!(self.elapsed(now), Some(elapsed) if elapsed > self.lost_after())
+  }
+
+  /* Apply the transitions caused by the passing of time. The notification is sent when the device becomes lost */
+  fn evaluate(&mut self, now: &Instant) {
+    if let Some(elapsed) = self.elapsed(now) {
+      if elapsed > self.lost_after() {
+        if self.health != DeviceHealth::Lost {
+          self.health = DeviceHealth::Lost;
+          self.recovery_messages = 0;
+          self.notify();
+        }
+      } else if elapsed > self.period_duration() && self.health == DeviceHealth::Healthy {
+        self.health = DeviceHealth::Late;
       }
-    } else {
-      /* Vacuously True case. We set this true because the absence of a device on the bus should not impact the functionality of the rest of the pod.
-        This allows us to deprecate a device without updating or recompiling the code. On the other hand, adding a new device requires logic to handle that device,
-        so recompilation will be required either way.
-      */
-      true
     }
   }
 
+  /* Returns the change in health since the last call, if any */
+  pub fn update(&mut self, now: &Instant) -> Option<(DeviceHealth, DeviceHealth)> {
+    self.evaluate(now);
+    if self.health == self.reported_health {
+      return None;
+    }
+    let transition = (self.reported_health, self.health);
+    self.reported_health = self.health;
+    Some(transition)
+  }
+
+  pub fn is_device_functioning(&mut self, now: &Instant) -> bool {
+    /* A device which was never heard from is not lost. Pod states which need it list it in their required devices */
+    self.evaluate(now);
+    self.health != DeviceHealth::Lost
+  }
+
   fn notify(&self) {
     self.sender.send(self.notification.clone()).unwrap();
   }
@@ -91,8 +350,18 @@ pub trait DeviceWatchdogMapFuncs {
   fn with_all_devices(sender: Sender<Self::Notification>, notification: Self::Notification, period: i64)
   -> DeviceWatchdogMap<Self::Notification>;
 
+  /* Create a map with all devices, each with the period from the config */
+  fn with_config(sender: Sender<Self::Notification>, notification: Self::Notification, config: &WatchdogConfig)
+  -> DeviceWatchdogMap<Self::Notification>;
+
   /* Checks each of the devices timestamps. Returns a list of fail */
-  fn check_devices(&self) -> Vec<Device>;
+  fn check_devices(&mut self) -> Vec<Device>;
+
+  /* Checks each of the devices timestamps. Returns the devices whose health changed since the last call */
+  fn update_devices(&mut self, now: &Instant) -> Vec<DeviceTransition>;
+
+  /* Health of every device, in the order of ALL_DEVICES */
+  fn snapshot(&self) -> Vec<(Device, DeviceHealth)>;
 
   /* Update Device Time */
   fn update_device_timestamp(&mut self, device: Device, timestamp: NaiveDateTime);
@@ -104,28 +373,43 @@ impl<T: Send + Clone> DeviceWatchdogMapFuncs for DeviceWatchdogMap<T> {
   fn with_all_devices(sender: Sender<T>, notification: T, period: i64)
   -> DeviceWatchdogMap<T> {
     let mut map = DeviceWatchdogMap::<T>::new();
-    map.insert(Device::BMS, DeviceWatchdog::<T>::new(sender.clone(), notification.clone(), period));
-    map.insert(Device::MC, DeviceWatchdog::<T>::new(sender.clone(), notification.clone(), period));
-    map.insert(Device::PRESSURE_HIGH, DeviceWatchdog::<T>::new(sender.clone(), notification.clone(), period));
-    map.insert(Device::PRESSURE_LOW_1, DeviceWatchdog::<T>::new(sender.clone(), notification.clone(), period));
-    map.insert(Device::PRESSURE_LOW_2, DeviceWatchdog::<T>::new(sender.clone(), notification.clone(), period));
-    map.insert(Device::ELEKID, DeviceWatchdog::<T>::new(sender.clone(), notification.clone(), period));
-    map.insert(Device::TORCHIC_1, DeviceWatchdog::<T>::new(sender.clone(), notification.clone(), period));
-    map.insert(Device::TORCHIC_2, DeviceWatchdog::<T>::new(sender.clone(), notification.clone(), period));
+    for device in ALL_DEVICES.iter() {
+      map.insert(*device, DeviceWatchdog::<T>::new(sender.clone(), notification.clone(), period));
+    }
     map
   }
 
-  fn check_devices(&self) -> Vec<Device> {
-    let now = get_now();
+  fn with_config(sender: Sender<T>, notification: T, config: &WatchdogConfig)
+  -> DeviceWatchdogMap<T> {
+    let mut map = DeviceWatchdogMap::<T>::new();
+    for device in ALL_DEVICES.iter() {
+      map.insert(*device, DeviceWatchdog::<T>::new(sender.clone(), notification.clone(), config.period(*device)));
+    }
+    map
+  }
+
+  fn check_devices(&mut self) -> Vec<Device> {
+    let now = Instant::now();
     let mut result_vec: Vec<Device> = Vec::new();
     for (device, watchdog) in self {
       if !watchdog.is_device_functioning(&now) {
-        result_vec.push(device.clone());
+        result_vec.push(*device);
       }
     }
     result_vec
   }
 
+  fn update_devices(&mut self, now: &Instant) -> Vec<DeviceTransition> {
+    ALL_DEVICES.iter().filter_map(|device| {
+      let (from, to) = self.get_mut(device)?.update(now)?;
+      Some(DeviceTransition { device: *device, from, to })
+    }).collect()
+  }
+
+  fn snapshot(&self) -> Vec<(Device, DeviceHealth)> {
+    ALL_DEVICES.iter().filter_map(|device| self.get(device).map(|watchdog| (*device, watchdog.health()))).collect()
+  }
+
   fn update_device_timestamp(&mut self, device: Device, timestamp: NaiveDateTime) {
     self.get_mut(&device).expect("Device Missing from map. Consider checking the with_all_devices function to ensure the device is initialized.").update_last_message(timestamp);
   }
@@ -142,9 +426,9 @@ mod test {
     /* Vacuously true case  */
     /* Setup */
     let (sender, receiver) = std::sync::mpsc::channel::<u8>();
-    let dut = DeviceWatchdog::new(sender, 1, 200); /* 20 second period to ensure that the timing doesn't interfere with the test */
+    let mut dut = DeviceWatchdog::new(sender, 1, 200); /* 20 second period to ensure that the timing doesn't interfere with the test */
     /* Test */
-    assert!(dut.is_device_functioning(&get_now()));
+    assert!(dut.is_device_functioning(&Instant::now()));
     assert!(receiver.try_recv().is_err()); /* There should not be a notification here */
   }
 
@@ -156,7 +440,7 @@ mod test {
     let mut dut = DeviceWatchdog::new(sender, 1, 200); /* 20 second period to ensure that the timing doesn't interfere with the test */
     /* Test */
     dut.update_last_message(get_now());
-    assert!(dut.is_device_functioning(&get_now()));
+    assert!(dut.is_device_functioning(&Instant::now()));
     assert!(receiver.try_recv().is_err()); /* There should not be a notification here */
   }
 
@@ -169,10 +453,47 @@ mod test {
     dut.update_last_message(get_now());
     std::thread::sleep(std::time::Duration::from_millis(401)); /* Sleep just past hte 2x period time */
     assert!(receiver.try_recv().is_err()); /* There should not be a notification here */
-    assert!(!dut.is_device_functioning(&get_now()));
+    assert!(!dut.is_device_functioning(&Instant::now()));
     assert!(receiver.try_recv().is_ok()); /* There should  be a notification here */
   }
 
+  #[test]
+  fn is_device_functioning_3() {
+    /* The notification is only sent when the device becomes lost */
+    let (sender, receiver) = std::sync::mpsc::channel::<u8>();
+    let mut dut = DeviceWatchdog::new(sender, 1, 200);
+    let start = Instant::now();
+    dut.record_message(start, get_now());
+    assert!(!dut.is_device_functioning(&(start + Duration::from_millis(401))));
+    assert!(!dut.is_device_functioning(&(start + Duration::from_millis(500))));
+    assert!(receiver.try_recv().is_ok());
+    assert!(receiver.try_recv().is_err());
+  }
+
+  #[test]
+  fn device_health_0() {
+    /* Late, lost, then recovered and healthy again after RECOVERY_MESSAGES messages */
+    let (sender, receiver) = std::sync::mpsc::channel::<u8>();
+    let mut dut = DeviceWatchdog::new(sender, 1, 100);
+    let start = Instant::now();
+    let at = |millis: u64| start + Duration::from_millis(millis);
+    assert_eq!(dut.update(&at(0)), None);
+    dut.record_message(at(0), get_now());
+    assert_eq!(dut.update(&at(50)), Some((DeviceHealth::NeverSeen, DeviceHealth::Healthy)));
+    assert_eq!(dut.update(&at(150)), Some((DeviceHealth::Healthy, DeviceHealth::Late)));
+    assert_eq!(dut.update(&at(250)), Some((DeviceHealth::Late, DeviceHealth::Lost)));
+    assert_eq!(dut.update(&at(300)), None);
+
+    dut.record_message(at(1000), get_now());
+    assert_eq!(dut.update(&at(1000)), Some((DeviceHealth::Lost, DeviceHealth::Recovered)));
+    dut.record_message(at(1050), get_now());
+    assert_eq!(dut.health(), DeviceHealth::Recovered);
+    dut.record_message(at(1100), get_now());
+    assert_eq!(dut.update(&at(1100)), Some((DeviceHealth::Recovered, DeviceHealth::Healthy)));
+    assert!(receiver.try_recv().is_ok());
+    assert!(receiver.try_recv().is_err());
+  }
+
   #[test]
   fn check_devices_0() {
     /* Setup */
@@ -200,4 +521,40 @@ mod test {
 
   }
 
+  #[test]
+  fn with_config_0() {
+    /* Each device is checked against its own period */
+    let (sender, receiver) = std::sync::mpsc::channel::<u8>();
+    let config = WatchdogConfig::from_json(r#"{ "periods_ms": { "BMS": 100, "MC": 10000 } }"#).unwrap();
+    let mut device_watchdog_map_dut = DeviceWatchdogMap::with_config(sender, 1, &config);
+    assert_eq!(device_watchdog_map_dut[&Device::ELEKID].period(), DEFAULT_PERIOD);
+    assert!(device_watchdog_map_dut.snapshot().iter().all(|(_, health)| *health == DeviceHealth::NeverSeen));
+    device_watchdog_map_dut.update_device_timestamp(Device::BMS, get_now());
+    device_watchdog_map_dut.update_device_timestamp(Device::MC, get_now());
+    std::thread::sleep(std::time::Duration::from_millis(201)); /* Sleep just past the 2x period time of the BMS */
+    assert_eq!(device_watchdog_map_dut.check_devices(), vec![Device::BMS]);
+    assert!(receiver.try_recv().is_ok());
+    assert!(receiver.try_recv().is_err());
+  }
+
+  #[test]
+  fn watchdog_config_0() {
+    let config = WatchdogConfig::default();
+    assert_eq!(config.period(Device::TORCHIC_1), DEFAULT_PERIOD);
+    assert_eq!(config.tick_interval(), Duration::from_millis(100));
+    assert!(config.required_devices[&PodState::Armed].contains(&Device::BMS));
+    assert_eq!(config.required_devices[&PodState::AutoPilot], vec![Device::ROBOTEQ]);
+    assert_eq!(Device::from_name("ROBOTEQ"), Some(Device::ROBOTEQ));
+
+    let config = WatchdogConfig::from_json(r#"{ "required_devices": { "AutoPilot": ["MC"] } }"#).unwrap();
+    assert_eq!(config.required_devices.get(&PodState::Armed), None);
+    assert_eq!(config.required_devices[&PodState::AutoPilot], vec![Device::MC]);
+    assert_eq!(config.to_json()["required_devices"]["AutoPilot"][0], "MC");
+    assert_eq!(WatchdogConfig::from_json(r#"{ "periods_ms": { "BMS": 2 } }"#).unwrap().tick_interval(), Duration::from_millis(1));
+
+    assert!(WatchdogConfig::from_json(r#"{ "periods_ms": { "FLUX_CAPACITOR": 100 } }"#).is_err());
+    assert!(WatchdogConfig::from_json(r#"{ "periods_ms": { "BMS": 0 } }"#).is_err());
+    assert!(WatchdogConfig::from_json(r#"{ "required_devices": { "Flying": [] } }"#).is_err());
+  }
+
 }
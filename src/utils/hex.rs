@@ -0,0 +1,41 @@
+/**
+ * Helpers for converting bytes to and from hexadecimal strings
+ */
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/**
+ * @brief decode a hex string into bytes. Returns None if the string has an odd length
+ * or contains a character which is not a hex digit.
+ */
+#[allow(clippy::manual_is_multiple_of)]
+pub fn decode(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| hex.get(index..index + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let bytes = [0x00, 0x01, 0xab, 0xff];
+        assert_eq!(encode(&bytes), "0001abff");
+        assert_eq!(decode("0001abff"), Some(bytes.to_vec()));
+        assert_eq!(decode("0001ABFF\n"), Some(bytes.to_vec()));
+    }
+
+    #[test]
+    fn invalid_hex() {
+        assert_eq!(decode("abc"), None);
+        assert_eq!(decode("zz"), None);
+    }
+}
@@ -2,3 +2,4 @@ pub mod requests;
 pub mod stream_utils;
 pub mod device_watchdog;
 pub mod rpm_integrator;
+pub mod hex;
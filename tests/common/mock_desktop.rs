@@ -9,6 +9,7 @@ use std::net::{
 };
 use relay::pod_states::PodState;
 use relay::pod_data::PodData;
+use relay::project_butterfree::udp::PROTOCOL_VERSION;
 use relay::utils::device_watchdog::get_now;
 use std::thread;
 use std::sync::mpsc::{
@@ -47,7 +48,7 @@ impl<T: ToSocketAddrs> MockDesktop<T> {
 
   pub fn Connect(&mut self) -> Result<(), Box<dyn Error>> {
     /* Send Connect Request */
-    let tcp_stream = TcpStream::connect(self.relay_board_tcp_addr).unwrap();
+    let mut tcp_stream = TcpStream::connect(&self.relay_board_tcp_addr).unwrap();
     tcp_stream.write("CONNECT\r\n".as_bytes())?;
 
     /* Receive Response  */
@@ -69,10 +70,18 @@ impl<T: ToSocketAddrs> MockDesktop<T> {
 
     self.thread_handle = Some(std::thread::Builder::new().name("UDP Thread".to_string()).spawn(move || {
       let mut timestamp = get_now();
+      let mut sequence: u32 = 0;
       let mut recv_buf: [u8; 1024] = [0; 1024];
       // udp_socket.
       loop {
-        udp_socket.send(&object!{ "requested_state": PodState::LowVoltage.to_byte(), "most_recent_timestamp": timestamp.timestamp() }.dump().as_bytes());
+        udp_socket.send(&object!{
+          "protocol_version": PROTOCOL_VERSION,
+          "sender_id": 1,
+          "sequence": sequence,
+          "requested_state": PodState::LowVoltage.to_byte(),
+          "most_recent_timestamp": timestamp.timestamp_millis()
+        }.dump().as_bytes());
+        sequence += 1;
         match udp_socket.recv(&mut recv_buf) {
           Ok(_data_read) => {
             if let Ok(json_data) = json::parse(&String::from_utf8(recv_buf.to_vec()).unwrap()) {